@@ -1,12 +1,34 @@
-use clap::{Parser, Subcommand};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
+use clap::{CommandFactory, FromArgMatches, Parser, Subcommand};
+
+use disco_client::certs;
 use disco_client::client::RaftClient;
 use disco_client::command::{Bootstrap, Command};
 use disco_common::engine::*;
+use disco_common::provider::{AwsProvider, Provider};
+use disco_daemon::ca::{self, CertificateAuthority, Usage};
+use disco_daemon::protobuf::{
+  BackupMeta, LogId, MetricsResponse, Node, ReadConsistency, Role, SignCertificateRequest,
+};
+use disco_daemon::settings::Settings;
+use disco_daemon::store::backup::{self, BackupWriter};
+use disco_daemon::telemetry;
+use disco_daemon::tls;
+use tracing::Instrument;
 
 #[derive(Parser, Clone, Debug)]
 #[clap(author, version, about, long_about = None)]
 pub struct Opt {
+  /// Namespace of the keys, locks and elections, the default one if omitted
+  #[clap(long, short = 'n', global = true, default_value = "")]
+  pub namespace: String,
+
+  /// OTLP/gRPC endpoint to export traces to, off if omitted
+  #[clap(long, env = "DISCO_OTLP_ENDPOINT", global = true)]
+  pub otlp_endpoint: Option<String>,
+
   #[clap(subcommand)]
   pub command: SubCommand,
 }
@@ -19,9 +41,45 @@ pub enum SubCommand {
     #[clap(long)]
     addr: String,
 
+    /// Read consistency: linearizable, lease or stale
+    #[clap(long, default_value = "linearizable")]
+    consistency: String,
+
+    /// Revision to read at, the latest if omitted
+    #[clap(long)]
+    revision: Option<i64>,
+
     /// Key to look up
     key: String,
   },
+  /// Show the retained changes of a key
+  History {
+    /// Network address to connect with
+    #[clap(long)]
+    addr: String,
+
+    /// Key to show the changes of
+    key: String,
+  },
+  /// Discard the history before a revision
+  Compact {
+    /// Network address to connect with
+    #[clap(long)]
+    addr: String,
+
+    /// Oldest revision that remains readable
+    revision: i64,
+  },
+  /// List the keys starting with a prefix
+  Ls {
+    /// Network address to connect with
+    #[clap(long)]
+    addr: String,
+
+    /// Prefix of the keys to list, all keys if omitted
+    #[clap(default_value = "")]
+    prefix: String,
+  },
   /// Set a value for a key
   Set {
     /// Network address to connect with
@@ -33,35 +91,709 @@ pub enum SubCommand {
     /// Value to store
     value: String,
   },
+  /// Delete a key
+  Delete {
+    /// Network address to connect with
+    #[clap(long)]
+    addr: String,
+
+    /// Key to delete
+    key: String,
+  },
+  /// Watch the changes of keys starting with a prefix
+  Watch {
+    /// Network address to connect with
+    #[clap(long)]
+    addr: String,
+
+    /// Revision to replay changes from, 0 for new changes only
+    #[clap(long, default_value_t = 0)]
+    start_revision: i64,
+
+    /// Prefix of the keys to watch, all keys if omitted
+    #[clap(default_value = "")]
+    prefix: String,
+  },
+  /// Manage namespaces and their quotas
+  Namespace {
+    #[clap(subcommand)]
+    command: NamespaceCommand,
+  },
+  /// Manage the roles granted to client certificates
+  Policy {
+    #[clap(subcommand)]
+    command: PolicyCommand,
+  },
+  /// Show the Raft state of a node
+  Metrics {
+    /// Network address to connect with
+    #[clap(long)]
+    addr: String,
+
+    /// Keep printing the metrics each time they change
+    #[clap(long)]
+    watch: bool,
+  },
+  /// Save a backup of the state of the cluster to a file
+  Backup {
+    /// Network address to connect with
+    #[clap(long)]
+    addr: String,
+
+    /// S3 bucket to upload the backup to, under the name of the file
+    #[clap(long)]
+    bucket: Option<String>,
+
+    /// Region of the S3 bucket
+    #[clap(long, default_value = "us-east-1")]
+    region: String,
+
+    /// File to write the backup to
+    file: String,
+  },
+  /// Seed the empty data directory of a node with a backup, starting a new single-node cluster
+  Restore {
+    /// Id of the node
+    #[clap(long)]
+    id: u64,
+
+    /// Network address the node serves on
+    #[clap(long)]
+    addr: String,
+
+    /// Legacy identity in the certificates of the node, if not `localhost`
+    #[clap(long)]
+    tls_identity: Option<String>,
+
+    /// Data directory of the node
+    #[clap(long)]
+    data_dir: String,
+
+    /// S3 bucket to download the backup from, under the name of the file
+    #[clap(long)]
+    bucket: Option<String>,
+
+    /// Name of the cluster the bucket belongs to, from the settings if omitted
+    #[clap(long)]
+    cluster: Option<String>,
+
+    /// Region of the S3 bucket
+    #[clap(long, default_value = "us-east-1")]
+    region: String,
+
+    /// File holding the backup
+    file: String,
+  },
+  /// Manage the cluster certificate authority and the certificates of the nodes
+  Certs {
+    #[clap(subcommand)]
+    command: CertsCommand,
+  },
   /// Start the server
-  Bootstrap {},
+  Bootstrap {
+    /// Network address of a node, exposing its store to the script as `disco`
+    #[clap(long)]
+    addr: Option<String>,
+  },
+}
+
+#[derive(Subcommand, Clone, Debug)]
+pub enum NamespaceCommand {
+  /// List the namespaces that have keys or quotas, with their usage
+  Ls {
+    /// Network address to connect with
+    #[clap(long)]
+    addr: String,
+  },
+  /// Set the quotas of a namespace, creating it if needed
+  Set {
+    /// Network address to connect with
+    #[clap(long)]
+    addr: String,
+
+    /// Maximum number of keys, 0 for no limit
+    #[clap(long, default_value_t = 0)]
+    max_keys: i64,
+
+    /// Maximum total size of the keys and their values in bytes, 0 for no limit
+    #[clap(long, default_value_t = 0)]
+    max_bytes: i64,
+
+    /// Name of the namespace
+    name: String,
+  },
+  /// Delete a namespace along with all of its keys
+  Rm {
+    /// Network address to connect with
+    #[clap(long)]
+    addr: String,
+
+    /// Name of the namespace
+    name: String,
+  },
+}
+
+#[derive(Subcommand, Clone, Debug)]
+pub enum PolicyCommand {
+  /// List the policies
+  Ls {
+    /// Network address to connect with
+    #[clap(long)]
+    addr: String,
+  },
+  /// Grant a role to the client certificates of a subject, replacing its policy
+  Set {
+    /// Network address to connect with
+    #[clap(long)]
+    addr: String,
+
+    /// Role: reader, writer or admin
+    #[clap(long, default_value = "reader")]
+    role: String,
+
+    /// Prefix of the keys of the namespace the role applies to, every key if omitted; repeatable
+    #[clap(long)]
+    prefix: Vec<String>,
+
+    /// Common name in the subject of the certificates
+    subject: String,
+  },
+  /// Remove the policy of a subject, denying its certificates every request
+  Rm {
+    /// Network address to connect with
+    #[clap(long)]
+    addr: String,
+
+    /// Common name in the subject of the certificates
+    subject: String,
+  },
+}
+
+#[derive(Subcommand, Clone, Debug)]
+pub enum CertsCommand {
+  /// Create the cluster CA, writing ca.crt and ca.key
+  InitCa {
+    /// Name of the cluster, from the settings if omitted
+    #[clap(long)]
+    cluster: Option<String>,
+
+    /// Directory to write the CA to
+    #[clap(long, default_value = "certs")]
+    out_dir: PathBuf,
+  },
+  /// Issue the server and client certificates of a node with the CA key
+  Issue {
+    /// Name of the cluster, from the settings if omitted
+    #[clap(long)]
+    cluster: Option<String>,
+
+    /// Directory holding ca.crt and ca.key
+    #[clap(long, default_value = "certs")]
+    ca_dir: PathBuf,
+
+    /// Id of the node
+    #[clap(long)]
+    id: u64,
+
+    /// DNS name the server certificate is also valid for, besides the node URI; repeatable
+    #[clap(long)]
+    dns_name: Vec<String>,
+
+    /// Directory to write the certificates and keys of the node to, with a copy of ca.crt
+    #[clap(long)]
+    out_dir: PathBuf,
+  },
+  /// Issue a client certificate for a subject with the CA key, such as a read-only monitoring one
+  IssueClient {
+    /// Name of the cluster, from the settings if omitted
+    #[clap(long)]
+    cluster: Option<String>,
+
+    /// Directory holding ca.crt and ca.key
+    #[clap(long, default_value = "certs")]
+    ca_dir: PathBuf,
+
+    /// Common name in the subject of the certificate, which policies grant a role to
+    #[clap(long)]
+    subject: String,
+
+    /// Directory to write the certificate and key to, with a copy of ca.crt
+    #[clap(long)]
+    out_dir: PathBuf,
+  },
+  /// Create a token letting a new node have its certificates signed by the leader
+  Token {
+    /// Network address to connect with
+    #[clap(long)]
+    addr: String,
+
+    /// Time to live of the token in seconds
+    #[clap(long, default_value_t = 3600)]
+    ttl: i64,
+
+    /// Only node that may join with the token, any new node if omitted
+    #[clap(long)]
+    node_id: Option<u64>,
+  },
+  /// Generate the keys of a new node and have the leader sign its certificates with a token
+  Join {
+    /// Network address of a node of the cluster
+    #[clap(long)]
+    addr: String,
+
+    /// Join token created with `disco certs token`
+    #[clap(long, env = "DISCO_JOIN_TOKEN")]
+    token: String,
+
+    /// Id of the new node
+    #[clap(long)]
+    id: u64,
+
+    /// CA certificate of the cluster, to verify the node at the address against
+    #[clap(long)]
+    ca_cert: PathBuf,
+
+    /// Name of the cluster, from the settings if omitted
+    #[clap(long)]
+    cluster: Option<String>,
+
+    /// Directory to write the certificates and keys of the node to, with a copy of ca.crt
+    #[clap(long)]
+    out_dir: PathBuf,
+  },
+}
+
+/// Name of the cluster given on the command line, or else the one in the settings.
+fn cluster_name(cluster: Option<String>) -> Result<String, Box<dyn std::error::Error>> {
+  match cluster {
+    Some(cluster) => Ok(cluster),
+    None => Ok(Settings::new()?.cluster_name),
+  }
+}
+
+/// Connects to a node, scoped to the namespace given on the command line.
+async fn connect(addr: String, namespace: &str) -> Result<RaftClient, Box<dyn std::error::Error>> {
+  let client = RaftClient::new(addr).await?;
+  Ok(client.with_namespace(namespace.to_string()))
+}
+
+/// Name of a backup file in storage.
+fn storage_key(file: &str) -> Result<String, Box<dyn std::error::Error>> {
+  Ok(
+    Path::new(file)
+      .file_name()
+      .ok_or_else(|| format!("{} is not a file name", file))?
+      .to_string_lossy()
+      .into_owned(),
+  )
+}
+
+/// Prints where a backup comes from and what it holds.
+fn print_backup(meta: &BackupMeta) {
+  let snapshot = meta.snapshot.clone().unwrap_or_default();
+  let members = snapshot
+    .last_membership
+    .map(|membership| {
+      membership
+        .nodes
+        .values()
+        .map(|node| format!("{}@{}", node.node_id, node.rpc_addr))
+        .collect::<Vec<_>>()
+        .join(", ")
+    })
+    .unwrap_or_default();
+
+  println!("Cluster: {}", meta.cluster_name);
+  println!("Snapshot: {}", snapshot.snapshot_id);
+  if let Some(log_id) = snapshot.last_log_id {
+    println!("Last log id: {}-{}", log_id.term, log_id.index);
+  }
+  println!("Membership: {}", members);
+  println!("Size: {} bytes", snapshot.size);
+}
+
+fn print_metrics(metrics: &MetricsResponse) {
+  let log_id = |log_id: Option<LogId>| match log_id {
+    Some(log_id) => format!("{}-{}", log_id.term, log_id.index),
+    None => "none".to_string(),
+  };
+  let optional = |value: Option<u64>| value.map_or("none".to_string(), |v| v.to_string());
+
+  println!("Node: {}", metrics.node_id);
+  println!("State: {:?}", metrics.state());
+  println!("Term: {}", metrics.current_term);
+  println!("Leader: {}", optional(metrics.current_leader));
+  println!("Last log index: {}", optional(metrics.last_log_index));
+  println!("Last applied: {}", log_id(metrics.last_applied));
+  println!("Snapshot: {}", log_id(metrics.snapshot));
+  println!("Purged: {}", log_id(metrics.purged));
+  for replication in &metrics.replication {
+    println!(
+      "Replication to {}: matched {}, lag {}, heartbeat {} ms ago",
+      replication.node_id,
+      log_id(replication.matched),
+      replication.lag,
+      optional(replication.millis_since_heartbeat)
+    );
+  }
+  if !metrics.error.is_empty() {
+    println!("Error: {}", metrics.error);
+  }
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-  // Initialize tracing first, before any logging happens
-  tracing_subscriber::fmt()
-    .with_max_level(tracing::Level::INFO)
-    .with_file(true)
-    .with_line_number(true)
-    .init();
+  let matches = Opt::command().get_matches();
+  let options = Opt::from_arg_matches(&matches)?;
+
+  // Initialize tracing next, before any logging happens; spans are flushed when it is dropped
+  let _telemetry = telemetry::init("disco", options.otlp_endpoint.as_deref())?;
+
+  // Each invocation is traced as a whole, down to the requests it sends to the cluster
+  let span = tracing::info_span!("disco", command = matches.subcommand_name());
+  run(options).instrument(span).await
+}
 
-  let options = Opt::parse();
+async fn run(options: Opt) -> Result<(), Box<dyn std::error::Error>> {
+  let namespace = options.namespace;
 
   let engine = Engine::new(Some("client.js"))?;
 
   match options.command {
-    SubCommand::Get { addr, key } => {
-      let client = RaftClient::new(addr).await?;
-      let result = client.get_value(key).await?;
+    SubCommand::Get {
+      addr,
+      consistency,
+      revision: Some(revision),
+      key,
+    } => {
+      if consistency != "linearizable" {
+        return Err("Reads at a past revision are always linearizable".into());
+      }
+
+      let client = connect(addr, &namespace).await?;
+      let result = client.get_value_at_revision(key, revision).await?;
+      println!("Value: {:?}", result.map(|kv| kv.value));
+    }
+    SubCommand::Get {
+      addr,
+      consistency,
+      revision: None,
+      key,
+    } => {
+      let consistency = ReadConsistency::from_str_name(&consistency.to_uppercase())
+        .ok_or_else(|| format!("Unknown read consistency: {}", consistency))?;
+
+      let client = connect(addr, &namespace).await?;
+      let result = client.get_value_with_consistency(key, consistency).await?;
       println!("Value: {:?}", result);
     }
+    SubCommand::History { addr, key } => {
+      let client = connect(addr, &namespace).await?;
+      let history = client.history(key).await?;
+      for kv in history.revisions {
+        match kv.version {
+          0 => println!("{} DELETE", kv.mod_revision),
+          version => println!("{} v{} = {}", kv.mod_revision, version, kv.value),
+        }
+      }
+      println!("Compacted before revision: {}", history.compact_revision);
+    }
+    SubCommand::Compact { addr, revision } => {
+      let client = connect(addr, &namespace).await?;
+      let result = client.compact(revision).await?;
+      println!("Compacted: {:?}", result);
+    }
+    SubCommand::Ls { addr, prefix } => {
+      let client = connect(addr, &namespace).await?;
+      let (revision, kvs) = client.list(prefix).await?;
+      for kv in kvs {
+        println!("{} = {}", kv.key, kv.value);
+      }
+      println!("Revision: {}", revision);
+    }
     SubCommand::Set { addr, key, value } => {
-      let client = RaftClient::new(addr).await?;
+      let client = connect(addr, &namespace).await?;
       let result = client.set_value(key, value).await?;
       println!("Set result: {:?}", result);
     }
-    SubCommand::Bootstrap {} => {
+    SubCommand::Delete { addr, key } => {
+      let client = connect(addr, &namespace).await?;
+      let result = client.delete_value(key).await?;
+      println!("Previous value: {:?}", result);
+    }
+    SubCommand::Watch {
+      addr,
+      start_revision,
+      prefix,
+    } => {
+      let client = connect(addr, &namespace).await?;
+      let mut stream = client.watch(prefix, start_revision).await?;
+      while let Some(response) = stream.message().await? {
+        for event in response.events {
+          match event.value {
+            Some(value) => println!("{} PUT {} = {}", event.revision, event.key, value),
+            None => println!("{} DELETE {}", event.revision, event.key),
+          }
+        }
+      }
+    }
+    SubCommand::Namespace {
+      command: NamespaceCommand::Ls { addr },
+    } => {
+      let client = connect(addr, &namespace).await?;
+      for ns in client.list_namespaces().await? {
+        let limit = |max: i64| match max {
+          0 => "unlimited".to_string(),
+          max => max.to_string(),
+        };
+
+        println!(
+          "{:?}: {}/{} keys, {}/{} bytes",
+          ns.name,
+          ns.keys,
+          limit(ns.max_keys),
+          ns.bytes,
+          limit(ns.max_bytes)
+        );
+      }
+    }
+    SubCommand::Namespace {
+      command:
+        NamespaceCommand::Set {
+          addr,
+          max_keys,
+          max_bytes,
+          name,
+        },
+    } => {
+      let client = connect(addr, &namespace).await?;
+      let result = client.set_namespace(name, max_keys, max_bytes).await?;
+      println!("Set quotas: {:?}", result);
+    }
+    SubCommand::Namespace {
+      command: NamespaceCommand::Rm { addr, name },
+    } => {
+      let client = connect(addr, &namespace).await?;
+      let result = client.delete_namespace(name).await?;
+      println!("Deleted: {:?}", result);
+    }
+    SubCommand::Policy {
+      command: PolicyCommand::Ls { addr },
+    } => {
+      let client = connect(addr, &namespace).await?;
+      for policy in client.list_policies().await? {
+        let keys = if policy.prefixes.is_empty() {
+          "every key".to_string()
+        } else {
+          policy
+            .prefixes
+            .iter()
+            .map(|prefix| format!("{:?} in {:?}", prefix.prefix, prefix.namespace))
+            .collect::<Vec<_>>()
+            .join(", ")
+        };
+
+        println!("{:?}: {:?} on {}", policy.subject, policy.role(), keys);
+      }
+    }
+    SubCommand::Policy {
+      command:
+        PolicyCommand::Set {
+          addr,
+          role,
+          prefix,
+          subject,
+        },
+    } => {
+      let role = Role::from_str_name(&role.to_uppercase())
+        .ok_or_else(|| format!("Unknown role: {}", role))?;
+
+      let client = connect(addr, &namespace).await?;
+      let result = client.set_policy(subject, role, prefix).await?;
+      println!("Set policy: {:?}", result);
+    }
+    SubCommand::Policy {
+      command: PolicyCommand::Rm { addr, subject },
+    } => {
+      let client = connect(addr, &namespace).await?;
+      let result = client.delete_policy(subject).await?;
+      println!("Deleted: {:?}", result);
+    }
+    SubCommand::Metrics { addr, watch } => {
+      let client = connect(addr, &namespace).await?;
+      if watch {
+        let mut stream = client.watch_metrics().await?;
+        while let Some(metrics) = stream.message().await? {
+          print_metrics(&metrics);
+          println!();
+        }
+      } else {
+        print_metrics(&client.metrics().await?);
+      }
+    }
+    SubCommand::Backup {
+      addr,
+      bucket,
+      region,
+      file,
+    } => {
+      let client = connect(addr, &namespace).await?;
+      let mut stream = client.backup().await?;
+
+      let meta = stream
+        .message()
+        .await?
+        .and_then(|response| response.into_meta())
+        .ok_or("Backup stream does not start with its meta")?;
+
+      let mut writer = BackupWriter::create(&file, &meta).await?;
+      while let Some(response) = stream.message().await? {
+        let chunk = response
+          .into_data_chunk()
+          .ok_or("Unexpected meta in backup stream")?;
+        writer.write(&chunk).await?;
+      }
+      writer.finish().await?;
+
+      print_backup(&meta);
+
+      if let Some(bucket) = bucket {
+        let key = storage_key(&file)?;
+        let provider = AwsProvider::new(meta.cluster_name.clone(), region).await?;
+        provider
+          .upload_file_to_storage(&bucket, &file, &key)
+          .await?;
+        println!("Uploaded to s3://{}/{}", bucket, key);
+      }
+    }
+    SubCommand::Restore {
+      id,
+      addr,
+      tls_identity,
+      data_dir,
+      bucket,
+      cluster,
+      region,
+      file,
+    } => {
+      if let Some(bucket) = bucket {
+        let key = storage_key(&file)?;
+        let provider = AwsProvider::new(cluster_name(cluster)?, region).await?;
+        provider
+          .download_file_from_storage(&bucket, &file, &key)
+          .await?;
+        println!("Downloaded s3://{}/{}", bucket, key);
+      }
+
+      let node = Node {
+        node_id: id,
+        rpc_addr: addr,
+        tls_identity: tls_identity.unwrap_or_default(),
+      };
+      // Encrypted at rest like the node would, from the same settings
+      let keyring = Settings::new()?.keyring()?;
+      let meta = backup::restore(&file, &data_dir, node, keyring).await?;
+
+      print_backup(&meta);
+      println!("Restored into {}", data_dir);
+    }
+    SubCommand::Certs {
+      command: CertsCommand::InitCa { cluster, out_dir },
+    } => {
+      let authority = CertificateAuthority::create(&cluster_name(cluster)?)?;
+      certs::write(&out_dir, "ca.crt", &authority.cert_pem(), false)?;
+      certs::write(&out_dir, "ca.key", &authority.key_pem(), true)?;
+    }
+    SubCommand::Certs {
+      command:
+        CertsCommand::Issue {
+          cluster,
+          ca_dir,
+          id,
+          dns_name,
+          out_dir,
+        },
+    } => {
+      let cluster = cluster_name(cluster)?;
+      let ca_cert = std::fs::read_to_string(ca_dir.join("ca.crt"))?;
+      let ca_key = std::fs::read_to_string(ca_dir.join("ca.key"))?;
+      let authority = CertificateAuthority::load(&ca_cert, &ca_key)?;
+
+      let server = authority.issue(&cluster, id, Usage::Server, &dns_name)?;
+      let client = authority.issue(&cluster, id, Usage::Client, &[])?;
+
+      certs::write(&out_dir, "ca.crt", &ca_cert, false)?;
+      certs::write(&out_dir, "server.crt", &server.cert_pem, false)?;
+      certs::write(&out_dir, "server.key", &server.key_pem, true)?;
+      certs::write(&out_dir, "client.crt", &client.cert_pem, false)?;
+      certs::write(&out_dir, "client.key", &client.key_pem, true)?;
+      println!("TLS identity: {}", tls::node_uri(&cluster, id));
+    }
+    SubCommand::Certs {
+      command:
+        CertsCommand::IssueClient {
+          cluster,
+          ca_dir,
+          subject,
+          out_dir,
+        },
+    } => {
+      let cluster = cluster_name(cluster)?;
+      let ca_cert = std::fs::read_to_string(ca_dir.join("ca.crt"))?;
+      let ca_key = std::fs::read_to_string(ca_dir.join("ca.key"))?;
+      let authority = CertificateAuthority::load(&ca_cert, &ca_key)?;
+
+      let client = authority.issue_client(&cluster, &subject)?;
+
+      certs::write(&out_dir, "ca.crt", &ca_cert, false)?;
+      certs::write(&out_dir, "client.crt", &client.cert_pem, false)?;
+      certs::write(&out_dir, "client.key", &client.key_pem, true)?;
+    }
+    SubCommand::Certs {
+      command: CertsCommand::Token { addr, ttl, node_id },
+    } => {
+      let client = connect(addr, &namespace).await?;
+      let response = client.create_join_token(ttl, node_id).await?;
+      println!("Token: {}", response.token);
+      println!("Lease: {}", response.lease_id);
+    }
+    SubCommand::Certs {
+      command:
+        CertsCommand::Join {
+          addr,
+          token,
+          id,
+          ca_cert,
+          cluster,
+          out_dir,
+        },
+    } => {
+      // The leader names the node in the certificates, whatever the requests ask for
+      let cluster = cluster_name(cluster)?;
+      let (server_csr, server_key) = ca::request(&cluster, id)?;
+      let (client_csr, client_key) = ca::request(&cluster, id)?;
+
+      let request = SignCertificateRequest {
+        token,
+        node_id: id,
+        server_csr,
+        client_csr,
+      };
+      let response = certs::sign(&addr, &std::fs::read(&ca_cert)?, request).await?;
+
+      certs::write(&out_dir, "ca.crt", &response.ca_certificate, false)?;
+      certs::write(&out_dir, "server.crt", &response.server_certificate, false)?;
+      certs::write(&out_dir, "server.key", &server_key, true)?;
+      certs::write(&out_dir, "client.crt", &response.client_certificate, false)?;
+      certs::write(&out_dir, "client.key", &client_key, true)?;
+      println!("TLS identity: {}", response.tls_identity);
+    }
+    SubCommand::Bootstrap { addr } => {
+      if let Some(addr) = addr {
+        let client = connect(addr, &namespace).await?;
+        engine.attach_store(Arc::new(client)).await?;
+      }
+
       let _ = Bootstrap::new(engine).run().await?;
     }
   }
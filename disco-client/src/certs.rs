@@ -0,0 +1,76 @@
+//! Certificates of the nodes, issued by the cluster CA.
+
+use std::fs;
+use std::io;
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use std::time::Duration;
+
+use disco_daemon::grpc::leader::LEADER_ADDR_METADATA;
+use disco_daemon::protobuf::app_service_client::AppServiceClient;
+use disco_daemon::protobuf::{SignCertificateRequest, SignCertificateResponse};
+use disco_daemon::tls::TlsClient;
+use tracing::info;
+
+/// Longest time spent dialing a node.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How many times the request follows a redirect to the leader before giving up.
+const MAX_REDIRECTS: usize = 3;
+
+/// Has the leader sign the certificate requests of a joining node.
+///
+/// The node has no certificate yet, so it only checks that the node at `addr` has one issued by
+/// the CA in `ca_cert`. Requests reaching a follower are redirected to the leader.
+pub async fn sign(
+  addr: &str,
+  ca_cert: &[u8],
+  request: SignCertificateRequest,
+) -> Result<SignCertificateResponse, Box<dyn std::error::Error>> {
+  let tls = TlsClient::anonymous(ca_cert)?;
+  let mut addr = addr
+    .split_once("://")
+    .map_or(addr, |(_, addr)| addr)
+    .to_string();
+
+  for _ in 0..=MAX_REDIRECTS {
+    let channel = tls
+      .connect_any(&addr, CONNECT_TIMEOUT)
+      .await
+      .map_err(|e| format!("Failed to connect to {}: {}", addr, e))?;
+
+    let status = match AppServiceClient::new(channel)
+      .sign_certificate(request.clone())
+      .await
+    {
+      Ok(response) => return Ok(response.into_inner()),
+      Err(status) => status,
+    };
+
+    addr = match status.metadata().get(LEADER_ADDR_METADATA) {
+      Some(leader_addr) => leader_addr.to_str()?.to_string(),
+      None => return Err(status.into()),
+    };
+    info!("Redirecting to leader at {}", addr);
+  }
+
+  Err(format!("Gave up after {} redirects", MAX_REDIRECTS).into())
+}
+
+/// Writes a PEM file into `dir`, readable by its owner only if it holds a private key.
+pub fn write(dir: &Path, name: &str, pem: &str, private: bool) -> io::Result<()> {
+  fs::create_dir_all(dir)?;
+
+  let path = dir.join(name);
+  let mut file = fs::OpenOptions::new()
+    .write(true)
+    .create(true)
+    .truncate(true)
+    .mode(if private { 0o600 } else { 0o644 })
+    .open(&path)?;
+  file.write_all(pem.as_bytes())?;
+
+  println!("Wrote {}", path.display());
+  Ok(())
+}
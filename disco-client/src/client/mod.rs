@@ -1,36 +1,284 @@
+use std::future::Future;
+use std::sync::RwLock;
 use std::time::Duration;
 
+use disco_daemon::grpc::leader::LEADER_ADDR_METADATA;
 use disco_daemon::protobuf::app_service_client::AppServiceClient;
-use disco_daemon::protobuf::{GetRequest, SetRequest};
-use tonic::{transport::Channel, Request, Status};
+use disco_daemon::protobuf::{
+  AddLearnerRequest, BackupResponse, BatchRequest, CampaignRequest, ChangeMembershipRequest,
+  ClientWriteResponse, CompactRequest, Compare, CompareAndSwapRequest, CreateJoinTokenRequest,
+  CreateJoinTokenResponse, DeleteNamespaceRequest, DeletePolicyRequest, DeleteRequest, GetRequest,
+  HistoryRequest, HistoryResponse, KeyPrefix, KeyValue, LeaderRequest, LeaseGrantRequest,
+  LeaseKeepAliveRequest, LeaseRevokeRequest, LockRequest, LockResponse, MetricsResponse, Namespace,
+  NamespaceRequest, Node, Operation, Policy, RangeRequest, RangeResponse, ReadConsistency,
+  ReleaseRequest, Response, Role, SetRequest, TxnRequest, WatchRequest, WatchResponse,
+  operation::Op,
+};
+use disco_daemon::telemetry::{self, TracedChannel};
+use tonic::{transport::Channel, Code, Status, Streaming};
+use tracing::info;
 
+mod store;
+
+/// How many times a single request follows a redirect to the leader before giving up.
+const MAX_REDIRECTS: usize = 3;
+
+#[derive(Debug)]
 pub struct RaftClient {
-  channel: Channel,
+  /// Scheme of the address the client was created with, reused when following redirects.
+  scheme: String,
+
+  /// Channel to the node requests are sent to; replaced when redirected to the leader.
+  channel: RwLock<Channel>,
+
+  /// Namespace of the keys the client reads and writes, empty for the default one.
+  namespace: String,
+}
+
+/// Whether a request failed because it outlasted the channel timeout.
+fn is_timeout(status: &Status) -> bool {
+  matches!(status.code(), Code::Cancelled | Code::DeadlineExceeded)
+}
+
+async fn connect(addr: String) -> Result<Channel, Box<dyn std::error::Error + Send + Sync>> {
+  let channel = Channel::from_shared(addr)?
+    .timeout(Duration::from_secs(5))
+    .connect()
+    .await?;
+
+  Ok(channel)
 }
 
 impl RaftClient {
   pub async fn new(addr: String) -> Result<Self, Box<dyn std::error::Error>> {
-    let channel = Channel::from_shared(addr.clone())?
-      .timeout(Duration::from_secs(5))
-      .connect()
-      .await?;
+    let scheme = match addr.split_once("://") {
+      Some((scheme, _)) => scheme.to_string(),
+      None => "http".to_string(),
+    };
+
+    let channel = connect(addr).await?;
+
+    Ok(Self {
+      scheme,
+      channel: RwLock::new(channel),
+      namespace: String::new(),
+    })
+  }
+
+  /// Scopes the client to a namespace, isolating its keys, locks and elections from the others.
+  pub fn with_namespace(mut self, namespace: String) -> Self {
+    self.namespace = namespace;
+    self
+  }
+
+  /// Places an operation of a batch or transaction that names no namespace in the client's.
+  fn scoped(&self, mut operation: Operation) -> Operation {
+    let namespace = match &mut operation.op {
+      Some(Op::Set(req)) => &mut req.namespace,
+      Some(Op::Delete(req)) => &mut req.namespace,
+      Some(Op::CompareAndSwap(req)) => &mut req.namespace,
+      None => return operation,
+    };
+
+    if namespace.is_empty() {
+      namespace.clone_from(&self.namespace);
+    }
+
+    operation
+  }
+
+  /// Sends a request to the connected node, following redirects to the leader.
+  ///
+  /// Nodes reject requests that only the leader can serve with the leader's address attached.
+  /// The client then switches over to the leader, for this request and the ones after it.
+  async fn call<T, F, Fut>(&self, request: F) -> Result<T, Status>
+  where
+    F: Fn(AppServiceClient<TracedChannel>) -> Fut,
+    Fut: Future<Output = Result<tonic::Response<T>, Status>>,
+  {
+    let mut redirects = 0;
+
+    loop {
+      let client = AppServiceClient::new(telemetry::traced(self.channel.read().unwrap().clone()));
+
+      let status = match request(client).await {
+        Ok(response) => return Ok(response.into_inner()),
+        Err(status) => status,
+      };
 
-    Ok(Self { channel })
+      let leader_addr = match status.metadata().get(LEADER_ADDR_METADATA) {
+        Some(addr) if redirects < MAX_REDIRECTS => addr.to_str().unwrap_or_default().to_string(),
+        _ => return Err(status),
+      };
+      redirects += 1;
+
+      let addr = format!("{}://{}", self.scheme, leader_addr);
+      info!("Redirecting to leader at {}", addr);
+
+      let channel = connect(addr.clone()).await.map_err(|e| {
+        Status::unavailable(format!("Failed to connect to leader at {}: {}", addr, e))
+      })?;
+      *self.channel.write().unwrap() = channel;
+    }
+  }
+
+  /// Sends a request that waits on the cluster, retrying it each time it outlasts the request
+  /// timeout until `timeout` has passed.
+  async fn wait<T, F, Fut>(&self, timeout: Duration, request: F) -> Result<T, Status>
+  where
+    F: Fn(AppServiceClient<TracedChannel>) -> Fut,
+    Fut: Future<Output = Result<tonic::Response<T>, Status>>,
+  {
+    let deadline = tokio::time::Instant::now() + timeout;
+
+    loop {
+      match tokio::time::timeout_at(deadline, self.call(&request)).await {
+        Err(_) => {
+          return Err(Status::deadline_exceeded(format!(
+            "Gave up waiting after {:?}",
+            timeout
+          )));
+        }
+        Ok(Err(status)) if is_timeout(&status) => continue,
+        Ok(result) => return result,
+      }
+    }
   }
 
   pub async fn get_value(&self, key: String) -> Result<Option<String>, Status> {
-    // Create a client using the channel
-    let mut client = AppServiceClient::new(self.channel.clone());
+    self
+      .get_value_with_consistency(key, ReadConsistency::Linearizable)
+      .await
+  }
 
+  /// Gets a value with the given read consistency; `Stale` reads are served by any node.
+  pub async fn get_value_with_consistency(
+    &self,
+    key: String,
+    consistency: ReadConsistency,
+  ) -> Result<Option<String>, Status> {
     // Create the GetRequest message
-    let request = Request::new(GetRequest { key });
+    let request = GetRequest {
+      key,
+      consistency: consistency.into(),
+      revision: 0,
+      namespace: self.namespace.clone(),
+    };
 
-    // Make the RPC call
-    let response = client.get(request).await?;
-    let result = response.into_inner();
+    // Make the RPC call; a missing key is not an error for the caller
+    let result = self
+      .call(|mut client| {
+        let request = request.clone();
+        async move { client.get(request).await }
+      })
+      .await;
 
-    // Return the response inner data
-    Ok(result.value)
+    match result {
+      Ok(response) => Ok(response.value),
+      Err(status) if status.code() == Code::NotFound => Ok(None),
+      Err(status) => Err(status),
+    }
+  }
+
+  /// Gets a key as of a past revision, along with the revisions of that value.
+  pub async fn get_value_at_revision(
+    &self,
+    key: String,
+    revision: i64,
+  ) -> Result<Option<KeyValue>, Status> {
+    let request = GetRequest {
+      key,
+      consistency: ReadConsistency::Linearizable.into(),
+      revision,
+      namespace: self.namespace.clone(),
+    };
+
+    let result = self
+      .call(|mut client| {
+        let request = request.clone();
+        async move { client.get(request).await }
+      })
+      .await;
+
+    match result {
+      Ok(response) => Ok(response.kv),
+      Err(status) if status.code() == Code::NotFound => Ok(None),
+      Err(status) => Err(status),
+    }
+  }
+
+  /// Lists the retained changes of a key, oldest first.
+  pub async fn history(&self, key: String) -> Result<HistoryResponse, Status> {
+    let request = HistoryRequest {
+      key,
+      consistency: ReadConsistency::Linearizable.into(),
+      namespace: self.namespace.clone(),
+    };
+
+    self
+      .call(|mut client| {
+        let request = request.clone();
+        async move { client.history(request).await }
+      })
+      .await
+  }
+
+  /// Discards the history before `revision` on every node.
+  pub async fn compact(&self, revision: i64) -> Result<bool, Status> {
+    let request = CompactRequest { revision };
+
+    let result = self
+      .call(|mut client| async move { client.compact(request).await })
+      .await?;
+
+    Ok(result.success)
+  }
+
+  /// Reads one page of the keys starting with `prefix` that sort after `start_after`.
+  ///
+  /// Pass the revision of the first page, or 0 for the latest, so that all pages are read from
+  /// the same state.
+  pub async fn range(
+    &self,
+    prefix: String,
+    start_after: String,
+    limit: u32,
+    revision: i64,
+  ) -> Result<RangeResponse, Status> {
+    let request = RangeRequest {
+      prefix,
+      start_after,
+      limit,
+      consistency: ReadConsistency::Linearizable.into(),
+      revision,
+      namespace: self.namespace.clone(),
+    };
+
+    self
+      .call(|mut client| {
+        let request = request.clone();
+        async move { client.range(request).await }
+      })
+      .await
+  }
+
+  /// Lists all keys starting with `prefix` in order, along with the revision they were read at.
+  pub async fn list(&self, prefix: String) -> Result<(i64, Vec<KeyValue>), Status> {
+    let mut kvs = Vec::new();
+    let mut start_after = String::new();
+    let mut revision = 0;
+
+    loop {
+      let page = self.range(prefix.clone(), start_after, 0, revision).await?;
+
+      revision = page.revision;
+      kvs.extend(page.kvs);
+
+      match kvs.last() {
+        Some(last) if page.more => start_after = last.key.clone(),
+        _ => return Ok((revision, kvs)),
+      }
+    }
   }
 
   pub async fn set_value(
@@ -38,17 +286,481 @@ impl RaftClient {
     key: String,
     value: String,
   ) -> Result<Option<String>, tonic::Status> {
-    // Create a client using the channel
-    let mut client = AppServiceClient::new(self.channel.clone());
-
     // Create the SetRequest message
-    let request = Request::new(SetRequest { key, value });
+    let request = SetRequest {
+      key,
+      value,
+      lease: 0,
+      namespace: self.namespace.clone(),
+    };
 
     // Make the RPC call
-    let response = client.set(request).await?;
-    let result = response.into_inner();
+    let result = self
+      .call(|mut client| {
+        let request = request.clone();
+        async move { client.set(request).await }
+      })
+      .await?;
 
     // Return the response inner data (success flag)
     Ok(result.value)
   }
+
+  /// Sets a value that is deleted when the lease is revoked or expires.
+  pub async fn set_value_with_lease(
+    &self,
+    key: String,
+    value: String,
+    lease: i64,
+  ) -> Result<bool, Status> {
+    let request = SetRequest {
+      key,
+      value,
+      lease,
+      namespace: self.namespace.clone(),
+    };
+
+    let result = self
+      .call(|mut client| {
+        let request = request.clone();
+        async move { client.set(request).await }
+      })
+      .await?;
+
+    Ok(result.success)
+  }
+
+  /// Deletes a key, returning its previous value if it existed.
+  pub async fn delete_value(&self, key: String) -> Result<Option<String>, Status> {
+    let request = DeleteRequest {
+      key,
+      namespace: self.namespace.clone(),
+    };
+
+    let result = self
+      .call(|mut client| {
+        let request = request.clone();
+        async move { client.delete(request).await }
+      })
+      .await?;
+
+    Ok(result.prev_value)
+  }
+
+  /// Sets `key` to `new` only if it currently holds `expected`.
+  ///
+  /// `None` as `expected` requires the key to be absent, `None` as `new` deletes it, and a
+  /// non-zero `lease` attaches the new value to that lease. The response reports whether the swap
+  /// happened and the value the key held before.
+  pub async fn compare_and_swap(
+    &self,
+    key: String,
+    expected: Option<String>,
+    new: Option<String>,
+    lease: i64,
+  ) -> Result<Response, Status> {
+    let request = CompareAndSwapRequest {
+      key,
+      expected,
+      new,
+      lease,
+      namespace: self.namespace.clone(),
+    };
+
+    self
+      .call(|mut client| {
+        let request = request.clone();
+        async move { client.compare_and_swap(request).await }
+      })
+      .await
+  }
+
+  /// Applies the operations atomically; if one compare-and-swap fails none of them take effect.
+  pub async fn batch(&self, operations: Vec<Operation>) -> Result<Response, Status> {
+    let request = BatchRequest {
+      operations: operations.into_iter().map(|op| self.scoped(op)).collect(),
+    };
+
+    self
+      .call(|mut client| {
+        let request = request.clone();
+        async move { client.batch(request).await }
+      })
+      .await
+  }
+
+  /// Applies `success` if every compare holds and `failure` otherwise, atomically.
+  ///
+  /// The response reports which branch was taken in `succeeded`, and in `success` whether its
+  /// operations took effect.
+  pub async fn txn(
+    &self,
+    compare: Vec<Compare>,
+    success: Vec<Operation>,
+    failure: Vec<Operation>,
+  ) -> Result<Response, Status> {
+    let compare = compare
+      .into_iter()
+      .map(|mut cmp| {
+        if cmp.namespace.is_empty() {
+          cmp.namespace.clone_from(&self.namespace);
+        }
+        cmp
+      })
+      .collect();
+
+    let request = TxnRequest {
+      compare,
+      success: success.into_iter().map(|op| self.scoped(op)).collect(),
+      failure: failure.into_iter().map(|op| self.scoped(op)).collect(),
+    };
+
+    self
+      .call(|mut client| {
+        let request = request.clone();
+        async move { client.txn(request).await }
+      })
+      .await
+  }
+
+  /// Grants a lease with the given time to live in seconds and returns its id.
+  pub async fn lease_grant(&self, ttl: i64) -> Result<i64, Status> {
+    let request = LeaseGrantRequest { ttl };
+
+    let result = self
+      .call(|mut client| async move { client.lease_grant(request).await })
+      .await?;
+
+    Ok(result.lease_id)
+  }
+
+  /// Renews a lease, returning `false` if it has already expired.
+  pub async fn lease_keep_alive(&self, id: i64) -> Result<bool, Status> {
+    let request = LeaseKeepAliveRequest { id };
+
+    let result = self
+      .call(|mut client| async move { client.lease_keep_alive(request).await })
+      .await?;
+
+    Ok(result.success)
+  }
+
+  /// Revokes a lease and deletes the keys attached to it.
+  pub async fn lease_revoke(&self, id: i64) -> Result<bool, Status> {
+    let request = LeaseRevokeRequest { id };
+
+    let result = self
+      .call(|mut client| async move { client.lease_revoke(request).await })
+      .await?;
+
+    Ok(result.success)
+  }
+
+  /// Acquires a named lock held by `lease`, waiting at most `timeout` until it is free.
+  ///
+  /// The wait outlasts the request timeout, so timed out attempts are retried; acquiring again
+  /// with the lease that already holds the lock returns the same fencing token.
+  pub async fn lock(
+    &self,
+    name: String,
+    lease: i64,
+    timeout: Duration,
+  ) -> Result<LockResponse, Status> {
+    let request = LockRequest {
+      name,
+      lease,
+      namespace: self.namespace.clone(),
+    };
+
+    self
+      .wait(timeout, |mut client| {
+        let request = request.clone();
+        async move { client.lock(request).await }
+      })
+      .await
+  }
+
+  /// Releases a named lock, returning `false` if it was no longer held with `token`.
+  pub async fn unlock(&self, name: String, token: i64) -> Result<bool, Status> {
+    let request = ReleaseRequest {
+      name,
+      token,
+      namespace: self.namespace.clone(),
+    };
+
+    let result = self
+      .call(|mut client| {
+        let request = request.clone();
+        async move { client.unlock(request).await }
+      })
+      .await?;
+
+    Ok(result.success)
+  }
+
+  /// Runs for leader of a named election with `lease`, waiting at most `timeout` until elected.
+  ///
+  /// Like [`RaftClient::lock`], timed out attempts are retried.
+  pub async fn campaign(
+    &self,
+    name: String,
+    lease: i64,
+    value: String,
+    timeout: Duration,
+  ) -> Result<LockResponse, Status> {
+    let request = CampaignRequest {
+      name,
+      lease,
+      value,
+      namespace: self.namespace.clone(),
+    };
+
+    self
+      .wait(timeout, |mut client| {
+        let request = request.clone();
+        async move { client.campaign(request).await }
+      })
+      .await
+  }
+
+  /// Gives up the leadership of a named election, returning `false` if the term had ended.
+  pub async fn resign(&self, name: String, token: i64) -> Result<bool, Status> {
+    let request = ReleaseRequest {
+      name,
+      token,
+      namespace: self.namespace.clone(),
+    };
+
+    let result = self
+      .call(|mut client| {
+        let request = request.clone();
+        async move { client.resign(request).await }
+      })
+      .await?;
+
+    Ok(result.success)
+  }
+
+  /// Returns the leader of a named election, with its value and the token of its term.
+  pub async fn leader(&self, name: String) -> Result<Option<KeyValue>, Status> {
+    let request = LeaderRequest {
+      name,
+      namespace: self.namespace.clone(),
+    };
+
+    let result = self
+      .call(|mut client| {
+        let request = request.clone();
+        async move { client.leader(request).await }
+      })
+      .await;
+
+    match result {
+      Ok(response) => Ok(response.kv),
+      Err(status) if status.code() == Code::NotFound => Ok(None),
+      Err(status) => Err(status),
+    }
+  }
+
+  /// Streams the changes made to keys starting with `prefix`.
+  ///
+  /// With a `start_revision` of 0 only new changes are sent. To resume after a disconnect, pass
+  /// the revision after the last one received.
+  pub async fn watch(
+    &self,
+    prefix: String,
+    start_revision: i64,
+  ) -> Result<Streaming<WatchResponse>, Status> {
+    let request = WatchRequest {
+      prefix,
+      start_revision,
+      namespace: self.namespace.clone(),
+    };
+
+    self
+      .call(|mut client| {
+        let request = request.clone();
+        async move { client.watch(request).await }
+      })
+      .await
+  }
+
+  /// Sets the quotas of a namespace, creating it if needed; 0 means no limit.
+  pub async fn set_namespace(
+    &self,
+    name: String,
+    max_keys: i64,
+    max_bytes: i64,
+  ) -> Result<bool, Status> {
+    let request = NamespaceRequest {
+      name,
+      max_keys,
+      max_bytes,
+    };
+
+    let result = self
+      .call(|mut client| {
+        let request = request.clone();
+        async move { client.set_namespace(request).await }
+      })
+      .await?;
+
+    Ok(result.success)
+  }
+
+  /// Deletes a namespace along with all of its keys.
+  pub async fn delete_namespace(&self, name: String) -> Result<bool, Status> {
+    let request = DeleteNamespaceRequest { name };
+
+    let result = self
+      .call(|mut client| {
+        let request = request.clone();
+        async move { client.delete_namespace(request).await }
+      })
+      .await?;
+
+    Ok(result.success)
+  }
+
+  /// Lists the namespaces that have keys or quotas, with their usage.
+  pub async fn list_namespaces(&self) -> Result<Vec<Namespace>, Status> {
+    let result = self
+      .call(|mut client| async move { client.list_namespaces(()).await })
+      .await?;
+
+    Ok(result.namespaces)
+  }
+
+  /// Grants `role` to the client certificates of `subject`, on the keys of the client's
+  /// namespace starting with one of `prefixes`, or on every key if there are none.
+  pub async fn set_policy(
+    &self,
+    subject: String,
+    role: Role,
+    prefixes: Vec<String>,
+  ) -> Result<bool, Status> {
+    let request = Policy {
+      subject,
+      role: role.into(),
+      prefixes: prefixes
+        .into_iter()
+        .map(|prefix| KeyPrefix {
+          namespace: self.namespace.clone(),
+          prefix,
+        })
+        .collect(),
+    };
+
+    let result = self
+      .call(|mut client| {
+        let request = request.clone();
+        async move { client.set_policy(request).await }
+      })
+      .await?;
+
+    Ok(result.success)
+  }
+
+  /// Removes the policy of a subject, whose certificates are then denied every request.
+  pub async fn delete_policy(&self, subject: String) -> Result<bool, Status> {
+    let request = DeletePolicyRequest { subject };
+
+    let result = self
+      .call(|mut client| {
+        let request = request.clone();
+        async move { client.delete_policy(request).await }
+      })
+      .await?;
+
+    Ok(result.success)
+  }
+
+  /// Lists the policies, ordered by subject.
+  pub async fn list_policies(&self) -> Result<Vec<Policy>, Status> {
+    let result = self
+      .call(|mut client| async move { client.list_policies(()).await })
+      .await?;
+
+    Ok(result.policies)
+  }
+
+  /// Adds a node as a learner, which replicates the log without voting.
+  ///
+  /// The node must present its URI `disco://<cluster>/node/<id>` in its certificates. Clusters
+  /// with the legacy identities also accept `tls_identity`, or `localhost` if it is empty.
+  pub async fn add_learner(
+    &self,
+    node_id: u64,
+    rpc_addr: String,
+    tls_identity: String,
+  ) -> Result<ClientWriteResponse, Status> {
+    let request = AddLearnerRequest {
+      node: Some(Node {
+        node_id,
+        rpc_addr,
+        tls_identity,
+      }),
+    };
+
+    self
+      .call(|mut client| {
+        let request = request.clone();
+        async move { client.add_learner(request).await }
+      })
+      .await
+  }
+
+  /// Replaces the set of voters; with `retain` removed voters stay on as learners.
+  pub async fn change_membership(
+    &self,
+    members: Vec<u64>,
+    retain: bool,
+  ) -> Result<ClientWriteResponse, Status> {
+    let request = ChangeMembershipRequest { members, retain };
+
+    self
+      .call(|mut client| {
+        let request = request.clone();
+        async move { client.change_membership(request).await }
+      })
+      .await
+  }
+
+  /// Returns the Raft metrics of the connected node.
+  pub async fn metrics(&self) -> Result<MetricsResponse, Status> {
+    self
+      .call(|mut client| async move { client.metrics(()).await })
+      .await
+  }
+
+  /// Streams the Raft metrics of the connected node, starting with the current ones.
+  pub async fn watch_metrics(&self) -> Result<Streaming<MetricsResponse>, Status> {
+    self
+      .call(|mut client| async move { client.watch_metrics(()).await })
+      .await
+  }
+
+  /// Takes a backup of the state machine, streamed as its meta followed by the data in chunks.
+  pub async fn backup(&self) -> Result<Streaming<BackupResponse>, Status> {
+    self
+      .call(|mut client| async move { client.backup(()).await })
+      .await
+  }
+
+  /// Creates a token letting a node have its certificates signed for `ttl` seconds, only node
+  /// `node_id` if given.
+  pub async fn create_join_token(
+    &self,
+    ttl: i64,
+    node_id: Option<u64>,
+  ) -> Result<CreateJoinTokenResponse, Status> {
+    let request = CreateJoinTokenRequest { ttl, node_id };
+
+    self
+      .call(|mut client| {
+        let request = request.clone();
+        async move { client.create_join_token(request).await }
+      })
+      .await
+  }
 }
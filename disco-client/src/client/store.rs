@@ -0,0 +1,76 @@
+use std::time::Duration;
+
+use anyhow::Result;
+use async_trait::async_trait;
+use disco_common::store::{Store, Txn, TxnResult};
+
+use super::RaftClient;
+
+/// Gives scripts access to the cluster the client is connected to.
+#[async_trait]
+impl Store for RaftClient {
+  async fn get(&self, key: &str) -> Result<Option<String>> {
+    Ok(self.get_value(key.to_string()).await?)
+  }
+
+  async fn set(&self, key: &str, value: &str) -> Result<()> {
+    self.set_value(key.to_string(), value.to_string()).await?;
+    Ok(())
+  }
+
+  async fn delete(&self, key: &str) -> Result<Option<String>> {
+    Ok(self.delete_value(key.to_string()).await?)
+  }
+
+  async fn txn(&self, txn: Txn) -> Result<TxnResult> {
+    let response = RaftClient::txn(
+      self,
+      txn.compare.into_iter().map(Into::into).collect(),
+      txn.success.into_iter().map(Into::into).collect(),
+      txn.failure.into_iter().map(Into::into).collect(),
+    )
+    .await?;
+
+    Ok(TxnResult {
+      succeeded: response.succeeded,
+      revision: response.revision,
+    })
+  }
+
+  async fn lease_grant(&self, ttl: i64) -> Result<i64> {
+    Ok(RaftClient::lease_grant(self, ttl).await?)
+  }
+
+  async fn lease_keep_alive(&self, id: i64) -> Result<bool> {
+    Ok(RaftClient::lease_keep_alive(self, id).await?)
+  }
+
+  async fn lease_revoke(&self, id: i64) -> Result<()> {
+    RaftClient::lease_revoke(self, id).await?;
+    Ok(())
+  }
+
+  async fn lock(&self, name: &str, lease: i64, timeout: Duration) -> Result<i64> {
+    let response = RaftClient::lock(self, name.to_string(), lease, timeout).await?;
+    Ok(response.token)
+  }
+
+  async fn unlock(&self, name: &str, token: i64) -> Result<bool> {
+    Ok(RaftClient::unlock(self, name.to_string(), token).await?)
+  }
+
+  async fn campaign(&self, name: &str, lease: i64, value: &str, timeout: Duration) -> Result<i64> {
+    let response =
+      RaftClient::campaign(self, name.to_string(), lease, value.to_string(), timeout).await?;
+    Ok(response.token)
+  }
+
+  async fn resign(&self, name: &str, token: i64) -> Result<bool> {
+    Ok(RaftClient::resign(self, name.to_string(), token).await?)
+  }
+
+  async fn leader(&self, name: &str) -> Result<Option<String>> {
+    let kv = RaftClient::leader(self, name.to_string()).await?;
+    Ok(kv.map(|kv| kv.value))
+  }
+}
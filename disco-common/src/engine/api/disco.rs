@@ -0,0 +1,365 @@
+use std::cell::RefCell;
+
+use boa_engine::{
+  Context, JsNativeError, JsObject, JsResult, JsString, JsValue, NativeFunction,
+  class::{Class, ClassBuilder},
+  object::{ObjectInitializer, builtins::JsArray},
+  property::Attribute,
+};
+
+use crate::store::{Compare, CompareResult, CompareTarget, Disco, Election, Lock, Op, Txn};
+
+fn this_disco(this: &JsValue) -> JsResult<Disco> {
+  Ok(
+    this
+      .as_object()
+      .ok_or_else(|| JsNativeError::typ().with_message("`this` is not an object"))?
+      .downcast_ref::<Disco>()
+      .ok_or_else(|| JsNativeError::typ().with_message("`this` is not a Disco"))?
+      .clone(),
+  )
+}
+
+fn string_arg(args: &[JsValue], index: usize, name: &str) -> JsResult<String> {
+  Ok(
+    args
+      .get(index)
+      .and_then(|arg| arg.as_string())
+      .ok_or_else(|| {
+        JsNativeError::typ().with_message(format!("Argument `{}` is not a string", name))
+      })?
+      .to_std_string_lossy(),
+  )
+}
+
+fn string_property(object: &JsObject, name: &str, context: &mut Context) -> JsResult<String> {
+  Ok(
+    object
+      .get(JsString::from(name), context)?
+      .as_string()
+      .ok_or_else(|| {
+        JsNativeError::typ().with_message(format!("Property `{}` is not a string", name))
+      })?
+      .to_std_string_lossy(),
+  )
+}
+
+fn has_property(object: &JsObject, name: &str, context: &mut Context) -> JsResult<bool> {
+  object.has_property(JsString::from(name), context)
+}
+
+/// Parses `{ key, value | version | mod_revision | create_revision | exists, result }`, where
+/// `result` is one of
+/// `==` (the default), `!=`, `>` or `<`.
+fn parse_compare(value: &JsValue, context: &mut Context) -> JsResult<Compare> {
+  let object = value
+    .as_object()
+    .ok_or_else(|| JsNativeError::typ().with_message("Compare is not an object"))?;
+
+  let key = string_property(&object, "key", context)?;
+
+  let result = object.get(JsString::from("result"), context)?;
+  let result = if result.is_undefined() {
+    CompareResult::Equal
+  } else {
+    match result
+      .as_string()
+      .map(|s| s.to_std_string_lossy())
+      .as_deref()
+    {
+      Some("==") => CompareResult::Equal,
+      Some("!=") => CompareResult::NotEqual,
+      Some(">") => CompareResult::Greater,
+      Some("<") => CompareResult::Less,
+      _ => {
+        return Err(
+          JsNativeError::typ()
+            .with_message("Compare `result` must be one of ==, !=, > or <")
+            .into(),
+        );
+      }
+    }
+  };
+
+  let target = if has_property(&object, "value", context)? {
+    CompareTarget::Value(string_property(&object, "value", context)?)
+  } else if has_property(&object, "version", context)? {
+    let version = object.get(JsString::from("version"), context)?;
+    CompareTarget::Version(version.to_length(context)? as i64)
+  } else if has_property(&object, "mod_revision", context)? {
+    let revision = object.get(JsString::from("mod_revision"), context)?;
+    CompareTarget::ModRevision(revision.to_length(context)? as i64)
+  } else if has_property(&object, "create_revision", context)? {
+    let revision = object.get(JsString::from("create_revision"), context)?;
+    CompareTarget::CreateRevision(revision.to_length(context)? as i64)
+  } else if has_property(&object, "exists", context)? {
+    CompareTarget::Exists(object.get(JsString::from("exists"), context)?.to_boolean())
+  } else {
+    return Err(
+      JsNativeError::typ()
+        .with_message(
+          "Compare needs one of `value`, `version`, `mod_revision`, `create_revision` or `exists`",
+        )
+        .into(),
+    );
+  };
+
+  Ok(Compare {
+    key,
+    result,
+    target,
+  })
+}
+
+/// Parses `{ op: "set", key, value }` or `{ op: "delete", key }`.
+fn parse_op(value: &JsValue, context: &mut Context) -> JsResult<Op> {
+  let object = value
+    .as_object()
+    .ok_or_else(|| JsNativeError::typ().with_message("Operation is not an object"))?;
+
+  let key = string_property(&object, "key", context)?;
+
+  match string_property(&object, "op", context)?.as_str() {
+    "set" => Ok(Op::Set {
+      key,
+      value: string_property(&object, "value", context)?,
+    }),
+    "delete" => Ok(Op::Delete { key }),
+    op => Err(
+      JsNativeError::typ()
+        .with_message(format!("Unknown operation `{}`", op))
+        .into(),
+    ),
+  }
+}
+
+/// Parses the optional array property `name` with `parse`.
+fn parse_array<T>(
+  object: &JsObject,
+  name: &str,
+  context: &mut Context,
+  parse: fn(&JsValue, &mut Context) -> JsResult<T>,
+) -> JsResult<Vec<T>> {
+  let value = object.get(JsString::from(name), context)?;
+  if value.is_undefined() {
+    return Ok(Vec::new());
+  }
+
+  let array = value
+    .as_object()
+    .and_then(|object| JsArray::from_object(object.clone()).ok())
+    .ok_or_else(|| JsNativeError::typ().with_message(format!("`{}` is not an array", name)))?;
+
+  let mut items = Vec::new();
+  for i in 0..array.length(context)? {
+    items.push(parse(&array.get(i, context)?, context)?);
+  }
+
+  Ok(items)
+}
+
+fn get(
+  this: &JsValue,
+  args: &[JsValue],
+  _context: &RefCell<&mut Context>,
+) -> impl Future<Output = JsResult<JsValue>> {
+  async move {
+    let disco = this_disco(this)?;
+    let key = string_arg(args, 0, "key")?;
+
+    let value = disco
+      .store()
+      .get(&key)
+      .await
+      .map_err(|e| JsNativeError::error().with_message(e.to_string()))?;
+
+    Ok(value.map_or_else(JsValue::null, |value| JsString::from(value).into()))
+  }
+}
+
+fn set(
+  this: &JsValue,
+  args: &[JsValue],
+  _context: &RefCell<&mut Context>,
+) -> impl Future<Output = JsResult<JsValue>> {
+  async move {
+    let disco = this_disco(this)?;
+    let key = string_arg(args, 0, "key")?;
+    let value = string_arg(args, 1, "value")?;
+
+    disco
+      .store()
+      .set(&key, &value)
+      .await
+      .map_err(|e| JsNativeError::error().with_message(e.to_string()))?;
+
+    Ok(JsValue::undefined())
+  }
+}
+
+fn delete(
+  this: &JsValue,
+  args: &[JsValue],
+  _context: &RefCell<&mut Context>,
+) -> impl Future<Output = JsResult<JsValue>> {
+  async move {
+    let disco = this_disco(this)?;
+    let key = string_arg(args, 0, "key")?;
+
+    let prev_value = disco
+      .store()
+      .delete(&key)
+      .await
+      .map_err(|e| JsNativeError::error().with_message(e.to_string()))?;
+
+    Ok(prev_value.map_or_else(JsValue::null, |value| JsString::from(value).into()))
+  }
+}
+
+/// `await disco.txn({ compare: [...], success: [...], failure: [...] })`, resolving to
+/// `{ succeeded, revision }`.
+fn txn(
+  this: &JsValue,
+  args: &[JsValue],
+  context: &RefCell<&mut Context>,
+) -> impl Future<Output = JsResult<JsValue>> {
+  async move {
+    let disco = this_disco(this)?;
+
+    let txn = {
+      let context = &mut context.borrow_mut();
+      let object = args
+        .first()
+        .and_then(|arg| arg.as_object())
+        .ok_or_else(|| JsNativeError::typ().with_message("Argument is not an object"))?;
+
+      Txn {
+        compare: parse_array(&object, "compare", context, parse_compare)?,
+        success: parse_array(&object, "success", context, parse_op)?,
+        failure: parse_array(&object, "failure", context, parse_op)?,
+      }
+    };
+
+    let result = disco
+      .store()
+      .txn(txn)
+      .await
+      .map_err(|e| JsNativeError::error().with_message(e.to_string()))?;
+
+    let context = &mut context.borrow_mut();
+    let object = ObjectInitializer::new(context)
+      .property(
+        JsString::from("succeeded"),
+        result.succeeded,
+        Attribute::all(),
+      )
+      .property(
+        JsString::from("revision"),
+        result.revision as f64,
+        Attribute::all(),
+      )
+      .build();
+
+    Ok(object.into())
+  }
+}
+
+/// `await disco.lock(name)`, resolving to a `Lock` once acquired.
+fn lock(
+  this: &JsValue,
+  args: &[JsValue],
+  context: &RefCell<&mut Context>,
+) -> impl Future<Output = JsResult<JsValue>> {
+  async move {
+    let disco = this_disco(this)?;
+    let name = string_arg(args, 0, "name")?;
+
+    let lock = Lock::acquire(disco.store().clone(), &name)
+      .await
+      .map_err(|e| JsNativeError::error().with_message(e.to_string()))?;
+
+    Ok(Lock::from_data(lock, &mut context.borrow_mut())?.into())
+  }
+}
+
+/// `await disco.campaign(name, value)`, resolving to an `Election` once elected.
+fn campaign(
+  this: &JsValue,
+  args: &[JsValue],
+  context: &RefCell<&mut Context>,
+) -> impl Future<Output = JsResult<JsValue>> {
+  async move {
+    let disco = this_disco(this)?;
+    let name = string_arg(args, 0, "name")?;
+    let value = string_arg(args, 1, "value")?;
+
+    let election = Election::campaign(disco.store().clone(), &name, &value)
+      .await
+      .map_err(|e| JsNativeError::error().with_message(e.to_string()))?;
+
+    Ok(Election::from_data(election, &mut context.borrow_mut())?.into())
+  }
+}
+
+/// `await disco.leader(name)`, resolving to the value of the current leader or `null`.
+fn leader(
+  this: &JsValue,
+  args: &[JsValue],
+  _context: &RefCell<&mut Context>,
+) -> impl Future<Output = JsResult<JsValue>> {
+  async move {
+    let disco = this_disco(this)?;
+    let name = string_arg(args, 0, "name")?;
+
+    let value = Election::leader(disco.store().as_ref(), &name)
+      .await
+      .map_err(|e| JsNativeError::error().with_message(e.to_string()))?;
+
+    Ok(value.map_or_else(JsValue::null, |value| JsString::from(value).into()))
+  }
+}
+
+impl Class for Disco {
+  const NAME: &'static str = "Disco";
+  const LENGTH: usize = 0;
+
+  fn init(class: &mut ClassBuilder<'_>) -> JsResult<()> {
+    class.method(JsString::from("get"), 1, NativeFunction::from_async_fn(get));
+    class.method(JsString::from("set"), 2, NativeFunction::from_async_fn(set));
+    class.method(
+      JsString::from("delete"),
+      1,
+      NativeFunction::from_async_fn(delete),
+    );
+    class.method(JsString::from("txn"), 1, NativeFunction::from_async_fn(txn));
+    class.method(
+      JsString::from("lock"),
+      1,
+      NativeFunction::from_async_fn(lock),
+    );
+    class.method(
+      JsString::from("campaign"),
+      2,
+      NativeFunction::from_async_fn(campaign),
+    );
+    class.method(
+      JsString::from("leader"),
+      1,
+      NativeFunction::from_async_fn(leader),
+    );
+
+    Ok(())
+  }
+
+  fn data_constructor(
+    _new_target: &JsValue,
+    _args: &[JsValue],
+    _context: &mut Context,
+  ) -> JsResult<Disco> {
+    Err(
+      JsNativeError::typ()
+        .with_message("Disco cannot be constructed, use the `disco` global")
+        .into(),
+    )
+  }
+}
@@ -0,0 +1,160 @@
+use std::cell::RefCell;
+
+use boa_engine::{
+  Context, JsNativeError, JsResult, JsString, JsValue, NativeFunction,
+  class::{Class, ClassBuilder},
+  property::Attribute,
+};
+use boa_interop::{IntoJsFunctionCopied, JsClass};
+
+use crate::store::{Election, Lock};
+
+fn unlock(
+  this: &JsValue,
+  _args: &[JsValue],
+  _context: &RefCell<&mut Context>,
+) -> impl Future<Output = JsResult<JsValue>> {
+  async move {
+    let lock = this
+      .as_object()
+      .ok_or_else(|| JsNativeError::typ().with_message("`this` is not an object"))?
+      .downcast_ref::<Lock>()
+      .ok_or_else(|| JsNativeError::typ().with_message("`this` is not a Lock"))?
+      .clone();
+
+    lock
+      .unlock()
+      .await
+      .map_err(|e| JsNativeError::error().with_message(e.to_string()))?;
+
+    Ok(JsValue::undefined())
+  }
+}
+
+fn resign(
+  this: &JsValue,
+  _args: &[JsValue],
+  _context: &RefCell<&mut Context>,
+) -> impl Future<Output = JsResult<JsValue>> {
+  async move {
+    let election = this
+      .as_object()
+      .ok_or_else(|| JsNativeError::typ().with_message("`this` is not an object"))?
+      .downcast_ref::<Election>()
+      .ok_or_else(|| JsNativeError::typ().with_message("`this` is not an Election"))?
+      .clone();
+
+    election
+      .resign()
+      .await
+      .map_err(|e| JsNativeError::error().with_message(e.to_string()))?;
+
+    Ok(JsValue::undefined())
+  }
+}
+
+impl Class for Lock {
+  const NAME: &'static str = "Lock";
+  const LENGTH: usize = 0;
+
+  fn init(class: &mut ClassBuilder<'_>) -> JsResult<()> {
+    let function_name = IntoJsFunctionCopied::into_js_function_copied(
+      |this: JsClass<Lock>| -> JsString { this.borrow().name().into() },
+      class.context(),
+    )
+    .to_js_function(class.context().realm());
+
+    let function_token = IntoJsFunctionCopied::into_js_function_copied(
+      |this: JsClass<Lock>| -> f64 { this.borrow().token() as f64 },
+      class.context(),
+    )
+    .to_js_function(class.context().realm());
+
+    class.accessor(
+      JsString::from("name"),
+      Some(function_name),
+      None,
+      Attribute::CONFIGURABLE | Attribute::NON_ENUMERABLE,
+    );
+
+    class.accessor(
+      JsString::from("token"),
+      Some(function_token),
+      None,
+      Attribute::CONFIGURABLE | Attribute::NON_ENUMERABLE,
+    );
+
+    class.method(
+      JsString::from("unlock"),
+      0,
+      NativeFunction::from_async_fn(unlock),
+    );
+
+    Ok(())
+  }
+
+  fn data_constructor(
+    _new_target: &JsValue,
+    _args: &[JsValue],
+    _context: &mut Context,
+  ) -> JsResult<Lock> {
+    Err(
+      JsNativeError::typ()
+        .with_message("Lock cannot be constructed, use `disco.lock(name)`")
+        .into(),
+    )
+  }
+}
+
+impl Class for Election {
+  const NAME: &'static str = "Election";
+  const LENGTH: usize = 0;
+
+  fn init(class: &mut ClassBuilder<'_>) -> JsResult<()> {
+    let function_name = IntoJsFunctionCopied::into_js_function_copied(
+      |this: JsClass<Election>| -> JsString { this.borrow().name().into() },
+      class.context(),
+    )
+    .to_js_function(class.context().realm());
+
+    let function_token = IntoJsFunctionCopied::into_js_function_copied(
+      |this: JsClass<Election>| -> f64 { this.borrow().token() as f64 },
+      class.context(),
+    )
+    .to_js_function(class.context().realm());
+
+    class.accessor(
+      JsString::from("name"),
+      Some(function_name),
+      None,
+      Attribute::CONFIGURABLE | Attribute::NON_ENUMERABLE,
+    );
+
+    class.accessor(
+      JsString::from("token"),
+      Some(function_token),
+      None,
+      Attribute::CONFIGURABLE | Attribute::NON_ENUMERABLE,
+    );
+
+    class.method(
+      JsString::from("resign"),
+      0,
+      NativeFunction::from_async_fn(resign),
+    );
+
+    Ok(())
+  }
+
+  fn data_constructor(
+    _new_target: &JsValue,
+    _args: &[JsValue],
+    _context: &mut Context,
+  ) -> JsResult<Election> {
+    Err(
+      JsNativeError::typ()
+        .with_message("Election cannot be constructed, use `disco.campaign(name, value)`")
+        .into(),
+    )
+  }
+}
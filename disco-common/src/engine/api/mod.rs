@@ -1,3 +1,5 @@
 mod aws_provider;
 mod cluster;
+mod disco;
+mod lock;
 mod storage;
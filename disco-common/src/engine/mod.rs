@@ -5,7 +5,8 @@ use boa_engine::{
 };
 use boa_runtime::Console;
 use std::{
-  cell::RefCell, future::Future, mem, path::Path, rc::Rc, thread::JoinHandle, time::Duration,
+  cell::RefCell, future::Future, mem, path::Path, rc::Rc, sync::Arc, thread::JoinHandle,
+  time::Duration,
 };
 use tokio::{
   io::{self, AsyncBufReadExt, AsyncWriteExt, BufReader},
@@ -23,7 +24,12 @@ use queue::Queue;
 
 mod api;
 
-use crate::{builder::Cluster, provider::AwsProvider};
+use crate::{
+  builder::Cluster,
+  provider::AwsProvider,
+  store::{Disco, Election, Lock, Store},
+  telemetry,
+};
 
 // Example async function. Note that the returned future must be 'static.
 fn delay(
@@ -114,6 +120,7 @@ impl std::error::Error for EngineError {}
 pub enum Command {
   Process(String, Vec<JsValue>, oneshot::Sender<JsValue>),
   LoadModule(String, oneshot::Sender<Result<(), String>>),
+  AttachStore(Arc<dyn Store>, oneshot::Sender<Result<(), String>>),
   Terminate,
 }
 
@@ -156,6 +163,9 @@ impl Engine {
 
       context.register_global_class::<AwsProvider>().unwrap();
       context.register_global_class::<Cluster>().unwrap();
+      context.register_global_class::<Disco>().unwrap();
+      context.register_global_class::<Lock>().unwrap();
+      context.register_global_class::<Election>().unwrap();
 
       // Bind the delay async function to the ECMAScript function "delay". (testing purposes)
       context
@@ -205,6 +215,20 @@ impl Engine {
                 }
               }
             }
+            Command::AttachStore(store, response_tx) => {
+              info!("Attaching store to the `disco` global");
+
+              let result = Disco::from_data(Disco::new(store), context)
+                .and_then(|disco| {
+                  context
+                    .global_object()
+                    .set(JsString::from("disco"), disco, false, context)
+                })
+                .map(|_| ())
+                .map_err(|e| e.to_string());
+
+              let _ = response_tx.send(result);
+            }
             Command::Process(data, input, response_tx) => {
               info!("Processing command: {:?}", data);
 
@@ -322,6 +346,22 @@ impl Engine {
       .map_err(EngineError::Script)
   }
 
+  /// Exposes the store to scripts as the `disco` global.
+  pub async fn attach_store(&self, store: Arc<dyn Store>) -> Result<(), EngineError> {
+    let (response_tx, response_rx) = oneshot::channel();
+
+    self
+      .command_tx
+      .send(Command::AttachStore(store, response_tx))
+      .await
+      .map_err(EngineError::SendCallback)?;
+
+    response_rx
+      .await
+      .map_err(EngineError::ReceiveCallback)?
+      .map_err(EngineError::Script)
+  }
+
   pub async fn load_module_from_file(&self, filename: &str) -> Result<(), EngineError> {
     let (_, script_contents) = Self::load_script(filename)?;
     self.load_module(&script_contents).await
@@ -329,6 +369,7 @@ impl Engine {
 
   pub async fn callback(&self, data: &str, input: &[JsValue]) -> Result<JsValue, EngineError> {
     let (response_tx, response_rx) = oneshot::channel();
+    let started = Instant::now();
 
     self
       .command_tx
@@ -336,7 +377,10 @@ impl Engine {
       .await
       .map_err(EngineError::SendCallback)?;
 
-    response_rx.await.map_err(EngineError::ReceiveCallback)
+    let response = response_rx.await.map_err(EngineError::ReceiveCallback);
+    telemetry::engine_callback(data, started.elapsed());
+
+    response
   }
 
   pub async fn init(&self) -> Result<JsValue, EngineError> {
@@ -5,3 +5,5 @@ pub mod builder;
 pub mod engine;
 pub mod provider;
 pub mod ssh;
+pub mod store;
+pub mod telemetry;
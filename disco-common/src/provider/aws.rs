@@ -1,4 +1,5 @@
 use crate::provider::{InstanceInfo, InstanceState, Provider};
+use crate::telemetry;
 use anyhow::{Context, Result, bail};
 use async_trait::async_trait;
 use aws_config;
@@ -89,6 +90,7 @@ impl AwsProvider {
   /// Look for the named IAM role, create it if it doesn't exist with EC2 permissions
   async fn iam_role(&self, role_name: &str) -> Result<()> {
     // Check if role exists first
+    telemetry::provider_call("iam", "get_role");
     let role_exists = match self.iam_client.get_role().role_name(role_name).send().await {
       Ok(_) => true,
       Err(aws_sdk_iam::error::SdkError::ServiceError(service_error)) => {
@@ -119,6 +121,7 @@ impl AwsProvider {
 
     // Create the IAM role only if it doesn't exist
     if !role_exists {
+      telemetry::provider_call("iam", "create_role");
       self
         .iam_client
         .create_role()
@@ -180,6 +183,7 @@ impl AwsProvider {
 
     let policy_name = format!("{}-policy", role_name);
 
+    telemetry::provider_call("iam", "put_role_policy");
     self
       .iam_client
       .put_role_policy()
@@ -196,6 +200,7 @@ impl AwsProvider {
   /// Look for the named security group, create it if it doesn't exist, allowing traffic on port 22
   async fn security_group(&self, name: &str) -> Result<String> {
     // First, try to find existing security group by name
+    telemetry::provider_call("ec2", "describe_security_groups");
     let resp = self
       .ec2_client
       .describe_security_groups()
@@ -221,6 +226,7 @@ impl AwsProvider {
       .context("Failed to get default VPC ID when creating security group")?;
 
     // Create security group
+    telemetry::provider_call("ec2", "create_security_group");
     let create_resp = self
       .ec2_client
       .create_security_group()
@@ -248,6 +254,7 @@ impl AwsProvider {
       .to_string();
 
     // Add inbound rule for SSH (port 22)
+    telemetry::provider_call("ec2", "authorize_security_group_ingress");
     self
       .ec2_client
       .authorize_security_group_ingress()
@@ -270,6 +277,7 @@ impl AwsProvider {
       .with_context(|| format!("Failed to add SSH rule to security group '{}'", name))?;
 
     // Add inbound rule for port 5080 from the same security group
+    telemetry::provider_call("ec2", "authorize_security_group_ingress");
     self
       .ec2_client
       .authorize_security_group_ingress()
@@ -296,6 +304,7 @@ impl AwsProvider {
 
   // Helper method to get the default VPC ID
   async fn get_default_vpc_id(&self) -> Result<String> {
+    telemetry::provider_call("ec2", "describe_vpcs");
     let resp = self
       .ec2_client
       .describe_vpcs()
@@ -336,6 +345,7 @@ impl Provider for AwsProvider {
 
   async fn get_ip_address_by_name(&self, name: &str) -> Result<Option<(String, String)>> {
     // Get the list of Elastic IP addresses
+    telemetry::provider_call("ec2", "describe_addresses");
     let resp = self
       .ec2_client
       .describe_addresses()
@@ -376,6 +386,7 @@ impl Provider for AwsProvider {
 
   async fn attach_ip_address_to_instance(&self, address: &str, instance_id: &str) -> Result<()> {
     // Associate the Elastic IP with the instance
+    telemetry::provider_call("ec2", "associate_address");
     self
       .ec2_client
       .associate_address()
@@ -395,6 +406,7 @@ impl Provider for AwsProvider {
 
   async fn get_key_pair_by_name(&self, name: &str) -> Result<Option<String>> {
     // Get the list of key pairs with the Name tag
+    telemetry::provider_call("ec2", "describe_key_pairs");
     let resp = self
       .ec2_client
       .describe_key_pairs()
@@ -424,6 +436,7 @@ impl Provider for AwsProvider {
       .with_context(|| format!("Failed to read public key file at {:?}", public_key_path))?;
 
     // Import the key pair to AWS with tag in a single API call
+    telemetry::provider_call("ec2", "import_key_pair");
     let resp = self
       .ec2_client
       .import_key_pair()
@@ -450,6 +463,7 @@ impl Provider for AwsProvider {
     }
 
     // Allocate a new Elastic IP address
+    telemetry::provider_call("ec2", "allocate_address");
     let resp = self
       .ec2_client
       .allocate_address()
@@ -491,6 +505,7 @@ impl Provider for AwsProvider {
 
   async fn get_instance_by_name(&self, name: &str) -> Result<Option<InstanceInfo>> {
     // Get the list of EC2 instances with the given name
+    telemetry::provider_call("ec2", "describe_instances");
     let resp = self
       .ec2_client
       .describe_instances()
@@ -552,6 +567,7 @@ impl Provider for AwsProvider {
       }
 
       // Query the instance status for all pending instances
+      telemetry::provider_call("ec2", "describe_instances");
       let resp = self
         .ec2_client
         .describe_instances()
@@ -596,6 +612,7 @@ impl Provider for AwsProvider {
   /// Create an IAM instance profile with role and policies
   async fn instance_profile(&self, role_name: &str, profile_name: &str) -> Result<()> {
     // Return early if instance profile already exists
+    telemetry::provider_call("iam", "get_instance_profile");
     let (profile_exists, has_role) = match self
       .iam_client
       .get_instance_profile()
@@ -631,6 +648,7 @@ impl Provider for AwsProvider {
 
     if !profile_exists {
       // Create the instance profile
+      telemetry::provider_call("iam", "create_instance_profile");
       self
         .iam_client
         .create_instance_profile()
@@ -643,6 +661,7 @@ impl Provider for AwsProvider {
       self.iam_role(role_name).await?;
 
       // Add the role to the instance profile
+      telemetry::provider_call("iam", "add_role_to_instance_profile");
       self
         .iam_client
         .add_role_to_instance_profile()
@@ -682,6 +701,7 @@ impl Provider for AwsProvider {
     self.instance_profile(name, name).await?;
 
     // Create EC2 instances
+    telemetry::provider_call("ec2", "run_instances");
     let resp = self
       .ec2_client
       .run_instances()
@@ -763,6 +783,7 @@ impl Provider for AwsProvider {
 
   async fn create_storage(&self, bucket_name: &str, iam_role_arn: &str) -> Result<()> {
     // Create the bucket
+    telemetry::provider_call("s3", "create_bucket");
     self
       .s3_client
       .create_bucket()
@@ -772,6 +793,7 @@ impl Provider for AwsProvider {
       .with_context(|| format!("Failed to create '{}' S3 bucket", bucket_name))?;
 
     // Block all public access
+    telemetry::provider_call("s3", "put_public_access_block");
     self
       .s3_client
       .put_public_access_block()
@@ -838,6 +860,7 @@ impl Provider for AwsProvider {
       iam_role_arn = iam_role_arn
     );
 
+    telemetry::provider_call("s3", "put_bucket_policy");
     self
       .s3_client
       .put_bucket_policy()
@@ -861,6 +884,7 @@ impl Provider for AwsProvider {
       .await
       .with_context(|| format!("Failed to read file at {}", file_path.display()))?;
 
+    telemetry::provider_call("s3", "put_object");
     self
       .s3_client
       .put_object()
@@ -890,6 +914,7 @@ impl Provider for AwsProvider {
     use tokio::fs::File;
     use tokio::io::AsyncWriteExt;
 
+    telemetry::provider_call("s3", "get_object");
     let response = self
       .s3_client
       .get_object()
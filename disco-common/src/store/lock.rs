@@ -0,0 +1,185 @@
+use anyhow::Result;
+use boa_engine::JsData;
+use boa_gc::{Finalize, Trace};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::task::JoinHandle;
+use tracing::{info, warn};
+
+use super::Store;
+
+/// Time to live of the lease holding a lock or leadership, in seconds. If the process holding it
+/// dies, it is released at most this long after the last keepalive.
+pub const SESSION_TTL: i64 = 10;
+
+/// Longest wait for a lock or leadership, after which acquiring it fails.
+pub const ACQUIRE_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// A lease kept alive in the background for as long as the session exists.
+#[derive(Debug)]
+struct Session {
+  store: Arc<dyn Store>,
+  lease: i64,
+  keep_alive: JoinHandle<()>,
+}
+
+impl Session {
+  async fn new(store: Arc<dyn Store>) -> Result<Self> {
+    let lease = store.lease_grant(SESSION_TTL).await?;
+
+    let keep_alive = tokio::spawn({
+      let store = store.clone();
+      async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(SESSION_TTL as u64 / 3));
+        interval.tick().await;
+
+        loop {
+          interval.tick().await;
+
+          match store.lease_keep_alive(lease).await {
+            Ok(true) => {}
+            Ok(false) => {
+              warn!("Lease {} expired", lease);
+              break;
+            }
+            Err(e) => warn!("Failed to keep lease {} alive: {}", lease, e),
+          }
+        }
+      }
+    });
+
+    Ok(Self {
+      store,
+      lease,
+      keep_alive,
+    })
+  }
+
+  /// Stops the keepalives and revokes the lease, deleting the keys attached to it.
+  async fn close(&self) -> Result<()> {
+    self.keep_alive.abort();
+    self.store.lease_revoke(self.lease).await
+  }
+
+  /// Revokes the lease if `result` is an error, as nothing is held with it then.
+  async fn close_on_error<T>(&self, result: Result<T>) -> Result<T> {
+    if result.is_err()
+      && let Err(e) = self.close().await
+    {
+      warn!("Failed to revoke lease {}: {}", self.lease, e);
+    }
+
+    result
+  }
+}
+
+impl Drop for Session {
+  fn drop(&mut self) {
+    // Without keepalives the lease expires, releasing whatever it held.
+    self.keep_alive.abort();
+  }
+}
+
+#[derive(Debug)]
+struct Held {
+  name: String,
+  token: i64,
+  session: Session,
+}
+
+/// A named lock, held until it is unlocked or the process holding it dies.
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "js", derive(Trace, Finalize, JsData))]
+pub struct Lock {
+  #[unsafe_ignore_trace]
+  inner: Arc<Held>,
+}
+
+impl Lock {
+  /// Waits until the named lock is free and acquires it, for at most [`ACQUIRE_TIMEOUT`].
+  pub async fn acquire(store: Arc<dyn Store>, name: &str) -> Result<Self> {
+    let session = Session::new(store).await?;
+    let token = session
+      .store
+      .lock(name, session.lease, ACQUIRE_TIMEOUT)
+      .await;
+    let token = session.close_on_error(token).await?;
+
+    info!("Acquired lock {} with token {}", name, token);
+
+    Ok(Self {
+      inner: Arc::new(Held {
+        name: name.to_string(),
+        token,
+        session,
+      }),
+    })
+  }
+
+  pub fn name(&self) -> &str {
+    &self.inner.name
+  }
+
+  /// Fencing token of this acquisition. Tokens of later acquisitions are greater, so resources
+  /// guarded by the lock can reject requests from a holder that lost it.
+  pub fn token(&self) -> i64 {
+    self.inner.token
+  }
+
+  pub async fn unlock(&self) -> Result<()> {
+    let held = &self.inner;
+    held.session.store.unlock(&held.name, held.token).await?;
+    held.session.close().await
+  }
+}
+
+/// Leadership of a named election, held until resigned or the process holding it dies.
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "js", derive(Trace, Finalize, JsData))]
+pub struct Election {
+  #[unsafe_ignore_trace]
+  inner: Arc<Held>,
+}
+
+impl Election {
+  /// Waits until elected leader of the named election, publishing `value` while leader, for at
+  /// most [`ACQUIRE_TIMEOUT`].
+  pub async fn campaign(store: Arc<dyn Store>, name: &str, value: &str) -> Result<Self> {
+    let session = Session::new(store).await?;
+    let token = session
+      .store
+      .campaign(name, session.lease, value, ACQUIRE_TIMEOUT)
+      .await;
+    let token = session.close_on_error(token).await?;
+
+    info!("Elected leader of {} with token {}", name, token);
+
+    Ok(Self {
+      inner: Arc::new(Held {
+        name: name.to_string(),
+        token,
+        session,
+      }),
+    })
+  }
+
+  /// Returns the value published by the leader of the named election, if any.
+  pub async fn leader(store: &dyn Store, name: &str) -> Result<Option<String>> {
+    store.leader(name).await
+  }
+
+  pub fn name(&self) -> &str {
+    &self.inner.name
+  }
+
+  /// Fencing token of this term, greater than the tokens of all previous terms.
+  pub fn token(&self) -> i64 {
+    self.inner.token
+  }
+
+  pub async fn resign(&self) -> Result<()> {
+    let held = &self.inner;
+    held.session.store.resign(&held.name, held.token).await?;
+    held.session.close().await
+  }
+}
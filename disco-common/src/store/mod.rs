@@ -0,0 +1,123 @@
+use anyhow::Result;
+use async_trait::async_trait;
+use boa_engine::JsData;
+use boa_gc::{Finalize, Trace};
+use std::sync::Arc;
+use std::time::Duration;
+
+mod lock;
+pub use lock::*;
+
+/// The part of a key's state a transaction compare looks at.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CompareTarget {
+  /// The value of the key; a missing key fails every value comparison.
+  Value(String),
+  /// The number of changes since the key was created, 0 if it does not exist.
+  Version(i64),
+  /// The revision of the last change to the key, 0 if it does not exist.
+  ModRevision(i64),
+  /// Whether the key exists.
+  Exists(bool),
+  /// The revision the key was created at, 0 if it does not exist.
+  CreateRevision(i64),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CompareResult {
+  Equal,
+  NotEqual,
+  Greater,
+  Less,
+}
+
+/// A condition on the current state of a key.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Compare {
+  pub key: String,
+  pub result: CompareResult,
+  pub target: CompareTarget,
+}
+
+/// A write within a transaction.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Op {
+  Set { key: String, value: String },
+  Delete { key: String },
+}
+
+/// Writes applied atomically: `success` if every compare holds, `failure` otherwise.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Txn {
+  pub compare: Vec<Compare>,
+  pub success: Vec<Op>,
+  pub failure: Vec<Op>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TxnResult {
+  /// Whether the compares held, i.e. which branch was applied.
+  pub succeeded: bool,
+  /// Revision of the store after the transaction.
+  pub revision: i64,
+}
+
+/// The replicated key-value store, as seen by scripts.
+#[async_trait]
+pub trait Store: Send + Sync + std::fmt::Debug {
+  /// Returns the current value of a key, `None` if it does not exist.
+  async fn get(&self, key: &str) -> Result<Option<String>>;
+
+  /// Sets a key to a value.
+  async fn set(&self, key: &str, value: &str) -> Result<()>;
+
+  /// Deletes a key, returning its previous value if it existed.
+  async fn delete(&self, key: &str) -> Result<Option<String>>;
+
+  /// Applies a transaction atomically.
+  async fn txn(&self, txn: Txn) -> Result<TxnResult>;
+
+  /// Grants a lease with the given time to live in seconds and returns its id.
+  async fn lease_grant(&self, ttl: i64) -> Result<i64>;
+
+  /// Renews a lease, returning `false` if it has already expired.
+  async fn lease_keep_alive(&self, id: i64) -> Result<bool>;
+
+  /// Revokes a lease, deleting the keys attached to it.
+  async fn lease_revoke(&self, id: i64) -> Result<()>;
+
+  /// Acquires a named lock with a lease, waiting at most `timeout` until it is free, and returns
+  /// its fencing token.
+  async fn lock(&self, name: &str, lease: i64, timeout: Duration) -> Result<i64>;
+
+  /// Releases a named lock, returning `false` if it was no longer held with the token.
+  async fn unlock(&self, name: &str, token: i64) -> Result<bool>;
+
+  /// Runs for leader of a named election with a lease, waiting at most `timeout` until elected,
+  /// and returns the fencing token of the term.
+  async fn campaign(&self, name: &str, lease: i64, value: &str, timeout: Duration) -> Result<i64>;
+
+  /// Gives up the leadership of a named election, returning `false` if the term had ended.
+  async fn resign(&self, name: &str, token: i64) -> Result<bool>;
+
+  /// Returns the value published by the leader of a named election, `None` if there is none.
+  async fn leader(&self, name: &str) -> Result<Option<String>>;
+}
+
+/// Handle to the store exposed to scripts as the `disco` global.
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "js", derive(Trace, Finalize, JsData))]
+pub struct Disco {
+  #[unsafe_ignore_trace]
+  store: Arc<dyn Store>,
+}
+
+impl Disco {
+  pub fn new(store: Arc<dyn Store>) -> Self {
+    Self { store }
+  }
+
+  pub fn store(&self) -> &Arc<dyn Store> {
+    &self.store
+  }
+}
@@ -0,0 +1,30 @@
+//! Instrumentation of the engine and the cloud providers.
+//!
+//! With the `metrics` feature, measurements are handed to the recorder installed by the process,
+//! if any; without it, they compile to nothing.
+
+use std::time::Duration;
+
+/// Records how long the engine took to run a script callback.
+pub fn engine_callback(name: &str, duration: Duration) {
+  #[cfg(feature = "metrics")]
+  metrics::histogram!("disco_engine_callback_duration_seconds", "callback" => name.to_string())
+    .record(duration);
+
+  #[cfg(not(feature = "metrics"))]
+  let _ = (name, duration);
+}
+
+/// Counts a call made to the API of a cloud provider.
+pub fn provider_call(service: &'static str, operation: &'static str) {
+  #[cfg(feature = "metrics")]
+  metrics::counter!(
+    "disco_provider_api_calls_total",
+    "service" => service,
+    "operation" => operation
+  )
+  .increment(1);
+
+  #[cfg(not(feature = "metrics"))]
+  let _ = (service, operation);
+}
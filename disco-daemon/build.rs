@@ -1,5 +1,6 @@
 fn main() -> Result<(), Box<dyn std::error::Error>> {
   println!("cargo:rerun-if-changed=src/*");
+  let out_dir = std::path::PathBuf::from(std::env::var("OUT_DIR")?);
   let config = prost_build::Config::new();
   let proto_files = [
     "proto/raft.proto",
@@ -11,14 +12,39 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
 
   tonic_build::configure()
     .btree_map(["."])
+    // Served through reflection, so that tools like grpcurl need no copy of the protos
+    .file_descriptor_set_path(out_dir.join("disco_descriptor.bin"))
     .type_attribute("disco.Node", "#[derive(Eq)]")
     .type_attribute("disco.SetRequest", "#[derive(Eq)]")
+    .type_attribute("disco.DeleteRequest", "#[derive(Eq)]")
+    .type_attribute("disco.CompareAndSwapRequest", "#[derive(Eq)]")
+    .type_attribute("disco.Operation", "#[derive(Eq)]")
+    .type_attribute("disco.Operation.op", "#[derive(Eq)]")
+    .type_attribute("disco.BatchRequest", "#[derive(Eq)]")
+    .type_attribute("disco.LeaseGrantRequest", "#[derive(Eq)]")
+    .type_attribute("disco.LeaseKeepAliveRequest", "#[derive(Eq)]")
+    .type_attribute("disco.LeaseRevokeRequest", "#[derive(Eq)]")
+    .type_attribute("disco.LeaseExpireRequest", "#[derive(Eq)]")
+    .type_attribute("disco.LeaseResetRequest", "#[derive(Eq)]")
+    .type_attribute("disco.Compare", "#[derive(Eq)]")
+    .type_attribute("disco.Compare.target", "#[derive(Eq)]")
+    .type_attribute("disco.TxnRequest", "#[derive(Eq)]")
+    .type_attribute("disco.Command", "#[derive(Eq)]")
+    .type_attribute("disco.Command.command", "#[derive(Eq)]")
+    .type_attribute("disco.CompactRequest", "#[derive(Eq)]")
+    .type_attribute("disco.NamespaceRequest", "#[derive(Eq)]")
+    .type_attribute("disco.DeleteNamespaceRequest", "#[derive(Eq)]")
+    .type_attribute("disco.KeyPrefix", "#[derive(Eq)]")
+    .type_attribute("disco.Policy", "#[derive(Eq)]")
+    .type_attribute("disco.DeletePolicyRequest", "#[derive(Eq)]")
     .type_attribute("disco.Response", "#[derive(Eq)]")
+    .type_attribute("disco.KeyValue", "#[derive(Eq)]")
     .type_attribute("disco.LeaderId", "#[derive(Eq)]")
     .type_attribute("disco.Vote", "#[derive(Eq)]")
     .type_attribute("disco.NodeIdSet", "#[derive(Eq)]")
     .type_attribute("disco.Membership", "#[derive(Eq)]")
     .type_attribute("disco.Entry", "#[derive(Eq)]")
+    .boxed("disco.BackupResponse.payload.meta")
     .compile_protos_with_config(config, &proto_files, &["proto"])?;
   Ok(())
 }
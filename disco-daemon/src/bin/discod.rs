@@ -4,20 +4,16 @@ use clap::Parser;
 use disco_daemon::config::Opt;
 use disco_daemon::node::Node;
 use disco_daemon::settings::Settings;
+use disco_daemon::telemetry;
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-  // Initialize tracing first, before any logging happens
-  tracing_subscriber::fmt()
-    .with_max_level(tracing::Level::INFO)
-    .with_env_filter(tracing_subscriber::EnvFilter::from_env("DISCO_LOG"))
-    .with_file(true)
-    .with_line_number(true)
-    .init();
-
   // Parse the parameters passed by arguments.
   let options = Opt::parse();
 
+  // Initialize tracing next, before any logging happens; spans are flushed when it is dropped
+  let _telemetry = telemetry::init("discod", options.otlp_endpoint.as_deref())?;
+
   let settings = Settings::new()?;
 
   let node = Node::new(options, settings).await?;
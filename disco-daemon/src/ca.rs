@@ -0,0 +1,396 @@
+//! The cluster certificate authority.
+//!
+//! The CA issues every node a server and a client certificate carrying the node URI
+//! `disco://<cluster>/node/<id>` as their subject alternative name, which is the identity the
+//! other nodes verify it against. Nodes either get their certificates issued offline with
+//! `disco certs issue`, or generate their own keys and have the leader sign their requests with
+//! `disco certs join`, proving that they may join with a token stored in the cluster.
+//!
+//! The CA is rolled by trusting a bundle of the old and the new CA certificates across the
+//! cluster, reissuing the node certificates from the new CA, and then dropping the old one.
+
+use std::sync::{Arc, RwLock};
+
+use aes_gcm::aead::OsRng;
+use aes_gcm::aead::rand_core::RngCore;
+use rcgen::{
+  BasicConstraints, Certificate, CertificateParams, CertificateSigningRequestParams, DnType,
+  ExtendedKeyUsagePurpose, IsCa, KeyPair, KeyUsagePurpose, SanType, SerialNumber,
+};
+use sha2::{Digest, Sha256};
+use time::{Duration, OffsetDateTime};
+
+use crate::tls;
+
+/// How long the CA certificate is valid for.
+const CA_VALIDITY: Duration = Duration::days(3650);
+
+/// How long the certificates of the nodes are valid for.
+const NODE_VALIDITY: Duration = Duration::days(365);
+
+/// How long the certificates of the clients are valid for.
+const CLIENT_VALIDITY: Duration = Duration::days(365);
+
+/// Leeway for clocks running behind the one of the issuing node.
+const BACKDATE: Duration = Duration::hours(1);
+
+/// Size of a join token in bytes, before hex encoding.
+const TOKEN_LEN: usize = 16;
+
+/// What a node certificate is used for.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Usage {
+  /// Accepting connections from the other nodes and from clients.
+  Server,
+  /// Dialing the other nodes.
+  Client,
+}
+
+/// A certificate with its private key, both PEM encoded.
+#[derive(Clone, Debug)]
+pub struct Issued {
+  pub cert_pem: String,
+  pub key_pem: String,
+}
+
+/// The CA of a node, replaced when the node reloads its certificates.
+pub type SharedAuthority = Arc<RwLock<Option<Arc<CertificateAuthority>>>>;
+
+/// The key and certificate of the cluster CA.
+pub struct CertificateAuthority {
+  cert: Certificate,
+  key: KeyPair,
+
+  /// PEM encoded certificates of the CAs the cluster trusts, this one included.
+  trusted_pem: String,
+}
+
+impl CertificateAuthority {
+  /// Creates a CA for `cluster_name`, with a new key.
+  pub fn create(cluster_name: &str) -> Result<Self, rcgen::Error> {
+    let key = KeyPair::generate()?;
+
+    let mut params = CertificateParams::default();
+    params
+      .distinguished_name
+      .push(DnType::CommonName, format!("{} CA", cluster_name));
+    params
+      .distinguished_name
+      .push(DnType::OrganizationName, cluster_name);
+    params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+    params.key_usages = vec![
+      KeyUsagePurpose::KeyCertSign,
+      KeyUsagePurpose::CrlSign,
+      KeyUsagePurpose::DigitalSignature,
+    ];
+    set_validity(&mut params, CA_VALIDITY);
+
+    let cert = params.self_signed(&key)?;
+    let trusted_pem = cert.pem();
+
+    Ok(CertificateAuthority {
+      cert,
+      key,
+      trusted_pem,
+    })
+  }
+
+  /// Loads a CA from its PEM encoded key and the PEM encoded certificates trusted by the cluster,
+  /// its own among them.
+  ///
+  /// While the CA is rolled, the cluster trusts both the old and the new CA: the certificate
+  /// matching the key is the one signing.
+  pub fn load(trusted_pem: &str, key_pem: &str) -> Result<Self, rcgen::Error> {
+    let key = KeyPair::from_pem(key_pem)?;
+    let public_key = key.public_key_der();
+
+    let certs = tls::certificates(trusted_pem.as_bytes())
+      .map_err(|_| rcgen::Error::CouldNotParseCertificate)?;
+    let der = certs
+      .iter()
+      .find(|der| {
+        x509_parser::parse_x509_certificate(der)
+          .is_ok_and(|(_, cert)| cert.public_key().raw == public_key.as_slice())
+      })
+      .ok_or(rcgen::Error::CouldNotParseCertificate)?;
+
+    // Only the subject and key identifier of the certificate matter for signing, so re-signing
+    // its parameters yields an equivalent issuer.
+    let cert = CertificateParams::from_ca_cert_der(der)?.self_signed(&key)?;
+
+    Ok(CertificateAuthority {
+      cert,
+      key,
+      trusted_pem: trusted_pem.to_string(),
+    })
+  }
+
+  /// The CA certificate, which every node trusts.
+  pub fn cert_pem(&self) -> String {
+    self.cert.pem()
+  }
+
+  /// The certificates of the CAs the cluster trusts, handed to joining nodes.
+  pub fn trusted_pem(&self) -> &str {
+    &self.trusted_pem
+  }
+
+  /// The CA private key, needed to issue certificates.
+  pub fn key_pem(&self) -> String {
+    self.key.serialize_pem()
+  }
+
+  /// Issues a certificate for node `node_id` of `cluster_name`, with a new key.
+  ///
+  /// `dns_names` are added next to the node URI, for clients reaching the node by name.
+  pub fn issue(
+    &self,
+    cluster_name: &str,
+    node_id: u64,
+    usage: Usage,
+    dns_names: &[String],
+  ) -> Result<Issued, rcgen::Error> {
+    let key = KeyPair::generate()?;
+    let params = node_params(cluster_name, node_id, usage, dns_names)?;
+    let cert = params.signed_by(&key, &self.cert, &self.key)?;
+
+    Ok(Issued {
+      cert_pem: cert.pem(),
+      key_pem: key.serialize_pem(),
+    })
+  }
+
+  /// Issues a client certificate for `subject` of `cluster_name`, with a new key.
+  ///
+  /// The subject is the common name the policies of the cluster grant a role to, such as a
+  /// read-only `monitoring`.
+  pub fn issue_client(&self, cluster_name: &str, subject: &str) -> Result<Issued, rcgen::Error> {
+    let key = KeyPair::generate()?;
+
+    let mut params = CertificateParams::default();
+    params.distinguished_name.push(DnType::CommonName, subject);
+    params
+      .distinguished_name
+      .push(DnType::OrganizationName, cluster_name);
+    params.key_usages = vec![KeyUsagePurpose::DigitalSignature];
+    params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ClientAuth];
+    params.use_authority_key_identifier_extension = true;
+    set_validity(&mut params, CLIENT_VALIDITY);
+
+    let cert = params.signed_by(&key, &self.cert, &self.key)?;
+
+    Ok(Issued {
+      cert_pem: cert.pem(),
+      key_pem: key.serialize_pem(),
+    })
+  }
+
+  /// Signs a certificate signing request of node `node_id` of `cluster_name`.
+  ///
+  /// Only the public key is taken from the request: the names it asks for are replaced with the
+  /// node URI, so that a node can only ever be issued its own identity.
+  pub fn sign(
+    &self,
+    csr_pem: &str,
+    cluster_name: &str,
+    node_id: u64,
+    usage: Usage,
+  ) -> Result<String, rcgen::Error> {
+    let mut csr = CertificateSigningRequestParams::from_pem(csr_pem)?;
+    csr.params = node_params(cluster_name, node_id, usage, &[])?;
+
+    Ok(csr.signed_by(&self.cert, &self.key)?.pem())
+  }
+}
+
+/// Generates a key for node `node_id` of `cluster_name` and a request to have it certified.
+///
+/// Returns the PEM encoded request and key.
+pub fn request(cluster_name: &str, node_id: u64) -> Result<(String, String), rcgen::Error> {
+  let key = KeyPair::generate()?;
+  let params = CertificateParams::new(vec![tls::node_uri(cluster_name, node_id)])?;
+  let csr = params.serialize_request(&key)?;
+
+  Ok((csr.pem()?, key.serialize_pem()))
+}
+
+/// Generates a new join token.
+pub fn new_token() -> String {
+  let mut bytes = [0u8; TOKEN_LEN];
+  OsRng.fill_bytes(&mut bytes);
+  hex(&bytes)
+}
+
+/// Returns the digest a join token is stored under, so that reading the store does not reveal
+/// the tokens.
+pub fn token_digest(token: &str) -> String {
+  hex(&Sha256::digest(token.as_bytes()))
+}
+
+fn hex(bytes: &[u8]) -> String {
+  bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn node_params(
+  cluster_name: &str,
+  node_id: u64,
+  usage: Usage,
+  dns_names: &[String],
+) -> Result<CertificateParams, rcgen::Error> {
+  let mut params = CertificateParams::default();
+  params
+    .distinguished_name
+    .push(DnType::CommonName, format!("node {}", node_id));
+  params
+    .distinguished_name
+    .push(DnType::OrganizationName, cluster_name);
+
+  params.subject_alt_names = vec![SanType::URI(
+    tls::node_uri(cluster_name, node_id).try_into()?,
+  )];
+  for name in dns_names {
+    params
+      .subject_alt_names
+      .push(SanType::DnsName(name.clone().try_into()?));
+  }
+
+  params.key_usages = vec![
+    KeyUsagePurpose::DigitalSignature,
+    KeyUsagePurpose::KeyEncipherment,
+  ];
+  params.extended_key_usages = vec![match usage {
+    Usage::Server => ExtendedKeyUsagePurpose::ServerAuth,
+    Usage::Client => ExtendedKeyUsagePurpose::ClientAuth,
+  }];
+  params.use_authority_key_identifier_extension = true;
+  set_validity(&mut params, NODE_VALIDITY);
+
+  Ok(params)
+}
+
+fn set_validity(params: &mut CertificateParams, validity: Duration) {
+  let now = OffsetDateTime::now_utc();
+  params.not_before = now - BACKDATE;
+  params.not_after = now + validity;
+
+  // Positive 127-bit serial numbers, unique without keeping track of the ones issued
+  let mut serial = [0u8; 16];
+  OsRng.fill_bytes(&mut serial);
+  serial[0] &= 0x7f;
+  params.serial_number = Some(SerialNumber::from_slice(&serial));
+}
+
+#[cfg(test)]
+mod tests {
+  use std::sync::Arc;
+
+  use rustls::RootCertStore;
+  use rustls::pki_types::{CertificateDer, UnixTime};
+  use rustls::server::WebPkiClientVerifier;
+
+  use super::*;
+
+  fn cert(pem: &str) -> CertificateDer<'static> {
+    tls::certificates(pem.as_bytes()).unwrap().remove(0)
+  }
+
+  /// Whether `cert_pem` chains up to one of `trusted_pem` as a client certificate.
+  fn verifies(trusted_pem: &str, cert_pem: &str) -> bool {
+    let mut roots = RootCertStore::empty();
+    roots.add_parsable_certificates(tls::certificates(trusted_pem.as_bytes()).unwrap());
+
+    let provider = Arc::new(rustls::crypto::aws_lc_rs::default_provider());
+    let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider)
+      .build()
+      .unwrap();
+
+    verifier
+      .verify_client_cert(&cert(cert_pem), &[], UnixTime::now())
+      .is_ok()
+  }
+
+  #[test]
+  fn test_issue() {
+    let ca = CertificateAuthority::create("test").unwrap();
+    let names = ["disco.example.com".to_string()];
+
+    let issued = ca.issue("test", 3, Usage::Client, &names).unwrap();
+    let der = cert(&issued.cert_pem);
+    assert_eq!(
+      tls::names(&der),
+      vec![tls::node_uri("test", 3), names[0].clone()]
+    );
+    assert!(tls::presents(&der, "disco://test/node/3"));
+    assert!(!tls::presents(&der, "disco://test/node/4"));
+    assert!(tls::private_key(issued.key_pem.as_bytes()).is_ok());
+    assert!(verifies(&ca.cert_pem(), &issued.cert_pem));
+
+    // Server certificates cannot be used to dial other nodes
+    let issued = ca.issue("test", 3, Usage::Server, &[]).unwrap();
+    assert!(!verifies(&ca.cert_pem(), &issued.cert_pem));
+
+    let other = CertificateAuthority::create("test").unwrap();
+    let issued = other.issue("test", 3, Usage::Client, &[]).unwrap();
+    assert!(!verifies(&ca.cert_pem(), &issued.cert_pem));
+  }
+
+  #[test]
+  fn test_issue_client() {
+    let ca = CertificateAuthority::create("test").unwrap();
+    let issued = ca.issue_client("test", "monitoring").unwrap();
+
+    let der = cert(&issued.cert_pem);
+    assert_eq!(tls::common_name(&der).as_deref(), Some("monitoring"));
+    assert!(tls::names(&der).is_empty());
+    assert!(verifies(&ca.cert_pem(), &issued.cert_pem));
+  }
+
+  #[test]
+  fn test_sign_replaces_requested_names() {
+    let ca = CertificateAuthority::create("test").unwrap();
+
+    // The request asks for the identity of another node
+    let (csr, key) = request("test", 1).unwrap();
+    let cert_pem = ca.sign(&csr, "test", 2, Usage::Client).unwrap();
+
+    assert_eq!(tls::names(&cert(&cert_pem)), vec![tls::node_uri("test", 2)]);
+    assert!(verifies(&ca.cert_pem(), &cert_pem));
+
+    // The certificate is for the key of the request
+    let der = cert(&cert_pem);
+    let (_, parsed) = x509_parser::parse_x509_certificate(&der).unwrap();
+    let key = KeyPair::from_pem(&key).unwrap();
+    assert_eq!(parsed.public_key().raw, key.public_key_der().as_slice());
+  }
+
+  #[test]
+  fn test_load() {
+    let old = CertificateAuthority::create("test").unwrap();
+    let new = CertificateAuthority::create("test").unwrap();
+    let bundle = format!("{}{}", old.cert_pem(), new.cert_pem());
+
+    // The certificate matching the key signs, whatever its position in the bundle
+    let loaded = CertificateAuthority::load(&bundle, &new.key_pem()).unwrap();
+    assert_eq!(loaded.trusted_pem(), bundle);
+
+    let issued = loaded.issue("test", 1, Usage::Client, &[]).unwrap();
+    assert!(verifies(&new.cert_pem(), &issued.cert_pem));
+    assert!(!verifies(&old.cert_pem(), &issued.cert_pem));
+    assert!(verifies(&bundle, &issued.cert_pem));
+
+    let other = CertificateAuthority::create("test").unwrap();
+    assert!(CertificateAuthority::load(&bundle, &other.key_pem()).is_err());
+  }
+
+  #[test]
+  fn test_tokens() {
+    let token = new_token();
+    assert_eq!(token.len(), 2 * TOKEN_LEN);
+    assert_ne!(token, new_token());
+
+    let digest = token_digest(&token);
+    assert_eq!(digest.len(), 64);
+    assert_eq!(digest, token_digest(&token));
+    assert_ne!(digest, token_digest(&new_token()));
+  }
+}
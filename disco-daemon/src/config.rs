@@ -11,9 +11,15 @@ pub struct Opt {
   pub addr: String,
 
   #[clap(long, env = "DISCO_CA_CERT")]
-  /// Path to the Certificate Authority certificate file
+  /// Path to the Certificate Authority certificate file, which may hold several CA certificates
+  /// while the CA is rolled
   pub ca_cert: String,
 
+  #[clap(long, env = "DISCO_CA_KEY")]
+  /// Path to the Certificate Authority private key file, letting the node sign the certificates
+  /// of joining nodes while it is the leader
+  pub ca_key: Option<String>,
+
   #[clap(long, env = "DISCO_SERVER_CERT")]
   /// Path to the server certificate file
   pub server_cert: String,
@@ -22,6 +28,11 @@ pub struct Opt {
   /// Path to the server private key file
   pub server_key: String,
 
+  #[clap(long, env = "DISCO_TLS_IDENTITY")]
+  /// Legacy identity in the node's certificates, a DNS name (defaults to "localhost"), only
+  /// recognized with legacy_tls_identities while certificates are reissued with the node URI
+  pub tls_identity: Option<String>,
+
   #[clap(long, env = "DISCO_CLIENT_CERT")]
   /// Path to the client certificate file
   pub client_cert: String,
@@ -33,4 +44,17 @@ pub struct Opt {
   #[clap(long, env = "DISCO_DATA_DIR")]
   /// Directory for storing application data
   pub data_dir: String,
+
+  #[clap(long, env = "DISCO_RESTORE_FROM")]
+  /// Path to a backup to seed the empty data directory with, starting a new single-node cluster.
+  /// Ignored once the data directory has been seeded with it, so it can stay set across restarts
+  pub restore_from: Option<String>,
+
+  #[clap(long, env = "DISCO_METRICS_ADDR")]
+  /// Network address to serve Prometheus metrics on over plain HTTP (e.g., "0.0.0.0:9100")
+  pub metrics_addr: Option<String>,
+
+  #[clap(long, env = "DISCO_OTLP_ENDPOINT")]
+  /// OTLP/gRPC endpoint to export traces to (e.g., "http://127.0.0.1:4317"), off if omitted
+  pub otlp_endpoint: Option<String>,
 }
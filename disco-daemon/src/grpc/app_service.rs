@@ -1,14 +1,60 @@
 use std::collections::BTreeMap;
+use std::pin::Pin;
 use std::sync::Arc;
+use std::time::Duration;
 
+use futures::Stream;
+use futures::StreamExt;
+use openraft::RaftSnapshotBuilder;
+use openraft::raft::ReadPolicy;
+use tokio::sync::broadcast::error::RecvError;
 use tonic::Request;
 use tonic::Response;
 use tonic::Status;
 use tracing::debug;
 
+use super::auth;
+use super::leader;
+use crate::ca;
+use crate::ca::SharedAuthority;
 use crate::protobuf;
+use crate::protobuf::Role;
 use crate::raft_types::*;
 use crate::store::StateMachineStore;
+use crate::store::lease;
+use crate::store::mvcc;
+use crate::store::namespace;
+use crate::store::policy;
+use crate::store::range;
+use crate::telemetry;
+use crate::tls;
+
+/// Prefix of the keys the cluster manages itself, which clients cannot write directly.
+pub const RESERVED_PREFIX: &str = "__disco/";
+
+/// Prefix of the keys backing named locks.
+pub const LOCK_PREFIX: &str = "__disco/lock/";
+
+/// Prefix of the keys backing named elections.
+pub const ELECTION_PREFIX: &str = "__disco/election/";
+
+/// Prefix of the keys backing join tokens, stored by their digest.
+pub const JOIN_TOKEN_PREFIX: &str = "__disco/join/";
+
+/// Prefix of the keys recording the nodes that joined with a token, by node id.
+pub const JOINED_PREFIX: &str = "__disco/joined/";
+
+/// Stream of changes sent to a watcher.
+pub type WatchResponseStream =
+  Pin<Box<dyn Stream<Item = Result<protobuf::WatchResponse, Status>> + Send>>;
+
+/// Stream of the metrics of a node.
+pub type MetricsResponseStream =
+  Pin<Box<dyn Stream<Item = Result<protobuf::MetricsResponse, Status>> + Send>>;
+
+/// Stream of the meta and data chunks of a backup.
+pub type BackupResponseStream =
+  Pin<Box<dyn Stream<Item = Result<protobuf::BackupResponse, Status>> + Send>>;
 
 /// External API service implementation providing key-value store operations.
 /// This service handles client requests for getting and setting values in the distributed store.
@@ -27,6 +73,10 @@ pub struct AppServiceImpl {
   /// The state machine store for direct reads
   /// The state machine's key-value store for direct reads
   state_machine_store: Arc<StateMachineStore>,
+  /// Name of the cluster, recorded in backups and named in certificates
+  cluster_name: String,
+  /// The cluster CA, if this node holds its key and can sign the certificates of joining nodes
+  ca: SharedAuthority,
 }
 
 impl AppServiceImpl {
@@ -35,12 +85,304 @@ impl AppServiceImpl {
   /// # Arguments
   /// * `raft` - The Raft node instance this service will use
   /// * `state_machine_store` - The state machine store for reading data
-  pub fn new(raft: Raft, state_machine_store: Arc<StateMachineStore>) -> Self {
+  /// * `cluster_name` - The name of the cluster this node belongs to
+  /// * `ca` - The cluster CA, if this node holds its key
+  pub fn new(
+    raft: Raft,
+    state_machine_store: Arc<StateMachineStore>,
+    cluster_name: String,
+    ca: SharedAuthority,
+  ) -> Self {
     AppServiceImpl {
       raft,
       state_machine_store,
+      cluster_name,
+      ca,
+    }
+  }
+
+  /// Replicates a command through Raft and returns its outcome once applied
+  ///
+  /// Writes rejected by the quotas of a namespace fail with `RESOURCE_EXHAUSTED`.
+  async fn write(&self, mut command: protobuf::Command) -> Result<protobuf::Response, Status> {
+    // Lease deadlines are derived from the proposer's clock, never from the applying node's
+    command.timestamp = lease::now();
+    command.trace_context = telemetry::current_context();
+
+    let res = self
+      .raft
+      .client_write(command)
+      .await
+      .map_err(|e| leader::status("Failed to write to store", e))?;
+
+    if res.data.quota_exceeded {
+      return Err(Status::resource_exhausted("Namespace quota exceeded"));
+    }
+
+    Ok(res.data)
+  }
+
+  /// Waits until the local state machine can serve a read with the requested consistency
+  async fn ensure_consistency(&self, consistency: protobuf::ReadConsistency) -> Result<(), Status> {
+    let read_policy = match consistency {
+      protobuf::ReadConsistency::Linearizable => ReadPolicy::ReadIndex,
+      protobuf::ReadConsistency::Lease => ReadPolicy::LeaseRead,
+      protobuf::ReadConsistency::Stale => return Ok(()),
+    };
+
+    // Returns once this node has confirmed it is the leader and applied up to the read log id
+    self
+      .raft
+      .ensure_linearizable(read_policy)
+      .await
+      .map_err(|e| leader::status("Failed to ensure consistent read", e))?;
+
+    Ok(())
+  }
+
+  /// Creates `key` of namespace `ns` attached to `lease` as soon as it does not exist, waiting for
+  /// the current holder to delete it or for its lease to expire
+  ///
+  /// Returns the revision the key was created at, which serves as fencing token: it grows with
+  /// every acquisition. Acquiring again with the lease that holds the key returns the same token,
+  /// so a request interrupted by a timeout can simply be retried.
+  ///
+  /// Gives up with `DEADLINE_EXCEEDED` once `timeout` has passed, if set.
+  async fn acquire(
+    &self,
+    ns: String,
+    key: String,
+    value: String,
+    lease: i64,
+    timeout: Option<Duration>,
+  ) -> Result<i64, Status> {
+    validate(&ns, &key)?;
+    if lease == 0 {
+      return Err(Status::invalid_argument("A lease is required"));
+    }
+
+    let Some(timeout) = timeout else {
+      return self.wait_to_acquire(ns, key, value, lease).await;
+    };
+
+    tokio::time::timeout(timeout, self.wait_to_acquire(ns, key, value, lease))
+      .await
+      .map_err(|_| Status::deadline_exceeded(format!("Gave up waiting after {:?}", timeout)))?
+  }
+
+  async fn wait_to_acquire(
+    &self,
+    ns: String,
+    key: String,
+    value: String,
+    lease: i64,
+  ) -> Result<i64, Status> {
+    let stored = namespace::key(&ns, &key);
+
+    loop {
+      // Subscribing before the attempt makes sure a release right after it is not missed.
+      let mut subscription = self
+        .state_machine_store
+        .watch(0)
+        .ok_or_else(|| Status::internal("Failed to watch the store"))?;
+
+      // Whether the lease holds the key already is decided by the log along with the attempt to
+      // create it, never by the local state alone.
+      let txn = protobuf::TxnRequest {
+        compare: vec![protobuf::Compare {
+          key: key.clone(),
+          result: protobuf::compare::CompareResult::Equal.into(),
+          target: Some(protobuf::compare::Target::Lease(lease)),
+          namespace: ns.clone(),
+        }],
+        success: vec![],
+        failure: vec![
+          protobuf::CompareAndSwapRequest {
+            key: key.clone(),
+            expected: None,
+            new: Some(value.clone()),
+            lease,
+            namespace: ns.clone(),
+          }
+          .into(),
+        ],
+      };
+      let res = self.write(txn.into()).await?;
+
+      if res.succeeded {
+        // The transaction has been applied here, so the key is read as of then or later. Should
+        // it have been released since, the next attempt finds out.
+        let sm = self
+          .state_machine_store
+          .state_machine
+          .lock()
+          .map_err(|e| Status::internal(format!("error getting lock on sm: {}", e)))?;
+
+        if let Some(record) = mvcc::latest(&sm, &stored)
+          && sm.key_leases.get(&stored) == Some(&lease)
+        {
+          return Ok(record.create_revision);
+        }
+        continue;
+      }
+
+      if res.success {
+        return Ok(res.revision);
+      }
+
+      // The swap only fails on a missing key if the lease is gone.
+      if res
+        .responses
+        .first()
+        .is_some_and(|res| res.prev_value.is_none())
+      {
+        return Err(Status::failed_precondition(format!(
+          "Lease {} does not exist",
+          lease
+        )));
+      }
+
+      debug!("Waiting for {} to be released", key);
+
+      // Wait for the key to be deleted. If the watcher falls behind, simply try again.
+      while let Ok(response) = subscription.receiver.recv().await {
+        let released = response.events.iter().any(|event| {
+          event.namespace == ns
+            && event.key == key
+            && event.r#type() == protobuf::event::EventType::Delete
+        });
+
+        if released {
+          break;
+        }
+      }
+    }
+  }
+
+  /// Checks that the client may write every key attached to lease `id`, which revoking the lease
+  /// deletes and renewing it keeps
+  #[allow(clippy::result_large_err)]
+  fn check_lease(&self, grant: &auth::Grant, id: i64) -> Result<(), Status> {
+    let sm = self
+      .state_machine_store
+      .state_machine
+      .lock()
+      .map_err(|e| Status::internal(format!("error getting lock on sm: {}", e)))?;
+
+    for (stored, _) in sm.key_leases.iter().filter(|(_, lease)| **lease == id) {
+      let (ns, key) = namespace::split(stored);
+      grant.check_write(ns, key)?;
+    }
+
+    Ok(())
+  }
+
+  /// Deletes `key` if it still holds the value written with fencing token `token`, the revision
+  /// it was created at
+  async fn release(
+    &self,
+    ns: String,
+    key: String,
+    token: i64,
+  ) -> Result<protobuf::Response, Status> {
+    validate(&ns, &key)?;
+
+    let txn = protobuf::TxnRequest {
+      compare: vec![protobuf::Compare {
+        key: key.clone(),
+        result: protobuf::compare::CompareResult::Equal.into(),
+        target: Some(protobuf::compare::Target::CreateRevision(token)),
+        namespace: ns.clone(),
+      }],
+      success: vec![protobuf::DeleteRequest { key, namespace: ns }.into()],
+      failure: vec![],
+    };
+
+    let res = self.write(txn.into()).await?;
+
+    Ok(protobuf::Response {
+      success: res.succeeded,
+      revision: res.revision,
+      ..Default::default()
+    })
+  }
+}
+
+/// Rejects keys that do not belong to the namespace they are addressed in
+#[allow(clippy::result_large_err)]
+fn validate(ns: &str, key: &str) -> Result<(), Status> {
+  namespace::validate(ns, key).map_err(Status::invalid_argument)
+}
+
+/// Rejects the keys a client cannot write: those `validate` rejects, empty ones and the reserved
+/// ones
+#[allow(clippy::result_large_err)]
+fn validate_write(ns: &str, key: &str) -> Result<(), Status> {
+  namespace::validate_write(ns, key).map_err(Status::invalid_argument)?;
+
+  if key.starts_with(RESERVED_PREFIX) {
+    return Err(Status::invalid_argument(format!(
+      "Keys under {:?} are reserved",
+      RESERVED_PREFIX
+    )));
+  }
+
+  Ok(())
+}
+
+/// Validates the key of every operation of a batch or transaction branch
+#[allow(clippy::result_large_err)]
+fn validate_operations(operations: &[protobuf::Operation]) -> Result<(), Status> {
+  for op in operations.iter().filter_map(|op| op.op.as_ref()) {
+    match op {
+      protobuf::operation::Op::Set(req) => validate_write(&req.namespace, &req.key)?,
+      protobuf::operation::Op::Delete(req) => validate_write(&req.namespace, &req.key)?,
+      protobuf::operation::Op::CompareAndSwap(req) => validate_write(&req.namespace, &req.key)?,
     }
   }
+
+  Ok(())
+}
+
+/// Returns how long the client is willing to wait for `request`, as sent in `grpc-timeout`
+fn request_timeout<T>(request: &Request<T>) -> Option<Duration> {
+  let timeout = request.metadata().get("grpc-timeout")?.to_str().ok()?;
+  let (amount, unit) = timeout.split_at(timeout.len().checked_sub(1)?);
+  let amount = amount.parse::<u64>().ok()?;
+
+  match unit {
+    "H" => Some(Duration::from_secs(amount.checked_mul(60 * 60)?)),
+    "M" => Some(Duration::from_secs(amount.checked_mul(60)?)),
+    "S" => Some(Duration::from_secs(amount)),
+    "m" => Some(Duration::from_millis(amount)),
+    "u" => Some(Duration::from_micros(amount)),
+    "n" => Some(Duration::from_nanos(amount)),
+    _ => None,
+  }
+}
+
+/// Resolves the revision a read is served at, 0 standing for the latest one
+#[allow(clippy::result_large_err)]
+fn read_revision(sm: &protobuf::StateMachineData, revision: i64) -> Result<i64, Status> {
+  if revision == 0 {
+    return Ok(sm.revision);
+  }
+
+  if revision < sm.compact_revision {
+    return Err(Status::out_of_range(format!(
+      "Revision {} has been compacted, the oldest available is {}",
+      revision, sm.compact_revision
+    )));
+  }
+
+  if revision > sm.revision {
+    return Err(Status::out_of_range(format!(
+      "Revision {} is ahead of the store at revision {}",
+      revision, sm.revision
+    )));
+  }
+
+  Ok(revision)
 }
 
 #[tonic::async_trait]
@@ -57,47 +399,672 @@ impl protobuf::app_service_server::AppService for AppServiceImpl {
     &self,
     request: Request<protobuf::SetRequest>,
   ) -> Result<Response<protobuf::Response>, Status> {
+    let grant = auth::authorize(&request, Role::Writer)?;
     let req = request.into_inner();
     debug!("Processing set request for key: {}", req.key.clone());
+    validate_write(&req.namespace, &req.key)?;
+    grant.check_write(&req.namespace, &req.key)?;
 
-    let res = self
-      .raft
-      .client_write(req.clone())
-      .await
-      .map_err(|e| Status::internal(format!("Failed to write to store: {}", e)))?;
+    let key = req.key.clone();
+    let res = self.write(req.into()).await?;
+
+    debug!("Successfully set value for key: {}", key);
+    Ok(Response::new(res))
+  }
+
+  /// Deletes a key from the distributed store
+  ///
+  /// # Arguments
+  /// * `request` - Contains the key to delete
+  ///
+  /// # Returns
+  /// * `Ok(Response)` - Response carrying the previous value, if the key existed
+  /// * `Err(Status)` - Error status if the delete operation fails
+  async fn delete(
+    &self,
+    request: Request<protobuf::DeleteRequest>,
+  ) -> Result<Response<protobuf::Response>, Status> {
+    let grant = auth::authorize(&request, Role::Writer)?;
+    let req = request.into_inner();
+    debug!("Processing delete request for key: {}", req.key);
+    validate_write(&req.namespace, &req.key)?;
+    grant.check_write(&req.namespace, &req.key)?;
+
+    let key = req.key.clone();
+    let res = self.write(req.into()).await?;
+
+    debug!("Successfully deleted key: {}", key);
+    Ok(Response::new(res))
+  }
+
+  /// Replaces the value of a key if it currently holds the expected value
+  ///
+  /// # Arguments
+  /// * `request` - Contains the key, the expected value and the new value
+  ///
+  /// # Returns
+  /// * `Ok(Response)` - Response with `success` unset if the comparison failed, along with the
+  ///   current value
+  /// * `Err(Status)` - Error status if the write fails
+  async fn compare_and_swap(
+    &self,
+    request: Request<protobuf::CompareAndSwapRequest>,
+  ) -> Result<Response<protobuf::Response>, Status> {
+    let grant = auth::authorize(&request, Role::Writer)?;
+    let req = request.into_inner();
+    debug!("Processing compare-and-swap request for key: {}", req.key);
+    validate_write(&req.namespace, &req.key)?;
+    grant.check_write(&req.namespace, &req.key)?;
+
+    let key = req.key.clone();
+    let res = self.write(req.into()).await?;
+
+    debug!(
+      "Compare-and-swap for key {} {}",
+      key,
+      if res.success { "succeeded" } else { "failed" }
+    );
+    Ok(Response::new(res))
+  }
+
+  /// Applies several writes atomically
+  ///
+  /// # Arguments
+  /// * `request` - Contains the operations to apply in order
+  ///
+  /// # Returns
+  /// * `Ok(Response)` - Response with the result of each operation; if `success` is unset none of
+  ///   them took effect
+  /// * `Err(Status)` - Error status if the write fails
+  async fn batch(
+    &self,
+    request: Request<protobuf::BatchRequest>,
+  ) -> Result<Response<protobuf::Response>, Status> {
+    let grant = auth::authorize(&request, Role::Writer)?;
+    let req = request.into_inner();
+    debug!(
+      "Processing batch request with {} operations",
+      req.operations.len()
+    );
+    validate_operations(&req.operations)?;
+    grant.check_operations(&req.operations)?;
+
+    let res = self.write(req.into()).await?;
+
+    debug!("Batch {}", if res.success { "applied" } else { "rejected" });
+    Ok(Response::new(res))
+  }
+
+  /// Applies one of two sets of writes atomically, depending on the state of some keys
+  ///
+  /// # Arguments
+  /// * `request` - Contains the compares and the operations to apply if they all hold or not
+  ///
+  /// # Returns
+  /// * `Ok(Response)` - Response with `succeeded` set if the compares held and the result of each
+  ///   operation of the chosen branch; if `success` is unset none of them took effect
+  /// * `Err(Status)` - Error status if the write fails
+  async fn txn(
+    &self,
+    request: Request<protobuf::TxnRequest>,
+  ) -> Result<Response<protobuf::Response>, Status> {
+    let grant = auth::authorize(&request, Role::Writer)?;
+    let req = request.into_inner();
+    debug!("Processing txn request with {} compares", req.compare.len());
+
+    for cmp in &req.compare {
+      validate(&cmp.namespace, &cmp.key)?;
+      grant.check_key(&cmp.namespace, &cmp.key)?;
+    }
+    validate_operations(&req.success)?;
+    validate_operations(&req.failure)?;
+    grant.check_operations(&req.success)?;
+    grant.check_operations(&req.failure)?;
+
+    let res = self.write(req.into()).await?;
+
+    debug!(
+      "Txn took the {} branch",
+      if res.succeeded { "success" } else { "failure" }
+    );
+    Ok(Response::new(res))
+  }
+
+  /// Grants a lease that expires unless it is kept alive
+  ///
+  /// The lease reaches no key until one is attached to it, which checks the grant for that key.
+  ///
+  /// # Arguments
+  /// * `request` - Contains the time to live of the lease in seconds
+  ///
+  /// # Returns
+  /// * `Ok(Response)` - Response carrying the id of the new lease in `lease_id`
+  /// * `Err(Status)` - Error status if the TTL is invalid or the write fails
+  async fn lease_grant(
+    &self,
+    request: Request<protobuf::LeaseGrantRequest>,
+  ) -> Result<Response<protobuf::Response>, Status> {
+    auth::authorize(&request, Role::Writer)?;
+    let req = request.into_inner();
+    debug!("Processing lease grant request with ttl: {}", req.ttl);
+
+    if req.ttl <= 0 {
+      return Err(Status::invalid_argument("Lease TTL must be positive"));
+    }
+
+    let res = self.write(req.into()).await?;
+
+    debug!("Granted lease {}", res.lease_id);
+    Ok(Response::new(res))
+  }
+
+  /// Renews a lease for another full TTL
+  ///
+  /// The renewal is written to the log, so it survives a change of leader and an expiry proposed
+  /// before it is applied finds the lease renewed.
+  ///
+  /// # Arguments
+  /// * `request` - Contains the id of the lease to renew
+  ///
+  /// # Returns
+  /// * `Ok(Response)` - Response with `success` unset if the lease no longer exists
+  /// * `Err(Status)` - Error status if the client may not write a key attached to the lease, or
+  ///   the write fails
+  async fn lease_keep_alive(
+    &self,
+    request: Request<protobuf::LeaseKeepAliveRequest>,
+  ) -> Result<Response<protobuf::Response>, Status> {
+    let grant = auth::authorize(&request, Role::Writer)?;
+    let req = request.into_inner();
+    debug!("Processing lease keepalive request for lease: {}", req.id);
+
+    self.check_lease(&grant, req.id)?;
+
+    let res = self.write(req.into()).await?;
+    Ok(Response::new(res))
+  }
+
+  /// Revokes a lease, deleting every key attached to it
+  ///
+  /// # Arguments
+  /// * `request` - Contains the id of the lease to revoke
+  ///
+  /// # Returns
+  /// * `Ok(Response)` - Response with `success` unset if the lease did not exist
+  /// * `Err(Status)` - Error status if the client may not write a key attached to the lease, or
+  ///   the write fails
+  async fn lease_revoke(
+    &self,
+    request: Request<protobuf::LeaseRevokeRequest>,
+  ) -> Result<Response<protobuf::Response>, Status> {
+    let grant = auth::authorize(&request, Role::Writer)?;
+    let req = request.into_inner();
+    debug!("Processing lease revoke request for lease: {}", req.id);
 
-    debug!("Successfully set value for key: {}", req.key);
-    Ok(Response::new(res.data))
+    self.check_lease(&grant, req.id)?;
+
+    let id = req.id;
+    let res = self.write(req.into()).await?;
+
+    debug!("Revoked lease {}", id);
+    Ok(Response::new(res))
+  }
+
+  /// Acquires a named lock, waiting until no other lease holds it
+  ///
+  /// # Arguments
+  /// * `request` - Contains the name of the lock and the lease to hold it with
+  ///
+  /// # Returns
+  /// * `Ok(Response)` - The key backing the lock and the fencing token of this acquisition
+  /// * `Err(Status)` - `FAILED_PRECONDITION` if the lease does not exist, `DEADLINE_EXCEEDED` if
+  ///   the lock is still held when the `grpc-timeout` of the request runs out
+  async fn lock(
+    &self,
+    request: Request<protobuf::LockRequest>,
+  ) -> Result<Response<protobuf::LockResponse>, Status> {
+    let grant = auth::authorize(&request, Role::Writer)?;
+    let timeout = request_timeout(&request);
+    let req = request.into_inner();
+    debug!(
+      "Processing lock request for {} with lease {}",
+      req.name, req.lease
+    );
+
+    let key = format!("{}{}", LOCK_PREFIX, req.name);
+    grant.check_write(&req.namespace, &key)?;
+    let value = req.lease.to_string();
+    let token = self
+      .acquire(req.namespace, key.clone(), value, req.lease, timeout)
+      .await?;
+
+    debug!("Lock {} acquired with token {}", req.name, token);
+    Ok(Response::new(protobuf::LockResponse { key, token }))
+  }
+
+  /// Releases a named lock
+  ///
+  /// # Arguments
+  /// * `request` - Contains the name of the lock and the fencing token it was acquired with
+  ///
+  /// # Returns
+  /// * `Ok(Response)` - Response with `success` unset if the lock was no longer held with the token
+  /// * `Err(Status)` - Error status if the write fails
+  async fn unlock(
+    &self,
+    request: Request<protobuf::ReleaseRequest>,
+  ) -> Result<Response<protobuf::Response>, Status> {
+    let grant = auth::authorize(&request, Role::Writer)?;
+    let req = request.into_inner();
+    debug!("Processing unlock request for {}", req.name);
+
+    let key = format!("{}{}", LOCK_PREFIX, req.name);
+    grant.check_write(&req.namespace, &key)?;
+
+    let res = self.release(req.namespace, key, req.token).await?;
+    Ok(Response::new(res))
+  }
+
+  /// Runs for leader of a named election, waiting until elected
+  ///
+  /// # Arguments
+  /// * `request` - Contains the name of the election, the lease to hold the leadership with and
+  ///   the value to publish while leader
+  ///
+  /// # Returns
+  /// * `Ok(Response)` - The key backing the leadership and the fencing token of this term
+  /// * `Err(Status)` - `FAILED_PRECONDITION` if the lease does not exist, `DEADLINE_EXCEEDED` if
+  ///   another lease still leads when the `grpc-timeout` of the request runs out
+  async fn campaign(
+    &self,
+    request: Request<protobuf::CampaignRequest>,
+  ) -> Result<Response<protobuf::LockResponse>, Status> {
+    let grant = auth::authorize(&request, Role::Writer)?;
+    let timeout = request_timeout(&request);
+    let req = request.into_inner();
+    debug!(
+      "Processing campaign request for {} with lease {}",
+      req.name, req.lease
+    );
+
+    let key = format!("{}{}", ELECTION_PREFIX, req.name);
+    grant.check_write(&req.namespace, &key)?;
+    let token = self
+      .acquire(req.namespace, key.clone(), req.value, req.lease, timeout)
+      .await?;
+
+    debug!("Elected leader of {} with token {}", req.name, token);
+    Ok(Response::new(protobuf::LockResponse { key, token }))
+  }
+
+  /// Gives up the leadership of a named election
+  ///
+  /// # Arguments
+  /// * `request` - Contains the name of the election and the fencing token of the term
+  ///
+  /// # Returns
+  /// * `Ok(Response)` - Response with `success` unset if the term had already ended
+  /// * `Err(Status)` - Error status if the write fails
+  async fn resign(
+    &self,
+    request: Request<protobuf::ReleaseRequest>,
+  ) -> Result<Response<protobuf::Response>, Status> {
+    let grant = auth::authorize(&request, Role::Writer)?;
+    let req = request.into_inner();
+    debug!("Processing resign request for {}", req.name);
+
+    let key = format!("{}{}", ELECTION_PREFIX, req.name);
+    grant.check_write(&req.namespace, &key)?;
+
+    let res = self.release(req.namespace, key, req.token).await?;
+    Ok(Response::new(res))
+  }
+
+  /// Returns the value published by the leader of a named election
+  ///
+  /// # Arguments
+  /// * `request` - Contains the name of the election
+  ///
+  /// # Returns
+  /// * `Ok(Response)` - The value of the leader, with the fencing token of its term as
+  ///   `kv.create_revision`
+  /// * `Err(Status)` - `NOT_FOUND` if the election has no leader
+  async fn leader(
+    &self,
+    request: Request<protobuf::LeaderRequest>,
+  ) -> Result<Response<protobuf::Response>, Status> {
+    let grant = auth::authorize(&request, Role::Reader)?;
+    let req = request.into_inner();
+    debug!("Processing leader request for {}", req.name);
+    grant.check_key(&req.namespace, &format!("{}{}", ELECTION_PREFIX, req.name))?;
+
+    self
+      .ensure_consistency(protobuf::ReadConsistency::Linearizable)
+      .await?;
+
+    let sm = self
+      .state_machine_store
+      .state_machine
+      .lock()
+      .map_err(|e| Status::internal(format!("error getting lock on sm: {}", e)))?;
+
+    let key = format!("{}{}", ELECTION_PREFIX, req.name);
+    validate(&req.namespace, &key)?;
+
+    let record = mvcc::latest(&sm, &namespace::key(&req.namespace, &key))
+      .ok_or_else(|| Status::not_found(format!("Election {} has no leader", req.name)))?;
+
+    Ok(Response::new(protobuf::Response {
+      value: record.value.clone(),
+      success: true,
+      revision: sm.revision,
+      kv: Some(mvcc::key_value(&key, record)),
+      ..Default::default()
+    }))
+  }
+
+  type WatchStream = WatchResponseStream;
+
+  /// Streams the changes made to keys starting with a prefix
+  ///
+  /// # Arguments
+  /// * `request` - Contains the prefix and the revision to resume from
+  ///
+  /// # Returns
+  /// * `Ok(Response)` - Stream with the changes of each command, in revision order
+  /// * `Err(Status)` - `OUT_OF_RANGE` if changes from the start revision are no longer retained
+  ///
+  /// The stream ends with an error if the watcher falls behind or the state is replaced by a
+  /// snapshot; the client then reads the current state and watches from its revision.
+  async fn watch(
+    &self,
+    request: Request<protobuf::WatchRequest>,
+  ) -> Result<Response<Self::WatchStream>, Status> {
+    let grant = auth::authorize(&request, Role::Reader)?;
+    let req = request.into_inner();
+    debug!(
+      "Processing watch request for prefix: {} from revision {}",
+      req.prefix, req.start_revision
+    );
+    validate(&req.namespace, &req.prefix)?;
+    grant.check_key(&req.namespace, &req.prefix)?;
+
+    let subscription = self
+      .state_machine_store
+      .watch(req.start_revision)
+      .ok_or_else(|| {
+        Status::out_of_range(format!(
+          "Revision {} is no longer available",
+          req.start_revision
+        ))
+      })?;
+
+    let backlog = futures::stream::iter(subscription.backlog).map(Ok);
+    let live = futures::stream::unfold(Some(subscription.receiver), |receiver| async move {
+      let mut receiver = receiver?;
+      match receiver.recv().await {
+        Ok(response) => Some((Ok(response), Some(receiver))),
+        Err(RecvError::Lagged(n)) => Some((
+          Err(Status::data_loss(format!(
+            "Watcher fell behind by {} changes",
+            n
+          ))),
+          None,
+        )),
+        Err(RecvError::Closed) => Some((Err(Status::aborted("Watch history was reset")), None)),
+      }
+    });
+
+    let (ns, prefix) = (req.namespace, req.prefix);
+    let stream = backlog.chain(live).filter_map(move |result| {
+      futures::future::ready(match result {
+        Ok(response) => matching_events(&response, &ns, &prefix).map(Ok),
+        Err(status) => Some(Err(status)),
+      })
+    });
+
+    Ok(Response::new(Box::pin(stream)))
   }
 
   /// Gets a value for a given key from the distributed store
   ///
   /// # Arguments
-  /// * `request` - Contains the key to retrieve
+  /// * `request` - Contains the key to retrieve, the read consistency and the revision to read at
   ///
   /// # Returns
-  /// * `Ok(Response)` - Success response containing the value
-  /// * `Err(Status)` - Error status if the get operation fails
+  /// * `Ok(Response)` - Success response containing the value and its revisions
+  /// * `Err(Status)` - `NOT_FOUND` if the key does not exist, `OUT_OF_RANGE` if the revision was
+  ///   compacted, `UNAVAILABLE` if a linearizable or lease read is sent to a node that cannot
+  ///   confirm it is the leader
   async fn get(
     &self,
     request: Request<protobuf::GetRequest>,
   ) -> Result<Response<protobuf::Response>, Status> {
+    let grant = auth::authorize(&request, Role::Reader)?;
+    let req = request.into_inner();
+    debug!(
+      "Processing {:?} get request for key: {}",
+      req.consistency(),
+      req.key
+    );
+    validate(&req.namespace, &req.key)?;
+    grant.check_key(&req.namespace, &req.key)?;
+
+    self.ensure_consistency(req.consistency()).await?;
+
+    let sm = self
+      .state_machine_store
+      .state_machine
+      .lock()
+      .map_err(|e| Status::internal(format!("error getting lock on sm: {}", e)))?;
+    let revision = read_revision(&sm, req.revision)?;
+    let record = mvcc::at(&sm, &namespace::key(&req.namespace, &req.key), revision)
+      .ok_or_else(|| Status::not_found(format!("Key not found: {}", req.key)))?;
+
+    debug!(
+      "Successfully retrieved value for key: {} at revision {}",
+      req.key, revision
+    );
+    Ok(Response::new(protobuf::Response {
+      value: record.value.clone(),
+      success: true,
+      revision,
+      kv: Some(mvcc::key_value(&req.key, record)),
+      ..Default::default()
+    }))
+  }
+
+  /// Lists the keys starting with a prefix in lexicographic order
+  ///
+  /// # Arguments
+  /// * `request` - Contains the prefix, the key to resume after, the page size, the read
+  ///   consistency and the revision to read at
+  ///
+  /// # Returns
+  /// * `Ok(Response)` - A page of keys with the revision it was read at; later pages are read at
+  ///   the same revision to get a consistent listing
+  /// * `Err(Status)` - `OUT_OF_RANGE` if the revision was compacted while listing
+  async fn range(
+    &self,
+    request: Request<protobuf::RangeRequest>,
+  ) -> Result<Response<protobuf::RangeResponse>, Status> {
+    let grant = auth::authorize(&request, Role::Reader)?;
+    let req = request.into_inner();
+    debug!(
+      "Processing {:?} range request for prefix: {} after: {}",
+      req.consistency(),
+      req.prefix,
+      req.start_after
+    );
+    validate(&req.namespace, &req.prefix)?;
+    grant.check_key(&req.namespace, &req.prefix)?;
+
+    self.ensure_consistency(req.consistency()).await?;
+
+    let sm = self
+      .state_machine_store
+      .state_machine
+      .lock()
+      .map_err(|e| Status::internal(format!("error getting lock on sm: {}", e)))?;
+
+    let revision = read_revision(&sm, req.revision)?;
+    let res = range::range(
+      &sm,
+      &req.namespace,
+      &req.prefix,
+      &req.start_after,
+      req.limit,
+      revision,
+    );
+
+    debug!(
+      "Listed {} keys for prefix: {} at revision {}",
+      res.kvs.len(),
+      req.prefix,
+      res.revision
+    );
+    Ok(Response::new(res))
+  }
+
+  /// Lists the retained changes of a key
+  ///
+  /// # Arguments
+  /// * `request` - Contains the key and the read consistency
+  ///
+  /// # Returns
+  /// * `Ok(Response)` - The changes of the key since the compaction revision, oldest first
+  /// * `Err(Status)` - Error status if the read cannot be served with the requested consistency
+  async fn history(
+    &self,
+    request: Request<protobuf::HistoryRequest>,
+  ) -> Result<Response<protobuf::HistoryResponse>, Status> {
+    let grant = auth::authorize(&request, Role::Reader)?;
+    let req = request.into_inner();
+    debug!(
+      "Processing {:?} history request for key: {}",
+      req.consistency(),
+      req.key
+    );
+    validate(&req.namespace, &req.key)?;
+    grant.check_key(&req.namespace, &req.key)?;
+
+    self.ensure_consistency(req.consistency()).await?;
+
+    let sm = self
+      .state_machine_store
+      .state_machine
+      .lock()
+      .map_err(|e| Status::internal(format!("error getting lock on sm: {}", e)))?;
+
+    let revisions = mvcc::history(&sm, &namespace::key(&req.namespace, &req.key))
+      .iter()
+      .map(|record| mvcc::key_value(&req.key, record))
+      .collect();
+
+    Ok(Response::new(protobuf::HistoryResponse {
+      revisions,
+      compact_revision: sm.compact_revision,
+      revision: sm.revision,
+    }))
+  }
+
+  /// Discards the history before a revision on every node
+  ///
+  /// # Arguments
+  /// * `request` - Contains the oldest revision that remains readable
+  ///
+  /// # Returns
+  /// * `Ok(Response)` - Response with `success` unset if the revision was already compacted or
+  ///   lies in the future
+  /// * `Err(Status)` - Error status if the write fails
+  async fn compact(
+    &self,
+    request: Request<protobuf::CompactRequest>,
+  ) -> Result<Response<protobuf::Response>, Status> {
+    auth::authorize(&request, Role::Admin)?;
+    let req = request.into_inner();
+    debug!("Processing compact request for revision: {}", req.revision);
+
+    let res = self.write(req.into()).await?;
+    Ok(Response::new(res))
+  }
+
+  /// Sets the quotas of a namespace, creating it if needed
+  ///
+  /// # Arguments
+  /// * `request` - Contains the name of the namespace and its quotas, 0 for no limit
+  ///
+  /// # Returns
+  /// * `Ok(Response)` - Success response once the quotas apply to new writes
+  /// * `Err(Status)` - `INVALID_ARGUMENT` if the name or a quota is invalid
+  async fn set_namespace(
+    &self,
+    request: Request<protobuf::NamespaceRequest>,
+  ) -> Result<Response<protobuf::Response>, Status> {
+    auth::authorize(&request, Role::Admin)?;
+    let req = request.into_inner();
+    debug!(
+      "Processing quotas for namespace {:?}: {} keys, {} bytes",
+      req.name, req.max_keys, req.max_bytes
+    );
+    validate(&req.name, "")?;
+
+    if req.max_keys < 0 || req.max_bytes < 0 {
+      return Err(Status::invalid_argument("Quotas cannot be negative"));
+    }
+
+    let res = self.write(req.into()).await?;
+    Ok(Response::new(res))
+  }
+
+  /// Deletes a namespace along with all of its keys
+  ///
+  /// # Arguments
+  /// * `request` - Contains the name of the namespace
+  ///
+  /// # Returns
+  /// * `Ok(Response)` - Response with `success` unset if the namespace had no keys nor quotas
+  /// * `Err(Status)` - `INVALID_ARGUMENT` for the default namespace, which cannot be deleted
+  async fn delete_namespace(
+    &self,
+    request: Request<protobuf::DeleteNamespaceRequest>,
+  ) -> Result<Response<protobuf::Response>, Status> {
+    auth::authorize(&request, Role::Admin)?;
     let req = request.into_inner();
-    debug!("Processing get request for key: {}", req.key);
+    debug!("Processing delete request for namespace {:?}", req.name);
+    validate(&req.name, "")?;
+
+    if req.name.is_empty() {
+      return Err(Status::invalid_argument(
+        "The default namespace cannot be deleted",
+      ));
+    }
+
+    let res = self.write(req.into()).await?;
+    Ok(Response::new(res))
+  }
+
+  /// Lists the namespaces that have keys or quotas, with their usage
+  async fn list_namespaces(
+    &self,
+    request: Request<()>,
+  ) -> Result<Response<protobuf::ListNamespacesResponse>, Status> {
+    auth::authorize(&request, Role::Reader)?;
+    debug!("Listing namespaces");
+
+    self
+      .ensure_consistency(protobuf::ReadConsistency::Linearizable)
+      .await?;
 
     let sm = self
       .state_machine_store
       .state_machine
       .lock()
       .map_err(|e| Status::internal(format!("error getting lock on sm: {}", e)))?;
-    let value = sm
-      .data
-      .get(&req.key)
-      .ok_or_else(|| Status::internal(format!("Key not found: {}", req.key)))?
-      .to_string();
 
-    debug!("Successfully retrieved value for key: {}", req.key);
-    Ok(Response::new(protobuf::Response { value: Some(value) }))
+    Ok(Response::new(protobuf::ListNamespacesResponse {
+      namespaces: namespace::list(&sm),
+    }))
   }
 
   /// Initializes a new Raft cluster with the specified nodes
@@ -109,6 +1076,7 @@ impl protobuf::app_service_server::AppService for AppServiceImpl {
   /// * Success response with initialization details
   /// * Error if initialization fails
   async fn init(&self, request: Request<protobuf::InitRequest>) -> Result<Response<()>, Status> {
+    auth::authorize(&request, Role::Admin)?;
     debug!("Initializing Raft cluster");
     let req = request.into_inner();
 
@@ -122,6 +1090,7 @@ impl protobuf::app_service_server::AppService for AppServiceImpl {
           protobuf::Node {
             rpc_addr: node.rpc_addr,
             node_id: node.node_id,
+            tls_identity: node.tls_identity,
           },
         )
       })
@@ -150,24 +1119,26 @@ impl protobuf::app_service_server::AppService for AppServiceImpl {
     &self,
     request: Request<protobuf::AddLearnerRequest>,
   ) -> Result<Response<protobuf::ClientWriteResponse>, Status> {
+    auth::authorize(&request, Role::Admin)?;
     let req = request.into_inner();
 
     let node = req
       .node
-      .ok_or_else(|| Status::internal("Node information is required"))?;
+      .ok_or_else(|| Status::invalid_argument("Node information is required"))?;
 
     debug!("Adding learner node {}", node.node_id);
 
     let raft_node = Node {
       rpc_addr: node.rpc_addr.clone(),
       node_id: node.node_id,
+      tls_identity: node.tls_identity.clone(),
     };
 
     let result = self
       .raft
       .add_learner(node.node_id, raft_node, true)
       .await
-      .map_err(|e| Status::internal(format!("Failed to add learner node: {}", e)))?;
+      .map_err(|e| leader::status("Failed to add learner node", e))?;
 
     debug!("Successfully added learner node {}", node.node_id);
     Ok(Response::new(result.into()))
@@ -185,6 +1156,7 @@ impl protobuf::app_service_server::AppService for AppServiceImpl {
     &self,
     request: Request<protobuf::ChangeMembershipRequest>,
   ) -> Result<Response<protobuf::ClientWriteResponse>, Status> {
+    auth::authorize(&request, Role::Admin)?;
     let req = request.into_inner();
 
     debug!(
@@ -196,7 +1168,7 @@ impl protobuf::app_service_server::AppService for AppServiceImpl {
       .raft
       .change_membership(req.members, req.retain)
       .await
-      .map_err(|e| Status::internal(format!("Failed to change membership: {}", e)))?;
+      .map_err(|e| leader::status("Failed to change membership", e))?;
 
     debug!("Successfully changed cluster membership");
     Ok(Response::new(result.into()))
@@ -205,14 +1177,427 @@ impl protobuf::app_service_server::AppService for AppServiceImpl {
   /// Retrieves metrics about the Raft node
   async fn metrics(
     &self,
-    _request: Request<()>,
+    request: Request<()>,
   ) -> Result<Response<protobuf::MetricsResponse>, Status> {
+    auth::authorize(&request, Role::Reader)?;
     debug!("Collecting metrics");
-    let metrics = self.raft.metrics().borrow().clone();
-    let resp = protobuf::MetricsResponse {
-      membership: Some(metrics.membership_config.membership().clone().into()),
-      other_metrics: metrics.to_string(),
-    };
+    let resp = protobuf::MetricsResponse::from(&*self.raft.metrics().borrow());
     Ok(Response::new(resp))
   }
+
+  type WatchMetricsStream = MetricsResponseStream;
+
+  /// Streams the metrics of the Raft node each time they change
+  ///
+  /// # Returns
+  /// * `Ok(Response)` - Stream with the current metrics, then the metrics after each change
+  ///
+  /// Changes made while an update is being sent are coalesced into the next one.
+  async fn watch_metrics(
+    &self,
+    request: Request<()>,
+  ) -> Result<Response<Self::WatchMetricsStream>, Status> {
+    auth::authorize(&request, Role::Reader)?;
+    debug!("Watching metrics");
+
+    let receiver = self.raft.metrics();
+    let stream = futures::stream::unfold((receiver, true), |(mut receiver, first)| async move {
+      // The stream ends once the node shuts down
+      if !first && receiver.changed().await.is_err() {
+        return None;
+      }
+
+      let response = protobuf::MetricsResponse::from(&*receiver.borrow_and_update());
+      Some((Ok(response), (receiver, false)))
+    });
+
+    Ok(Response::new(Box::pin(stream)))
+  }
+
+  type BackupStream = BackupResponseStream;
+
+  /// Builds a snapshot of the state machine and streams it back
+  ///
+  /// # Returns
+  /// * `Ok(Response)` - Stream with the meta of the backup, then the snapshot data in chunks
+  /// * `Err(Status)` - `UNAVAILABLE` if sent to a node that cannot confirm it is the leader
+  ///
+  /// The snapshot is taken after a linearizable read, so it holds every write acknowledged
+  /// before the request.
+  async fn backup(&self, request: Request<()>) -> Result<Response<Self::BackupStream>, Status> {
+    auth::authorize(&request, Role::Admin)?;
+    debug!("Processing backup request");
+
+    self
+      .ensure_consistency(protobuf::ReadConsistency::Linearizable)
+      .await?;
+
+    let snapshot = self
+      .state_machine_store
+      .clone()
+      .build_snapshot()
+      .await
+      .map_err(|e| Status::internal(format!("Failed to build snapshot: {}", e)))?;
+
+    let reader = snapshot
+      .snapshot
+      .open()
+      .await
+      .map_err(|e| Status::internal(format!("Failed to open snapshot: {}", e)))?;
+
+    let meta = protobuf::BackupMeta {
+      cluster_name: self.cluster_name.clone(),
+      snapshot: Some(protobuf::SnapshotFileMeta {
+        size: snapshot.snapshot.size(),
+        checksum: snapshot.snapshot.checksum(),
+        ..snapshot.meta.into()
+      }),
+      created_at: lease::now(),
+    };
+
+    let head = futures::stream::once(futures::future::ready(Ok(protobuf::BackupResponse {
+      payload: Some(protobuf::backup_response::Payload::Meta(Box::new(meta))),
+    })));
+    let chunks = futures::stream::unfold(Some(reader), |reader| async move {
+      let mut reader = reader?;
+      match reader.next_chunk().await {
+        Ok(None) => None,
+        Ok(Some(chunk)) => {
+          let response = protobuf::BackupResponse {
+            payload: Some(protobuf::backup_response::Payload::Chunk(chunk)),
+          };
+          Some((Ok(response), Some(reader)))
+        }
+        Err(e) => Some((
+          Err(Status::internal(format!("Failed to read snapshot: {}", e))),
+          None,
+        )),
+      }
+    });
+
+    Ok(Response::new(Box::pin(head.chain(chunks))))
+  }
+
+  /// Creates a join token, stored by its digest and attached to a lease that expires with it
+  ///
+  /// # Arguments
+  /// * `request` - Contains the time to live of the token and the node it is reserved for, if any
+  ///
+  /// # Returns
+  /// * `Ok(Response)` - The token and the lease it is attached to
+  /// * `Err(Status)` - Error status if the TTL is invalid or the writes fail
+  ///
+  /// The token can be used once, until it expires or its lease is revoked.
+  async fn create_join_token(
+    &self,
+    request: Request<protobuf::CreateJoinTokenRequest>,
+  ) -> Result<Response<protobuf::CreateJoinTokenResponse>, Status> {
+    auth::authorize(&request, Role::Admin)?;
+    let req = request.into_inner();
+    debug!("Processing join token request with ttl: {}", req.ttl);
+
+    if req.ttl <= 0 {
+      return Err(Status::invalid_argument("Join token TTL must be positive"));
+    }
+
+    let lease_id = self
+      .write(protobuf::LeaseGrantRequest { ttl: req.ttl }.into())
+      .await?
+      .lease_id;
+
+    let token = ca::new_token();
+    self
+      .write(
+        protobuf::SetRequest {
+          key: format!("{}{}", JOIN_TOKEN_PREFIX, ca::token_digest(&token)),
+          value: req.node_id.map(|id| id.to_string()).unwrap_or_default(),
+          lease: lease_id,
+          namespace: String::new(),
+        }
+        .into(),
+      )
+      .await?;
+
+    debug!("Created join token with lease {}", lease_id);
+    Ok(Response::new(protobuf::CreateJoinTokenResponse {
+      token,
+      lease_id,
+    }))
+  }
+
+  /// Signs the certificate requests of a node joining with a token
+  ///
+  /// # Arguments
+  /// * `request` - Contains the join token, the id of the node and its certificate requests
+  ///
+  /// # Returns
+  /// * `Ok(Response)` - The CA certificate and the certificates of the node, issued for its node
+  ///   URI
+  /// * `Err(Status)` - `PERMISSION_DENIED` if the token is unknown, expired, used or reserved for
+  ///   another node, `ALREADY_EXISTS` if the node is a member or already joined with a token,
+  ///   `FAILED_PRECONDITION` if the leader does not hold the CA key
+  async fn sign_certificate(
+    &self,
+    request: Request<protobuf::SignCertificateRequest>,
+  ) -> Result<Response<protobuf::SignCertificateResponse>, Status> {
+    let req = request.into_inner();
+    debug!("Processing certificate request of node {}", req.node_id);
+
+    // Only the leader signs, so that a token revoked on it cannot be used on a lagging node
+    self
+      .ensure_consistency(protobuf::ReadConsistency::Linearizable)
+      .await?;
+
+    // Cloned out of the lock, as the CA may be reloaded meanwhile
+    let authority = self
+      .ca
+      .read()
+      .map_err(|e| Status::internal(format!("error getting lock on ca: {}", e)))?
+      .clone()
+      .ok_or_else(|| Status::failed_precondition("The leader does not hold the CA key"))?;
+
+    let digest = ca::token_digest(&req.token);
+    let token_key = format!("{}{}", JOIN_TOKEN_PREFIX, digest);
+    let joined_key = format!("{}{}", JOINED_PREFIX, req.node_id);
+
+    let token_revision = {
+      let sm = self
+        .state_machine_store
+        .state_machine
+        .lock()
+        .map_err(|e| Status::internal(format!("error getting lock on sm: {}", e)))?;
+
+      // Expired leases linger until the leader revokes them, so their deadline is checked here
+      let now = lease::now();
+      let token = mvcc::latest(&sm, &token_key)
+        .filter(|_| {
+          sm.key_leases
+            .get(&token_key)
+            .and_then(|id| sm.leases.get(id))
+            .is_some_and(|lease| lease.expires_at > now)
+        })
+        .ok_or_else(|| Status::permission_denied("Unknown, expired or used join token"))?;
+
+      let reserved_for = token.value.as_deref().unwrap_or_default();
+      if !reserved_for.is_empty() && reserved_for != req.node_id.to_string() {
+        return Err(Status::permission_denied(format!(
+          "The join token is reserved for node {}",
+          reserved_for
+        )));
+      }
+
+      if mvcc::latest(&sm, &joined_key).is_some() {
+        return Err(Status::already_exists(format!(
+          "Node {} already joined with a token",
+          req.node_id
+        )));
+      }
+
+      token.mod_revision
+    };
+
+    // Certificates of a member would let their holder impersonate it
+    if self
+      .raft
+      .metrics()
+      .borrow()
+      .membership_config
+      .membership()
+      .get_node(&req.node_id)
+      .is_some()
+    {
+      return Err(Status::already_exists(format!(
+        "Node {} is already a member of the cluster",
+        req.node_id
+      )));
+    }
+
+    let sign = |csr: &str, usage| {
+      authority
+        .sign(csr, &self.cluster_name, req.node_id, usage)
+        .map_err(|e| Status::invalid_argument(format!("Invalid certificate request: {}", e)))
+    };
+    let server_certificate = sign(&req.server_csr, ca::Usage::Server)?;
+    let client_certificate = sign(&req.client_csr, ca::Usage::Client)?;
+
+    // The token is consumed and the node recorded in one write, so that of concurrent requests
+    // with the same token or for the same node only one gets its certificates
+    let txn = protobuf::TxnRequest {
+      compare: vec![
+        protobuf::Compare {
+          key: token_key.clone(),
+          result: protobuf::compare::CompareResult::Equal.into(),
+          target: Some(protobuf::compare::Target::ModRevision(token_revision)),
+          namespace: String::new(),
+        },
+        protobuf::Compare {
+          key: joined_key.clone(),
+          result: protobuf::compare::CompareResult::Equal.into(),
+          target: Some(protobuf::compare::Target::Exists(false)),
+          namespace: String::new(),
+        },
+      ],
+      success: vec![
+        protobuf::DeleteRequest {
+          key: token_key,
+          namespace: String::new(),
+        }
+        .into(),
+        protobuf::SetRequest {
+          key: joined_key,
+          value: digest,
+          lease: 0,
+          namespace: String::new(),
+        }
+        .into(),
+      ],
+      failure: vec![],
+    };
+
+    if !self.write(txn.into()).await?.succeeded {
+      return Err(Status::permission_denied(
+        "The join token was used by another request",
+      ));
+    }
+
+    debug!("Signed the certificates of node {}", req.node_id);
+    Ok(Response::new(protobuf::SignCertificateResponse {
+      ca_certificate: authority.trusted_pem().to_string(),
+      server_certificate,
+      client_certificate,
+      tls_identity: tls::node_uri(&self.cluster_name, req.node_id),
+    }))
+  }
+
+  /// Grants a role to the client certificates of a subject, replacing its policy
+  ///
+  /// # Arguments
+  /// * `request` - Contains the subject, its role and the key prefixes the role applies to
+  ///
+  /// # Returns
+  /// * `Ok(Response)` - Success response once the policy applies to new requests
+  /// * `Err(Status)` - `INVALID_ARGUMENT` if the subject is empty or a prefix invalid
+  async fn set_policy(
+    &self,
+    request: Request<protobuf::Policy>,
+  ) -> Result<Response<protobuf::Response>, Status> {
+    auth::authorize(&request, Role::Admin)?;
+    let req = request.into_inner();
+    debug!(
+      "Processing {:?} policy for subject {:?}",
+      req.role(),
+      req.subject
+    );
+
+    if req.subject.is_empty() {
+      return Err(Status::invalid_argument("The subject cannot be empty"));
+    }
+    for prefix in &req.prefixes {
+      validate(&prefix.namespace, &prefix.prefix)?;
+    }
+
+    let res = self.write(req.into()).await?;
+    Ok(Response::new(res))
+  }
+
+  /// Removes the policy of a subject, whose certificates are then denied every request
+  ///
+  /// # Arguments
+  /// * `request` - Contains the subject
+  ///
+  /// # Returns
+  /// * `Ok(Response)` - Response with `success` unset if the subject had no policy
+  /// * `Err(Status)` - Error status if the write fails
+  async fn delete_policy(
+    &self,
+    request: Request<protobuf::DeletePolicyRequest>,
+  ) -> Result<Response<protobuf::Response>, Status> {
+    auth::authorize(&request, Role::Admin)?;
+    let req = request.into_inner();
+    debug!("Processing delete request for policy of {:?}", req.subject);
+
+    let res = self.write(req.into()).await?;
+    Ok(Response::new(res))
+  }
+
+  /// Lists the policies, ordered by subject
+  async fn list_policies(
+    &self,
+    request: Request<()>,
+  ) -> Result<Response<protobuf::ListPoliciesResponse>, Status> {
+    auth::authorize(&request, Role::Admin)?;
+    debug!("Listing policies");
+
+    self
+      .ensure_consistency(protobuf::ReadConsistency::Linearizable)
+      .await?;
+
+    let sm = self
+      .state_machine_store
+      .state_machine
+      .lock()
+      .map_err(|e| Status::internal(format!("error getting lock on sm: {}", e)))?;
+
+    Ok(Response::new(protobuf::ListPoliciesResponse {
+      policies: policy::list(&sm),
+    }))
+  }
+}
+
+/// Narrows the changes made by a command down to the keys of `ns` under `prefix`, `None` if none
+/// is left.
+fn matching_events(
+  response: &protobuf::WatchResponse,
+  ns: &str,
+  prefix: &str,
+) -> Option<protobuf::WatchResponse> {
+  let events = response
+    .events
+    .iter()
+    .filter(|event| event.namespace == ns && event.key.starts_with(prefix))
+    .cloned()
+    .collect::<Vec<_>>();
+
+  if events.is_empty() {
+    return None;
+  }
+
+  Some(protobuf::WatchResponse {
+    revision: response.revision,
+    events,
+  })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn with_timeout(timeout: &str) -> Request<()> {
+    let mut request = Request::new(());
+    request
+      .metadata_mut()
+      .insert("grpc-timeout", timeout.parse().unwrap());
+    request
+  }
+
+  #[test]
+  fn test_request_timeout() {
+    assert_eq!(request_timeout(&Request::new(())), None);
+    assert_eq!(
+      request_timeout(&with_timeout("5S")),
+      Some(Duration::from_secs(5))
+    );
+    assert_eq!(
+      request_timeout(&with_timeout("250m")),
+      Some(Duration::from_millis(250))
+    );
+    assert_eq!(
+      request_timeout(&with_timeout("2H")),
+      Some(Duration::from_secs(7200))
+    );
+
+    for invalid in ["", "S", "5", "5s", "-5S", "5 S"] {
+      assert_eq!(request_timeout(&with_timeout(invalid)), None, "{}", invalid);
+    }
+  }
 }
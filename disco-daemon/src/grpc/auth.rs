@@ -0,0 +1,331 @@
+//! Authorization of the `AppService` requests by client certificate.
+//!
+//! The `Authorizer` interceptor looks up the policy of the common name in the subject of the
+//! client certificate, and attaches the role it grants to the request. Each method then checks
+//! that the role allows it, and that the keys it reaches fall within the prefixes of the policy.
+//!
+//! Certificates issued for the node URI of a member of the cluster are always admins, so that the
+//! nodes keep reaching the store of the leader. Until a first policy is stored, every certificate issued by
+//! the cluster CA is an admin as well; deleting every policy later does not make it one again.
+
+use std::sync::Arc;
+
+use rustls::pki_types::CertificateDer;
+use tonic::service::Interceptor;
+use tonic::{Request, Status};
+
+use super::app_service::JOIN_TOKEN_PREFIX;
+use crate::StateMachineStore;
+use crate::protobuf;
+use crate::protobuf::Role;
+use crate::raft_types::*;
+use crate::tls;
+
+/// What the client of a request may do.
+#[derive(Clone, Debug)]
+pub struct Grant {
+  /// Common name in the subject of the client certificate.
+  subject: String,
+
+  role: Role,
+
+  /// Keys the role applies to, every key if empty.
+  prefixes: Vec<protobuf::KeyPrefix>,
+}
+
+impl Grant {
+  fn admin(subject: String) -> Self {
+    Grant {
+      subject,
+      role: Role::Admin,
+      prefixes: Vec::new(),
+    }
+  }
+
+  /// Checks that `key` of `namespace` falls within the prefixes of the grant. For a range or a
+  /// watch, `key` is the prefix of the keys reached.
+  #[allow(clippy::result_large_err)]
+  pub fn check_key(&self, namespace: &str, key: &str) -> Result<(), Status> {
+    if self.prefixes.is_empty()
+      || self
+        .prefixes
+        .iter()
+        .any(|prefix| prefix.namespace == namespace && key.starts_with(&prefix.prefix))
+    {
+      return Ok(());
+    }
+
+    Err(Status::permission_denied(format!(
+      "{} may not reach key {:?} of namespace {:?}",
+      self.subject, key, namespace
+    )))
+  }
+
+  /// Checks that `key` of `namespace` may be written.
+  ///
+  /// Join tokens are only written by admins, as they let their holder have certificates issued.
+  #[allow(clippy::result_large_err)]
+  pub fn check_write(&self, namespace: &str, key: &str) -> Result<(), Status> {
+    if namespace.is_empty() && key.starts_with(JOIN_TOKEN_PREFIX) && self.role != Role::Admin {
+      return Err(Status::permission_denied(format!(
+        "{} may not write join tokens",
+        self.subject
+      )));
+    }
+
+    self.check_key(namespace, key)
+  }
+
+  /// Checks the key of every operation of a batch or transaction branch.
+  #[allow(clippy::result_large_err)]
+  pub fn check_operations(&self, operations: &[protobuf::Operation]) -> Result<(), Status> {
+    for op in operations.iter().filter_map(|op| op.op.as_ref()) {
+      match op {
+        protobuf::operation::Op::Set(req) => self.check_write(&req.namespace, &req.key)?,
+        protobuf::operation::Op::Delete(req) => self.check_write(&req.namespace, &req.key)?,
+        protobuf::operation::Op::CompareAndSwap(req) => {
+          self.check_write(&req.namespace, &req.key)?
+        }
+      }
+    }
+
+    Ok(())
+  }
+}
+
+/// Returns what the client of a request may do, once checked that its role is `role` or above.
+#[allow(clippy::result_large_err)]
+pub fn authorize<T>(request: &Request<T>, role: Role) -> Result<Grant, Status> {
+  let grant = request
+    .extensions()
+    .get::<Grant>()
+    .ok_or_else(|| Status::unauthenticated("A client certificate is required"))?;
+
+  if grant.role < role {
+    return Err(Status::permission_denied(format!(
+      "{} is a {:?}, the request needs a {:?}",
+      grant.subject, grant.role, role
+    )));
+  }
+
+  Ok(grant.clone())
+}
+
+/// Attaches to every request the `Grant` of its client certificate, and rejects the certificates
+/// of subjects without a policy with `PERMISSION_DENIED`.
+///
+/// Requests without a certificate go through without a grant: the methods open to them need
+/// none, and the others turn them away.
+#[derive(Clone)]
+pub struct Authorizer {
+  raft: Raft,
+  state_machine_store: Arc<StateMachineStore>,
+  identities: tls::NodeIdentities,
+}
+
+impl Authorizer {
+  pub fn new(
+    raft: Raft,
+    state_machine_store: Arc<StateMachineStore>,
+    identities: tls::NodeIdentities,
+  ) -> Self {
+    Authorizer {
+      raft,
+      state_machine_store,
+      identities,
+    }
+  }
+
+  #[allow(clippy::result_large_err)]
+  fn grant(&self, cert: &CertificateDer<'_>) -> Result<Grant, Status> {
+    let is_member = {
+      let metrics = self.raft.metrics();
+      let metrics = metrics.borrow();
+      let members = metrics.membership_config.membership().nodes();
+
+      is_member(&self.identities, cert, members.map(|(_, node)| node))
+    };
+
+    let sm = self
+      .state_machine_store
+      .state_machine
+      .lock()
+      .map_err(|e| Status::internal(format!("error getting lock on sm: {}", e)))?;
+
+    grant_for(cert, is_member, &sm)
+  }
+}
+
+/// Returns whether `cert` is issued for one of `members`.
+///
+/// Members are only recognized by their node URI, even with the legacy identities: any
+/// certificate issued by the cluster CA may carry `localhost` or another DNS name, including the
+/// ones of clients.
+fn is_member<'a>(
+  identities: &tls::NodeIdentities,
+  cert: &CertificateDer<'_>,
+  mut members: impl Iterator<Item = &'a Node>,
+) -> bool {
+  members.any(|node| tls::presents(cert, &identities.uri(node)))
+}
+
+/// Returns what the client presenting `cert` may do: everything for a member, what the policy of
+/// its subject grants otherwise.
+#[allow(clippy::result_large_err)]
+fn grant_for(
+  cert: &CertificateDer<'_>,
+  is_member: bool,
+  sm: &protobuf::StateMachineData,
+) -> Result<Grant, Status> {
+  let subject = tls::common_name(cert).unwrap_or_default();
+
+  if is_member || !sm.policies_enforced {
+    return Ok(Grant::admin(subject));
+  }
+
+  let policy = sm
+    .policies
+    .get(&subject)
+    .ok_or_else(|| Status::permission_denied(format!("No policy for {:?}", subject)))?;
+
+  Ok(Grant {
+    role: policy.role(),
+    prefixes: policy.prefixes.clone(),
+    subject,
+  })
+}
+
+impl Interceptor for Authorizer {
+  fn call(&mut self, mut request: Request<()>) -> Result<Request<()>, Status> {
+    let Some(certs) = request.peer_certs() else {
+      return Ok(request);
+    };
+    let Some(cert) = certs.first() else {
+      return Ok(request);
+    };
+
+    let grant = self.grant(cert)?;
+    request.extensions_mut().insert(grant);
+
+    Ok(request)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::ca::{CertificateAuthority, Usage};
+  use crate::store::command::tests::run;
+  use crate::tls::tests::issue_dns;
+
+  fn grant(role: Role, prefixes: &[(&str, &str)]) -> Grant {
+    Grant {
+      subject: "test".to_string(),
+      role,
+      prefixes: prefixes
+        .iter()
+        .map(|(namespace, prefix)| protobuf::KeyPrefix {
+          namespace: namespace.to_string(),
+          prefix: prefix.to_string(),
+        })
+        .collect(),
+    }
+  }
+
+  fn set(namespace: &str, key: &str) -> protobuf::Operation {
+    protobuf::SetRequest {
+      key: key.to_string(),
+      namespace: namespace.to_string(),
+      ..Default::default()
+    }
+    .into()
+  }
+
+  fn node(node_id: u64, tls_identity: &str) -> Node {
+    Node {
+      node_id,
+      rpc_addr: String::new(),
+      tls_identity: tls_identity.to_string(),
+    }
+  }
+
+  fn policy(subject: &str, role: Role) -> protobuf::Policy {
+    protobuf::Policy {
+      subject: subject.to_string(),
+      role: role.into(),
+      prefixes: Vec::new(),
+    }
+  }
+
+  #[test]
+  fn test_check_key() {
+    let all = grant(Role::Reader, &[]);
+    assert!(all.check_key("", "a").is_ok());
+    assert!(all.check_key("ns", "a").is_ok());
+
+    let some = grant(Role::Reader, &[("", "app/"), ("ns", "")]);
+    assert!(some.check_key("", "app/a").is_ok());
+    assert!(some.check_key("ns", "a").is_ok());
+
+    let status = some.check_key("", "other").unwrap_err();
+    assert_eq!(status.code(), tonic::Code::PermissionDenied);
+    assert!(some.check_key("other", "app/a").is_err());
+
+    // A range over a shorter prefix reaches keys outside of the grant
+    assert!(some.check_key("", "app").is_err());
+  }
+
+  #[test]
+  fn test_check_write() {
+    let token = format!("{}abc", JOIN_TOKEN_PREFIX);
+
+    assert!(grant(Role::Writer, &[]).check_write("", "a").is_ok());
+    assert!(grant(Role::Writer, &[]).check_write("", &token).is_err());
+    assert!(grant(Role::Writer, &[]).check_write("ns", &token).is_ok());
+    assert!(grant(Role::Admin, &[]).check_write("", &token).is_ok());
+
+    let operations = [set("", "app/a"), set("", "other")];
+    let writer = grant(Role::Writer, &[("", "app/")]);
+    assert!(writer.check_operations(&operations[..1]).is_ok());
+    assert!(writer.check_operations(&operations).is_err());
+  }
+
+  #[test]
+  fn test_authorize() {
+    let mut request = Request::new(());
+    let status = authorize(&request, Role::Reader).unwrap_err();
+    assert_eq!(status.code(), tonic::Code::Unauthenticated);
+
+    request.extensions_mut().insert(grant(Role::Writer, &[]));
+    assert!(authorize(&request, Role::Reader).is_ok());
+    assert!(authorize(&request, Role::Writer).is_ok());
+
+    let status = authorize(&request, Role::Admin).unwrap_err();
+    assert_eq!(status.code(), tonic::Code::PermissionDenied);
+  }
+
+  #[test]
+  fn test_members_are_admins() {
+    let ca = CertificateAuthority::create("test").unwrap();
+    let identities = tls::NodeIdentities::new("test", true);
+    let members = [node(1, ""), node(2, "db.example.com")];
+
+    let mut sm = protobuf::StateMachineData::default();
+    run(&mut sm, policy("monitoring", Role::Reader));
+
+    let member = ca.issue("test", 1, Usage::Client, &[]).unwrap();
+    let member = tls::certificates(member.cert_pem.as_bytes()).unwrap();
+    assert!(is_member(&identities, &member[0], members.iter()));
+    assert_eq!(grant_for(&member[0], true, &sm).unwrap().role, Role::Admin);
+
+    // Client certificates carrying the legacy identity of a member only get their policy
+    let client = issue_dns(&ca, "monitoring", "localhost");
+    assert!(!is_member(&identities, &client, members.iter()));
+    assert_eq!(grant_for(&client, false, &sm).unwrap().role, Role::Reader);
+
+    let client = issue_dns(&ca, "deploy", "db.example.com");
+    assert!(!is_member(&identities, &client, members.iter()));
+    let status = grant_for(&client, false, &sm).unwrap_err();
+    assert_eq!(status.code(), tonic::Code::PermissionDenied);
+  }
+}
@@ -0,0 +1,45 @@
+//! Redirection of requests that only the leader can serve.
+//!
+//! Instead of proxying, a node that is not the leader rejects such requests with `UNAVAILABLE`
+//! and names the current leader in the response metadata. Clients reconnect to that address and
+//! retry, so they can be pointed at any node of the cluster.
+
+use std::error::Error;
+
+use openraft::TryAsRef;
+use tonic::Status;
+use tonic::metadata::MetadataValue;
+
+use crate::raft_types::*;
+
+/// Metadata key carrying the id of the current leader.
+pub const LEADER_ID_METADATA: &str = "x-disco-leader-id";
+
+/// Metadata key carrying the `rpc_addr` of the current leader.
+pub const LEADER_ADDR_METADATA: &str = "x-disco-leader-addr";
+
+/// Converts a Raft error into a status, attaching the leader when the request has to be sent
+/// there instead.
+pub fn status<E>(message: &str, error: RaftError<E>) -> Status
+where
+  E: Error + TryAsRef<ForwardToLeader>,
+{
+  let Some(forward) = error.forward_to_leader() else {
+    return Status::internal(format!("{}: {}", message, error));
+  };
+
+  let mut status = Status::unavailable(format!("{}: {}", message, error));
+  let metadata = status.metadata_mut();
+
+  if let Some(leader_id) = forward.leader_id {
+    metadata.insert(LEADER_ID_METADATA, MetadataValue::from(leader_id));
+  }
+
+  if let Some(leader_node) = &forward.leader_node
+    && let Ok(addr) = MetadataValue::try_from(leader_node.rpc_addr.as_str())
+  {
+    metadata.insert(LEADER_ADDR_METADATA, addr);
+  }
+
+  status
+}
@@ -1,2 +1,4 @@
 pub mod app_service;
+pub mod auth;
+pub mod leader;
 pub mod raft_service;
@@ -1,3 +1,6 @@
+use std::io;
+use std::sync::Arc;
+
 use futures::StreamExt;
 use openraft::Snapshot;
 use tonic::Request;
@@ -6,8 +9,10 @@ use tonic::Status;
 use tonic::Streaming;
 use tracing::debug;
 
+use crate::StateMachineStore;
 use crate::protobuf;
 use crate::raft_types::*;
+use crate::tls;
 
 /// Internal gRPC service implementation for Raft protocol communications.
 /// This service handles the core Raft consensus protocol operations between cluster nodes.
@@ -19,10 +24,17 @@ use crate::raft_types::*;
 ///
 /// # Protocol Safety
 /// This service implements critical consensus protocol operations and should only be
-/// exposed to other trusted Raft cluster nodes, never to external clients.
+/// exposed to other trusted Raft cluster nodes, never to external clients. Requests are only
+/// accepted from client certificates issued for the identity of one of them.
 pub struct RaftServiceImpl {
   /// The local Raft node instance that this service operates on
   raft: Raft,
+
+  /// The state machine store, which stages incoming snapshots on disk
+  state_machine_store: Arc<StateMachineStore>,
+
+  /// The identities the members are recognized by
+  identities: tls::NodeIdentities,
 }
 
 impl RaftServiceImpl {
@@ -30,8 +42,53 @@ impl RaftServiceImpl {
   ///
   /// # Arguments
   /// * `raft` - The Raft node instance this service will operate on
-  pub fn new(raft: Raft) -> Self {
-    RaftServiceImpl { raft }
+  /// * `state_machine_store` - The state machine store receiving snapshots
+  /// * `identities` - The identities the members are recognized by
+  pub fn new(
+    raft: Raft,
+    state_machine_store: Arc<StateMachineStore>,
+    identities: tls::NodeIdentities,
+  ) -> Self {
+    RaftServiceImpl {
+      raft,
+      state_machine_store,
+      identities,
+    }
+  }
+
+  /// Checks that the request comes from a member of the cluster.
+  ///
+  /// Until this node has joined a cluster and learned its members, the certificate of any node of
+  /// the cluster is accepted, but not the ones issued to other clients.
+  #[allow(clippy::result_large_err)]
+  fn authorize<T>(&self, request: &Request<T>) -> Result<(), Status> {
+    let certs = request
+      .peer_certs()
+      .ok_or_else(|| Status::unauthenticated("A client certificate is required"))?;
+    let cert = certs
+      .first()
+      .ok_or_else(|| Status::unauthenticated("A client certificate is required"))?;
+
+    let is_member = {
+      let metrics = self.raft.metrics();
+      let metrics = metrics.borrow();
+      let mut members = metrics.membership_config.membership().nodes().peekable();
+
+      if members.peek().is_none() {
+        self.identities.is_node(cert)
+      } else {
+        members.any(|(_, node)| self.identities.authenticates(cert, node))
+      }
+    };
+
+    if is_member {
+      Ok(())
+    } else {
+      Err(Status::permission_denied(format!(
+        "{:?} is not a member of the cluster",
+        tls::names(cert)
+      )))
+    }
   }
 }
 
@@ -54,6 +111,7 @@ impl protobuf::raft_service_server::RaftService for RaftServiceImpl {
     request: Request<protobuf::VoteRequest>,
   ) -> Result<Response<protobuf::VoteResponse>, Status> {
     debug!("Processing vote request");
+    self.authorize(&request)?;
 
     let vote_resp = self
       .raft
@@ -82,6 +140,7 @@ impl protobuf::raft_service_server::RaftService for RaftServiceImpl {
     request: Request<protobuf::AppendEntriesRequest>,
   ) -> Result<Response<protobuf::AppendEntriesResponse>, Status> {
     debug!("Processing append entries request");
+    self.authorize(&request)?;
 
     let append_resp = self
       .raft
@@ -106,6 +165,7 @@ impl protobuf::raft_service_server::RaftService for RaftServiceImpl {
     request: Request<Streaming<protobuf::SnapshotRequest>>,
   ) -> Result<Response<protobuf::SnapshotResponse>, Status> {
     debug!("Processing streaming snapshot installation request");
+    self.authorize(&request)?;
     let mut stream = request.into_inner();
 
     // Get the first chunk which contains metadata
@@ -116,6 +176,8 @@ impl protobuf::raft_service_server::RaftService for RaftServiceImpl {
 
     let vote;
     let snapshot_meta;
+    let size;
+    let checksum;
     {
       let meta = first_chunk
         .into_meta()
@@ -124,6 +186,8 @@ impl protobuf::raft_service_server::RaftService for RaftServiceImpl {
       debug!("Received snapshot metadata chunk: {:?}", meta);
 
       vote = meta.vote.unwrap();
+      size = meta.size;
+      checksum = meta.checksum;
 
       snapshot_meta = SnapshotMeta {
         last_log_id: meta.last_log_id.map(|log_id| log_id.into()),
@@ -135,19 +199,37 @@ impl protobuf::raft_service_server::RaftService for RaftServiceImpl {
       };
     }
 
-    // Collect snapshot data
-    let mut snapshot_data_bytes = Vec::new();
+    // Stream the snapshot data to disk instead of buffering it in memory
+    let mut writer = self
+      .state_machine_store
+      .receive_snapshot()
+      .await
+      .map_err(|e| Status::internal(format!("Failed to stage snapshot: {}", e)))?;
 
     while let Some(chunk) = stream.next().await {
       let data = chunk?
         .into_data_chunk()
         .ok_or_else(|| Status::invalid_argument("Snapshot chunk must be data"))?;
-      snapshot_data_bytes.extend_from_slice(&data);
+      writer
+        .write(&data)
+        .await
+        .map_err(|e| Status::internal(format!("Failed to write snapshot chunk: {}", e)))?;
     }
 
+    // Reject a damaged transfer here, so that the leader sends the snapshot again
+    let snapshot_file = writer
+      .finish(size, checksum)
+      .await
+      .map_err(|e| match e.kind() {
+        io::ErrorKind::InvalidData => {
+          Status::data_loss(format!("Snapshot verification failed: {}", e))
+        }
+        _ => Status::internal(format!("Failed to write snapshot: {}", e)),
+      })?;
+
     let snapshot = Snapshot {
       meta: snapshot_meta,
-      snapshot: snapshot_data_bytes,
+      snapshot: snapshot_file,
     };
 
     // Install the full snapshot
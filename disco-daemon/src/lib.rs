@@ -1,14 +1,21 @@
+pub mod ca;
 pub mod config;
 pub mod controller;
 pub mod grpc;
 pub mod network;
 pub mod node;
+#[cfg(feature = "prometheus")]
+pub mod prometheus;
 pub mod raft_types;
 pub mod settings;
 pub mod store;
+pub mod telemetry;
+pub mod tls;
 
 pub mod protobuf {
   tonic::include_proto!("disco");
+
+  pub const FILE_DESCRIPTOR_SET: &[u8] = tonic::include_file_descriptor_set!("disco_descriptor");
 }
 
 mod pb_impl;
@@ -16,13 +23,13 @@ mod pb_impl;
 openraft::declare_raft_types!(
     /// Declare the type configuration for example K/V store.
     pub TypeConfig:
-        D = protobuf::SetRequest,
+        D = protobuf::Command,
         R = protobuf::Response,
         LeaderId = protobuf::LeaderId,
         Vote = protobuf::Vote,
         Entry = protobuf::Entry,
         Node = protobuf::Node,
-        SnapshotData = Vec<u8>,
+        SnapshotData = store::SnapshotFile,
 );
 
 pub type NodeId = u64;
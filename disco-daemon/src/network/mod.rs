@@ -1,5 +1,6 @@
 use std::collections::HashMap;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 use tokio::sync::RwLock;
 
 use openraft::AnyError;
@@ -9,37 +10,52 @@ use openraft::error::Unreachable;
 use openraft::network::RPCOption;
 use openraft::network::v2::RaftNetworkV2;
 use tonic::codegen::tokio_stream::wrappers::ReceiverStream;
-use tonic::transport::{Certificate, Channel, ClientTlsConfig, Endpoint, Identity};
+use tonic::{Code, Status};
+use tracing::Instrument;
+use tracing::Span;
 
 use crate::NodeId;
 use crate::TypeConfig;
 use crate::protobuf;
 use crate::raft_types::*;
+use crate::telemetry;
+use crate::telemetry::TracedChannel;
+use crate::tls::{NodeIdentities, TlsClient};
+
+mod peer;
+
+use peer::Peer;
 
 /// Network implementation for gRPC-based Raft communication.
 /// Provides the networking layer for Raft nodes to communicate with each other.
 pub struct Network {
-  // TLS configuration
-  tls_config: ClientTlsConfig,
+  // Mutual TLS with the other nodes, each verified against its own identity
+  tls: Arc<TlsClient>,
+  identities: NodeIdentities,
+
+  // Connections to the other nodes, kept across the clients openraft creates for them
+  peers: Arc<RwLock<HashMap<NodeId, Arc<Peer>>>>,
 }
 
 impl Network {
   pub fn new(
+    identities: NodeIdentities,
     ca_cert: &[u8],
     client_cert: &[u8],
     client_key: &[u8],
   ) -> Result<Self, Box<dyn std::error::Error>> {
-    // Load certificates
-    let ca = Certificate::from_pem(ca_cert);
-    let identity = Identity::from_pem(client_cert, client_key);
+    let tls = TlsClient::new(ca_cert, client_cert, client_key)?;
 
-    // Configure mTLS
-    let tls_config = ClientTlsConfig::new()
-      .ca_certificate(ca)
-      .identity(identity)
-      .domain_name("localhost"); // Adjust to match your server certificate
+    Ok(Network {
+      tls: Arc::new(tls),
+      identities,
+      peers: Arc::default(),
+    })
+  }
 
-    Ok(Network { tls_config })
+  /// Returns the mutual TLS client used to connect to other nodes.
+  pub fn tls_client(&self) -> Arc<TlsClient> {
+    self.tls.clone()
   }
 }
 
@@ -48,79 +64,102 @@ impl Network {
 impl RaftNetworkFactory<TypeConfig> for Network {
   type Network = NetworkConnection;
 
+  /// Returns a client sharing the connection to `target`, which is only dialed once a request is
+  /// sent. The connection is replaced if the node moved to another address or identity.
   #[tracing::instrument(level = "debug", skip_all)]
-  async fn new_client(&mut self, _: NodeId, node: &Node) -> Self::Network {
-    let server_addr = &node.rpc_addr;
-
-    // Build the endpoint step by step
-    let endpoint_result = Endpoint::from_shared(format!("https://{}", server_addr))
-      .and_then(|ep| ep.tls_config(self.tls_config.clone()));
-
-    let channel = match endpoint_result {
-      Ok(endpoint) => {
-        match endpoint
-          .tcp_keepalive(Some(std::time::Duration::from_secs(30)))
-          .http2_keep_alive_interval(std::time::Duration::from_secs(30))
-          .keep_alive_timeout(std::time::Duration::from_secs(5))
-          .connect_timeout(std::time::Duration::from_secs(10))
-          .connect()
-          .await
-        {
-          Ok(channel) => Some(channel),
-          Err(e) => {
-            tracing::error!("Failed to connect to {}: {}", server_addr, e);
-            None
-          }
-        }
-      }
-      Err(e) => {
-        tracing::error!("Failed to configure TLS for {}: {}", server_addr, e);
-        None
-      }
-    };
+  async fn new_client(&mut self, target: NodeId, node: &Node) -> Self::Network {
+    if let Some(peer) = self.peers.read().await.get(&target)
+      && peer.node() == node
+    {
+      return NetworkConnection::new(peer.clone());
+    }
 
-    NetworkConnection::new(channel)
+    let peer = Arc::new(Peer::new(
+      node.clone(),
+      self.identities.of(node),
+      self.tls.clone(),
+    ));
+    self.peers.write().await.insert(target, peer.clone());
+
+    NetworkConnection::new(peer)
   }
 }
 
-/// Represents an active network connection to a remote Raft node.
+/// Represents a network connection to a remote Raft node.
 /// Handles serialization and deserialization of Raft messages over gRPC.
+///
+/// Every request must complete within the `hard_ttl` of its `RPCOption`, dialing included.
+/// Snapshots may take longer: the TTL bounds the dial and the wait for each chunk to be taken.
 pub struct NetworkConnection {
-  // Pre-established channel, or None if connection failed
-  channel: Option<Channel>,
-  // Cached client created from the channel
-  client: Option<protobuf::raft_service_client::RaftServiceClient<Channel>>,
+  peer: Arc<Peer>,
 }
 
 impl NetworkConnection {
-  /// Creates a new NetworkConnection with a pre-established channel
-  pub fn new(channel: Option<Channel>) -> Self {
-    NetworkConnection {
-      channel,
-      client: None,
-    }
+  pub fn new(peer: Arc<Peer>) -> Self {
+    NetworkConnection { peer }
   }
 
-  /// Get or create the gRPC client from the established channel
-  fn get_client(
-    &mut self,
-  ) -> Result<&mut protobuf::raft_service_client::RaftServiceClient<Channel>, RPCError> {
-    // If we don't have a channel, connection failed during creation
-    let channel = self.channel.as_ref().ok_or_else(|| {
-      RPCError::Unreachable(Unreachable::new(&std::io::Error::new(
-        std::io::ErrorKind::NotConnected,
-        "No connection available",
-      )))
-    })?;
-
-    // Create client if we don't have one yet
-    if self.client.is_none() {
-      self.client = Some(protobuf::raft_service_client::RaftServiceClient::new(
-        channel.clone(),
-      ));
+  /// Returns a client for the node, dialing it if needed before `deadline`.
+  async fn client(
+    &self,
+    deadline: Instant,
+  ) -> Result<protobuf::raft_service_client::RaftServiceClient<TracedChannel>, RPCError> {
+    let channel = tokio::time::timeout_at(deadline.into(), self.peer.channel(deadline))
+      .await
+      .map_err(|_| {
+        RPCError::Network(NetworkError::new(&std::io::Error::new(
+          std::io::ErrorKind::TimedOut,
+          format!("timed out connecting to {}", self.peer.node().rpc_addr),
+        )))
+      })?
+      .map_err(|e| RPCError::Unreachable(Unreachable::new(&e)))?;
+
+    Ok(protobuf::raft_service_client::RaftServiceClient::new(
+      telemetry::traced(channel),
+    ))
+  }
+
+  /// Maps the status of a failed request to an error, dropping the connection if the node could
+  /// not be reached over it.
+  async fn error(&self, status: Status) -> RPCError {
+    if status.code() == Code::Unavailable {
+      self.peer.disconnect().await;
+      RPCError::Unreachable(Unreachable::new(&status))
+    } else {
+      RPCError::Network(NetworkError::new(&status))
     }
+  }
+}
+
+/// Wraps `message` in a request that the client and the server give up on at `deadline`.
+fn with_deadline<T>(message: T, deadline: Instant) -> tonic::Request<T> {
+  let mut request = tonic::Request::new(message);
+  request.set_timeout(deadline.saturating_duration_since(Instant::now()));
+  request
+}
 
-    Ok(self.client.as_mut().unwrap())
+/// Queues a request on a snapshot stream, failing if none was taken by the node within `timeout`.
+async fn send_snapshot_request(
+  tx: &tokio::sync::mpsc::Sender<protobuf::SnapshotRequest>,
+  request: protobuf::SnapshotRequest,
+  timeout: Duration,
+) -> Result<(), StreamingError> {
+  match tokio::time::timeout(timeout, tx.send(request)).await {
+    Ok(result) => result.map_err(|e| StreamingError::from(NetworkError::new(&e))),
+    Err(_) => Err(StreamingError::from(NetworkError::new(
+      &std::io::Error::new(
+        std::io::ErrorKind::TimedOut,
+        format!("snapshot transfer made no progress for {:?}", timeout),
+      ),
+    ))),
+  }
+}
+
+fn streaming_error(e: RPCError) -> StreamingError {
+  match e {
+    RPCError::Unreachable(u) => StreamingError::from(u),
+    RPCError::Network(n) => StreamingError::from(n),
+    _ => StreamingError::from(NetworkError::new(&AnyError::error("Connection error"))),
   }
 }
 
@@ -130,14 +169,34 @@ impl RaftNetworkV2<TypeConfig> for NetworkConnection {
   async fn append_entries(
     &mut self,
     req: AppendEntriesRequest,
-    _option: RPCOption,
+    option: RPCOption,
   ) -> Result<AppendEntriesResponse, RPCError> {
-    let client = self.get_client()?;
+    let deadline = Instant::now() + option.hard_ttl();
+    let mut client = self.client(deadline).await?;
+    let request = protobuf::AppendEntriesRequest::from(req);
+
+    // Heartbeats are not traced; replicated commands are linked to the requests that proposed them
+    let span = if request.entries.is_empty() {
+      Span::none()
+    } else {
+      tracing::info_span!("append_entries", entries = request.entries.len())
+    };
+    for command in request
+      .entries
+      .iter()
+      .filter_map(|entry| entry.app_data.as_ref())
+    {
+      telemetry::add_link(&span, &command.trace_context);
+    }
 
-    let response = client
-      .append_entries(protobuf::AppendEntriesRequest::from(req))
+    let response = match client
+      .append_entries(with_deadline(request, deadline))
+      .instrument(span)
       .await
-      .map_err(|e| RPCError::Network(NetworkError::new(&e)))?;
+    {
+      Ok(response) => response,
+      Err(status) => return Err(self.error(status).await),
+    };
 
     Ok(AppendEntriesResponse::from(response.into_inner()))
   }
@@ -146,22 +205,31 @@ impl RaftNetworkV2<TypeConfig> for NetworkConnection {
     &mut self,
     vote: Vote,
     snapshot: Snapshot,
-    _cancel: impl std::future::Future<Output = openraft::error::ReplicationClosed>
+    cancel: impl std::future::Future<Output = openraft::error::ReplicationClosed>
     + openraft::OptionalSend
     + 'static,
-    _option: RPCOption,
+    option: RPCOption,
   ) -> Result<SnapshotResponse, crate::raft_types::StreamingError> {
-    let client = self.get_client().map_err(|e| match e {
-      RPCError::Unreachable(u) => StreamingError::from(u),
-      RPCError::Network(n) => StreamingError::from(n),
-      _ => StreamingError::from(NetworkError::new(&AnyError::error("Connection error"))),
-    })?;
+    // A whole snapshot can take far longer to send than a single request, so the TTL only
+    // bounds the dial and the progress of each chunk; the transfer goes on until it fails or
+    // replication is closed.
+    let timeout = option.hard_ttl();
+    let mut client = self
+      .client(Instant::now() + timeout)
+      .await
+      .map_err(streaming_error)?;
 
-    let (tx, rx) = tokio::sync::mpsc::channel(1024);
+    // The snapshot is read lazily, so keep only a few chunks in flight
+    let (tx, rx) = tokio::sync::mpsc::channel(4);
     let strm = ReceiverStream::new(rx);
 
-    // Start the RPC call but don't await it yet
-    let response_future = client.snapshot(strm);
+    // Start the RPC call; it is driven concurrently with sending the chunks below
+    let response_future = async {
+      match client.snapshot(strm).await {
+        Ok(response) => Ok(response),
+        Err(status) => Err(streaming_error(self.error(status).await)),
+      }
+    };
 
     // 1. Send meta chunk
     let meta = &snapshot.meta;
@@ -173,26 +241,43 @@ impl RaftNetworkV2<TypeConfig> for NetworkConnection {
           last_membership_log_id: meta.last_membership.log_id().map(|log_id| log_id.into()),
           last_membership: Some(meta.last_membership.membership().clone().into()),
           snapshot_id: meta.snapshot_id.to_string(),
+          size: snapshot.snapshot.size(),
+          checksum: snapshot.snapshot.checksum(),
         },
       )),
     };
 
-    tx.send(request).await.map_err(|e| NetworkError::new(&e))?;
-
-    // 2. Send data chunks
-    let chunk_size = 1024 * 1024;
-    for chunk in snapshot.snapshot.chunks(chunk_size) {
-      let request = protobuf::SnapshotRequest {
-        payload: Some(protobuf::snapshot_request::Payload::Chunk(chunk.to_vec())),
-      };
-      tx.send(request).await.map_err(|e| NetworkError::new(&e))?;
-    }
+    let mut reader = snapshot
+      .snapshot
+      .open()
+      .await
+      .map_err(|e| NetworkError::new(&e))?;
+
+    let send_chunks = async move {
+      send_snapshot_request(&tx, request, timeout).await?;
+
+      // 2. Send data chunks as they are read from the snapshot file
+      while let Some(chunk) = reader
+        .next_chunk()
+        .await
+        .map_err(|e| NetworkError::new(&e))?
+      {
+        let request = protobuf::SnapshotRequest {
+          payload: Some(protobuf::snapshot_request::Payload::Chunk(chunk)),
+        };
+        send_snapshot_request(&tx, request, timeout).await?;
+      }
 
-    // 3. Close the stream by dropping the sender
-    drop(tx);
+      // 3. Close the stream by dropping the sender
+      drop(tx);
+      Ok::<_, StreamingError>(())
+    };
 
-    // 4. Now await the response
-    let response = response_future.await.map_err(|e| NetworkError::new(&e))?;
+    // 4. Await the response while the chunks are being sent
+    let ((), response) = tokio::select! {
+      result = async { futures::try_join!(send_chunks, response_future) } => result?,
+      closed = cancel => return Err(StreamingError::Closed(closed)),
+    };
 
     let message = response.into_inner();
 
@@ -203,20 +288,21 @@ impl RaftNetworkV2<TypeConfig> for NetworkConnection {
     })
   }
 
-  async fn vote(&mut self, req: VoteRequest, _option: RPCOption) -> Result<VoteResponse, RPCError> {
-    let client = self.get_client()?;
+  async fn vote(&mut self, req: VoteRequest, option: RPCOption) -> Result<VoteResponse, RPCError> {
+    let deadline = Instant::now() + option.hard_ttl();
+    let mut client = self.client(deadline).await?;
 
     // Convert the openraft VoteRequest to protobuf VoteRequest
     let proto_vote_req: protobuf::VoteRequest = req.into();
 
     // Create a tonic Request with the protobuf VoteRequest
-    let request = tonic::Request::new(proto_vote_req);
+    let request = with_deadline(proto_vote_req, deadline);
 
     // Send the vote request
-    let response = client
-      .vote(request)
-      .await
-      .map_err(|e| RPCError::Network(NetworkError::new(&e)))?;
+    let response = match client.vote(request).await {
+      Ok(response) => response,
+      Err(status) => return Err(self.error(status).await),
+    };
 
     // Convert the response back to openraft VoteResponse
     let proto_vote_resp: protobuf::VoteResponse = response.into_inner();
@@ -0,0 +1,189 @@
+//! Connections to the other nodes, dialed on first use and dialed again after transport errors.
+
+use std::io;
+use std::sync::Arc;
+use std::time::Duration;
+use std::time::Instant;
+
+use tokio::sync::Mutex;
+use tonic::transport::Channel;
+
+use crate::protobuf;
+use crate::tls::TlsClient;
+
+/// Longest time spent dialing a node, even if the request allows for more.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Delay before dialing again after the first failure, doubled after each further one.
+const INITIAL_BACKOFF: Duration = Duration::from_millis(100);
+
+/// Longest delay between two attempts to dial a node.
+const MAX_BACKOFF: Duration = Duration::from_secs(5);
+
+/// The connection to a node, shared by every client openraft creates for it.
+#[derive(Debug)]
+pub struct Peer {
+  node: protobuf::Node,
+
+  /// Identities the node may present, one of which it must.
+  identities: Vec<String>,
+
+  tls: Arc<TlsClient>,
+  state: Mutex<State>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+  channel: Option<Channel>,
+
+  /// Consecutive failures to dial the node or to reach it over the channel.
+  failures: u32,
+
+  /// The node is not dialed again before this instant.
+  retry_at: Option<Instant>,
+}
+
+impl State {
+  /// Records a failure and returns how long to wait before dialing again.
+  fn fail(&mut self) -> Duration {
+    self.failures = self.failures.saturating_add(1);
+
+    let delay = INITIAL_BACKOFF
+      .saturating_mul(1 << (self.failures - 1).min(16))
+      .min(MAX_BACKOFF);
+    self.retry_at = Some(Instant::now() + delay);
+
+    delay
+  }
+}
+
+impl Peer {
+  pub fn new(node: protobuf::Node, identities: Vec<String>, tls: Arc<TlsClient>) -> Self {
+    Peer {
+      node,
+      identities,
+      tls,
+      state: Mutex::new(State::default()),
+    }
+  }
+
+  /// The node as recorded in the membership, with the address it is dialed at and the identity
+  /// recorded for it.
+  pub fn node(&self) -> &protobuf::Node {
+    &self.node
+  }
+
+  /// Returns the channel to the node, dialing it if needed before `deadline`.
+  ///
+  /// While backing off from a failure, fails right away without dialing.
+  pub async fn channel(&self, deadline: Instant) -> io::Result<Channel> {
+    let mut state = self.state.lock().await;
+
+    if let Some(channel) = &state.channel {
+      return Ok(channel.clone());
+    }
+
+    if let Some(retry_at) = state.retry_at
+      && let Some(remaining) = retry_at.checked_duration_since(Instant::now())
+    {
+      return Err(io::Error::new(
+        io::ErrorKind::NotConnected,
+        format!(
+          "not dialing {} again for {:?}",
+          self.node.rpc_addr, remaining
+        ),
+      ));
+    }
+
+    match self.dial(deadline).await {
+      Ok(channel) => {
+        if state.failures > 0 {
+          tracing::info!(
+            "Connected to {} after {} failures",
+            self.node.rpc_addr,
+            state.failures
+          );
+        }
+
+        *state = State {
+          channel: Some(channel.clone()),
+          ..Default::default()
+        };
+
+        Ok(channel)
+      }
+      Err(e) => {
+        let delay = state.fail();
+        tracing::warn!(
+          "Failed to connect to {}, retrying in {:?}: {}",
+          self.node.rpc_addr,
+          delay,
+          e
+        );
+
+        Err(io::Error::new(io::ErrorKind::NotConnected, e))
+      }
+    }
+  }
+
+  /// Drops the channel after a transport error, so that the node is dialed again once the backoff
+  /// has passed.
+  pub async fn disconnect(&self) {
+    let mut state = self.state.lock().await;
+
+    if state.channel.take().is_some() {
+      let delay = state.fail();
+      tracing::warn!(
+        "Lost connection to {}, retrying in {:?}",
+        self.node.rpc_addr,
+        delay
+      );
+    }
+  }
+
+  async fn dial(
+    &self,
+    deadline: Instant,
+  ) -> Result<Channel, Box<dyn std::error::Error + Send + Sync>> {
+    let timeout = deadline
+      .saturating_duration_since(Instant::now())
+      .min(CONNECT_TIMEOUT);
+
+    self
+      .tls
+      .connect(&self.node.rpc_addr, &self.identities, timeout)
+      .await
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_backoff() {
+    let mut state = State::default();
+
+    assert_eq!(state.fail(), INITIAL_BACKOFF);
+    assert_eq!(state.fail(), INITIAL_BACKOFF * 2);
+    assert_eq!(state.fail(), INITIAL_BACKOFF * 4);
+    assert!(state.retry_at.unwrap() > Instant::now());
+
+    for _ in 0..10 {
+      assert!(state.fail() <= MAX_BACKOFF);
+    }
+    assert_eq!(state.fail(), MAX_BACKOFF);
+  }
+
+  #[test]
+  fn test_backoff_does_not_overflow() {
+    let mut state = State {
+      failures: u32::MAX - 1,
+      ..Default::default()
+    };
+
+    assert_eq!(state.fail(), MAX_BACKOFF);
+    assert_eq!(state.fail(), MAX_BACKOFF);
+    assert_eq!(state.failures, u32::MAX);
+  }
+}
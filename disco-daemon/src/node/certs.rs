@@ -0,0 +1,85 @@
+//! The certificate and key files of a node, read at startup and again whenever they may have been
+//! replaced.
+
+use std::io;
+use std::sync::Arc;
+
+use tokio::fs;
+use tokio::try_join;
+use tracing::warn;
+
+use crate::ca::CertificateAuthority;
+use crate::config::Opt;
+use crate::tls;
+
+/// Contents of the certificate and key files of a node.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CertificateFiles {
+  pub server_cert: Vec<u8>,
+  pub server_key: Vec<u8>,
+  pub ca_cert: Vec<u8>,
+  pub client_cert: Vec<u8>,
+  pub client_key: Vec<u8>,
+
+  /// Key of the cluster CA, if the node signs the certificates of joining nodes.
+  pub ca_key: Option<Vec<u8>>,
+}
+
+impl CertificateFiles {
+  /// Reads the files named in `config`, in parallel.
+  pub async fn read(config: &Opt) -> io::Result<Self> {
+    let ca_key = async {
+      match &config.ca_key {
+        Some(path) => fs::read(path).await.map(Some),
+        None => Ok(None),
+      }
+    };
+
+    let (server_cert, server_key, ca_cert, client_cert, client_key, ca_key) = try_join!(
+      fs::read(&config.server_cert),
+      fs::read(&config.server_key),
+      fs::read(&config.ca_cert),
+      fs::read(&config.client_cert),
+      fs::read(&config.client_key),
+      ca_key
+    )?;
+
+    Ok(CertificateFiles {
+      server_cert,
+      server_key,
+      ca_cert,
+      client_cert,
+      client_key,
+      ca_key,
+    })
+  }
+
+  /// Loads the cluster CA, if the node holds its key.
+  pub fn authority(&self) -> Result<Option<Arc<CertificateAuthority>>, Box<dyn std::error::Error>> {
+    let Some(key) = &self.ca_key else {
+      return Ok(None);
+    };
+
+    let authority = CertificateAuthority::load(
+      std::str::from_utf8(&self.ca_cert)?,
+      std::str::from_utf8(key)?,
+    )?;
+
+    Ok(Some(Arc::new(authority)))
+  }
+
+  /// Warns about the certificates not issued for `identity`, which the other nodes verify this
+  /// node against.
+  pub fn check_identity(&self, identity: &str) -> io::Result<()> {
+    for (name, pem) in [("server", &self.server_cert), ("client", &self.client_cert)] {
+      if !tls::certificates(pem)?
+        .first()
+        .is_some_and(|cert| tls::presents(cert, identity))
+      {
+        warn!("The {} certificate is not issued for {}", name, identity);
+      }
+    }
+
+    Ok(())
+  }
+}
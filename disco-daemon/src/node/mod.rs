@@ -1,4 +1,7 @@
+mod certs;
 mod node;
-mod runtime;
+pub(crate) mod runtime;
+mod store;
 
 pub use node::*;
+pub use store::*;
@@ -1,25 +1,43 @@
 use disco_common::engine::*;
+use std::path::Path;
 use std::sync::Arc;
-use tokio::fs;
-use tokio::try_join;
+use std::time::Duration;
+use std::time::Instant;
+use tokio::net::TcpListener;
+use tokio::signal::unix::{Signal, SignalKind, signal};
 use tracing::info;
+use tracing::warn;
 
-use openraft::{Config, ServerState, metrics::RaftServerMetrics};
+use openraft::{ServerState, metrics::RaftServerMetrics};
 use tokio::sync::{Mutex, watch::Receiver};
-use tonic::transport::{Certificate, Identity, Server, ServerTlsConfig};
+use tonic::server::NamedService;
+use tonic::transport::Server;
+use tonic_health::ServingStatus;
+use tonic_health::server::HealthReporter;
 
 use crate::TypeConfig;
+use crate::ca::SharedAuthority;
 use crate::config::Opt;
 use crate::controller::Controller;
 use crate::grpc::app_service::AppServiceImpl;
+use crate::grpc::auth::Authorizer;
 use crate::grpc::raft_service::RaftServiceImpl;
 use crate::network::Network;
 use crate::protobuf;
 use crate::raft_types::Raft;
 use crate::settings::Settings;
+use crate::store::LOG_DIR;
 use crate::store::LogStore;
+use crate::store::SNAPSHOT_DIR;
 use crate::store::StateMachineStore;
-
+use crate::store::backup;
+use crate::store::lease;
+use crate::telemetry::TraceContextLayer;
+use crate::tls;
+use crate::tls::{ClientCertLayer, TlsClient, TlsServer};
+
+use super::ClusterStore;
+use super::certs::CertificateFiles;
 use super::runtime;
 
 pub type NodeId = u64;
@@ -34,10 +52,10 @@ struct NodeInner {
 
   // Keep the fields you were using directly
   raft: Raft,
+  log_store: LogStore,
   state_machine_store: Arc<StateMachineStore>,
 
   // cluster-wide settings that never change
-  #[allow(dead_code)]
   settings: Settings,
 
   // each node runs a disco Engine for scripted customizations
@@ -46,71 +64,119 @@ struct NodeInner {
   // controller is started and stopped based on raft leader status
   controller: Arc<Mutex<Option<Controller>>>,
 
-  // TLS certificates
-  server_cert: Vec<u8>,
-  server_key: Vec<u8>,
-  ca_cert: Vec<u8>,
-  client_cert: Vec<u8>,
-  client_key: Vec<u8>,
+  // TLS certificates as last loaded, swapped in place when their files change
+  certificate_files: Mutex<CertificateFiles>,
+  tls_server: Arc<TlsServer>,
+  tls_client: Arc<TlsClient>,
+
+  // The cluster CA, if this node holds its key
+  ca: SharedAuthority,
 }
 
 impl Node {
   const START_FILE: &str = "cluster.js";
+  const LEASE_CHECK_INTERVAL: Duration = Duration::from_millis(500);
+  const SNAPSHOT_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+  const HEALTH_CHECK_INTERVAL: Duration = Duration::from_millis(500);
+  const CERTIFICATE_CHECK_INTERVAL: Duration = Duration::from_secs(10);
 
   pub async fn new(config: Opt, settings: Settings) -> Result<Node, Box<dyn std::error::Error>> {
     // Load all TLS certificates in parallel at startup
-    let (server_cert, server_key, ca_cert, client_cert, client_key) = try_join!(
-      fs::read(&config.server_cert),
-      fs::read(&config.server_key),
-      fs::read(&config.ca_cert),
-      fs::read(&config.client_cert),
-      fs::read(&config.client_key)
-    )?;
+    let files = CertificateFiles::read(&config).await?;
+
+    // Holding the CA key lets the node sign the certificates of joining nodes
+    let ca = files.authority()?;
+
+    // Raft state lives under the data directory so that it survives restarts
+    let data_dir = Path::new(&config.data_dir);
+    let keyring = settings.keyring()?;
+
+    // A restored node starts out as the only member of a new cluster. Restarting it with the
+    // same backup keeps the state it has built since.
+    if let Some(path) = &config.restore_from {
+      if backup::is_restored(path, data_dir)? {
+        info!("{} was already restored from {}", data_dir.display(), path);
+      } else {
+        let node = protobuf::Node {
+          node_id: config.id,
+          rpc_addr: config.addr.clone(),
+          tls_identity: config.tls_identity.clone().unwrap_or_default(),
+        };
+        let meta = backup::restore(path, data_dir, node, keyring.clone()).await?;
+
+        if meta.cluster_name != settings.cluster_name {
+          warn!(
+            "Restored a backup of cluster {} into cluster {}",
+            meta.cluster_name, settings.cluster_name
+          );
+        }
+      }
+    }
+
+    let log_store = LogStore::open(data_dir.join(LOG_DIR), keyring.clone())?;
+    let state_machine_store = Arc::new(
+      StateMachineStore::open(
+        data_dir.join(SNAPSHOT_DIR),
+        settings.snapshots_to_keep,
+        keyring,
+      )
+      .await?,
+    );
 
-    let log_store = LogStore::default();
-    let state_machine_store = Arc::new(StateMachineStore::default());
+    // Other nodes verify this node against its URI, so its certificates must carry it
+    files.check_identity(&tls::node_uri(&settings.cluster_name, config.id))?;
+
+    let tls_server = TlsServer::new(&files.ca_cert, &files.server_cert, &files.server_key)?;
 
     // Create the network layer with client certificates
-    let network = Network::new(&ca_cert, &client_cert, &client_key)?;
-
-    let raft_config: Config = Config {
-      cluster_name: settings.cluster_name.clone(),
-      election_timeout_min: settings.election_timeout_min,
-      election_timeout_max: settings.election_timeout_max,
-      heartbeat_interval: settings.heartbeat_interval,
-      install_snapshot_timeout: settings.install_snapshot_timeout,
-      ..Default::default()
-    }
-    .validate()?; // Proper error handling instead of unwrap
+    let identities = settings.node_identities();
+    let network = Network::new(
+      identities.clone(),
+      &files.ca_cert,
+      &files.client_cert,
+      &files.client_key,
+    )?;
+    let tls_client = network.tls_client();
+
+    let raft_config = settings.raft_config()?;
 
     // Create a local raft instance
     let raft = Raft::new(
       config.id.clone(),
       Arc::new(raft_config),
       network,
-      log_store,
+      log_store.clone(),
       state_machine_store.clone(),
     )
     .await?; // Proper error handling
 
     let engine = Engine::new(Some(Self::START_FILE))?;
 
+    // Scripts reach the store through the leader, like any other client
+    engine
+      .attach_store(Arc::new(ClusterStore::new(
+        raft.clone(),
+        tls_client.clone(),
+        identities,
+      )))
+      .await?;
+
     let _cluster = engine.callback("init", &[]).await?;
 
     let node_inner = NodeInner {
       config,
       raft,
+      log_store,
       state_machine_store,
       settings,
       engine,
       controller: Arc::new(Mutex::new(None)),
 
       // Store the loaded certificates
-      server_cert,
-      server_key,
-      ca_cert,
-      client_cert,
-      client_key,
+      certificate_files: Mutex::new(files),
+      tls_server: Arc::new(tls_server),
+      tls_client,
+      ca: Arc::new(std::sync::RwLock::new(ca)),
     };
 
     Ok(Node {
@@ -126,6 +192,32 @@ impl Node {
       self.inner.clone(),
     ));
 
+    // Spawn the lease expiry loop, which only acts while this node is the leader
+    runtime::spawn(Self::expire_leases(self.inner.clone()));
+
+    // Spawn the snapshot loop for the triggers Raft does not handle itself
+    runtime::spawn(Self::trigger_snapshots(self.inner.clone()));
+
+    // Spawn the certificate reload loop, also woken up by SIGHUP
+    runtime::spawn(Self::reload_certificates(
+      self.inner.clone(),
+      signal(SignalKind::hangup())?,
+    ));
+
+    if let Some(addr) = &self.inner.config.metrics_addr {
+      #[cfg(feature = "prometheus")]
+      {
+        crate::prometheus::serve(addr.parse()?, self.inner.raft.clone())?;
+        info!("Serving metrics at {}", addr);
+      }
+
+      #[cfg(not(feature = "prometheus"))]
+      warn!(
+        "Not serving metrics at {}: built without the prometheus feature",
+        addr
+      );
+    }
+
     info!(
       "Node {} starting server at {}",
       self.inner.config.id, self.inner.config.addr
@@ -135,31 +227,70 @@ impl Node {
       .install_default()
       .expect("Failed to install crypto provider");
 
-    let server_identity = Identity::from_pem(&self.inner.server_cert, &self.inner.server_key);
-    let ca_certificate = Certificate::from_pem(&self.inner.ca_cert);
-
-    // Configure TLS
-    let tls_config = ServerTlsConfig::new()
-      .identity(server_identity)
-      .client_ca_root(ca_certificate);
-
     // Create the services
-    let internal_service = RaftServiceImpl::new(self.inner.raft.clone());
+    let internal_service = RaftServiceImpl::new(
+      self.inner.raft.clone(),
+      self.inner.state_machine_store.clone(),
+      self.inner.settings.node_identities(),
+    );
     let api_service = AppServiceImpl::new(
       self.inner.raft.clone(),
       self.inner.state_machine_store.clone(),
+      self.inner.settings.cluster_name.clone(),
+      self.inner.ca.clone(),
+    );
+
+    // Grant the clients of the AppService the role of their certificate
+    let authorizer = Authorizer::new(
+      self.inner.raft.clone(),
+      self.inner.state_machine_store.clone(),
+      self.inner.settings.node_identities(),
     );
 
+    // The node is not serving until it has caught up with the cluster
+    let (mut health_reporter, health_service) = tonic_health::server::health_reporter();
+    health_reporter
+      .set_service_status("", ServingStatus::NotServing)
+      .await;
+    runtime::spawn(Self::report_health(self.inner.clone(), health_reporter));
+
+    // Describe the services to clients such as grpcurl, over both versions of the protocol
+    let reflection_service = tonic_reflection::server::Builder::configure()
+      .register_encoded_file_descriptor_set(protobuf::FILE_DESCRIPTOR_SET)
+      .register_encoded_file_descriptor_set(tonic_health::pb::FILE_DESCRIPTOR_SET)
+      .build_v1()?;
+    let reflection_service_v1alpha = tonic_reflection::server::Builder::configure()
+      .register_encoded_file_descriptor_set(protobuf::FILE_DESCRIPTOR_SET)
+      .register_encoded_file_descriptor_set(tonic_health::pb::FILE_DESCRIPTOR_SET)
+      .build_v1alpha()?;
+
+    // Continue the trace of the caller in each request
+    let server = Server::builder().layer(TraceContextLayer);
+
+    // Count and time the requests when metrics are exported
+    #[cfg(feature = "prometheus")]
+    let server = server.layer(crate::prometheus::GrpcMetricsLayer);
+
+    // Require a client certificate for everything but joining
+    let server = server.layer(ClientCertLayer);
+
+    // TLS is set up by the TlsServer, so that its certificates can be reloaded; clients without a
+    // certificate are turned away by the ClientCertLayer, except for joining nodes requesting theirs
+    let listener = TcpListener::bind(&self.inner.config.addr).await?;
+    let incoming = self.inner.tls_server.clone().incoming(listener);
+
     // Start and await the server with TLS
-    Server::builder()
-      .tls_config(tls_config)?
+    server
       .add_service(protobuf::raft_service_server::RaftServiceServer::new(
         internal_service,
       ))
-      .add_service(protobuf::app_service_server::AppServiceServer::new(
-        api_service,
-      ))
-      .serve(self.inner.config.addr.parse()?)
+      .add_service(
+        protobuf::app_service_server::AppServiceServer::with_interceptor(api_service, authorizer),
+      )
+      .add_service(health_service)
+      .add_service(reflection_service)
+      .add_service(reflection_service_v1alpha)
+      .serve_with_incoming(incoming)
       .await?;
 
     Ok(())
@@ -219,7 +350,243 @@ impl Node {
   }
 }
 
+impl Node {
+  /// Proposes the expiry of leases whose deadline has passed. Followers learn about expired
+  /// leases through the replicated command, so all nodes drop them at the same log index.
+  ///
+  /// A new leader first gives every lease a full TTL, as their holders could not renew them while
+  /// there was no leader.
+  async fn expire_leases(node_inner: Arc<NodeInner>) {
+    let mut interval = tokio::time::interval(Self::LEASE_CHECK_INTERVAL);
+    let mut reset_term = None;
+
+    loop {
+      interval.tick().await;
+
+      let (is_leader, term) = {
+        let metrics = node_inner.raft.metrics();
+        let metrics = metrics.borrow();
+        (
+          metrics.current_leader == Some(metrics.id),
+          metrics.current_term,
+        )
+      };
+      if !is_leader {
+        continue;
+      }
+
+      if reset_term != Some(term) {
+        let mut command = protobuf::Command::from(protobuf::LeaseResetRequest {});
+        command.timestamp = lease::now();
+
+        // No lease is expired before the reset is committed
+        match node_inner.raft.client_write(command).await {
+          Ok(_) => reset_term = Some(term),
+          Err(e) => {
+            warn!("Failed to reset lease deadlines: {}", e);
+            continue;
+          }
+        }
+      }
+
+      let now = lease::now();
+      let ids = node_inner.state_machine_store.expired_leases(now);
+      if ids.is_empty() {
+        continue;
+      }
+
+      info!("Expiring leases {:?}", ids);
+
+      let mut command = protobuf::Command::from(protobuf::LeaseExpireRequest { ids });
+      command.timestamp = now;
+
+      if let Err(e) = node_inner.raft.client_write(command).await {
+        warn!("Failed to expire leases: {}", e);
+      }
+    }
+  }
+}
+
+impl Node {
+  /// Triggers a snapshot once the logs applied since the last one take up `snapshot_bytes`, or
+  /// `snapshot_interval_secs` after the last one. Every node snapshots its own state machine.
+  async fn trigger_snapshots(node_inner: Arc<NodeInner>) {
+    let settings = &node_inner.settings;
+    if settings.snapshot_bytes == 0 && settings.snapshot_interval_secs == 0 {
+      return;
+    }
+
+    let snapshot_interval = Duration::from_secs(settings.snapshot_interval_secs);
+    let mut interval = tokio::time::interval(Self::SNAPSHOT_CHECK_INTERVAL);
+
+    let mut last_snapshot = None;
+    let mut since = Instant::now();
+
+    loop {
+      interval.tick().await;
+
+      let (snapshot, last_applied) = {
+        let metrics = node_inner.raft.metrics();
+        let metrics = metrics.borrow();
+        (metrics.snapshot, metrics.last_applied)
+      };
+
+      if snapshot != last_snapshot {
+        last_snapshot = snapshot;
+        since = Instant::now();
+      }
+
+      // Nothing was applied since the last snapshot
+      if last_applied <= snapshot {
+        continue;
+      }
+
+      let is_due = settings.snapshot_interval_secs > 0 && since.elapsed() >= snapshot_interval;
+      let is_large = settings.snapshot_bytes > 0
+        && node_inner
+          .log_store
+          .size_after(snapshot.map(|log_id| log_id.index()))
+          .await
+          >= settings.snapshot_bytes;
+
+      if !is_due && !is_large {
+        continue;
+      }
+
+      info!("Triggering a snapshot at {:?}", last_applied);
+
+      if let Err(e) = node_inner.raft.trigger().snapshot().await {
+        warn!("Failed to trigger a snapshot: {}", e);
+      }
+
+      // Give the snapshot time to be built before checking again
+      since = Instant::now();
+    }
+  }
+}
+
+impl Node {
+  /// Reports the node as serving once it is a member of the cluster and has applied every entry
+  /// known to be committed, both overall and for the `AppService`. The `RaftService` is always
+  /// serving, as that is how a lagging node catches up.
+  async fn report_health(node_inner: Arc<NodeInner>, mut reporter: HealthReporter) {
+    reporter
+      .set_serving::<protobuf::raft_service_server::RaftServiceServer<RaftServiceImpl>>()
+      .await;
+
+    let app_service =
+      <protobuf::app_service_server::AppServiceServer<AppServiceImpl> as NamedService>::NAME;
+    let mut interval = tokio::time::interval(Self::HEALTH_CHECK_INTERVAL);
+    let mut was_serving = None;
+
+    loop {
+      interval.tick().await;
+
+      // Read before the metrics, so that entries committed in between are not counted as lagging
+      let committed = node_inner.log_store.committed().await;
+
+      let (id, is_member, last_applied) = {
+        let metrics = node_inner.raft.metrics();
+        let metrics = metrics.borrow();
+        (
+          metrics.id,
+          metrics
+            .membership_config
+            .membership()
+            .get_node(&metrics.id)
+            .is_some(),
+          metrics.last_applied,
+        )
+      };
+
+      let is_serving = is_member && last_applied >= committed;
+      if was_serving == Some(is_serving) {
+        continue;
+      }
+      was_serving = Some(is_serving);
+
+      let status = if is_serving {
+        info!("Node {} is serving", id);
+        ServingStatus::Serving
+      } else {
+        info!(
+          "Node {} is not serving: member {}, applied {:?} of {:?}",
+          id, is_member, last_applied, committed
+        );
+        ServingStatus::NotServing
+      };
+
+      reporter.set_service_status("", status).await;
+      reporter.set_service_status(app_service, status).await;
+    }
+  }
+}
+
+impl Node {
+  /// Reloads the certificates when their files change, checked every
+  /// `CERTIFICATE_CHECK_INTERVAL` and right away on SIGHUP. Certificates that fail to load are
+  /// reported and the current ones kept. Established connections keep the certificates they were
+  /// set up with.
+  async fn reload_certificates(node_inner: Arc<NodeInner>, mut hangup: Signal) {
+    let mut interval = tokio::time::interval(Self::CERTIFICATE_CHECK_INTERVAL);
+
+    // Files that failed to load, reported once rather than at every check
+    let mut rejected = None;
+
+    loop {
+      tokio::select! {
+        _ = interval.tick() => {}
+        _ = hangup.recv() => info!("Received SIGHUP, reloading the certificates"),
+      }
+
+      let files = match CertificateFiles::read(&node_inner.config).await {
+        Ok(files) => files,
+        Err(e) => {
+          warn!("Failed to read the certificates: {}", e);
+          continue;
+        }
+      };
+
+      let mut current = node_inner.certificate_files.lock().await;
+      if *current == files || rejected.as_ref() == Some(&files) {
+        continue;
+      }
+
+      match node_inner.load_certificates(&files) {
+        Ok(()) => {
+          info!("Reloaded the certificates");
+          *current = files;
+          rejected = None;
+        }
+        Err(e) => {
+          warn!(
+            "Failed to reload the certificates, keeping the current ones: {}",
+            e
+          );
+          rejected = Some(files);
+        }
+      }
+    }
+  }
+}
+
 impl NodeInner {
+  /// Swaps in the certificates of `files`, once all of them are known to load, so that the node
+  /// never runs with only part of them.
+  fn load_certificates(&self, files: &CertificateFiles) -> Result<(), Box<dyn std::error::Error>> {
+    let ca = files.authority()?;
+    let tls_server = TlsServer::new(&files.ca_cert, &files.server_cert, &files.server_key)?;
+    let tls_client = TlsClient::new(&files.ca_cert, &files.client_cert, &files.client_key)?;
+
+    files.check_identity(&tls::node_uri(&self.settings.cluster_name, self.config.id))?;
+
+    self.tls_server.replace(tls_server);
+    self.tls_client.replace(tls_client);
+    *self.ca.write().unwrap() = ca;
+
+    Ok(())
+  }
+
   pub async fn start_controller(controller: &Arc<Mutex<Option<Controller>>>) {
     let mut controller_guard = controller.lock().await;
     if controller_guard.is_none() {
@@ -0,0 +1,329 @@
+//! Access to the replicated store for the scripts run by a node.
+
+use std::fmt;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{Result, anyhow};
+use disco_common::store::{Store, Txn, TxnResult};
+use tokio::sync::Mutex;
+use tonic::transport::Channel;
+use tonic::{Code, Status};
+
+use crate::grpc::leader::LEADER_ID_METADATA;
+use crate::protobuf;
+use crate::protobuf::app_service_client::AppServiceClient;
+use crate::raft_types::*;
+use crate::telemetry::{self, TracedChannel};
+use crate::tls::{NodeIdentities, TlsClient};
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How many times a request follows the leader named by the node that turned it away.
+const MAX_REDIRECTS: usize = 3;
+
+/// Store backing the `disco` global of the node's engine.
+///
+/// Requests go to the `AppService` of the current leader over the same mutual TLS as the Raft
+/// traffic, so scripts see the cluster exactly like external clients do. The leases behind locks
+/// and elections are kept alive by this process, and expire once the node dies.
+///
+/// The connection to the leader is dropped as soon as a request fails with `UNAVAILABLE`. If the
+/// node names another leader, the request is sent there right away, without waiting for this
+/// node to learn about it.
+pub struct ClusterStore {
+  raft: Raft,
+  tls: Arc<TlsClient>,
+  identities: NodeIdentities,
+  /// Connection to the last known leader, with the leader.
+  channel: Mutex<Option<(protobuf::Node, Channel)>>,
+}
+
+impl ClusterStore {
+  pub fn new(raft: Raft, tls: Arc<TlsClient>, identities: NodeIdentities) -> Self {
+    ClusterStore {
+      raft,
+      tls,
+      identities,
+      channel: Mutex::new(None),
+    }
+  }
+
+  /// Returns a client connected to `leader`, or to the current leader if not set, reusing the
+  /// connection while it does not change.
+  async fn client(&self, leader: Option<u64>) -> Result<AppServiceClient<TracedChannel>> {
+    let leader = {
+      let metrics = self.raft.metrics();
+      let metrics = metrics.borrow();
+
+      let leader = leader
+        .or(metrics.current_leader)
+        .ok_or_else(|| anyhow!("The cluster has no leader"))?;
+
+      metrics
+        .membership_config
+        .membership()
+        .get_node(&leader)
+        .ok_or_else(|| anyhow!("Leader {} is not a member of the cluster", leader))?
+        .clone()
+    };
+
+    let mut channel = self.channel.lock().await;
+
+    if let Some((cached_leader, cached)) = channel.as_ref()
+      && *cached_leader == leader
+    {
+      return Ok(AppServiceClient::new(telemetry::traced(cached.clone())));
+    }
+
+    let connected = self
+      .tls
+      .connect(
+        &leader.rpc_addr,
+        &self.identities.of(&leader),
+        CONNECT_TIMEOUT,
+      )
+      .await
+      .map_err(|e| anyhow!(e))?;
+
+    *channel = Some((leader, connected.clone()));
+
+    Ok(AppServiceClient::new(telemetry::traced(connected)))
+  }
+
+  /// Sends a request to the leader, following the leader named by a node that turns it away.
+  async fn call<T, F, Fut>(&self, request: F) -> Result<T, Status>
+  where
+    F: Fn(AppServiceClient<TracedChannel>) -> Fut,
+    Fut: Future<Output = Result<tonic::Response<T>, Status>>,
+  {
+    let mut leader = None;
+    let mut redirects = 0;
+
+    loop {
+      let client = self
+        .client(leader)
+        .await
+        .map_err(|e| Status::unavailable(format!("{:#}", e)))?;
+
+      let status = match request(client).await {
+        Ok(response) => return Ok(response.into_inner()),
+        Err(status) => status,
+      };
+
+      if status.code() != Code::Unavailable {
+        return Err(status);
+      }
+
+      // The leader changed or went away; the next request connects again.
+      *self.channel.lock().await = None;
+
+      leader = status
+        .metadata()
+        .get(LEADER_ID_METADATA)
+        .and_then(|id| id.to_str().ok())
+        .and_then(|id| id.parse().ok());
+
+      if leader.is_none() || redirects == MAX_REDIRECTS {
+        return Err(status);
+      }
+      redirects += 1;
+    }
+  }
+}
+
+impl fmt::Debug for ClusterStore {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.debug_struct("ClusterStore").finish_non_exhaustive()
+  }
+}
+
+#[tonic::async_trait]
+impl Store for ClusterStore {
+  async fn get(&self, key: &str) -> Result<Option<String>> {
+    let request = protobuf::GetRequest {
+      key: key.to_string(),
+      consistency: protobuf::ReadConsistency::Linearizable.into(),
+      revision: 0,
+      namespace: String::new(),
+    };
+
+    let response = self
+      .call(|mut client| {
+        let request = request.clone();
+        async move { client.get(request).await }
+      })
+      .await;
+
+    match response {
+      Ok(response) => Ok(response.value),
+      Err(status) if status.code() == Code::NotFound => Ok(None),
+      Err(status) => Err(status.into()),
+    }
+  }
+
+  async fn set(&self, key: &str, value: &str) -> Result<()> {
+    let request = protobuf::SetRequest {
+      key: key.to_string(),
+      value: value.to_string(),
+      lease: 0,
+      namespace: String::new(),
+    };
+
+    self
+      .call(|mut client| {
+        let request = request.clone();
+        async move { client.set(request).await }
+      })
+      .await?;
+    Ok(())
+  }
+
+  async fn delete(&self, key: &str) -> Result<Option<String>> {
+    let request = protobuf::DeleteRequest {
+      key: key.to_string(),
+      namespace: String::new(),
+    };
+
+    let response = self
+      .call(|mut client| {
+        let request = request.clone();
+        async move { client.delete(request).await }
+      })
+      .await?;
+    Ok(response.prev_value)
+  }
+
+  async fn txn(&self, txn: Txn) -> Result<TxnResult> {
+    let request = protobuf::TxnRequest::from(txn);
+
+    let response = self
+      .call(|mut client| {
+        let request = request.clone();
+        async move { client.txn(request).await }
+      })
+      .await?;
+    Ok(TxnResult {
+      succeeded: response.succeeded,
+      revision: response.revision,
+    })
+  }
+
+  async fn lease_grant(&self, ttl: i64) -> Result<i64> {
+    let request = protobuf::LeaseGrantRequest { ttl };
+
+    let response = self
+      .call(|mut client| async move { client.lease_grant(request).await })
+      .await?;
+    Ok(response.lease_id)
+  }
+
+  async fn lease_keep_alive(&self, id: i64) -> Result<bool> {
+    let request = protobuf::LeaseKeepAliveRequest { id };
+
+    let response = self
+      .call(|mut client| async move { client.lease_keep_alive(request).await })
+      .await?;
+    Ok(response.success)
+  }
+
+  async fn lease_revoke(&self, id: i64) -> Result<()> {
+    let request = protobuf::LeaseRevokeRequest { id };
+
+    self
+      .call(|mut client| async move { client.lease_revoke(request).await })
+      .await?;
+    Ok(())
+  }
+
+  async fn lock(&self, name: &str, lease: i64, timeout: Duration) -> Result<i64> {
+    let request = protobuf::LockRequest {
+      name: name.to_string(),
+      lease,
+      namespace: String::new(),
+    };
+
+    // The timeout is sent along, so that the leader stops waiting as well
+    let lock = self.call(|mut client| {
+      let mut request = tonic::Request::new(request.clone());
+      request.set_timeout(timeout);
+      async move { client.lock(request).await }
+    });
+    let response = tokio::time::timeout(timeout, lock)
+      .await
+      .map_err(|_| anyhow!("Gave up waiting for lock {} after {:?}", name, timeout))??;
+    Ok(response.token)
+  }
+
+  async fn unlock(&self, name: &str, token: i64) -> Result<bool> {
+    let request = protobuf::ReleaseRequest {
+      name: name.to_string(),
+      token,
+      namespace: String::new(),
+    };
+
+    let response = self
+      .call(|mut client| {
+        let request = request.clone();
+        async move { client.unlock(request).await }
+      })
+      .await?;
+    Ok(response.success)
+  }
+
+  async fn campaign(&self, name: &str, lease: i64, value: &str, timeout: Duration) -> Result<i64> {
+    let request = protobuf::CampaignRequest {
+      name: name.to_string(),
+      lease,
+      value: value.to_string(),
+      namespace: String::new(),
+    };
+
+    let campaign = self.call(|mut client| {
+      let mut request = tonic::Request::new(request.clone());
+      request.set_timeout(timeout);
+      async move { client.campaign(request).await }
+    });
+    let response = tokio::time::timeout(timeout, campaign)
+      .await
+      .map_err(|_| anyhow!("Gave up campaigning for {} after {:?}", name, timeout))??;
+    Ok(response.token)
+  }
+
+  async fn resign(&self, name: &str, token: i64) -> Result<bool> {
+    let request = protobuf::ReleaseRequest {
+      name: name.to_string(),
+      token,
+      namespace: String::new(),
+    };
+
+    let response = self
+      .call(|mut client| {
+        let request = request.clone();
+        async move { client.resign(request).await }
+      })
+      .await?;
+    Ok(response.success)
+  }
+
+  async fn leader(&self, name: &str) -> Result<Option<String>> {
+    let request = protobuf::LeaderRequest {
+      name: name.to_string(),
+      namespace: String::new(),
+    };
+
+    let response = self
+      .call(|mut client| {
+        let request = request.clone();
+        async move { client.leader(request).await }
+      })
+      .await;
+
+    match response {
+      Ok(response) => Ok(response.value),
+      Err(status) if status.code() == Code::NotFound => Ok(None),
+      Err(status) => Err(status.into()),
+    }
+  }
+}
@@ -0,0 +1,19 @@
+use crate::protobuf;
+
+impl protobuf::BackupResponse {
+  pub fn into_meta(self) -> Option<protobuf::BackupMeta> {
+    let p = self.payload?;
+    match p {
+      protobuf::backup_response::Payload::Meta(meta) => Some(*meta),
+      protobuf::backup_response::Payload::Chunk(_) => None,
+    }
+  }
+
+  pub fn into_data_chunk(self) -> Option<Vec<u8>> {
+    let p = self.payload?;
+    match p {
+      protobuf::backup_response::Payload::Meta(_) => None,
+      protobuf::backup_response::Payload::Chunk(chunk) => Some(chunk),
+    }
+  }
+}
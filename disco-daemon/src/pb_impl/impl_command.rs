@@ -0,0 +1,162 @@
+use crate::protobuf;
+use crate::protobuf::command::Command;
+use crate::protobuf::operation::Op;
+
+impl From<protobuf::SetRequest> for protobuf::Command {
+  fn from(req: protobuf::SetRequest) -> Self {
+    protobuf::Command {
+      command: Some(Command::Set(req)),
+      ..Default::default()
+    }
+  }
+}
+
+impl From<protobuf::DeleteRequest> for protobuf::Command {
+  fn from(req: protobuf::DeleteRequest) -> Self {
+    protobuf::Command {
+      command: Some(Command::Delete(req)),
+      ..Default::default()
+    }
+  }
+}
+
+impl From<protobuf::CompareAndSwapRequest> for protobuf::Command {
+  fn from(req: protobuf::CompareAndSwapRequest) -> Self {
+    protobuf::Command {
+      command: Some(Command::CompareAndSwap(req)),
+      ..Default::default()
+    }
+  }
+}
+
+impl From<protobuf::BatchRequest> for protobuf::Command {
+  fn from(req: protobuf::BatchRequest) -> Self {
+    protobuf::Command {
+      command: Some(Command::Batch(req)),
+      ..Default::default()
+    }
+  }
+}
+
+impl From<protobuf::LeaseGrantRequest> for protobuf::Command {
+  fn from(req: protobuf::LeaseGrantRequest) -> Self {
+    protobuf::Command {
+      command: Some(Command::LeaseGrant(req)),
+      ..Default::default()
+    }
+  }
+}
+
+impl From<protobuf::LeaseKeepAliveRequest> for protobuf::Command {
+  fn from(req: protobuf::LeaseKeepAliveRequest) -> Self {
+    protobuf::Command {
+      command: Some(Command::LeaseKeepAlive(req)),
+      ..Default::default()
+    }
+  }
+}
+
+impl From<protobuf::LeaseRevokeRequest> for protobuf::Command {
+  fn from(req: protobuf::LeaseRevokeRequest) -> Self {
+    protobuf::Command {
+      command: Some(Command::LeaseRevoke(req)),
+      ..Default::default()
+    }
+  }
+}
+
+impl From<protobuf::LeaseExpireRequest> for protobuf::Command {
+  fn from(req: protobuf::LeaseExpireRequest) -> Self {
+    protobuf::Command {
+      command: Some(Command::LeaseExpire(req)),
+      ..Default::default()
+    }
+  }
+}
+
+impl From<protobuf::LeaseResetRequest> for protobuf::Command {
+  fn from(req: protobuf::LeaseResetRequest) -> Self {
+    protobuf::Command {
+      command: Some(Command::LeaseReset(req)),
+      ..Default::default()
+    }
+  }
+}
+
+impl From<protobuf::CompactRequest> for protobuf::Command {
+  fn from(req: protobuf::CompactRequest) -> Self {
+    protobuf::Command {
+      command: Some(Command::Compact(req)),
+      ..Default::default()
+    }
+  }
+}
+
+impl From<protobuf::TxnRequest> for protobuf::Command {
+  fn from(req: protobuf::TxnRequest) -> Self {
+    protobuf::Command {
+      command: Some(Command::Txn(req)),
+      ..Default::default()
+    }
+  }
+}
+
+impl From<protobuf::NamespaceRequest> for protobuf::Command {
+  fn from(req: protobuf::NamespaceRequest) -> Self {
+    protobuf::Command {
+      command: Some(Command::Namespace(req)),
+      ..Default::default()
+    }
+  }
+}
+
+impl From<protobuf::DeleteNamespaceRequest> for protobuf::Command {
+  fn from(req: protobuf::DeleteNamespaceRequest) -> Self {
+    protobuf::Command {
+      command: Some(Command::DeleteNamespace(req)),
+      ..Default::default()
+    }
+  }
+}
+
+impl From<protobuf::Policy> for protobuf::Command {
+  fn from(req: protobuf::Policy) -> Self {
+    protobuf::Command {
+      command: Some(Command::Policy(req)),
+      ..Default::default()
+    }
+  }
+}
+
+impl From<protobuf::DeletePolicyRequest> for protobuf::Command {
+  fn from(req: protobuf::DeletePolicyRequest) -> Self {
+    protobuf::Command {
+      command: Some(Command::DeletePolicy(req)),
+      ..Default::default()
+    }
+  }
+}
+
+impl From<protobuf::SetRequest> for protobuf::Operation {
+  fn from(req: protobuf::SetRequest) -> Self {
+    protobuf::Operation {
+      op: Some(Op::Set(req)),
+    }
+  }
+}
+
+impl From<protobuf::DeleteRequest> for protobuf::Operation {
+  fn from(req: protobuf::DeleteRequest) -> Self {
+    protobuf::Operation {
+      op: Some(Op::Delete(req)),
+    }
+  }
+}
+
+impl From<protobuf::CompareAndSwapRequest> for protobuf::Operation {
+  fn from(req: protobuf::CompareAndSwapRequest) -> Self {
+    protobuf::Operation {
+      op: Some(Op::CompareAndSwap(req)),
+    }
+  }
+}
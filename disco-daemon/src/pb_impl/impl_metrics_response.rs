@@ -0,0 +1,61 @@
+use openraft::ServerState;
+
+use crate::protobuf;
+use crate::raft_types::RaftMetrics;
+
+impl From<ServerState> for protobuf::ServerState {
+  fn from(state: ServerState) -> Self {
+    match state {
+      ServerState::Learner => protobuf::ServerState::Learner,
+      ServerState::Follower => protobuf::ServerState::Follower,
+      ServerState::Candidate => protobuf::ServerState::Candidate,
+      ServerState::Leader => protobuf::ServerState::Leader,
+      ServerState::Shutdown => protobuf::ServerState::Shutdown,
+    }
+  }
+}
+
+impl From<&RaftMetrics> for protobuf::MetricsResponse {
+  fn from(metrics: &RaftMetrics) -> Self {
+    // Number of logs up to and including the last one
+    let log_len = metrics.last_log_index.map_or(0, |index| index + 1);
+
+    let replication = metrics
+      .replication
+      .iter()
+      .flatten()
+      .map(|(node_id, matched)| {
+        let heartbeat = metrics
+          .heartbeat
+          .as_ref()
+          .and_then(|heartbeat| heartbeat.get(node_id))
+          .and_then(|acked| acked.as_ref());
+
+        protobuf::ReplicationMetrics {
+          node_id: *node_id,
+          matched: matched.map(Into::into),
+          lag: log_len.saturating_sub(matched.map_or(0, |log_id| log_id.index() + 1)),
+          millis_since_heartbeat: heartbeat.map(|acked| acked.elapsed().as_millis() as u64),
+        }
+      })
+      .collect();
+
+    protobuf::MetricsResponse {
+      membership: Some(metrics.membership_config.membership().clone().into()),
+      node_id: metrics.id,
+      state: protobuf::ServerState::from(metrics.state).into(),
+      current_term: metrics.current_term,
+      vote: Some(metrics.vote),
+      current_leader: metrics.current_leader,
+      last_log_index: metrics.last_log_index,
+      last_applied: metrics.last_applied.map(Into::into),
+      snapshot: metrics.snapshot.map(Into::into),
+      purged: metrics.purged.map(Into::into),
+      replication,
+      error: match &metrics.running_state {
+        Ok(()) => String::new(),
+        Err(fatal) => fatal.to_string(),
+      },
+    }
+  }
+}
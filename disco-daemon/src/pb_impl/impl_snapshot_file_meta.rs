@@ -0,0 +1,28 @@
+use crate::protobuf;
+use crate::raft_types::SnapshotMeta;
+use crate::raft_types::StoredMembership;
+
+impl From<SnapshotMeta> for protobuf::SnapshotFileMeta {
+  fn from(meta: SnapshotMeta) -> Self {
+    protobuf::SnapshotFileMeta {
+      last_log_id: meta.last_log_id.map(|log_id| log_id.into()),
+      last_membership_log_id: meta.last_membership.log_id().map(|log_id| log_id.into()),
+      last_membership: Some(meta.last_membership.membership().clone().into()),
+      snapshot_id: meta.snapshot_id,
+      ..Default::default()
+    }
+  }
+}
+
+impl From<protobuf::SnapshotFileMeta> for SnapshotMeta {
+  fn from(meta: protobuf::SnapshotFileMeta) -> Self {
+    SnapshotMeta {
+      last_log_id: meta.last_log_id.map(|log_id| log_id.into()),
+      last_membership: StoredMembership::new(
+        meta.last_membership_log_id.map(|log_id| log_id.into()),
+        meta.last_membership.unwrap_or_default().into(),
+      ),
+      snapshot_id: meta.snapshot_id,
+    }
+  }
+}
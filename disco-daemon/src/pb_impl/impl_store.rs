@@ -0,0 +1,63 @@
+//! Conversions from the store types used by scripts.
+//!
+//! The keys end up in the default namespace; clients scoped to another one set it afterwards.
+
+use disco_common::store;
+
+use crate::protobuf;
+use crate::protobuf::compare;
+
+impl From<store::Compare> for protobuf::Compare {
+  fn from(cmp: store::Compare) -> Self {
+    let result = match cmp.result {
+      store::CompareResult::Equal => compare::CompareResult::Equal,
+      store::CompareResult::NotEqual => compare::CompareResult::NotEqual,
+      store::CompareResult::Greater => compare::CompareResult::Greater,
+      store::CompareResult::Less => compare::CompareResult::Less,
+    };
+
+    let target = match cmp.target {
+      store::CompareTarget::Value(value) => compare::Target::Value(value),
+      store::CompareTarget::Version(version) => compare::Target::Version(version),
+      store::CompareTarget::ModRevision(revision) => compare::Target::ModRevision(revision),
+      store::CompareTarget::Exists(exists) => compare::Target::Exists(exists),
+      store::CompareTarget::CreateRevision(revision) => compare::Target::CreateRevision(revision),
+    };
+
+    protobuf::Compare {
+      key: cmp.key,
+      result: result.into(),
+      target: Some(target),
+      namespace: String::new(),
+    }
+  }
+}
+
+impl From<store::Op> for protobuf::Operation {
+  fn from(op: store::Op) -> Self {
+    match op {
+      store::Op::Set { key, value } => protobuf::SetRequest {
+        key,
+        value,
+        lease: 0,
+        namespace: String::new(),
+      }
+      .into(),
+      store::Op::Delete { key } => protobuf::DeleteRequest {
+        key,
+        namespace: String::new(),
+      }
+      .into(),
+    }
+  }
+}
+
+impl From<store::Txn> for protobuf::TxnRequest {
+  fn from(txn: store::Txn) -> Self {
+    protobuf::TxnRequest {
+      compare: txn.compare.into_iter().map(Into::into).collect(),
+      success: txn.success.into_iter().map(Into::into).collect(),
+      failure: txn.failure.into_iter().map(Into::into).collect(),
+    }
+  }
+}
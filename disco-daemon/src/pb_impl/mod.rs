@@ -2,12 +2,17 @@
 
 mod impl_append_entries_request;
 mod impl_append_entries_response;
+mod impl_backup_response;
 mod impl_client_write_response;
+mod impl_command;
 mod impl_entry;
 mod impl_leader_id;
 mod impl_log_id;
 mod impl_membership;
+mod impl_metrics_response;
+mod impl_snapshot_file_meta;
 mod impl_snapshot_request;
+mod impl_store;
 mod impl_vote;
 mod impl_vote_request;
 mod impl_vote_response;
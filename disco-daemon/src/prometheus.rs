@@ -0,0 +1,220 @@
+//! Prometheus metrics of the daemon, served over plain HTTP on a separate port.
+//!
+//! Besides the Raft state and the gRPC requests recorded here, the engine and the cloud
+//! providers report through `disco_common::telemetry` to the same recorder.
+
+use std::future::Future;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use metrics::{counter, gauge, histogram};
+use metrics_exporter_prometheus::{BuildError, PrometheusBuilder};
+use tonic::codegen::Service;
+use tonic::codegen::http;
+use tower::Layer;
+
+use crate::node::runtime;
+use crate::protobuf;
+use crate::raft_types::Raft;
+
+/// How often the gauges of the Raft state and the process are refreshed.
+const REFRESH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Installs the recorder, serves the metrics on `addr` and keeps the gauges of `raft` up to date.
+pub fn serve(addr: SocketAddr, raft: Raft) -> Result<(), BuildError> {
+  PrometheusBuilder::new()
+    .with_http_listener(addr)
+    .install()?;
+
+  runtime::spawn(refresh(raft));
+
+  Ok(())
+}
+
+async fn refresh(raft: Raft) {
+  let mut interval = tokio::time::interval(REFRESH_INTERVAL);
+
+  loop {
+    interval.tick().await;
+
+    let metrics = protobuf::MetricsResponse::from(&*raft.metrics().borrow());
+    record_raft(&metrics);
+
+    if let Some(rss) = resident_set_size() {
+      gauge!("disco_process_resident_memory_bytes").set(rss as f64);
+    }
+  }
+}
+
+fn record_raft(metrics: &protobuf::MetricsResponse) {
+  let index = |log_id: Option<protobuf::LogId>| log_id.map_or(0.0, |log_id| log_id.index as f64);
+
+  gauge!("disco_raft_term").set(metrics.current_term as f64);
+  gauge!("disco_raft_is_leader")
+    .set((metrics.state() == protobuf::ServerState::Leader) as u8 as f64);
+  gauge!("disco_raft_last_log_index").set(metrics.last_log_index.unwrap_or_default() as f64);
+  gauge!("disco_raft_last_applied_index").set(index(metrics.last_applied));
+  gauge!("disco_raft_snapshot_index").set(index(metrics.snapshot));
+  gauge!("disco_raft_purged_index").set(index(metrics.purged));
+
+  for replication in &metrics.replication {
+    let node = replication.node_id.to_string();
+
+    gauge!("disco_raft_replication_lag", "node" => node.clone()).set(replication.lag as f64);
+    if let Some(millis) = replication.millis_since_heartbeat {
+      gauge!("disco_raft_heartbeat_age_seconds", "node" => node).set(millis as f64 / 1000.0);
+    }
+  }
+}
+
+/// Resident set size of the process in bytes, from `/proc` where available.
+fn resident_set_size() -> Option<u64> {
+  let status = std::fs::read_to_string("/proc/self/status").ok()?;
+  let line = status.lines().find(|line| line.starts_with("VmRSS:"))?;
+  let kib = line.split_whitespace().nth(1)?.parse::<u64>().ok()?;
+
+  Some(kib * 1024)
+}
+
+/// The `grpc-status` of a request to a path no service routes.
+const UNIMPLEMENTED: &str = "12";
+
+/// The `service` and `method` labels of requests to paths no service routes.
+const UNKNOWN: &str = "unknown";
+
+/// Counts and times the requests to the gRPC services.
+///
+/// Streaming calls are timed until the response headers are sent, not until the stream ends.
+#[derive(Clone, Debug, Default)]
+pub struct GrpcMetricsLayer;
+
+impl<S> Layer<S> for GrpcMetricsLayer {
+  type Service = GrpcMetrics<S>;
+
+  fn layer(&self, inner: S) -> Self::Service {
+    GrpcMetrics { inner }
+  }
+}
+
+#[derive(Clone, Debug)]
+pub struct GrpcMetrics<S> {
+  inner: S,
+}
+
+impl<S, B, R> Service<http::Request<B>> for GrpcMetrics<S>
+where
+  S: Service<http::Request<B>, Response = http::Response<R>>,
+  S::Future: Send + 'static,
+{
+  type Response = S::Response;
+  type Error = S::Error;
+  type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+  fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+    self.inner.poll_ready(cx)
+  }
+
+  fn call(&mut self, request: http::Request<B>) -> Self::Future {
+    // Paths look like `/disco.AppService/Get`
+    let (service, method) = request
+      .uri()
+      .path()
+      .trim_start_matches('/')
+      .split_once('/')
+      .map(|(service, method)| (service.to_string(), method.to_string()))
+      .unwrap_or_default();
+
+    let started = Instant::now();
+    let response = self.inner.call(request);
+
+    Box::pin(async move {
+      let response = response.await;
+
+      // Failed calls carry their status in the headers; successful ones only send it in the
+      // trailers, after the response has been handed back.
+      let code = match &response {
+        Ok(response) => response
+          .headers()
+          .get("grpc-status")
+          .and_then(|code| code.to_str().ok())
+          .unwrap_or("0")
+          .to_string(),
+        Err(_) => "transport".to_string(),
+      };
+
+      // Paths that no service routes are answered with UNIMPLEMENTED. They are counted together,
+      // so that clients cannot add a series per path.
+      let (service, method) = if code == UNIMPLEMENTED {
+        (UNKNOWN.to_string(), UNKNOWN.to_string())
+      } else {
+        (service, method)
+      };
+
+      counter!(
+        "disco_grpc_requests_total",
+        "service" => service.clone(),
+        "method" => method.clone(),
+        "code" => code
+      )
+      .increment(1);
+      histogram!(
+        "disco_grpc_request_duration_seconds",
+        "service" => service,
+        "method" => method
+      )
+      .record(started.elapsed());
+
+      response
+    })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::convert::Infallible;
+
+  use metrics_exporter_prometheus::PrometheusBuilder;
+
+  use super::*;
+
+  #[test]
+  fn test_grpc_metrics_labels() {
+    let recorder = PrometheusBuilder::new().build_recorder();
+    let handle = recorder.handle();
+
+    metrics::with_local_recorder(&recorder, || {
+      let mut service =
+        GrpcMetricsLayer.layer(tower::service_fn(|request: http::Request<()>| async move {
+          let response = match request.uri().path() {
+            "/disco.AppService/Get" => http::Response::builder(),
+            "/disco.AppService/Set" => http::Response::builder().header("grpc-status", "9"),
+            _ => http::Response::builder().header("grpc-status", UNIMPLEMENTED),
+          };
+          Ok::<_, Infallible>(response.body(()).unwrap())
+        }));
+
+      for path in [
+        "/disco.AppService/Get",
+        "/disco.AppService/Set",
+        "/disco.AppService/Missing",
+        "/missing",
+        "/",
+      ] {
+        let request = http::Request::builder().uri(path).body(()).unwrap();
+        futures::executor::block_on(service.call(request)).unwrap();
+      }
+    });
+
+    let rendered = handle.render();
+    for series in [
+      r#"disco_grpc_requests_total{service="disco.AppService",method="Get",code="0"} 1"#,
+      r#"disco_grpc_requests_total{service="disco.AppService",method="Set",code="9"} 1"#,
+      r#"disco_grpc_requests_total{service="unknown",method="unknown",code="12"} 3"#,
+    ] {
+      assert!(rendered.contains(series), "{} not in\n{}", series, rendered);
+    }
+    assert!(!rendered.contains("missing") && !rendered.contains("Missing"));
+  }
+}
@@ -1,5 +1,12 @@
-use config::{Config, ConfigError, Environment, File};
+use config::builder::DefaultState;
+use config::{Config, ConfigBuilder, ConfigError, Environment, File};
+use openraft::SnapshotPolicy;
 use serde::Deserialize;
+use std::io;
+use std::sync::Arc;
+
+use crate::store::crypto::{CommandKeyProvider, FileKeyProvider, KeyProvider, Keyring};
+use crate::tls::NodeIdentities;
 
 #[derive(Debug, Deserialize)]
 pub struct Settings {
@@ -7,20 +14,40 @@ pub struct Settings {
   pub election_timeout_min: u64,
   pub election_timeout_max: u64,
   pub heartbeat_interval: u64,
+  /// Time in milliseconds to connect to a follower sending it a snapshot, and for it to take
+  /// each chunk
   pub install_snapshot_timeout: u64,
   pub external_commands_max: usize,
+  pub snapshots_to_keep: usize,
+  /// Take a snapshot once this many logs were applied since the last one, 0 to disable
+  pub snapshot_logs: u64,
+  /// Take a snapshot once the logs since the last one take up this many bytes, 0 to disable
+  pub snapshot_bytes: u64,
+  /// Take a snapshot at least this often if logs were applied, in seconds, 0 to disable
+  pub snapshot_interval_secs: u64,
+  /// Number of logs kept after they were included in a snapshot, for lagging followers to
+  /// catch up without a snapshot
+  pub max_in_snapshot_log_to_keep: u64,
+  /// Minimum number of logs purged at once
+  pub purge_batch_size: u64,
+  /// Maximum number of entries sent to a follower in one append request
+  pub max_payload_entries: u64,
+  /// Number of logs a follower may lag behind before it is sent a snapshot instead
+  pub replication_lag_threshold: u64,
+  /// File holding the master keys that encrypt the data directory, one `<id>:<hex key>` per
+  /// line with the current key last
+  pub encryption_key_file: Option<String>,
+  /// Command printing a master key as `<id>:<hex key>`: the current one when run without
+  /// arguments, the one with the given id otherwise
+  pub encryption_key_command: Option<String>,
+  /// Also recognize the nodes by the identity recorded for them, `localhost` if none, while
+  /// reissuing certificates without the URI `disco://<cluster>/node/<id>`
+  pub legacy_tls_identities: bool,
 }
 
 impl Settings {
   pub fn new() -> Result<Self, ConfigError> {
-    let config = Config::builder()
-      // Start with default values
-      .set_default("cluster_name", "cluster")?
-      .set_default("election_timeout_min", 150)?
-      .set_default("election_timeout_max", 300)?
-      .set_default("heartbeat_interval", 50)?
-      .set_default("install_snapshot_timeout", 120)?
-      .set_default("external_commands_max", 100)?
+    let config = Self::defaults()?
       // Load from a config file
       // Will look for config.yaml, config.json, config.toml, etc.
       .add_source(File::with_name("config").required(false))
@@ -29,6 +56,242 @@ impl Settings {
       .build()?;
 
     // Deserialize the configuration into our Settings struct
-    config.try_deserialize()
+    let settings: Settings = config.try_deserialize()?;
+    settings.validate()?;
+
+    Ok(settings)
+  }
+
+  fn defaults() -> Result<ConfigBuilder<DefaultState>, ConfigError> {
+    Config::builder()
+      .set_default("cluster_name", "cluster")?
+      .set_default("election_timeout_min", 150)?
+      .set_default("election_timeout_max", 300)?
+      .set_default("heartbeat_interval", 50)?
+      .set_default("install_snapshot_timeout", 120)?
+      .set_default("external_commands_max", 100)?
+      .set_default("snapshots_to_keep", 3)?
+      .set_default("snapshot_logs", 5000)?
+      .set_default("snapshot_bytes", 64 * 1024 * 1024)?
+      .set_default("snapshot_interval_secs", 3600)?
+      .set_default("max_in_snapshot_log_to_keep", 1000)?
+      .set_default("purge_batch_size", 1)?
+      .set_default("max_payload_entries", 300)?
+      .set_default("replication_lag_threshold", 5000)?
+      .set_default("legacy_tls_identities", false)
+  }
+
+  /// Checks the settings against each other, naming the offending ones.
+  fn validate(&self) -> Result<(), ConfigError> {
+    let invalid = |message: String| Err(ConfigError::Message(message));
+
+    if self.election_timeout_min >= self.election_timeout_max {
+      return invalid(format!(
+        "election_timeout_min ({}) must be less than election_timeout_max ({})",
+        self.election_timeout_min, self.election_timeout_max
+      ));
+    }
+    if self.heartbeat_interval >= self.election_timeout_min {
+      return invalid(format!(
+        "heartbeat_interval ({}) must be less than election_timeout_min ({})",
+        self.heartbeat_interval, self.election_timeout_min
+      ));
+    }
+    if self.install_snapshot_timeout < self.heartbeat_interval {
+      return invalid(format!(
+        "install_snapshot_timeout ({}) must not be less than heartbeat_interval ({})",
+        self.install_snapshot_timeout, self.heartbeat_interval
+      ));
+    }
+    if self.snapshots_to_keep == 0 {
+      return invalid("snapshots_to_keep must be at least 1".to_string());
+    }
+    if self.purge_batch_size == 0 {
+      return invalid("purge_batch_size must be at least 1".to_string());
+    }
+    if self.max_payload_entries == 0 {
+      return invalid("max_payload_entries must be at least 1".to_string());
+    }
+    if self.replication_lag_threshold < self.max_payload_entries {
+      return invalid(format!(
+        "replication_lag_threshold ({}) must not be less than max_payload_entries ({})",
+        self.replication_lag_threshold, self.max_payload_entries
+      ));
+    }
+
+    Ok(())
+  }
+
+  /// Builds the Raft configuration of this node.
+  ///
+  /// Raft only triggers snapshots by log count; the byte and interval triggers are checked by
+  /// the node.
+  pub fn raft_config(&self) -> Result<openraft::Config, ConfigError> {
+    let snapshot_policy = match self.snapshot_logs {
+      0 => SnapshotPolicy::Never,
+      logs => SnapshotPolicy::LogsSinceLast(logs),
+    };
+
+    openraft::Config {
+      cluster_name: self.cluster_name.clone(),
+      election_timeout_min: self.election_timeout_min,
+      election_timeout_max: self.election_timeout_max,
+      heartbeat_interval: self.heartbeat_interval,
+      install_snapshot_timeout: self.install_snapshot_timeout,
+      snapshot_policy,
+      max_in_snapshot_log_to_keep: self.max_in_snapshot_log_to_keep,
+      purge_batch_size: self.purge_batch_size,
+      max_payload_entries: self.max_payload_entries,
+      replication_lag_threshold: self.replication_lag_threshold,
+      ..Default::default()
+    }
+    .validate()
+    .map_err(|e| ConfigError::Message(format!("invalid raft settings: {}", e)))
+  }
+
+  /// Returns the identities the nodes of the cluster are recognized by.
+  pub fn node_identities(&self) -> NodeIdentities {
+    NodeIdentities::new(&self.cluster_name, self.legacy_tls_identities)
+  }
+
+  /// Returns the keyring encrypting the data directory, `None` if encryption at rest is off.
+  pub fn keyring(&self) -> io::Result<Option<Arc<Keyring>>> {
+    let provider: Box<dyn KeyProvider> =
+      match (&self.encryption_key_file, &self.encryption_key_command) {
+        (None, None) => return Ok(None),
+        (Some(path), None) => Box::new(FileKeyProvider::new(path)),
+        (None, Some(command)) => Box::new(CommandKeyProvider::new(command)),
+        (Some(_), Some(_)) => {
+          return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "encryption_key_file and encryption_key_command cannot both be set",
+          ));
+        }
+      };
+
+    let keyring = Keyring::new(provider);
+
+    // Fail now rather than on the first write if the current key cannot be obtained
+    let key_id = keyring.current_key_id()?;
+    tracing::info!("Encrypting data at rest with key {}", key_id);
+
+    Ok(Some(Arc::new(keyring)))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use config::FileFormat;
+
+  use super::*;
+
+  fn defaults() -> Settings {
+    Settings::defaults()
+      .unwrap()
+      .build()
+      .unwrap()
+      .try_deserialize()
+      .unwrap()
+  }
+
+  fn assert_invalid(settings: Settings, setting: &str) {
+    match settings.validate() {
+      Err(ConfigError::Message(message)) => assert!(message.contains(setting), "{}", message),
+      result => panic!("expected {} to be rejected, got {:?}", setting, result),
+    }
+  }
+
+  #[test]
+  fn test_defaults_are_valid() {
+    let settings = defaults();
+    settings.validate().unwrap();
+    settings.raft_config().unwrap();
+  }
+
+  #[test]
+  fn test_config_file_agrees_with_defaults() {
+    let settings: Settings = Settings::defaults()
+      .unwrap()
+      .add_source(File::from_str(
+        include_str!("../config.yaml"),
+        FileFormat::Yaml,
+      ))
+      .build()
+      .unwrap()
+      .try_deserialize()
+      .unwrap();
+    settings.validate().unwrap();
+
+    let defaults = defaults();
+    assert_eq!(settings.snapshot_logs, defaults.snapshot_logs);
+    assert_eq!(settings.snapshot_bytes, defaults.snapshot_bytes);
+    assert_eq!(
+      settings.snapshot_interval_secs,
+      defaults.snapshot_interval_secs
+    );
+    assert_eq!(
+      settings.install_snapshot_timeout,
+      defaults.install_snapshot_timeout
+    );
+  }
+
+  #[test]
+  fn test_validate() {
+    assert_invalid(
+      Settings {
+        election_timeout_min: 300,
+        ..defaults()
+      },
+      "election_timeout_min",
+    );
+    assert_invalid(
+      Settings {
+        heartbeat_interval: 150,
+        ..defaults()
+      },
+      "heartbeat_interval",
+    );
+    assert_invalid(
+      Settings {
+        install_snapshot_timeout: 0,
+        ..defaults()
+      },
+      "install_snapshot_timeout",
+    );
+    assert_invalid(
+      Settings {
+        snapshots_to_keep: 0,
+        ..defaults()
+      },
+      "snapshots_to_keep",
+    );
+    assert_invalid(
+      Settings {
+        purge_batch_size: 0,
+        ..defaults()
+      },
+      "purge_batch_size",
+    );
+    assert_invalid(
+      Settings {
+        replication_lag_threshold: 10,
+        ..defaults()
+      },
+      "replication_lag_threshold",
+    );
+  }
+
+  #[test]
+  fn test_snapshot_policy() {
+    let config = Settings {
+      snapshot_logs: 0,
+      ..defaults()
+    }
+    .raft_config()
+    .unwrap();
+    assert_eq!(config.snapshot_policy, SnapshotPolicy::Never);
+
+    let config = defaults().raft_config().unwrap();
+    assert_eq!(config.snapshot_policy, SnapshotPolicy::LogsSinceLast(5000));
   }
 }
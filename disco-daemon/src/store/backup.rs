@@ -0,0 +1,556 @@
+//! Backups of the state machine, taken from a running cluster and used to seed a new one.
+//!
+//! A backup file starts with a magic number and the length-delimited `BackupMeta`, followed by
+//! the snapshot data whose size and CRC32 are recorded in the meta.
+//!
+//! A restored data directory records the id of the snapshot it was seeded with, so that a node
+//! restarted with the same backup keeps the state it has built since.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::io::BufRead;
+use std::io::Read;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use openraft::storage::RaftLogStorage;
+use prost::Message;
+use tokio::io::AsyncWriteExt;
+
+use super::Keyring;
+use super::LOG_DIR;
+use super::LogStore;
+use super::SNAPSHOT_DIR;
+use super::file;
+use super::snapshot::SnapshotStore;
+use crate::protobuf as pb;
+use crate::raft_types::SnapshotMeta;
+
+const MAGIC: &[u8; 8] = b"DISCOBK1";
+
+/// File in a restored data directory holding the id of the snapshot of the backup.
+const RESTORED_FILE: &str = "restored";
+
+/// Streams a backup received from a node into a file.
+///
+/// The data is written next to the destination and only moved into place once it matches the
+/// size and checksum announced in the meta.
+#[derive(Debug)]
+pub struct BackupWriter {
+  path: PathBuf,
+  partial: PathBuf,
+  file: tokio::fs::File,
+  expected: pb::SnapshotFileMeta,
+  hasher: crc32fast::Hasher,
+  size: u64,
+}
+
+impl BackupWriter {
+  pub async fn create(path: impl AsRef<Path>, meta: &pb::BackupMeta) -> io::Result<Self> {
+    let path = path.as_ref().to_path_buf();
+    let partial = path.with_extension("partial");
+
+    let mut file = tokio::fs::File::create(&partial).await?;
+    file.write_all(MAGIC).await?;
+    file
+      .write_all(&meta.encode_length_delimited_to_vec())
+      .await?;
+
+    Ok(BackupWriter {
+      path,
+      partial,
+      file,
+      expected: meta.snapshot.clone().unwrap_or_default(),
+      hasher: crc32fast::Hasher::new(),
+      size: 0,
+    })
+  }
+
+  pub async fn write(&mut self, chunk: &[u8]) -> io::Result<()> {
+    self.hasher.update(chunk);
+    self.size += chunk.len() as u64;
+    self.file.write_all(chunk).await
+  }
+
+  /// Checks the received data and moves the file into place.
+  pub async fn finish(mut self) -> io::Result<PathBuf> {
+    let checksum = self.hasher.clone().finalize();
+    if self.size != self.expected.size || checksum != self.expected.checksum {
+      let _ = tokio::fs::remove_file(&self.partial).await;
+      return Err(corrupt(&self.path, &self.expected, self.size, checksum));
+    }
+
+    self.file.flush().await?;
+    self.file.sync_all().await?;
+    tokio::fs::rename(&self.partial, &self.path).await?;
+
+    Ok(self.path)
+  }
+}
+
+/// Reads a backup file, failing if its data does not match the meta.
+pub fn read(path: impl AsRef<Path>) -> io::Result<(pb::BackupMeta, Vec<u8>)> {
+  let path = path.as_ref();
+  let contents = fs::read(path)?;
+
+  let Some(mut rest) = contents.strip_prefix(MAGIC.as_slice()) else {
+    return Err(not_backup(path));
+  };
+
+  let meta = pb::BackupMeta::decode_length_delimited(&mut rest)
+    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+  let expected = meta.snapshot.clone().unwrap_or_default();
+  let checksum = crc32fast::hash(rest);
+  if rest.len() as u64 != expected.size || checksum != expected.checksum {
+    return Err(corrupt(path, &expected, rest.len() as u64, checksum));
+  }
+
+  Ok((meta, rest.to_vec()))
+}
+
+/// Reads the meta of a backup file, leaving its data alone.
+pub fn read_meta(path: impl AsRef<Path>) -> io::Result<pb::BackupMeta> {
+  let path = path.as_ref();
+  let mut reader = io::BufReader::new(fs::File::open(path)?);
+
+  let mut magic = [0u8; MAGIC.len()];
+  reader.read_exact(&mut magic)?;
+  if &magic != MAGIC {
+    return Err(not_backup(path));
+  }
+
+  let len = prost::decode_length_delimiter(reader.fill_buf()?)
+    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+  reader.consume(prost::length_delimiter_len(len));
+
+  let mut meta = vec![0u8; len];
+  reader.read_exact(&mut meta)?;
+
+  pb::BackupMeta::decode(meta.as_slice()).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/// Whether `data_dir` was already seeded with `backup`, which is then not restored again.
+///
+/// Fails if it was seeded with another backup. A backup that no longer exists is assumed to be
+/// the one the data directory was seeded with.
+pub fn is_restored(backup: impl AsRef<Path>, data_dir: impl AsRef<Path>) -> io::Result<bool> {
+  let restored = match fs::read_to_string(data_dir.as_ref().join(RESTORED_FILE)) {
+    Ok(snapshot_id) => snapshot_id,
+    Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(false),
+    Err(e) => return Err(e),
+  };
+
+  let meta = match read_meta(&backup) {
+    Ok(meta) => meta,
+    Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(true),
+    Err(e) => return Err(e),
+  };
+
+  let snapshot_id = meta.snapshot.unwrap_or_default().snapshot_id;
+  if snapshot_id != restored {
+    return Err(io::Error::new(
+      io::ErrorKind::AlreadyExists,
+      format!(
+        "{} was restored from snapshot {}, not from snapshot {} of {}",
+        data_dir.as_ref().display(),
+        restored,
+        snapshot_id,
+        backup.as_ref().display()
+      ),
+    ));
+  }
+
+  Ok(true)
+}
+
+fn not_backup(path: &Path) -> io::Error {
+  io::Error::new(
+    io::ErrorKind::InvalidData,
+    format!("{} is not a disco backup", path.display()),
+  )
+}
+
+fn corrupt(path: &Path, expected: &pb::SnapshotFileMeta, size: u64, checksum: u32) -> io::Error {
+  io::Error::new(
+    io::ErrorKind::InvalidData,
+    format!(
+      "backup {} is corrupt: expected {} bytes with crc32 {:08x}, found {} bytes with crc32 {:08x}",
+      path.display(),
+      expected.size,
+      expected.checksum,
+      size,
+      checksum
+    ),
+  )
+}
+
+/// Seeds the empty data directory of `node` with a backup, making it the only member of a new
+/// cluster that starts from the state of the backup.
+///
+/// The membership recorded in the backup is replaced, as the nodes it names belong to the cluster
+/// the backup was taken from. With a keyring, the restored state is encrypted. Returns the meta of
+/// the backup.
+pub async fn restore(
+  backup: impl AsRef<Path>,
+  data_dir: impl AsRef<Path>,
+  node: pb::Node,
+  keyring: Option<Arc<Keyring>>,
+) -> io::Result<pb::BackupMeta> {
+  let data_dir = data_dir.as_ref();
+
+  let is_empty = match fs::read_dir(data_dir) {
+    Ok(mut entries) => entries.next().is_none(),
+    Err(e) if e.kind() == io::ErrorKind::NotFound => true,
+    Err(e) => return Err(e),
+  };
+
+  if !is_empty {
+    return Err(io::Error::new(
+      io::ErrorKind::AlreadyExists,
+      format!(
+        "refusing to restore into {}: the data directory is not empty",
+        data_dir.display()
+      ),
+    ));
+  }
+
+  let (meta, data) = read(backup)?;
+  let snapshot = meta.snapshot.clone().unwrap_or_default();
+
+  let Some(last_log_id) = snapshot.last_log_id else {
+    return Err(io::Error::new(
+      io::ErrorKind::InvalidData,
+      "the backup holds no applied log entries",
+    ));
+  };
+
+  let mut sm = pb::StateMachineData::decode(data.as_slice())
+    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+  let node_id = node.node_id;
+  let membership = pb::Membership {
+    configs: vec![pb::NodeIdSet {
+      node_ids: BTreeMap::from([(node.node_id, ())]),
+    }],
+    nodes: BTreeMap::from([(node.node_id, node)]),
+  };
+
+  // The membership takes effect at the last log id of the backup, as if it had been applied there.
+  sm.last_membership = Some(membership.clone());
+  sm.last_membership_log_id = Some(last_log_id);
+
+  let restored = SnapshotMeta::from(pb::SnapshotFileMeta {
+    last_log_id: Some(last_log_id),
+    last_membership_log_id: Some(last_log_id),
+    last_membership: Some(membership),
+    snapshot_id: format!("{}-restored", snapshot.snapshot_id),
+    ..Default::default()
+  });
+
+  SnapshotStore::open(data_dir.join(SNAPSHOT_DIR), 1, keyring.clone())?
+    .save(&restored, &sm.encode_to_vec())?;
+
+  // The log continues right after the snapshot. The node votes for itself in the term of the
+  // backup, as a vote older than the last log id would keep it from ever being elected.
+  let mut log_store = LogStore::open(data_dir.join(LOG_DIR), keyring)?;
+  log_store
+    .purge(last_log_id.into())
+    .await
+    .map_err(io::Error::other)?;
+  log_store
+    .save_vote(&pb::Vote {
+      leader_id: Some(pb::LeaderId {
+        term: last_log_id.term,
+        node_id,
+      }),
+      committed: false,
+    })
+    .await
+    .map_err(io::Error::other)?;
+
+  file::write_atomic(
+    &data_dir.join(RESTORED_FILE),
+    snapshot.snapshot_id.as_bytes(),
+  )?;
+
+  tracing::info!(
+    "Restored snapshot {} of cluster {} into {}",
+    snapshot.snapshot_id,
+    meta.cluster_name,
+    data_dir.display()
+  );
+
+  Ok(meta)
+}
+
+#[cfg(test)]
+mod tests {
+  use std::time::Duration;
+
+  use openraft::RaftNetworkFactory;
+  use openraft::ServerState;
+  use openraft::error::ReplicationClosed;
+  use openraft::network::RPCOption;
+  use openraft::network::v2::RaftNetworkV2;
+  use openraft::raft::ReadPolicy;
+  use openraft::storage::RaftLogReader;
+  use tempfile::TempDir;
+
+  use super::*;
+  use crate::TypeConfig;
+  use crate::raft_types::*;
+  use crate::store::StateMachineStore;
+  use crate::store::command::tests::{set, value};
+  use crate::store::crypto::tests::keyring;
+
+  /// The network of a cluster with a single member, which never sends anything.
+  struct NoNetwork;
+
+  impl RaftNetworkFactory<TypeConfig> for NoNetwork {
+    type Network = NoNetwork;
+
+    async fn new_client(&mut self, _target: u64, _node: &Node) -> Self::Network {
+      NoNetwork
+    }
+  }
+
+  impl RaftNetworkV2<TypeConfig> for NoNetwork {
+    async fn append_entries(
+      &mut self,
+      _req: AppendEntriesRequest,
+      _option: RPCOption,
+    ) -> Result<AppendEntriesResponse, RPCError> {
+      unreachable!("a single member has no one to replicate to")
+    }
+
+    async fn full_snapshot(
+      &mut self,
+      _vote: Vote,
+      _snapshot: Snapshot,
+      _cancel: impl Future<Output = ReplicationClosed> + openraft::OptionalSend + 'static,
+      _option: RPCOption,
+    ) -> Result<SnapshotResponse, StreamingError> {
+      unreachable!("a single member has no one to send snapshots to")
+    }
+
+    async fn vote(
+      &mut self,
+      _req: VoteRequest,
+      _option: RPCOption,
+    ) -> Result<VoteResponse, RPCError> {
+      unreachable!("a single member has no one to ask for votes")
+    }
+  }
+
+  /// Returns the meta and data of a backup of a state machine at revision 7.
+  fn backup(snapshot_id: &str) -> (pb::BackupMeta, Vec<u8>) {
+    let last_log_id = pb::LogId { term: 2, index: 42 };
+    let data = pb::StateMachineData {
+      revision: 7,
+      last_applied: Some(last_log_id),
+      ..Default::default()
+    }
+    .encode_to_vec();
+
+    let meta = pb::BackupMeta {
+      cluster_name: "test".to_string(),
+      snapshot: Some(pb::SnapshotFileMeta {
+        last_log_id: Some(last_log_id),
+        snapshot_id: snapshot_id.to_string(),
+        size: data.len() as u64,
+        checksum: crc32fast::hash(&data),
+        ..Default::default()
+      }),
+      created_at: 1,
+    };
+
+    (meta, data)
+  }
+
+  async fn write(path: &Path, meta: &pb::BackupMeta, data: &[u8]) -> io::Result<PathBuf> {
+    let mut writer = BackupWriter::create(path, meta).await?;
+    for chunk in data.chunks(16) {
+      writer.write(chunk).await?;
+    }
+    writer.finish().await
+  }
+
+  fn node() -> pb::Node {
+    pb::Node {
+      node_id: 5,
+      rpc_addr: "127.0.0.1:5000".to_string(),
+      ..Default::default()
+    }
+  }
+
+  #[tokio::test]
+  async fn test_write_and_read() {
+    let dir = TempDir::new().unwrap();
+    let path = dir.path().join("test.bak");
+    let (meta, data) = backup("2-42-1");
+
+    write(&path, &meta, &data).await.unwrap();
+    assert_eq!(read(&path).unwrap(), (meta.clone(), data));
+    assert_eq!(read_meta(&path).unwrap(), meta);
+  }
+
+  #[tokio::test]
+  async fn test_reject_corrupt_backups() {
+    let dir = TempDir::new().unwrap();
+    let path = dir.path().join("test.bak");
+    let (meta, data) = backup("2-42-1");
+
+    // A short transfer is dropped rather than moved into place
+    let err = write(&path, &meta, &data[1..]).await.unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    assert!(!path.exists());
+    assert!(!path.with_extension("partial").exists());
+
+    write(&path, &meta, &data).await.unwrap();
+    let mut contents = fs::read(&path).unwrap();
+    *contents.last_mut().unwrap() ^= 1;
+    fs::write(&path, &contents).unwrap();
+    assert_eq!(read(&path).unwrap_err().kind(), io::ErrorKind::InvalidData);
+
+    fs::write(&path, b"not a backup").unwrap();
+    assert_eq!(read(&path).unwrap_err().kind(), io::ErrorKind::InvalidData);
+    assert_eq!(
+      read_meta(&path).unwrap_err().kind(),
+      io::ErrorKind::InvalidData
+    );
+  }
+
+  #[tokio::test]
+  async fn test_restore() {
+    let dir = TempDir::new().unwrap();
+    let path = dir.path().join("test.bak");
+    let (meta, data) = backup("2-42-1");
+    write(&path, &meta, &data).await.unwrap();
+
+    let data_dir = dir.path().join("data");
+    assert_eq!(restore(&path, &data_dir, node(), None).await.unwrap(), meta);
+
+    let snapshots = SnapshotStore::open(data_dir.join(SNAPSHOT_DIR), 1, None).unwrap();
+    let (snapshot, file) = snapshots.latest().unwrap().unwrap();
+    assert_eq!(snapshot.snapshot_id, "2-42-1-restored");
+
+    let sm = file.decode::<pb::StateMachineData>().await.unwrap();
+    assert_eq!(sm.revision, 7);
+    assert_eq!(sm.last_membership.unwrap().nodes[&5], node());
+    assert!(data_dir.join(LOG_DIR).exists());
+
+    // The data directory is only ever seeded once
+    let err = restore(&path, &data_dir, node(), None).await.unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+  }
+
+  #[tokio::test]
+  async fn test_restored_node_is_elected() {
+    let dir = TempDir::new().unwrap();
+    let path = dir.path().join("test.bak");
+    let (meta, data) = backup("2-42-1");
+    write(&path, &meta, &data).await.unwrap();
+
+    let data_dir = dir.path().join("data");
+    restore(&path, &data_dir, node(), None).await.unwrap();
+
+    let mut log_store = LogStore::open(data_dir.join(LOG_DIR), None).unwrap();
+    let vote = log_store.read_vote().await.unwrap().unwrap();
+    assert_eq!(
+      vote.leader_id,
+      Some(pb::LeaderId {
+        term: 2,
+        node_id: 5
+      })
+    );
+
+    let state_machine_store = Arc::new(
+      StateMachineStore::open(data_dir.join(SNAPSHOT_DIR), 1, None)
+        .await
+        .unwrap(),
+    );
+    let raft = Raft::new(
+      5,
+      Arc::new(openraft::Config::default().validate().unwrap()),
+      NoNetwork,
+      log_store,
+      state_machine_store.clone(),
+    )
+    .await
+    .unwrap();
+
+    raft
+      .wait(Some(Duration::from_secs(10)))
+      .state(ServerState::Leader, "the restored node is elected")
+      .await
+      .unwrap();
+
+    let response = raft
+      .client_write(pb::Command::from(set("k", "v")))
+      .await
+      .unwrap();
+    assert!(response.data.success);
+
+    raft
+      .ensure_linearizable(ReadPolicy::ReadIndex)
+      .await
+      .unwrap();
+    {
+      let sm = state_machine_store.state_machine.lock().unwrap();
+      assert_eq!(value(&sm, "k").as_deref(), Some("v"));
+      assert_eq!(sm.revision, 8);
+    }
+
+    raft.shutdown().await.unwrap();
+  }
+
+  #[tokio::test]
+  async fn test_restore_encrypted() {
+    let dir = TempDir::new().unwrap();
+    let path = dir.path().join("test.bak");
+    let (meta, data) = backup("2-42-1");
+    write(&path, &meta, &data).await.unwrap();
+
+    let keyring = Arc::new(keyring(&dir.path().join("keys"), &["k1"]));
+    let data_dir = dir.path().join("data");
+    restore(&path, &data_dir, node(), Some(keyring.clone()))
+      .await
+      .unwrap();
+
+    let snapshots = SnapshotStore::open(data_dir.join(SNAPSHOT_DIR), 1, Some(keyring)).unwrap();
+    let (_, file) = snapshots.latest().unwrap().unwrap();
+    let sm = file.decode::<pb::StateMachineData>().await.unwrap();
+    assert_eq!(sm.revision, 7);
+
+    let plain = SnapshotStore::open(data_dir.join(SNAPSHOT_DIR), 1, None).unwrap();
+    assert!(plain.latest().is_err());
+  }
+
+  #[tokio::test]
+  async fn test_is_restored() {
+    let dir = TempDir::new().unwrap();
+    let path = dir.path().join("test.bak");
+    let (meta, data) = backup("2-42-1");
+    write(&path, &meta, &data).await.unwrap();
+
+    let data_dir = dir.path().join("data");
+    assert!(!is_restored(&path, &data_dir).unwrap());
+
+    restore(&path, &data_dir, node(), None).await.unwrap();
+    assert!(is_restored(&path, &data_dir).unwrap());
+
+    // Another backup does not match the seeded data directory
+    let other = dir.path().join("other.bak");
+    let (meta, data) = backup("3-50-1");
+    write(&other, &meta, &data).await.unwrap();
+    let err = is_restored(&other, &data_dir).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+
+    // A backup removed since it was restored is assumed to match
+    fs::remove_file(&path).unwrap();
+    assert!(is_restored(&path, &data_dir).unwrap());
+  }
+}
@@ -0,0 +1,581 @@
+//! Applies replicated commands to the state machine data.
+
+use super::lease;
+use super::mvcc;
+use super::namespace;
+use super::policy;
+use crate::protobuf as pb;
+use crate::protobuf::command::Command;
+use crate::protobuf::compare::CompareResult;
+use crate::protobuf::compare::Target;
+use crate::protobuf::event::EventType;
+use crate::protobuf::operation::Op;
+
+/// A change made by a batch, recorded so that it can be rolled back.
+struct Undo {
+  key: String,
+  replaced: Option<pb::KeyRevision>,
+  prev_lease: Option<i64>,
+}
+
+/// Applies a command and returns its outcome, appending the changes it made to `events`.
+///
+/// Commands are deterministic, so every node computes the same response for the same entry. A
+/// command that changes any key advances the store revision by one; all of its events carry the
+/// new revision.
+pub fn apply(
+  sm: &mut pb::StateMachineData,
+  command: pb::Command,
+  events: &mut Vec<pb::Event>,
+) -> pb::Response {
+  let now = command.timestamp;
+  let first_event = events.len();
+
+  let mut response = match command.command {
+    Some(Command::Set(req)) => apply_op(sm, Op::Set(req), &mut Vec::new(), events),
+    Some(Command::Delete(req)) => apply_op(sm, Op::Delete(req), &mut Vec::new(), events),
+    Some(Command::CompareAndSwap(req)) => {
+      apply_op(sm, Op::CompareAndSwap(req), &mut Vec::new(), events)
+    }
+    Some(Command::Batch(batch)) => apply_batch(sm, batch, events),
+    Some(Command::LeaseGrant(req)) => lease::grant(sm, req, now),
+    Some(Command::LeaseKeepAlive(req)) => lease::keep_alive(sm, req, now),
+    Some(Command::LeaseRevoke(req)) => lease::revoke(sm, req.id, events),
+    Some(Command::LeaseExpire(req)) => lease::expire(sm, req, now, events),
+    Some(Command::LeaseReset(_)) => lease::reset(sm, now),
+    Some(Command::Compact(req)) => mvcc::compact(sm, req),
+    Some(Command::Txn(txn)) => apply_txn(sm, txn, events),
+    Some(Command::Namespace(req)) => namespace::set_quotas(sm, req),
+    Some(Command::DeleteNamespace(req)) => namespace::delete(sm, req, events),
+    Some(Command::Policy(req)) => policy::set(sm, req),
+    Some(Command::DeletePolicy(req)) => policy::delete(sm, req),
+    None => pb::Response::default(),
+  };
+
+  if events.len() > first_event {
+    sm.revision += 1;
+
+    for event in &mut events[first_event..] {
+      event.revision = sm.revision;
+    }
+  }
+
+  response.revision = sm.revision;
+  response
+}
+
+/// Records a change to a stored key; the revision is assigned once the whole command has been
+/// applied.
+pub fn event(stored: String, value: Option<String>) -> pb::Event {
+  let event_type = match value {
+    Some(_) => EventType::Put,
+    None => EventType::Delete,
+  };
+
+  let (namespace, key) = namespace::split(&stored);
+
+  pb::Event {
+    r#type: event_type.into(),
+    key: key.to_string(),
+    value,
+    revision: 0,
+    namespace: namespace.to_string(),
+  }
+}
+
+/// Applies the operations of a batch in order, rolling all of them back if one fails.
+fn apply_batch(
+  sm: &mut pb::StateMachineData,
+  batch: pb::BatchRequest,
+  events: &mut Vec<pb::Event>,
+) -> pb::Response {
+  let first_event = events.len();
+  let mut undo = Vec::new();
+  let mut responses = Vec::with_capacity(batch.operations.len());
+  let mut success = true;
+
+  for op in batch.operations.into_iter().filter_map(|op| op.op) {
+    let response = apply_op(sm, op, &mut undo, events);
+    success &= response.success;
+    responses.push(response);
+
+    if !success {
+      break;
+    }
+  }
+
+  if !success {
+    events.truncate(first_event);
+
+    for Undo {
+      key,
+      replaced,
+      prev_lease,
+    } in undo.into_iter().rev()
+    {
+      match prev_lease {
+        Some(lease) => sm.key_leases.insert(key.clone(), lease),
+        None => sm.key_leases.remove(&key),
+      };
+      mvcc::undo(sm, &key, replaced);
+    }
+  }
+
+  pb::Response {
+    success,
+    quota_exceeded: responses.iter().any(|response| response.quota_exceeded),
+    responses,
+    ..Default::default()
+  }
+}
+
+/// Applies the success or the failure branch of a transaction, depending on its compares.
+///
+/// The chosen branch is applied like a batch, so a failing compare-and-swap inside it rolls the
+/// whole branch back.
+fn apply_txn(
+  sm: &mut pb::StateMachineData,
+  txn: pb::TxnRequest,
+  events: &mut Vec<pb::Event>,
+) -> pb::Response {
+  let succeeded = txn.compare.iter().all(|cmp| compare(sm, cmp));
+  let operations = if succeeded { txn.success } else { txn.failure };
+
+  pb::Response {
+    succeeded,
+    ..apply_batch(sm, pb::BatchRequest { operations }, events)
+  }
+}
+
+/// Evaluates a transaction guard against the current state of its key.
+fn compare(sm: &pb::StateMachineData, cmp: &pb::Compare) -> bool {
+  let key = namespace::key(&cmp.namespace, &cmp.key);
+  let record = mvcc::latest(sm, &key);
+
+  let ordering = match &cmp.target {
+    Some(Target::Value(value)) => match record.and_then(|record| record.value.as_ref()) {
+      Some(current) => current.cmp(value),
+      None => return false,
+    },
+    Some(Target::Version(version)) => record.map_or(0, |record| record.version).cmp(version),
+    Some(Target::ModRevision(revision)) => {
+      record.map_or(0, |record| record.mod_revision).cmp(revision)
+    }
+    Some(Target::Exists(exists)) => record.is_some().cmp(exists),
+    Some(Target::CreateRevision(revision)) => record
+      .map_or(0, |record| record.create_revision)
+      .cmp(revision),
+    Some(Target::Lease(lease)) => sm.key_leases.get(&key).copied().unwrap_or(0).cmp(lease),
+    None => return false,
+  };
+
+  match cmp.result() {
+    CompareResult::Equal => ordering.is_eq(),
+    CompareResult::NotEqual => ordering.is_ne(),
+    CompareResult::Greater => ordering.is_gt(),
+    CompareResult::Less => ordering.is_lt(),
+  }
+}
+
+fn apply_op(
+  sm: &mut pb::StateMachineData,
+  op: Op,
+  undo: &mut Vec<Undo>,
+  events: &mut Vec<pb::Event>,
+) -> pb::Response {
+  let (key, expected, new, lease) = match op {
+    Op::Set(req) => (
+      namespace::key(&req.namespace, &req.key),
+      None,
+      Some(req.value),
+      req.lease,
+    ),
+    Op::Delete(req) => (namespace::key(&req.namespace, &req.key), None, None, 0),
+    Op::CompareAndSwap(req) => (
+      namespace::key(&req.namespace, &req.key),
+      Some(req.expected),
+      req.new,
+      req.lease,
+    ),
+  };
+
+  let current = mvcc::latest(sm, &key).and_then(|record| record.value.clone());
+
+  // A plain set or delete always applies; a compare-and-swap only if the key holds `expected`.
+  // Keys can only be attached to a lease that is still alive, and only grow within the quotas of
+  // their namespace.
+  let compare_failed = expected.is_some_and(|expected| expected != current);
+  let lease_missing = new.is_some() && lease != 0 && !sm.leases.contains_key(&lease);
+  let quota_exceeded = !compare_failed
+    && !lease_missing
+    && !namespace::fits(
+      sm,
+      &key,
+      current.as_ref().map(String::len),
+      new.as_ref().map(String::len),
+    );
+
+  if compare_failed || lease_missing || quota_exceeded {
+    return pb::Response {
+      value: current.clone(),
+      success: false,
+      prev_value: current,
+      quota_exceeded,
+      ..Default::default()
+    };
+  }
+
+  // Deleting a missing key changes nothing, so there is nothing to record or report to watchers.
+  if new.is_none() && current.is_none() {
+    return pb::Response {
+      success: true,
+      ..Default::default()
+    };
+  }
+
+  let replaced = mvcc::put(sm, &key, new.clone());
+
+  // Writing a key without a lease detaches it from the one it had.
+  let prev_lease = if new.is_some() && lease != 0 {
+    sm.key_leases.insert(key.clone(), lease)
+  } else {
+    sm.key_leases.remove(&key)
+  };
+
+  events.push(event(key.clone(), new.clone()));
+
+  undo.push(Undo {
+    key,
+    replaced,
+    prev_lease,
+  });
+
+  pb::Response {
+    value: new,
+    success: true,
+    prev_value: current,
+    ..Default::default()
+  }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+  use super::*;
+
+  pub(crate) fn set(key: &str, value: &str) -> pb::SetRequest {
+    pb::SetRequest {
+      key: key.to_string(),
+      value: value.to_string(),
+      lease: 0,
+      namespace: String::new(),
+    }
+  }
+
+  pub(crate) fn delete(key: &str) -> pb::DeleteRequest {
+    pb::DeleteRequest {
+      key: key.to_string(),
+      namespace: String::new(),
+    }
+  }
+
+  pub(crate) fn cas(
+    key: &str,
+    expected: Option<&str>,
+    new: Option<&str>,
+  ) -> pb::CompareAndSwapRequest {
+    pb::CompareAndSwapRequest {
+      key: key.to_string(),
+      expected: expected.map(str::to_string),
+      new: new.map(str::to_string),
+      lease: 0,
+      namespace: String::new(),
+    }
+  }
+
+  /// Applies a command, discarding its events.
+  pub(crate) fn run(
+    sm: &mut pb::StateMachineData,
+    command: impl Into<pb::Command>,
+  ) -> pb::Response {
+    apply(sm, command.into(), &mut Vec::new())
+  }
+
+  pub(crate) fn value(sm: &pb::StateMachineData, key: &str) -> Option<String> {
+    mvcc::latest(sm, key).and_then(|record| record.value.clone())
+  }
+
+  fn compare(key: &str, result: CompareResult, target: Target) -> pb::Compare {
+    pb::Compare {
+      key: key.to_string(),
+      result: result.into(),
+      target: Some(target),
+      namespace: String::new(),
+    }
+  }
+
+  #[test]
+  fn test_set_and_delete() {
+    let mut sm = pb::StateMachineData::default();
+
+    let response = run(&mut sm, set("a", "1"));
+    assert!(response.success);
+    assert_eq!(response.prev_value, None);
+    assert_eq!(response.value.as_deref(), Some("1"));
+    assert_eq!(response.revision, 1);
+
+    let response = run(&mut sm, set("a", "2"));
+    assert_eq!(response.prev_value.as_deref(), Some("1"));
+    assert_eq!(response.revision, 2);
+
+    let response = run(&mut sm, delete("a"));
+    assert!(response.success);
+    assert_eq!(response.prev_value.as_deref(), Some("2"));
+    assert_eq!(value(&sm, "a"), None);
+    assert_eq!(response.revision, 3);
+
+    // Deleting a missing key succeeds without a new revision or event
+    let mut events = Vec::new();
+    let response = apply(&mut sm, delete("a").into(), &mut events);
+    assert!(response.success);
+    assert_eq!(response.revision, 3);
+    assert!(events.is_empty());
+  }
+
+  #[test]
+  fn test_compare_and_swap() {
+    let mut sm = pb::StateMachineData::default();
+
+    // A missing key is expected as `None`
+    assert!(!run(&mut sm, cas("a", Some("1"), Some("2"))).success);
+    assert!(run(&mut sm, cas("a", None, Some("1"))).success);
+    assert!(!run(&mut sm, cas("a", None, Some("2"))).success);
+
+    let response = run(&mut sm, cas("a", Some("2"), Some("3")));
+    assert!(!response.success);
+    assert_eq!(response.value.as_deref(), Some("1"));
+    assert_eq!(response.revision, 1);
+
+    let response = run(&mut sm, cas("a", Some("1"), Some("3")));
+    assert!(response.success);
+    assert_eq!(response.prev_value.as_deref(), Some("1"));
+    assert_eq!(value(&sm, "a").as_deref(), Some("3"));
+
+    // Swapping to `None` deletes the key
+    assert!(run(&mut sm, cas("a", Some("3"), None)).success);
+    assert_eq!(value(&sm, "a"), None);
+  }
+
+  #[test]
+  fn test_batch_applies_every_operation_at_one_revision() {
+    let mut sm = pb::StateMachineData::default();
+    run(&mut sm, set("a", "1"));
+
+    let batch = pb::BatchRequest {
+      operations: vec![
+        set("b", "1").into(),
+        delete("a").into(),
+        cas("c", None, Some("1")).into(),
+      ],
+    };
+    let mut events = Vec::new();
+    let response = apply(&mut sm, batch.into(), &mut events);
+
+    assert!(response.success);
+    assert_eq!(response.responses.len(), 3);
+    assert_eq!(response.revision, 2);
+    assert_eq!(events.len(), 3);
+    assert!(events.iter().all(|event| event.revision == 2));
+    assert_eq!(value(&sm, "a"), None);
+    assert_eq!(value(&sm, "c").as_deref(), Some("1"));
+  }
+
+  #[test]
+  fn test_batch_rolls_back_on_failure() {
+    let mut sm = pb::StateMachineData::default();
+    run(&mut sm, set("a", "1"));
+
+    let batch = pb::BatchRequest {
+      operations: vec![
+        set("b", "1").into(),
+        set("a", "2").into(),
+        delete("a").into(),
+        cas("c", Some("missing"), Some("1")).into(),
+        set("d", "1").into(),
+      ],
+    };
+    let mut events = Vec::new();
+    let response = apply(&mut sm, batch.into(), &mut events);
+
+    // Operations after the failing one are not attempted
+    assert!(!response.success);
+    assert_eq!(response.responses.len(), 4);
+    assert!(events.is_empty());
+
+    assert_eq!(sm.revision, 1);
+    assert_eq!(value(&sm, "a").as_deref(), Some("1"));
+    assert_eq!(mvcc::latest(&sm, "a").unwrap().mod_revision, 1);
+    assert_eq!(value(&sm, "b"), None);
+    assert_eq!(value(&sm, "d"), None);
+  }
+
+  #[test]
+  fn test_txn_branches() {
+    let mut sm = pb::StateMachineData::default();
+    run(&mut sm, set("a", "1"));
+    run(&mut sm, set("a", "2"));
+
+    let txn = pb::TxnRequest {
+      compare: vec![
+        compare("a", CompareResult::Equal, Target::Value("2".to_string())),
+        compare("a", CompareResult::Equal, Target::Version(2)),
+        compare("a", CompareResult::Greater, Target::ModRevision(1)),
+        compare("b", CompareResult::Equal, Target::Exists(false)),
+      ],
+      success: vec![set("b", "1").into(), set("c", "1").into()],
+      failure: vec![set("failed", "1").into()],
+    };
+
+    let mut events = Vec::new();
+    let response = apply(&mut sm, txn.clone().into(), &mut events);
+    assert!(response.succeeded);
+    assert!(response.success);
+    assert_eq!(response.revision, 3);
+    assert_eq!(events.len(), 2);
+    assert_eq!(value(&sm, "b").as_deref(), Some("1"));
+
+    // `b` exists now, so the same transaction takes the failure branch
+    let response = run(&mut sm, txn);
+    assert!(!response.succeeded);
+    assert!(response.success);
+    assert_eq!(value(&sm, "failed").as_deref(), Some("1"));
+  }
+
+  #[test]
+  fn test_txn_compares() {
+    let mut sm = pb::StateMachineData::default();
+    run(&mut sm, set("a", "1"));
+    run(&mut sm, set("b", "1"));
+    run(&mut sm, set("a", "2"));
+
+    let succeeds = |sm: &mut pb::StateMachineData, cmp: pb::Compare| {
+      let txn = pb::TxnRequest {
+        compare: vec![cmp],
+        ..Default::default()
+      };
+      run(sm, txn).succeeded
+    };
+
+    assert!(succeeds(
+      &mut sm,
+      compare("a", CompareResult::Less, Target::Value("3".to_string()))
+    ));
+    assert!(succeeds(
+      &mut sm,
+      compare("a", CompareResult::Equal, Target::CreateRevision(1))
+    ));
+    assert!(succeeds(
+      &mut sm,
+      compare("b", CompareResult::Greater, Target::CreateRevision(1))
+    ));
+    assert!(succeeds(
+      &mut sm,
+      compare("a", CompareResult::Equal, Target::ModRevision(3))
+    ));
+    assert!(succeeds(
+      &mut sm,
+      compare("a", CompareResult::NotEqual, Target::Version(1))
+    ));
+    assert!(succeeds(
+      &mut sm,
+      compare("a", CompareResult::Equal, Target::Lease(0))
+    ));
+
+    // A missing key has no value, and zero versions and revisions
+    let missing = |target| compare("missing", CompareResult::NotEqual, target);
+    assert!(!succeeds(&mut sm, missing(Target::Value("1".to_string()))));
+    assert!(succeeds(&mut sm, missing(Target::Version(1))));
+    assert!(!succeeds(&mut sm, missing(Target::CreateRevision(0))));
+    assert!(!succeeds(&mut sm, missing(Target::Exists(false))));
+    assert!(!succeeds(&mut sm, missing(Target::Lease(0))));
+
+    // A compare without a target never holds
+    let cmp = pb::Compare {
+      key: "a".to_string(),
+      ..Default::default()
+    };
+    assert!(!succeeds(&mut sm, cmp));
+  }
+
+  /// Locks are keys created with a compare-and-swap on their absence, unless a transaction finds
+  /// them attached to the lease already, and released by a transaction comparing their create
+  /// revision, which is the fencing token of the holder.
+  #[test]
+  fn test_lock_fencing() {
+    let mut sm = pb::StateMachineData::default();
+    let grant = |sm: &mut pb::StateMachineData| {
+      let grant = pb::Command {
+        timestamp: 1000,
+        ..pb::LeaseGrantRequest { ttl: 10 }.into()
+      };
+      run(sm, grant).lease_id
+    };
+    let acquire = |sm: &mut pb::StateMachineData, lease| {
+      let txn = pb::TxnRequest {
+        compare: vec![compare("lock", CompareResult::Equal, Target::Lease(lease))],
+        success: vec![],
+        failure: vec![
+          pb::CompareAndSwapRequest {
+            lease,
+            ..cas("lock", None, Some("held"))
+          }
+          .into(),
+        ],
+      };
+      let response = run(sm, txn);
+      if response.succeeded {
+        mvcc::latest(sm, "lock").map(|record| record.create_revision)
+      } else {
+        response.success.then_some(response.revision)
+      }
+    };
+    let release = |sm: &mut pb::StateMachineData, token| {
+      let txn = pb::TxnRequest {
+        compare: vec![compare(
+          "lock",
+          CompareResult::Equal,
+          Target::CreateRevision(token),
+        )],
+        success: vec![delete("lock").into()],
+        failure: vec![],
+      };
+      run(sm, txn).succeeded
+    };
+
+    let (first, second) = (grant(&mut sm), grant(&mut sm));
+    let token = acquire(&mut sm, first).unwrap();
+    assert_eq!(acquire(&mut sm, second), None);
+    assert_eq!(acquire(&mut sm, first), Some(token));
+
+    // Rewriting the key keeps its create revision, and with it the token
+    run(
+      &mut sm,
+      pb::SetRequest {
+        lease: first,
+        ..set("lock", "still held")
+      },
+    );
+    assert!(release(&mut sm, token));
+
+    let next = acquire(&mut sm, second).unwrap();
+    assert!(next > token);
+
+    // A holder that lost the lock cannot release it for the next one
+    assert!(!release(&mut sm, token));
+    assert_eq!(value(&sm, "lock").as_deref(), Some("held"));
+
+    // Revoking the lease releases the lock
+    lease::revoke(&mut sm, second, &mut Vec::new());
+    assert_eq!(value(&sm, "lock"), None);
+    assert!(acquire(&mut sm, first).unwrap() > next);
+  }
+}
@@ -0,0 +1,600 @@
+//! Envelope encryption of the files written to the data directory.
+//!
+//! Every segment and snapshot file is encrypted with its own randomly generated data key, using
+//! AES-256-GCM. The data key is stored next to the file, wrapped with a master key obtained from a
+//! [`KeyProvider`]; master keys never touch the data directory.
+//!
+//! Master keys are identified by an id recorded with every wrapped data key. To rotate, a provider
+//! starts returning a new current key while still resolving the old one. New files are wrapped
+//! with the new key right away, and the data keys of the files that remain are rewrapped the next
+//! time a snapshot is taken, after which the old key is no longer needed. The current key is
+//! asked from the provider again once [`CURRENT_KEY_TTL`] has passed, so a rotation is noticed
+//! within that time.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::path::PathBuf;
+use std::process::Command;
+use std::sync::Mutex;
+use std::sync::MutexGuard;
+use std::time::Duration;
+use std::time::Instant;
+
+use aes_gcm::Aes256Gcm;
+use aes_gcm::Key;
+use aes_gcm::KeyInit;
+use aes_gcm::Nonce;
+use aes_gcm::aead::Aead;
+use aes_gcm::aead::AeadCore;
+use aes_gcm::aead::OsRng;
+use aes_gcm::aead::Payload;
+
+use crate::protobuf as pb;
+
+/// Size of the nonce in front of every sealed message.
+const NONCE_LEN: usize = 12;
+
+/// Size of the authentication tag at the end of every sealed message.
+pub const TAG_LEN: usize = 16;
+
+/// Size added by [`DataKey::seal`] to the plaintext.
+pub const SEAL_OVERHEAD: usize = NONCE_LEN + TAG_LEN;
+
+/// How long the current master key is used before the provider is asked for it again.
+pub const CURRENT_KEY_TTL: Duration = Duration::from_secs(60);
+
+/// A master key, used to wrap data keys.
+#[derive(Clone)]
+pub struct MasterKey {
+  pub id: String,
+  key: [u8; 32],
+}
+
+impl MasterKey {
+  /// Parses `<id>:<key>`, where the key is 32 bytes written as 64 hexadecimal digits.
+  pub fn parse(line: &str) -> io::Result<Self> {
+    let invalid = |message: &str| io::Error::new(io::ErrorKind::InvalidData, message.to_string());
+
+    let (id, hex) = line
+      .trim()
+      .split_once(':')
+      .ok_or_else(|| invalid("encryption key must be written as <id>:<hex key>"))?;
+
+    if id.is_empty() {
+      return Err(invalid("encryption key id must not be empty"));
+    }
+
+    let hex = hex.as_bytes();
+    if hex.len() != 64 {
+      return Err(invalid(
+        "encryption key must be 32 bytes written as 64 hex digits",
+      ));
+    }
+
+    let mut key = [0u8; 32];
+    for (i, byte) in key.iter_mut().enumerate() {
+      let digits = std::str::from_utf8(&hex[2 * i..2 * i + 2]).unwrap_or_default();
+      *byte = u8::from_str_radix(digits, 16)
+        .map_err(|_| invalid("encryption key must be written in hex digits"))?;
+    }
+
+    Ok(MasterKey {
+      id: id.to_string(),
+      key,
+    })
+  }
+}
+
+impl fmt::Debug for MasterKey {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.debug_struct("MasterKey").field("id", &self.id).finish()
+  }
+}
+
+/// Source of the master keys.
+pub trait KeyProvider: Send + Sync + fmt::Debug {
+  /// Returns the key new data keys are wrapped with.
+  fn current(&self) -> io::Result<MasterKey>;
+
+  /// Returns the key with the given id, which may have been retired.
+  fn get(&self, id: &str) -> io::Result<MasterKey>;
+}
+
+/// Reads the master keys from a file holding one `<id>:<hex key>` per line, the current one last.
+///
+/// Blank lines and lines starting with `#` are ignored. The file is read again whenever the keyring
+/// needs a key it does not hold, so a key is rotated by appending the new one.
+#[derive(Debug)]
+pub struct FileKeyProvider {
+  path: PathBuf,
+}
+
+impl FileKeyProvider {
+  pub fn new(path: impl Into<PathBuf>) -> Self {
+    FileKeyProvider { path: path.into() }
+  }
+
+  fn keys(&self) -> io::Result<Vec<MasterKey>> {
+    fs::read_to_string(&self.path)?
+      .lines()
+      .map(str::trim)
+      .filter(|line| !line.is_empty() && !line.starts_with('#'))
+      .map(MasterKey::parse)
+      .collect()
+  }
+}
+
+impl KeyProvider for FileKeyProvider {
+  fn current(&self) -> io::Result<MasterKey> {
+    self.keys()?.pop().ok_or_else(|| {
+      io::Error::new(
+        io::ErrorKind::NotFound,
+        format!("no encryption key in {}", self.path.display()),
+      )
+    })
+  }
+
+  fn get(&self, id: &str) -> io::Result<MasterKey> {
+    self
+      .keys()?
+      .into_iter()
+      .find(|key| key.id == id)
+      .ok_or_else(|| {
+        io::Error::new(
+          io::ErrorKind::NotFound,
+          format!("no encryption key {} in {}", id, self.path.display()),
+        )
+      })
+  }
+}
+
+/// Obtains the master keys from an external command, such as a wrapper around a KMS.
+///
+/// The command is run by the shell and prints a key as `<id>:<hex key>`: the current one when run
+/// without arguments, or the one with the id passed as its only argument.
+#[derive(Debug)]
+pub struct CommandKeyProvider {
+  command: String,
+}
+
+impl CommandKeyProvider {
+  pub fn new(command: impl Into<String>) -> Self {
+    CommandKeyProvider {
+      command: command.into(),
+    }
+  }
+
+  fn run(&self, id: Option<&str>) -> io::Result<MasterKey> {
+    let mut command = Command::new("sh");
+    command.arg("-c").arg(format!("{} \"$@\"", self.command));
+    command.arg("sh").args(id);
+
+    let output = command.output()?;
+    if !output.status.success() {
+      return Err(io::Error::other(format!(
+        "encryption key command failed with {}: {}",
+        output.status,
+        String::from_utf8_lossy(&output.stderr).trim()
+      )));
+    }
+
+    let key = MasterKey::parse(&String::from_utf8_lossy(&output.stdout))?;
+    if id.is_some_and(|id| id != key.id) {
+      return Err(io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!(
+          "encryption key command returned key {} instead of {}",
+          key.id,
+          id.unwrap_or_default()
+        ),
+      ));
+    }
+
+    Ok(key)
+  }
+}
+
+impl KeyProvider for CommandKeyProvider {
+  fn current(&self) -> io::Result<MasterKey> {
+    self.run(None)
+  }
+
+  fn get(&self, id: &str) -> io::Result<MasterKey> {
+    self.run(Some(id))
+  }
+}
+
+/// Generates, wraps and unwraps the data keys of the encrypted files.
+#[derive(Debug)]
+pub struct Keyring {
+  provider: Box<dyn KeyProvider>,
+  cache: Mutex<Cache>,
+}
+
+#[derive(Debug, Default)]
+struct Cache {
+  /// Master keys already obtained from the provider, by id.
+  keys: HashMap<String, MasterKey>,
+
+  /// Id of the current master key and when the provider returned it.
+  current: Option<(String, Instant)>,
+}
+
+impl Keyring {
+  pub fn new(provider: Box<dyn KeyProvider>) -> Self {
+    Keyring {
+      provider,
+      cache: Mutex::new(Cache::default()),
+    }
+  }
+
+  /// Id of the current master key.
+  pub fn current_key_id(&self) -> io::Result<String> {
+    self.current().map(|key| key.id)
+  }
+
+  /// The current master key, asked from the provider again once [`CURRENT_KEY_TTL`] has passed.
+  fn current(&self) -> io::Result<MasterKey> {
+    {
+      let cache = self.cache()?;
+      if let Some((id, since)) = &cache.current
+        && since.elapsed() < CURRENT_KEY_TTL
+        && let Some(key) = cache.keys.get(id)
+      {
+        return Ok(key.clone());
+      }
+    }
+
+    let key = self.provider.current()?;
+    let mut cache = self.cache()?;
+    cache.current = Some((key.id.clone(), Instant::now()));
+    cache.keys.insert(key.id.clone(), key.clone());
+    Ok(key)
+  }
+
+  fn get(&self, id: &str) -> io::Result<MasterKey> {
+    if let Some(key) = self.cache()?.keys.get(id) {
+      return Ok(key.clone());
+    }
+
+    let key = self.provider.get(id)?;
+    self.cache()?.keys.insert(key.id.clone(), key.clone());
+    Ok(key)
+  }
+
+  fn cache(&self) -> io::Result<MutexGuard<'_, Cache>> {
+    self
+      .cache
+      .lock()
+      .map_err(|_| io::Error::other("encryption key cache is poisoned"))
+  }
+
+  /// Generates a new data key, wrapped with the current master key.
+  pub fn generate(&self) -> io::Result<DataKey> {
+    let key = Aes256Gcm::generate_key(OsRng);
+    let wrapped = wrap(&self.current()?, &key)?;
+
+    Ok(DataKey {
+      cipher: Aes256Gcm::new(&key),
+      wrapped,
+    })
+  }
+
+  /// Recovers a data key stored with a file.
+  pub fn unwrap(&self, wrapped: &pb::WrappedKey) -> io::Result<DataKey> {
+    let master = self.get(&wrapped.key_id)?;
+    let key = Key::<Aes256Gcm>::clone_from_slice(&open(
+      &master.key,
+      &wrapped.nonce,
+      &wrapped.ciphertext,
+      wrapped.key_id.as_bytes(),
+    )?);
+
+    Ok(DataKey {
+      cipher: Aes256Gcm::new(&key),
+      wrapped: wrapped.clone(),
+    })
+  }
+
+  /// Wraps a data key again with the current master key, `None` if it already is.
+  pub fn rewrap(&self, wrapped: &pb::WrappedKey) -> io::Result<Option<pb::WrappedKey>> {
+    let current = self.current()?;
+    if wrapped.key_id == current.id {
+      return Ok(None);
+    }
+
+    let master = self.get(&wrapped.key_id)?;
+    let key = open(
+      &master.key,
+      &wrapped.nonce,
+      &wrapped.ciphertext,
+      wrapped.key_id.as_bytes(),
+    )?;
+
+    wrap(&current, Key::<Aes256Gcm>::from_slice(&key)).map(Some)
+  }
+}
+
+/// Recovers the data key stored with the file at `path`, `None` if the file is not encrypted.
+pub fn data_key(
+  keyring: Option<&Keyring>,
+  wrapped: Option<&pb::WrappedKey>,
+  path: &Path,
+) -> io::Result<Option<DataKey>> {
+  let Some(wrapped) = wrapped else {
+    return Ok(None);
+  };
+
+  let keyring = keyring.ok_or_else(|| {
+    io::Error::new(
+      io::ErrorKind::InvalidData,
+      format!(
+        "{} is encrypted but no encryption key is configured",
+        path.display()
+      ),
+    )
+  })?;
+
+  keyring.unwrap(wrapped).map(Some)
+}
+
+fn wrap(master: &MasterKey, key: &Key<Aes256Gcm>) -> io::Result<pb::WrappedKey> {
+  let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+  let ciphertext = Aes256Gcm::new(&master.key.into())
+    .encrypt(
+      &nonce,
+      Payload {
+        msg: key.as_slice(),
+        aad: master.id.as_bytes(),
+      },
+    )
+    .map_err(|_| io::Error::other("failed to wrap data key"))?;
+
+  Ok(pb::WrappedKey {
+    key_id: master.id.clone(),
+    nonce: nonce.to_vec(),
+    ciphertext,
+  })
+}
+
+fn open(key: &[u8; 32], nonce: &[u8], ciphertext: &[u8], aad: &[u8]) -> io::Result<Vec<u8>> {
+  if nonce.len() != NONCE_LEN {
+    return Err(io::Error::new(
+      io::ErrorKind::InvalidData,
+      "wrapped data key has an invalid nonce",
+    ));
+  }
+
+  Aes256Gcm::new(&(*key).into())
+    .decrypt(
+      Nonce::from_slice(nonce),
+      Payload {
+        msg: ciphertext,
+        aad,
+      },
+    )
+    .map_err(|_| {
+      io::Error::new(
+        io::ErrorKind::InvalidData,
+        "failed to unwrap data key: wrong master key or corrupt data",
+      )
+    })
+}
+
+/// The key a single file is encrypted with, along with its wrapped form stored next to the file.
+#[derive(Clone)]
+pub struct DataKey {
+  cipher: Aes256Gcm,
+  wrapped: pb::WrappedKey,
+}
+
+impl DataKey {
+  pub fn wrapped(&self) -> &pb::WrappedKey {
+    &self.wrapped
+  }
+
+  /// Replaces the wrapped form after the data key was rewrapped.
+  pub fn set_wrapped(&mut self, wrapped: pb::WrappedKey) {
+    self.wrapped = wrapped;
+  }
+
+  /// Encrypts a message, bound to `aad`, returning the nonce followed by the ciphertext.
+  pub fn seal(&self, aad: &[u8], plaintext: &[u8]) -> io::Result<Vec<u8>> {
+    let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+    let ciphertext = self
+      .cipher
+      .encrypt(
+        &nonce,
+        Payload {
+          msg: plaintext,
+          aad,
+        },
+      )
+      .map_err(|_| io::Error::other("failed to encrypt data"))?;
+
+    let mut sealed = Vec::with_capacity(NONCE_LEN + ciphertext.len());
+    sealed.extend_from_slice(&nonce);
+    sealed.extend_from_slice(&ciphertext);
+    Ok(sealed)
+  }
+
+  /// Decrypts a message produced by [`DataKey::seal`] with the same `aad`.
+  pub fn open(&self, aad: &[u8], sealed: &[u8]) -> io::Result<Vec<u8>> {
+    if sealed.len() < SEAL_OVERHEAD {
+      return Err(io::Error::new(
+        io::ErrorKind::InvalidData,
+        "encrypted data is truncated",
+      ));
+    }
+
+    let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+    self
+      .cipher
+      .decrypt(
+        Nonce::from_slice(nonce),
+        Payload {
+          msg: ciphertext,
+          aad,
+        },
+      )
+      .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "failed to decrypt data"))
+  }
+}
+
+impl fmt::Debug for DataKey {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.debug_struct("DataKey")
+      .field("key_id", &self.wrapped.key_id)
+      .finish()
+  }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+  use super::*;
+
+  /// Opens a keyring over a key file holding the master keys `ids`, the current one last.
+  pub(crate) fn keyring(path: &Path, ids: &[&str]) -> Keyring {
+    write_keys(path, ids);
+    Keyring::new(Box::new(FileKeyProvider::new(path)))
+  }
+
+  /// Rewrites the key file with `ids`, as if [`CURRENT_KEY_TTL`] had passed since the keyring
+  /// last asked for the current key.
+  pub(crate) fn rotate(keyring: &Keyring, path: &Path, ids: &[&str]) {
+    write_keys(path, ids);
+    keyring.cache.lock().unwrap().current = None;
+  }
+
+  fn write_keys(path: &Path, ids: &[&str]) {
+    let keys = ids
+      .iter()
+      .map(|id| {
+        let byte = id.bytes().fold(0u8, |sum, b| sum.wrapping_add(b));
+        format!("{}:{}\n", id, format!("{:02x}", byte).repeat(32))
+      })
+      .collect::<String>();
+    fs::write(path, keys).unwrap();
+  }
+
+  #[test]
+  fn test_parse_master_key() {
+    let key = MasterKey::parse(&format!("k1:{}\n", "0a".repeat(32))).unwrap();
+    assert_eq!(key.id, "k1");
+    assert_eq!(key.key, [10u8; 32]);
+
+    for line in [
+      "k1".to_string(),
+      format!(":{}", "0a".repeat(32)),
+      format!("k1:{}", "0a".repeat(31)),
+      format!("k1:{}", "zz".repeat(32)),
+    ] {
+      assert!(MasterKey::parse(&line).is_err(), "{}", line);
+    }
+  }
+
+  #[test]
+  fn test_seal_and_open() {
+    let dir = tempfile::TempDir::new().unwrap();
+    let keyring = keyring(&dir.path().join("keys"), &["k1"]);
+
+    let key = keyring.generate().unwrap();
+    assert_eq!(key.wrapped().key_id, "k1");
+
+    let sealed = key.seal(b"aad", b"data").unwrap();
+    assert_eq!(sealed.len(), 4 + SEAL_OVERHEAD);
+
+    let unwrapped = keyring.unwrap(key.wrapped()).unwrap();
+    assert_eq!(unwrapped.open(b"aad", &sealed).unwrap(), b"data");
+  }
+
+  #[test]
+  fn test_open_rejects_tampered_data() {
+    let dir = tempfile::TempDir::new().unwrap();
+    let key = keyring(&dir.path().join("keys"), &["k1"])
+      .generate()
+      .unwrap();
+    let sealed = key.seal(b"aad", b"data").unwrap();
+
+    assert!(key.open(b"other", &sealed).is_err());
+    assert!(key.open(b"aad", &sealed[..SEAL_OVERHEAD - 1]).is_err());
+
+    for i in [0, NONCE_LEN, sealed.len() - 1] {
+      let mut tampered = sealed.clone();
+      tampered[i] ^= 1;
+      assert!(key.open(b"aad", &tampered).is_err(), "byte {}", i);
+    }
+  }
+
+  #[test]
+  fn test_unwrap_rejects_tampered_key() {
+    let dir = tempfile::TempDir::new().unwrap();
+    let keyring = keyring(&dir.path().join("keys"), &["k1", "k2"]);
+    let wrapped = keyring.generate().unwrap().wrapped().clone();
+
+    let mut tampered = wrapped.clone();
+    tampered.ciphertext[0] ^= 1;
+    assert!(keyring.unwrap(&tampered).is_err());
+
+    // The id of the master key is bound to the wrapped key.
+    let mut tampered = wrapped.clone();
+    tampered.key_id = "k1".to_string();
+    assert!(keyring.unwrap(&tampered).is_err());
+
+    let mut tampered = wrapped;
+    tampered.key_id = "k3".to_string();
+    assert!(keyring.unwrap(&tampered).is_err());
+  }
+
+  #[test]
+  fn test_rewrap_after_rotation() {
+    let dir = tempfile::TempDir::new().unwrap();
+    let path = dir.path().join("keys");
+    let keyring = keyring(&path, &["k1"]);
+
+    let key = keyring.generate().unwrap();
+    let sealed = key.seal(b"aad", b"data").unwrap();
+    assert_eq!(keyring.rewrap(key.wrapped()).unwrap(), None);
+
+    rotate(&keyring, &path, &["k1", "k2"]);
+    let rewrapped = keyring.rewrap(key.wrapped()).unwrap().unwrap();
+    assert_eq!(rewrapped.key_id, "k2");
+    assert_eq!(keyring.rewrap(&rewrapped).unwrap(), None);
+
+    // Once rewrapped, the retired key is no longer needed.
+    let retired = self::keyring(&path, &["k2"]);
+    let unwrapped = retired.unwrap(&rewrapped).unwrap();
+    assert_eq!(unwrapped.open(b"aad", &sealed).unwrap(), b"data");
+    assert!(retired.unwrap(key.wrapped()).is_err());
+  }
+
+  #[test]
+  fn test_current_key_is_cached() {
+    let dir = tempfile::TempDir::new().unwrap();
+    let path = dir.path().join("keys");
+    let keyring = keyring(&path, &["k1"]);
+    assert_eq!(keyring.current_key_id().unwrap(), "k1");
+
+    write_keys(&path, &["k1", "k2"]);
+    assert_eq!(keyring.current_key_id().unwrap(), "k1");
+
+    rotate(&keyring, &path, &["k1", "k2"]);
+    assert_eq!(keyring.current_key_id().unwrap(), "k2");
+  }
+
+  #[test]
+  fn test_command_key_provider() {
+    let provider = CommandKeyProvider::new(format!(
+      "f() {{ echo \"${{1:-k2}}:{}\"; }}; f",
+      "0a".repeat(32)
+    ));
+    assert_eq!(provider.current().unwrap().id, "k2");
+    assert_eq!(provider.get("k1").unwrap().id, "k1");
+
+    assert!(CommandKeyProvider::new("false").current().is_err());
+    assert!(CommandKeyProvider::new("echo k3:").get("k1").is_err());
+  }
+}
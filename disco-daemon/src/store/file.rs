@@ -0,0 +1,68 @@
+//! Small filesystem helpers shared by the on-disk stores.
+
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::Write;
+use std::path::Path;
+
+/// Flushes a directory so that renames and newly created files inside it survive a crash.
+pub fn sync_dir(dir: &Path) -> io::Result<()> {
+  File::open(dir)?.sync_all()
+}
+
+/// Atomically replaces `path` with `contents`.
+///
+/// The data is written to a temporary file next to `path`, fsynced, renamed over the destination
+/// and finally the parent directory is fsynced, so readers observe either the old or the new
+/// contents but never a partial write.
+pub fn write_atomic(path: &Path, contents: &[u8]) -> io::Result<()> {
+  let tmp = path.with_extension("tmp");
+
+  {
+    let mut file = File::create(&tmp)?;
+    file.write_all(contents)?;
+    file.sync_all()?;
+  }
+
+  fs::rename(&tmp, path)?;
+
+  if let Some(parent) = path.parent() {
+    sync_dir(parent)?;
+  }
+
+  Ok(())
+}
+
+/// Removes `path` if it exists and makes the removal durable.
+pub fn remove_durable(path: &Path) -> io::Result<()> {
+  match fs::remove_file(path) {
+    Ok(()) => {}
+    Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+    Err(e) => return Err(e),
+  }
+
+  if let Some(parent) = path.parent() {
+    sync_dir(parent)?;
+  }
+
+  Ok(())
+}
+
+/// Reads a protobuf message previously stored with [`write_message`], `None` if absent.
+pub fn read_message<M: prost::Message + Default>(path: &Path) -> io::Result<Option<M>> {
+  let bytes = match fs::read(path) {
+    Ok(bytes) => bytes,
+    Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+    Err(e) => return Err(e),
+  };
+
+  M::decode(bytes.as_slice())
+    .map(Some)
+    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/// Atomically stores a protobuf message at `path`.
+pub fn write_message<M: prost::Message>(path: &Path, message: &M) -> io::Result<()> {
+  write_atomic(path, &message.encode_to_vec())
+}
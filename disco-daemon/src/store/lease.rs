@@ -0,0 +1,265 @@
+//! Leases keep the keys attached to them alive for as long as their owner renews them.
+//!
+//! Deadlines are computed from the timestamp the leader stamps on each command rather than from
+//! the local clock, so applying the log yields the same leases on every node. Expiry is itself a
+//! replicated command, proposed by the leader once it sees a deadline pass.
+//!
+//! Keepalives go through the log too, so they are ordered with the expiry of the lease and
+//! survive a change of leader. A new leader first gives every lease a full TTL from its election,
+//! which covers the time the cluster went without a leader to renew them.
+
+use std::time::SystemTime;
+
+use super::command;
+use super::mvcc;
+use crate::protobuf as pb;
+
+/// Milliseconds since the epoch, the unit of command timestamps and lease deadlines.
+pub fn now() -> u64 {
+  SystemTime::now()
+    .duration_since(SystemTime::UNIX_EPOCH)
+    .map(|d| d.as_millis() as u64)
+    .unwrap_or_default()
+}
+
+pub fn deadline(now: u64, ttl: i64) -> u64 {
+  now.saturating_add((ttl as u64).saturating_mul(1000))
+}
+
+pub fn grant(sm: &mut pb::StateMachineData, req: pb::LeaseGrantRequest, now: u64) -> pb::Response {
+  if req.ttl <= 0 {
+    return pb::Response::default();
+  }
+
+  let id = sm.next_lease_id.max(1);
+  sm.next_lease_id = id + 1;
+  sm.leases.insert(
+    id,
+    pb::Lease {
+      id,
+      ttl: req.ttl,
+      expires_at: deadline(now, req.ttl),
+    },
+  );
+
+  pb::Response {
+    success: true,
+    lease_id: id,
+    ..Default::default()
+  }
+}
+
+/// Renews a lease for another full TTL from the command timestamp.
+pub fn keep_alive(
+  sm: &mut pb::StateMachineData,
+  req: pb::LeaseKeepAliveRequest,
+  now: u64,
+) -> pb::Response {
+  let Some(lease) = sm.leases.get_mut(&req.id) else {
+    return pb::Response {
+      lease_id: req.id,
+      ..Default::default()
+    };
+  };
+
+  lease.expires_at = deadline(now, lease.ttl);
+
+  pb::Response {
+    success: true,
+    lease_id: req.id,
+    ..Default::default()
+  }
+}
+
+/// Removes a lease and deletes every key attached to it.
+pub fn revoke(sm: &mut pb::StateMachineData, id: i64, events: &mut Vec<pb::Event>) -> pb::Response {
+  let success = sm.leases.remove(&id).is_some();
+
+  let keys = sm
+    .key_leases
+    .iter()
+    .filter(|(_, lease)| **lease == id)
+    .map(|(key, _)| key.clone())
+    .collect::<Vec<_>>();
+
+  for key in keys {
+    sm.key_leases.remove(&key);
+    mvcc::put(sm, &key, None);
+    events.push(command::event(key, None));
+  }
+
+  pb::Response {
+    success,
+    lease_id: id,
+    ..Default::default()
+  }
+}
+
+/// Pushes the deadline of every lease to at least a full TTL after `now`, the election of the
+/// leader proposing it.
+pub fn reset(sm: &mut pb::StateMachineData, now: u64) -> pb::Response {
+  for lease in sm.leases.values_mut() {
+    lease.expires_at = lease.expires_at.max(deadline(now, lease.ttl));
+  }
+
+  pb::Response {
+    success: true,
+    ..Default::default()
+  }
+}
+
+/// Revokes the listed leases that are expired as of the command timestamp.
+///
+/// A keepalive may have been applied after the leader proposed the expiry, so the deadline is
+/// checked again here.
+pub fn expire(
+  sm: &mut pb::StateMachineData,
+  req: pb::LeaseExpireRequest,
+  now: u64,
+  events: &mut Vec<pb::Event>,
+) -> pb::Response {
+  for id in req.ids {
+    if sm
+      .leases
+      .get(&id)
+      .is_some_and(|lease| lease.expires_at <= now)
+    {
+      tracing::debug!("Lease {} expired", id);
+      revoke(sm, id, events);
+    }
+  }
+
+  pb::Response {
+    success: true,
+    ..Default::default()
+  }
+}
+
+/// Returns the ids of the leases whose deadline has passed at `now`.
+pub fn expired(sm: &pb::StateMachineData, now: u64) -> Vec<i64> {
+  sm.leases
+    .values()
+    .filter(|lease| lease.expires_at <= now)
+    .map(|lease| lease.id)
+    .collect()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::store::command::tests::{run, set, value};
+
+  /// Stamps a command with the time the leader proposed it.
+  fn at(command: impl Into<pb::Command>, timestamp: u64) -> pb::Command {
+    pb::Command {
+      timestamp,
+      ..command.into()
+    }
+  }
+
+  fn attached(key: &str, lease: i64) -> pb::SetRequest {
+    pb::SetRequest {
+      lease,
+      ..set(key, "1")
+    }
+  }
+
+  #[test]
+  fn test_grant() {
+    let mut sm = pb::StateMachineData::default();
+
+    assert!(!run(&mut sm, at(pb::LeaseGrantRequest { ttl: 0 }, 1000)).success);
+
+    let response = run(&mut sm, at(pb::LeaseGrantRequest { ttl: 2 }, 1000));
+    assert!(response.success);
+    assert_eq!(response.lease_id, 1);
+    assert_eq!(sm.leases[&1].expires_at, 3000);
+
+    let response = run(&mut sm, at(pb::LeaseGrantRequest { ttl: 5 }, 1000));
+    assert_eq!(response.lease_id, 2);
+  }
+
+  #[test]
+  fn test_keys_need_a_live_lease() {
+    let mut sm = pb::StateMachineData::default();
+    run(&mut sm, at(pb::LeaseGrantRequest { ttl: 2 }, 1000));
+
+    assert!(run(&mut sm, attached("a", 1)).success);
+    assert_eq!(sm.key_leases["a"], 1);
+
+    assert!(!run(&mut sm, attached("b", 7)).success);
+    assert_eq!(value(&sm, "b"), None);
+
+    // Setting the key again without a lease detaches it
+    assert!(run(&mut sm, set("a", "2")).success);
+    assert!(sm.key_leases.is_empty());
+  }
+
+  #[test]
+  fn test_keep_alive() {
+    let mut sm = pb::StateMachineData::default();
+    run(&mut sm, at(pb::LeaseGrantRequest { ttl: 2 }, 1000));
+
+    let response = run(&mut sm, at(pb::LeaseKeepAliveRequest { id: 1 }, 2500));
+    assert!(response.success);
+    assert_eq!(response.lease_id, 1);
+    assert_eq!(sm.leases[&1].expires_at, 4500);
+
+    assert!(!run(&mut sm, at(pb::LeaseKeepAliveRequest { id: 2 }, 2500)).success);
+  }
+
+  #[test]
+  fn test_revoke() {
+    let mut sm = pb::StateMachineData::default();
+    run(&mut sm, at(pb::LeaseGrantRequest { ttl: 2 }, 1000));
+    run(&mut sm, attached("a", 1));
+    run(&mut sm, attached("b", 1));
+    run(&mut sm, set("c", "1"));
+
+    let mut events = Vec::new();
+    let response = revoke(&mut sm, 1, &mut events);
+    assert!(response.success);
+    assert_eq!(events.len(), 2);
+    assert_eq!(value(&sm, "a"), None);
+    assert_eq!(value(&sm, "b"), None);
+    assert_eq!(value(&sm, "c").as_deref(), Some("1"));
+    assert!(sm.leases.is_empty());
+    assert!(sm.key_leases.is_empty());
+
+    assert!(!revoke(&mut sm, 1, &mut Vec::new()).success);
+  }
+
+  #[test]
+  fn test_expire_checks_the_deadline_again() {
+    let mut sm = pb::StateMachineData::default();
+    run(&mut sm, at(pb::LeaseGrantRequest { ttl: 2 }, 1000));
+    run(&mut sm, attached("a", 1));
+
+    assert!(expired(&sm, 2999).is_empty());
+    assert_eq!(expired(&sm, 3000), vec![1]);
+
+    // A keepalive applied after the leader saw the lease expire keeps it alive
+    assert!(run(&mut sm, at(pb::LeaseKeepAliveRequest { id: 1 }, 2500)).success);
+    run(&mut sm, at(pb::LeaseExpireRequest { ids: vec![1] }, 3000));
+    assert_eq!(value(&sm, "a").as_deref(), Some("1"));
+
+    run(&mut sm, at(pb::LeaseExpireRequest { ids: vec![1] }, 4500));
+    assert_eq!(value(&sm, "a"), None);
+    assert!(sm.leases.is_empty());
+    assert!(sm.key_leases.is_empty());
+  }
+
+  #[test]
+  fn test_reset_gives_a_full_ttl() {
+    let mut sm = pb::StateMachineData::default();
+    run(&mut sm, at(pb::LeaseGrantRequest { ttl: 2 }, 1000));
+    run(&mut sm, at(pb::LeaseGrantRequest { ttl: 2 }, 1000));
+    run(&mut sm, at(pb::LeaseKeepAliveRequest { id: 2 }, 9000));
+
+    run(&mut sm, at(pb::LeaseResetRequest {}, 5000));
+    assert_eq!(sm.leases[&1].expires_at, 7000);
+
+    // A deadline already past the reset is kept
+    assert_eq!(sm.leases[&2].expires_at, 11000);
+  }
+}
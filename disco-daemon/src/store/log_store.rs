@@ -1,75 +1,213 @@
-//! Provide `LogStore`, which is a in-memory implementation of `RaftLogStore` for demonstration
-//! purpose only.
+//! Provide `LogStore`, a durable implementation of `RaftLogStorage` backed by segment files.
+//!
+//! Layout of the log directory:
+//! - `vote`, `committed`, `purged`: protobuf encoded metadata, replaced atomically on update.
+//! - `<first-index>.seg`: append-only [`Segment`] files holding the protobuf encoded entries.
+//! - `<first-index>.key`: the wrapped data key of a segment, when encryption is enabled.
+//!
+//! Only the position of each entry is kept in memory; entries are read back from disk on demand.
 
 use std::collections::BTreeMap;
 use std::fmt::Debug;
+use std::fs;
+use std::io;
 use std::ops::RangeBounds;
+use std::path::Path;
+use std::path::PathBuf;
 use std::sync::Arc;
 
-use openraft::alias::LogIdOf;
-use openraft::alias::VoteOf;
 use openraft::entry::RaftEntry;
-use openraft::storage::IOFlushed;
-use openraft::LogState;
-use openraft::RaftTypeConfig;
-use openraft::StorageError;
+use prost::Message;
 use tokio::sync::Mutex;
 
-/// RaftLogStore implementation with a in-memory storage
-#[derive(Clone, Debug, Default)]
-pub struct LogStore<C: RaftTypeConfig> {
-  inner: Arc<Mutex<LogStoreInner<C>>>,
+use super::crypto::Keyring;
+use super::file;
+use super::segment::Segment;
+use crate::protobuf as pb;
+use crate::raft_types::*;
+
+/// A new segment is started once the active one grows past this size.
+const SEGMENT_MAX_BYTES: u64 = 16 * 1024 * 1024;
+
+const VOTE_FILE: &str = "vote";
+const COMMITTED_FILE: &str = "committed";
+const PURGED_FILE: &str = "purged";
+
+/// RaftLogStore implementation with a durable on-disk storage
+#[derive(Clone, Debug)]
+pub struct LogStore {
+  inner: Arc<Mutex<LogStoreInner>>,
+}
+
+impl LogStore {
+  /// Opens the log stored in `dir`, creating it if needed, and recovers its state.
+  ///
+  /// With a keyring, new segments are encrypted.
+  pub fn open(dir: impl AsRef<Path>, keyring: Option<Arc<Keyring>>) -> io::Result<Self> {
+    let inner = LogStoreInner::open(dir.as_ref().to_path_buf(), keyring)?;
+
+    Ok(LogStore {
+      inner: Arc::new(Mutex::new(inner)),
+    })
+  }
+
+  /// Returns the log id of the last entry known to be committed.
+  pub async fn committed(&self) -> Option<LogId> {
+    self.inner.lock().await.committed
+  }
+
+  /// Returns the number of bytes taken up in the segment files by the entries after `index`.
+  pub async fn size_after(&self, index: Option<u64>) -> u64 {
+    let inner = self.inner.lock().await;
+
+    let start = index.map_or(0, |index| index + 1);
+    let Some((_, first)) = inner.index.range(start..).next() else {
+      return 0;
+    };
+
+    inner
+      .segments
+      .range(first.segment..)
+      .map(|(_, segment)| segment.size())
+      .sum::<u64>()
+      .saturating_sub(first.offset)
+  }
+}
+
+/// Location of an entry within the segment files.
+#[derive(Debug, Clone)]
+struct Position {
+  /// The first index of the segment holding the entry.
+  segment: u64,
+
+  /// Byte offset of the record within the segment.
+  offset: u64,
+
+  log_id: LogId,
 }
 
 #[derive(Debug)]
-pub struct LogStoreInner<C: RaftTypeConfig> {
+pub struct LogStoreInner {
+  dir: PathBuf,
+
   /// The last purged log id.
-  last_purged_log_id: Option<LogIdOf<C>>,
+  last_purged_log_id: Option<LogId>,
+
+  /// Position of every entry that has not been purged, by log index.
+  index: BTreeMap<u64, Position>,
 
-  /// The Raft log.
-  log: BTreeMap<u64, C::Entry>,
+  /// Segment files by the index of their first entry; the last one is appended to.
+  segments: BTreeMap<u64, Segment>,
 
   /// The commit log id.
-  committed: Option<LogIdOf<C>>,
+  committed: Option<LogId>,
 
   /// The current granted vote.
-  vote: Option<VoteOf<C>>,
+  vote: Option<Vote>,
+
+  /// Encrypts the segments, if enabled.
+  keyring: Option<Arc<Keyring>>,
 }
 
-impl<C: RaftTypeConfig> Default for LogStoreInner<C> {
-  fn default() -> Self {
-    Self {
-      last_purged_log_id: None,
-      log: BTreeMap::new(),
-      committed: None,
-      vote: None,
+impl LogStoreInner {
+  fn open(dir: PathBuf, keyring: Option<Arc<Keyring>>) -> io::Result<Self> {
+    fs::create_dir_all(&dir)?;
+
+    let vote = file::read_message::<Vote>(&dir.join(VOTE_FILE))?;
+    let committed = file::read_message::<pb::LogId>(&dir.join(COMMITTED_FILE))?.map(LogId::from);
+    let last_purged_log_id =
+      file::read_message::<pb::LogId>(&dir.join(PURGED_FILE))?.map(LogId::from);
+
+    let mut paths = fs::read_dir(&dir)?
+      .map(|entry| entry.map(|e| e.path()))
+      .collect::<Result<Vec<_>, _>>()?;
+    paths.retain(|path| Segment::first_index(path).is_some());
+    paths.sort_by_key(|path| Segment::first_index(path));
+
+    let purged_index = last_purged_log_id.as_ref().map(|log_id| log_id.index());
+
+    let mut index = BTreeMap::new();
+    let mut segments = BTreeMap::new();
+
+    for (i, path) in paths.iter().enumerate() {
+      let first = Segment::first_index(path).unwrap_or_default();
+      let is_last = i + 1 == paths.len();
+
+      let (segment, records) = Segment::open(path, is_last, keyring.as_deref())?;
+
+      for record in records {
+        let entry = pb::Entry::decode(record.payload.as_slice())
+          .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+        // Entries before the purge point may linger in a partially purged segment.
+        if Some(entry.index) <= purged_index {
+          continue;
+        }
+
+        index.insert(
+          entry.index,
+          Position {
+            segment: first,
+            offset: record.offset,
+            log_id: entry.log_id(),
+          },
+        );
+      }
+
+      segments.insert(first, segment);
     }
+
+    tracing::info!(
+      "Recovered {} log entries from {} segments in {}",
+      index.len(),
+      segments.len(),
+      dir.display()
+    );
+
+    Ok(LogStoreInner {
+      dir,
+      last_purged_log_id,
+      index,
+      segments,
+      committed,
+      vote,
+      keyring,
+    })
+  }
+
+  fn read_entry(&self, pos: &Position) -> Result<Entry, StorageError> {
+    let segment = self.segments.get(&pos.segment).ok_or_else(|| {
+      StorageError::read_logs(&io::Error::new(
+        io::ErrorKind::NotFound,
+        format!("segment {} is missing", pos.segment),
+      ))
+    })?;
+
+    let payload = segment
+      .read(pos.offset)
+      .map_err(|e| StorageError::read_logs(&e))?;
+
+    pb::Entry::decode(payload.as_slice()).map_err(|e| StorageError::read_logs(&e))
   }
-}
 
-impl<C: RaftTypeConfig> LogStoreInner<C> {
   async fn try_get_log_entries<RB: RangeBounds<u64> + Clone + Debug>(
     &mut self,
     range: RB,
-  ) -> Result<Vec<C::Entry>, StorageError<C>>
-  where
-    C::Entry: Clone,
-  {
-    let response = self
-      .log
-      .range(range.clone())
-      .map(|(_, val)| val.clone())
-      .collect::<Vec<_>>();
-    Ok(response)
+  ) -> Result<Vec<Entry>, StorageError> {
+    self
+      .index
+      .range(range)
+      .map(|(_, pos)| self.read_entry(pos))
+      .collect()
   }
 
-  async fn get_log_state(&mut self) -> Result<LogState<C>, StorageError<C>> {
-    let last = self.log.iter().next_back().map(|(_, ent)| ent.log_id());
+  async fn get_log_state(&mut self) -> Result<LogState, StorageError> {
+    let last = self.index.values().next_back().map(|pos| pos.log_id);
 
-    let last_purged = self.last_purged_log_id.clone();
+    let last_purged = self.last_purged_log_id;
 
     let last = match last {
-      None => last_purged.clone(),
+      None => last_purged,
       Some(x) => Some(x),
     };
 
@@ -79,65 +217,167 @@ impl<C: RaftTypeConfig> LogStoreInner<C> {
     })
   }
 
-  async fn save_committed(&mut self, committed: Option<LogIdOf<C>>) -> Result<(), StorageError<C>> {
+  async fn save_committed(&mut self, committed: Option<LogId>) -> Result<(), StorageError> {
+    let path = self.dir.join(COMMITTED_FILE);
+    match committed {
+      Some(log_id) => file::write_message(&path, &pb::LogId::from(log_id)),
+      None => file::remove_durable(&path),
+    }
+    .map_err(|e| StorageError::write(&e))?;
+
     self.committed = committed;
     Ok(())
   }
 
-  async fn read_committed(&mut self) -> Result<Option<LogIdOf<C>>, StorageError<C>> {
-    Ok(self.committed.clone())
+  async fn read_committed(&mut self) -> Result<Option<LogId>, StorageError> {
+    Ok(self.committed)
   }
 
-  async fn save_vote(&mut self, vote: &VoteOf<C>) -> Result<(), StorageError<C>> {
-    self.vote = Some(vote.clone());
+  async fn save_vote(&mut self, vote: &Vote) -> Result<(), StorageError> {
+    file::write_message(&self.dir.join(VOTE_FILE), vote)
+      .map_err(|e| StorageError::write_vote(&e))?;
+
+    self.vote = Some(*vote);
     Ok(())
   }
 
-  async fn read_vote(&mut self) -> Result<Option<VoteOf<C>>, StorageError<C>> {
-    Ok(self.vote.clone())
+  async fn read_vote(&mut self) -> Result<Option<Vote>, StorageError> {
+    Ok(self.vote)
   }
 
-  async fn append<I>(&mut self, entries: I, callback: IOFlushed<C>) -> Result<(), StorageError<C>>
+  /// Returns the segment new entries are appended to, starting a new one when it is full or when
+  /// encryption was turned on or off since it was created.
+  fn active_segment(&mut self, next_index: u64) -> io::Result<u64> {
+    if let Some((first, segment)) = self.segments.iter().next_back()
+      && segment.size() < SEGMENT_MAX_BYTES
+      && segment.is_encrypted() == self.keyring.is_some()
+    {
+      return Ok(*first);
+    }
+
+    // A segment starting at the next index holds no entries yet, so it can be replaced.
+    if let Some(segment) = self.segments.remove(&next_index) {
+      segment.remove()?;
+    }
+
+    let segment = Segment::create(&self.dir, next_index, self.keyring.as_deref())?;
+    self.segments.insert(next_index, segment);
+    Ok(next_index)
+  }
+
+  async fn append<I>(&mut self, entries: I, callback: IOFlushed) -> Result<(), StorageError>
   where
-    I: IntoIterator<Item = C::Entry>,
+    I: IntoIterator<Item = Entry>,
   {
-    // Simple implementation that calls the flush-before-return `append_to_log`.
+    let mut touched = Vec::new();
+
     for entry in entries {
-      self.log.insert(entry.index(), entry);
+      let log_id = entry.log_id();
+
+      let first = self
+        .active_segment(log_id.index())
+        .map_err(|e| StorageError::write_logs(&e))?;
+
+      let segment = self.segments.get_mut(&first).unwrap();
+      let offset = segment
+        .append(&entry.encode_to_vec())
+        .map_err(|e| StorageError::write_logs(&e))?;
+
+      if touched.last() != Some(&first) {
+        touched.push(first);
+      }
+
+      self.index.insert(
+        log_id.index(),
+        Position {
+          segment: first,
+          offset,
+          log_id,
+        },
+      );
     }
+
+    // Entries must be on stable storage before reporting them as flushed.
+    for first in touched {
+      self.segments[&first]
+        .sync()
+        .map_err(|e| StorageError::write_logs(&e))?;
+    }
+
     callback.io_completed(Ok(()));
 
     Ok(())
   }
 
-  async fn truncate(&mut self, log_id: LogIdOf<C>) -> Result<(), StorageError<C>> {
-    let keys = self
-      .log
-      .range(log_id.index()..)
-      .map(|(k, _v)| *k)
+  async fn truncate(&mut self, log_id: LogId) -> Result<(), StorageError> {
+    let removed = self.index.split_off(&log_id.index());
+
+    let Some(first_removed) = removed.values().next() else {
+      return Ok(());
+    };
+
+    // Drop whole segments newest first, so a crash part way leaves a contiguous log.
+    let later = self
+      .segments
+      .range(first_removed.segment + 1..)
+      .map(|(k, _)| *k)
+      .rev()
       .collect::<Vec<_>>();
-    for key in keys {
-      self.log.remove(&key);
+
+    for first in later {
+      if let Some(segment) = self.segments.remove(&first) {
+        segment.remove().map_err(|e| StorageError::write_logs(&e))?;
+      }
+    }
+
+    if first_removed.offset == 0 {
+      if let Some(segment) = self.segments.remove(&first_removed.segment) {
+        segment.remove().map_err(|e| StorageError::write_logs(&e))?;
+      }
+    } else if let Some(segment) = self.segments.get_mut(&first_removed.segment) {
+      segment
+        .truncate(first_removed.offset)
+        .map_err(|e| StorageError::write_logs(&e))?;
     }
 
     Ok(())
   }
 
-  async fn purge(&mut self, log_id: LogIdOf<C>) -> Result<(), StorageError<C>> {
-    {
-      let ld = &mut self.last_purged_log_id;
-      assert!(ld.as_ref() <= Some(&log_id));
-      *ld = Some(log_id.clone());
+  async fn purge(&mut self, log_id: LogId) -> Result<(), StorageError> {
+    assert!(self.last_purged_log_id.as_ref() <= Some(&log_id));
+
+    // Persist the purge point first; entries below it are ignored on recovery.
+    file::write_message(&self.dir.join(PURGED_FILE), &pb::LogId::from(log_id))
+      .map_err(|e| StorageError::write_logs(&e))?;
+    self.last_purged_log_id = Some(log_id);
+
+    self.index = self.index.split_off(&(log_id.index() + 1));
+
+    // A segment can be deleted once none of its entries are referenced anymore.
+    let live = self
+      .index
+      .values()
+      .map(|pos| pos.segment)
+      .collect::<std::collections::BTreeSet<_>>();
+    let dead = self
+      .segments
+      .keys()
+      .filter(|first| !live.contains(first) && **first <= log_id.index())
+      .copied()
+      .collect::<Vec<_>>();
+
+    for first in dead {
+      if let Some(segment) = self.segments.remove(&first) {
+        segment.remove().map_err(|e| StorageError::write_logs(&e))?;
+      }
     }
 
-    {
-      let keys = self
-        .log
-        .range(..=log_id.index())
-        .map(|(k, _v)| *k)
-        .collect::<Vec<_>>();
-      for key in keys {
-        self.log.remove(&key);
+    // The log is purged after a snapshot, which is when keys retired by a rotation are replaced.
+    if let Some(keyring) = &self.keyring {
+      for segment in self.segments.values_mut() {
+        segment
+          .rewrap(keyring)
+          .map_err(|e| StorageError::write_logs(&e))?;
       }
     }
 
@@ -149,78 +389,65 @@ mod impl_log_store {
   use std::fmt::Debug;
   use std::ops::RangeBounds;
 
-  use openraft::alias::LogIdOf;
-  use openraft::alias::VoteOf;
-  use openraft::storage::IOFlushed;
-  use openraft::storage::RaftLogStorage;
-  use openraft::LogState;
   use openraft::RaftLogReader;
-  use openraft::RaftTypeConfig;
-  use openraft::StorageError;
+  use openraft::storage::RaftLogStorage;
 
   use super::LogStore;
+  use crate::TypeConfig;
+  use crate::raft_types::*;
 
-  impl<C: RaftTypeConfig> RaftLogReader<C> for LogStore<C>
-  where
-    C::Entry: Clone,
-  {
+  impl RaftLogReader<TypeConfig> for LogStore {
     async fn try_get_log_entries<RB: RangeBounds<u64> + Clone + Debug>(
       &mut self,
       range: RB,
-    ) -> Result<Vec<C::Entry>, StorageError<C>> {
+    ) -> Result<Vec<Entry>, StorageError> {
       let mut inner = self.inner.lock().await;
       inner.try_get_log_entries(range).await
     }
 
-    async fn read_vote(&mut self) -> Result<Option<VoteOf<C>>, StorageError<C>> {
+    async fn read_vote(&mut self) -> Result<Option<Vote>, StorageError> {
       let mut inner = self.inner.lock().await;
       inner.read_vote().await
     }
   }
 
-  impl<C: RaftTypeConfig> RaftLogStorage<C> for LogStore<C>
-  where
-    C::Entry: Clone,
-  {
+  impl RaftLogStorage<TypeConfig> for LogStore {
     type LogReader = Self;
 
-    async fn get_log_state(&mut self) -> Result<LogState<C>, StorageError<C>> {
+    async fn get_log_state(&mut self) -> Result<LogState, StorageError> {
       let mut inner = self.inner.lock().await;
       inner.get_log_state().await
     }
 
-    async fn save_committed(
-      &mut self,
-      committed: Option<LogIdOf<C>>,
-    ) -> Result<(), StorageError<C>> {
+    async fn save_committed(&mut self, committed: Option<LogId>) -> Result<(), StorageError> {
       let mut inner = self.inner.lock().await;
       inner.save_committed(committed).await
     }
 
-    async fn read_committed(&mut self) -> Result<Option<LogIdOf<C>>, StorageError<C>> {
+    async fn read_committed(&mut self) -> Result<Option<LogId>, StorageError> {
       let mut inner = self.inner.lock().await;
       inner.read_committed().await
     }
 
-    async fn save_vote(&mut self, vote: &VoteOf<C>) -> Result<(), StorageError<C>> {
+    async fn save_vote(&mut self, vote: &Vote) -> Result<(), StorageError> {
       let mut inner = self.inner.lock().await;
       inner.save_vote(vote).await
     }
 
-    async fn append<I>(&mut self, entries: I, callback: IOFlushed<C>) -> Result<(), StorageError<C>>
+    async fn append<I>(&mut self, entries: I, callback: IOFlushed) -> Result<(), StorageError>
     where
-      I: IntoIterator<Item = C::Entry>,
+      I: IntoIterator<Item = Entry>,
     {
       let mut inner = self.inner.lock().await;
       inner.append(entries, callback).await
     }
 
-    async fn truncate(&mut self, log_id: LogIdOf<C>) -> Result<(), StorageError<C>> {
+    async fn truncate(&mut self, log_id: LogId) -> Result<(), StorageError> {
       let mut inner = self.inner.lock().await;
       inner.truncate(log_id).await
     }
 
-    async fn purge(&mut self, log_id: LogIdOf<C>) -> Result<(), StorageError<C>> {
+    async fn purge(&mut self, log_id: LogId) -> Result<(), StorageError> {
       let mut inner = self.inner.lock().await;
       inner.purge(log_id).await
     }
@@ -1,4 +1,6 @@
 use std::fmt::Debug;
+use std::io;
+use std::path::Path;
 use std::sync::Arc;
 use std::sync::Mutex;
 
@@ -9,30 +11,99 @@ use openraft::RaftSnapshotBuilder;
 use crate::protobuf as pb;
 use crate::protobuf::Response;
 use crate::raft_types::*;
+use crate::telemetry;
 use crate::TypeConfig;
 
+pub mod backup;
+pub mod command;
+pub mod crypto;
+mod file;
+pub mod lease;
 pub mod log_store;
-pub type LogStore = log_store::LogStore<TypeConfig>;
-
-#[derive(Debug)]
-pub struct StoredSnapshot {
-  pub meta: SnapshotMeta,
-
-  /// The data of the state machine at the time of this snapshot.
-  pub data: SnapshotData,
-}
+pub mod mvcc;
+pub mod namespace;
+pub mod policy;
+pub mod range;
+mod segment;
+pub mod snapshot;
+pub mod watch;
+
+pub use crypto::Keyring;
+pub use log_store::LogStore;
+pub use snapshot::SnapshotFile;
+use snapshot::SnapshotStore;
+use snapshot::SnapshotWriter;
+use watch::Subscription;
+use watch::WatchHub;
+
+/// Directory of the Raft log, within the data directory.
+pub const LOG_DIR: &str = "log";
+
+/// Directory of the state machine snapshots, within the data directory.
+pub const SNAPSHOT_DIR: &str = "snapshots";
 
 /// Defines a state machine for the Raft cluster. This state machine represents a copy of the
 /// data for this node. Additionally, it is responsible for storing the last snapshot of the data.
-#[derive(Debug, Default)]
+#[derive(Debug)]
 pub struct StateMachineStore {
   /// The Raft state machine.
   pub state_machine: Mutex<pb::StateMachineData>,
 
   snapshot_idx: Mutex<u64>,
 
-  /// The last received snapshot.
-  current_snapshot: Mutex<Option<StoredSnapshot>>,
+  /// Snapshots persisted in the data directory.
+  snapshots: SnapshotStore,
+
+  /// Watchers of the changes made by applied entries.
+  watchers: WatchHub,
+}
+
+impl StateMachineStore {
+  /// Opens the snapshot directory and restores the state machine from the newest snapshot in it.
+  ///
+  /// Entries applied after that snapshot are replayed by Raft from the log on startup. With a
+  /// keyring, new snapshots are encrypted.
+  pub async fn open(
+    dir: impl AsRef<Path>,
+    snapshots_to_keep: usize,
+    keyring: Option<Arc<Keyring>>,
+  ) -> io::Result<Self> {
+    let snapshots = SnapshotStore::open(dir, snapshots_to_keep, keyring)?;
+
+    let state_machine = match snapshots.latest()? {
+      Some((meta, snapshot)) => {
+        tracing::info!("Restoring state machine from snapshot {}", meta.snapshot_id);
+        snapshot.decode().await?
+      }
+      None => pb::StateMachineData::default(),
+    };
+
+    let watchers = WatchHub::new(state_machine.revision);
+
+    Ok(StateMachineStore {
+      state_machine: Mutex::new(state_machine),
+      snapshot_idx: Mutex::new(0),
+      snapshots,
+      watchers,
+    })
+  }
+
+  /// Subscribes to the changes made at `start_revision` and after, 0 for new changes only.
+  ///
+  /// Returns `None` if those changes are no longer retained.
+  pub fn watch(&self, start_revision: i64) -> Option<Subscription> {
+    self.watchers.subscribe(start_revision)
+  }
+
+  /// Returns the leases whose deadline has passed at `now`.
+  pub fn expired_leases(&self, now: u64) -> Vec<i64> {
+    lease::expired(&self.state_machine.lock().unwrap(), now)
+  }
+
+  /// Starts streaming a snapshot sent by the leader to disk.
+  pub async fn receive_snapshot(&self) -> io::Result<SnapshotWriter> {
+    self.snapshots.receive().await
+  }
 }
 
 impl RaftSnapshotBuilder<TypeConfig> for Arc<StateMachineStore> {
@@ -57,6 +128,7 @@ impl RaftSnapshotBuilder<TypeConfig> for Arc<StateMachineStore> {
         .into();
       last_membership = StoredMembership::new(last_membership_log_id, membership);
 
+      // Compaction drops old revisions as it is applied, so only retained history is written.
       data = prost::Message::encode_to_vec(&state_machine);
     }
 
@@ -83,21 +155,13 @@ impl RaftSnapshotBuilder<TypeConfig> for Arc<StateMachineStore> {
       snapshot_id,
     };
 
-    let stored = StoredSnapshot {
-      meta: meta.clone(),
-      data: data.clone(),
-    };
+    // Raft purges the log up to the snapshot once this returns, so it must be durable first.
+    let snapshot = self
+      .snapshots
+      .save(&meta, &data)
+      .map_err(|e| StorageError::write_snapshot(None, &e))?;
 
-    // Emulation of storing snapshot locally
-    {
-      let mut current_snapshot = self.current_snapshot.lock().unwrap();
-      *current_snapshot = Some(stored);
-    }
-
-    Ok(Snapshot {
-      meta,
-      snapshot: data,
-    })
+    Ok(Snapshot { meta, snapshot })
   }
 }
 
@@ -133,25 +197,49 @@ impl RaftStateMachine<TypeConfig> for Arc<StateMachineStore> {
 
       sm.last_applied = Some(log_id.into());
 
-      let value = if let Some(req) = entry.app_data {
-        sm.data.insert(req.key, req.value.clone());
-        Some(req.value)
+      let response = if let Some(cmd) = entry.app_data {
+        // Every node applies the command as part of the trace of the request that proposed it
+        let span = tracing::info_span!("apply", log_index = log_id.index());
+        telemetry::set_parent(&span, &cmd.trace_context);
+        let _entered = span.enter();
+
+        let mut events = Vec::new();
+        let response = command::apply(&mut sm, cmd, &mut events);
+
+        // Published while holding the lock, so watchers see changes in revision order.
+        if !events.is_empty() {
+          self.watchers.publish(pb::WatchResponse {
+            revision: sm.revision,
+            events,
+          });
+        }
+
+        response
       } else if let Some(mem) = entry.membership {
         sm.last_membership_log_id = Some(log_id.into());
         sm.last_membership = Some(mem);
-        None
+        Response::default()
       } else {
-        None
+        Response::default()
       };
 
-      res.push(Response { value });
+      res.push(response);
     }
     Ok(res)
   }
 
   #[tracing::instrument(level = "trace", skip(self))]
   async fn begin_receiving_snapshot(&mut self) -> Result<SnapshotData, StorageError> {
-    Ok(Default::default())
+    // Nothing is written to it, and the staging file goes away with the returned snapshot
+    let writer = self
+      .receive_snapshot()
+      .await
+      .map_err(|e| StorageError::write_snapshot(None, &e))?;
+
+    writer
+      .finish(0, crc32fast::hash(&[]))
+      .await
+      .map_err(|e| StorageError::write_snapshot(None, &e))
   }
 
   #[tracing::instrument(level = "trace", skip(self, snapshot))]
@@ -162,38 +250,34 @@ impl RaftStateMachine<TypeConfig> for Arc<StateMachineStore> {
   ) -> Result<(), StorageError> {
     tracing::info!("install snapshot");
 
-    let new_snapshot = StoredSnapshot {
-      meta: meta.clone(),
-      data: snapshot,
-    };
+    // The data is checked against the checksum announced by the leader before it is applied.
+    let d: pb::StateMachineData = snapshot
+      .decode()
+      .await
+      .map_err(|e| StorageError::read_snapshot(None, &e))?;
 
-    // Update the state machine.
-    {
-      let d: pb::StateMachineData = prost::Message::decode(new_snapshot.data.as_ref())
-        .map_err(|e| StorageError::read_snapshot(None, &e))?;
+    // Keep the received snapshot, so a restart does not have to fetch it again.
+    self
+      .snapshots
+      .install(meta, &snapshot)
+      .map_err(|e| StorageError::write_snapshot(None, &e))?;
 
-      let mut state_machine = self.state_machine.lock().unwrap();
-      *state_machine = d;
-    }
+    // Update the state machine. Watchers cannot be told what changed, so they start over.
+    let mut state_machine = self.state_machine.lock().unwrap();
+    self.watchers.reset(d.revision);
+    *state_machine = d;
 
-    // Update current snapshot.
-    let mut current_snapshot = self.current_snapshot.lock().unwrap();
-    *current_snapshot = Some(new_snapshot);
     Ok(())
   }
 
   #[tracing::instrument(level = "trace", skip(self))]
   async fn get_current_snapshot(&mut self) -> Result<Option<Snapshot>, StorageError> {
-    match &*self.current_snapshot.lock().unwrap() {
-      Some(snapshot) => {
-        let data = snapshot.data.clone();
-        Ok(Some(Snapshot {
-          meta: snapshot.meta.clone(),
-          snapshot: data,
-        }))
-      }
-      None => Ok(None),
-    }
+    let latest = self
+      .snapshots
+      .latest()
+      .map_err(|e| StorageError::read_snapshot(None, &e))?;
+
+    Ok(latest.map(|(meta, snapshot)| Snapshot { meta, snapshot }))
   }
 
   async fn get_snapshot_builder(&mut self) -> Self::SnapshotBuilder {
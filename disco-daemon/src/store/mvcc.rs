@@ -0,0 +1,304 @@
+//! Multi-version storage of the keys in the state machine.
+//!
+//! Every change to a key is kept as a record tagged with the revision of the command that made
+//! it, oldest first; a deletion is a record without a value. A read at a past revision sees the
+//! last record at or before it. Compaction drops the records that no read at or after the
+//! compaction revision can see, and is the only way history is ever discarded.
+
+use super::namespace;
+use crate::protobuf as pb;
+
+/// Revision assigned to the changes of the command being applied.
+///
+/// The store revision only advances once the whole command has been applied, so all of its
+/// changes share the same revision.
+fn pending_revision(sm: &pb::StateMachineData) -> i64 {
+  sm.revision + 1
+}
+
+/// Returns the current state of a key, `None` if it does not exist.
+pub fn latest<'a>(sm: &'a pb::StateMachineData, key: &str) -> Option<&'a pb::KeyRevision> {
+  sm.keys
+    .get(key)
+    .and_then(|history| history.revisions.last())
+    .filter(|record| record.value.is_some())
+}
+
+/// Returns the state of a key as of `revision`, `None` if it did not exist then.
+///
+/// Revisions before the compaction revision may have lost their records; callers check the
+/// revision against [`pb::StateMachineData::compact_revision`] first.
+pub fn at<'a>(
+  sm: &'a pb::StateMachineData,
+  key: &str,
+  revision: i64,
+) -> Option<&'a pb::KeyRevision> {
+  visible(sm.keys.get(key)?, revision).filter(|record| record.value.is_some())
+}
+
+fn visible(history: &pb::KeyHistory, revision: i64) -> Option<&pb::KeyRevision> {
+  let n = history
+    .revisions
+    .partition_point(|record| record.mod_revision <= revision);
+
+  n.checked_sub(1).map(|i| &history.revisions[i])
+}
+
+/// Length of the current value of a key, `None` if it does not exist.
+fn live_len(history: &pb::KeyHistory) -> Option<usize> {
+  history
+    .revisions
+    .last()
+    .and_then(|record| record.value.as_ref())
+    .map(String::len)
+}
+
+/// Returns the retained changes of a key, oldest first.
+pub fn history<'a>(sm: &'a pb::StateMachineData, key: &str) -> &'a [pb::KeyRevision] {
+  sm.keys
+    .get(key)
+    .map(|history| history.revisions.as_slice())
+    .unwrap_or_default()
+}
+
+/// Converts a record into the representation sent to clients.
+pub fn key_value(key: &str, record: &pb::KeyRevision) -> pb::KeyValue {
+  pb::KeyValue {
+    key: key.to_string(),
+    value: record.value.clone().unwrap_or_default(),
+    mod_revision: record.mod_revision,
+    create_revision: record.create_revision,
+    version: record.version,
+  }
+}
+
+/// Writes a new value for a key, or deletes it if `value` is `None`, at the pending revision.
+///
+/// A key changed more than once by the same command keeps only its last change. The record
+/// replaced that way is returned so that [`undo`] can restore it. The usage of the namespace of
+/// the key is updated, but its quotas are left for the caller to check.
+pub fn put(
+  sm: &mut pb::StateMachineData,
+  key: &str,
+  value: Option<String>,
+) -> Option<pb::KeyRevision> {
+  let revision = pending_revision(sm);
+  let history = sm.keys.entry(key.to_string()).or_default();
+  let prev_len = live_len(history);
+
+  let replaced = match history.revisions.last() {
+    Some(last) if last.mod_revision == revision => history.revisions.pop(),
+    _ => None,
+  };
+
+  let prev = history
+    .revisions
+    .last()
+    .filter(|record| record.value.is_some());
+
+  let record = match (&value, prev) {
+    (Some(_), Some(prev)) => Some(pb::KeyRevision {
+      value,
+      create_revision: prev.create_revision,
+      mod_revision: revision,
+      version: prev.version + 1,
+    }),
+    (Some(_), None) => Some(pb::KeyRevision {
+      value,
+      create_revision: revision,
+      mod_revision: revision,
+      version: 1,
+    }),
+    (None, Some(_)) => Some(pb::KeyRevision {
+      value: None,
+      create_revision: 0,
+      mod_revision: revision,
+      version: 0,
+    }),
+    // Deleting a key created by the same command leaves no trace of it.
+    (None, None) => None,
+  };
+
+  history.revisions.extend(record);
+  let new_len = live_len(history);
+
+  if history.revisions.is_empty() {
+    sm.keys.remove(key);
+  }
+
+  namespace::charge(sm, key, prev_len, new_len);
+
+  replaced
+}
+
+/// Reverts a [`put`] made at the pending revision, restoring the record it replaced.
+pub fn undo(sm: &mut pb::StateMachineData, key: &str, replaced: Option<pb::KeyRevision>) {
+  let revision = pending_revision(sm);
+  let history = sm.keys.entry(key.to_string()).or_default();
+  let prev_len = live_len(history);
+
+  if history
+    .revisions
+    .last()
+    .is_some_and(|record| record.mod_revision == revision)
+  {
+    history.revisions.pop();
+  }
+
+  history.revisions.extend(replaced);
+  let new_len = live_len(history);
+
+  if history.revisions.is_empty() {
+    sm.keys.remove(key);
+  }
+
+  namespace::charge(sm, key, prev_len, new_len);
+}
+
+/// Discards the changes that are no longer visible at the requested revision or after it.
+///
+/// Fails if the revision is not after the current compaction revision or lies in the future.
+pub fn compact(sm: &mut pb::StateMachineData, req: pb::CompactRequest) -> pb::Response {
+  if req.revision <= sm.compact_revision || req.revision > sm.revision {
+    return pb::Response::default();
+  }
+
+  sm.keys.retain(|_, history| {
+    let visible = history
+      .revisions
+      .partition_point(|record| record.mod_revision <= req.revision);
+
+    // The record visible at the compaction revision is kept unless it is a deletion, which
+    // hides nothing once the records before it are gone.
+    let keep_from = match visible.checked_sub(1) {
+      Some(i) if history.revisions[i].value.is_none() => visible,
+      Some(i) => i,
+      None => 0,
+    };
+
+    history.revisions.drain(..keep_from);
+    !history.revisions.is_empty()
+  });
+
+  tracing::info!("Compacted history before revision {}", req.revision);
+  sm.compact_revision = req.revision;
+
+  pb::Response {
+    success: true,
+    ..Default::default()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::store::command::tests::{cas, delete, run, set};
+
+  /// Writes `a` at revisions 1 and 2, `b` at 3, deletes `a` at 4 and recreates it at 5.
+  fn store() -> pb::StateMachineData {
+    let mut sm = pb::StateMachineData::default();
+    run(&mut sm, set("a", "1"));
+    run(&mut sm, set("a", "2"));
+    run(&mut sm, set("b", "1"));
+    run(&mut sm, delete("a"));
+    run(&mut sm, set("a", "3"));
+    sm
+  }
+
+  fn value(record: Option<&pb::KeyRevision>) -> Option<&str> {
+    record.and_then(|record| record.value.as_deref())
+  }
+
+  #[test]
+  fn test_reads_at_past_revisions() {
+    let sm = store();
+    assert_eq!(sm.revision, 5);
+
+    assert_eq!(value(at(&sm, "a", 0)), None);
+    assert_eq!(value(at(&sm, "a", 1)), Some("1"));
+    assert_eq!(value(at(&sm, "a", 3)), Some("2"));
+    assert_eq!(value(at(&sm, "a", 4)), None);
+    assert_eq!(value(at(&sm, "a", 5)), Some("3"));
+
+    let record = at(&sm, "a", 2).unwrap();
+    assert_eq!(record.create_revision, 1);
+    assert_eq!(record.mod_revision, 2);
+    assert_eq!(record.version, 2);
+
+    // Recreating a deleted key starts over
+    let record = latest(&sm, "a").unwrap();
+    assert_eq!(record.create_revision, 5);
+    assert_eq!(record.version, 1);
+    assert_eq!(history(&sm, "a").len(), 4);
+  }
+
+  #[test]
+  fn test_one_record_per_command() {
+    let mut sm = store();
+
+    let batch = pb::BatchRequest {
+      operations: vec![set("c", "1").into(), set("c", "2").into()],
+    };
+    assert!(run(&mut sm, batch).success);
+    assert_eq!(history(&sm, "c").len(), 1);
+    assert_eq!(latest(&sm, "c").unwrap().version, 1);
+
+    // A key created and deleted by the same command leaves no history
+    let batch = pb::BatchRequest {
+      operations: vec![set("d", "1").into(), delete("d").into()],
+    };
+    assert!(run(&mut sm, batch).success);
+    assert!(history(&sm, "d").is_empty());
+    assert!(!sm.keys.contains_key("d"));
+  }
+
+  #[test]
+  fn test_undo_restores_history() {
+    let mut sm = store();
+
+    let batch = pb::BatchRequest {
+      operations: vec![
+        set("a", "4").into(),
+        set("a", "5").into(),
+        set("c", "1").into(),
+        cas("b", Some("2"), Some("3")).into(),
+      ],
+    };
+    assert!(!run(&mut sm, batch).success);
+
+    assert_eq!(history(&sm, "a").len(), 4);
+    assert_eq!(value(latest(&sm, "a")), Some("3"));
+    assert!(!sm.keys.contains_key("c"));
+  }
+
+  #[test]
+  fn test_compact() {
+    let mut sm = store();
+
+    assert!(!compact(&mut sm, pb::CompactRequest { revision: 6 }).success);
+    assert!(compact(&mut sm, pb::CompactRequest { revision: 3 }).success);
+    assert_eq!(sm.compact_revision, 3);
+
+    // The record visible at the compaction revision stays readable
+    assert_eq!(value(at(&sm, "a", 3)), Some("2"));
+    assert_eq!(history(&sm, "a").len(), 3);
+    assert_eq!(history(&sm, "b").len(), 1);
+
+    assert!(!compact(&mut sm, pb::CompactRequest { revision: 3 }).success);
+
+    // A deletion visible at the compaction revision is dropped with what it hid
+    assert!(compact(&mut sm, pb::CompactRequest { revision: 4 }).success);
+    assert_eq!(history(&sm, "a").len(), 1);
+    assert_eq!(value(latest(&sm, "a")), Some("3"));
+  }
+
+  #[test]
+  fn test_compact_drops_deleted_keys() {
+    let mut sm = store();
+    run(&mut sm, delete("b"));
+
+    assert!(compact(&mut sm, pb::CompactRequest { revision: 6 }).success);
+    assert!(!sm.keys.contains_key("b"));
+    assert_eq!(sm.keys.len(), 1);
+  }
+}
@@ -0,0 +1,362 @@
+//! Namespaces: isolated keyspaces sharing the state machine.
+//!
+//! The keys of a namespace are stored in the shared map as `\0<namespace>\0<key>`, while the
+//! default namespace, named by the empty string, stores its keys as they are. Namespace names
+//! cannot contain `\0` and keys of the default namespace cannot start with it, so a request can
+//! only ever reach the keys of its own namespace.
+//!
+//! The usage of every namespace is updated as its keys change. Writes that would take a namespace
+//! over one of its quotas are rejected when applied, so every node rejects the same writes.
+
+use std::ops::Bound;
+
+use super::command;
+use super::mvcc;
+use crate::protobuf as pb;
+
+/// Separates the namespace from the key in a stored key.
+const SEPARATOR: char = '\0';
+
+/// Lower bound of the stored keys of the default namespace, which sort after those of every other
+/// namespace. Only the empty key would sort before it, and [`validate_write`] rejects it.
+pub const DEFAULT_START: &str = "\u{1}";
+
+/// Returns the key under which `key` of `namespace` is stored.
+pub fn key(namespace: &str, key: &str) -> String {
+  if namespace.is_empty() {
+    key.to_string()
+  } else {
+    format!("{}{}{}{}", SEPARATOR, namespace, SEPARATOR, key)
+  }
+}
+
+/// Splits a stored key into its namespace and the key within it.
+pub fn split(stored: &str) -> (&str, &str) {
+  stored
+    .strip_prefix(SEPARATOR)
+    .and_then(|rest| rest.split_once(SEPARATOR))
+    .unwrap_or(("", stored))
+}
+
+/// Checks that `key` can be addressed within `namespace`.
+pub fn validate(namespace: &str, key: &str) -> Result<(), String> {
+  if namespace.contains(SEPARATOR) {
+    return Err(format!(
+      "Namespace {:?} contains a NUL character",
+      namespace
+    ));
+  }
+
+  if namespace.is_empty() && key.starts_with(SEPARATOR) {
+    return Err(format!("Key {:?} starts with a NUL character", key));
+  }
+
+  Ok(())
+}
+
+/// Checks that `key` can be written within `namespace`: on top of what [`validate`] checks, the
+/// key must not be empty.
+pub fn validate_write(namespace: &str, key: &str) -> Result<(), String> {
+  validate(namespace, key)?;
+
+  if key.is_empty() {
+    return Err("Keys cannot be empty".to_string());
+  }
+
+  Ok(())
+}
+
+/// Size of a key and its value, as counted against the `max_bytes` quota.
+fn size(stored: &str, value_len: usize) -> i64 {
+  (split(stored).1.len() + value_len) as i64
+}
+
+/// Change in key count and bytes when the value of a key goes from `prev` to `new`, given as the
+/// lengths of the values and `None` for a missing key.
+fn delta(stored: &str, prev: Option<usize>, new: Option<usize>) -> (i64, i64) {
+  let usage = |len: Option<usize>| len.map_or((0, 0), |len| (1, size(stored, len)));
+  let (prev_keys, prev_bytes) = usage(prev);
+  let (new_keys, new_bytes) = usage(new);
+
+  (new_keys - prev_keys, new_bytes - prev_bytes)
+}
+
+/// Whether the quotas of the namespace of a key allow its value to go from `prev` to `new`.
+///
+/// A write that does not grow the usage is always allowed, so a namespace over a lowered quota
+/// can still be cleaned up.
+pub fn fits(
+  sm: &pb::StateMachineData,
+  stored: &str,
+  prev: Option<usize>,
+  new: Option<usize>,
+) -> bool {
+  let Some(ns) = sm.namespaces.get(split(stored).0) else {
+    return true;
+  };
+
+  let (keys, bytes) = delta(stored, prev, new);
+  let exceeds = |delta: i64, used: i64, max: i64| delta > 0 && max > 0 && used + delta > max;
+
+  !exceeds(keys, ns.keys, ns.max_keys) && !exceeds(bytes, ns.bytes, ns.max_bytes)
+}
+
+/// Records the change in usage when the value of a key goes from `prev` to `new`.
+pub fn charge(
+  sm: &mut pb::StateMachineData,
+  stored: &str,
+  prev: Option<usize>,
+  new: Option<usize>,
+) {
+  let (keys, bytes) = delta(stored, prev, new);
+  if keys == 0 && bytes == 0 {
+    return;
+  }
+
+  let name = split(stored).0;
+  let ns = sm
+    .namespaces
+    .entry(name.to_string())
+    .or_insert_with(|| pb::Namespace {
+      name: name.to_string(),
+      ..Default::default()
+    });
+
+  ns.keys += keys;
+  ns.bytes += bytes;
+
+  forget_if_unused(sm, name);
+}
+
+/// Drops a namespace that has neither keys nor quotas, so that it is no longer listed.
+fn forget_if_unused(sm: &mut pb::StateMachineData, name: &str) {
+  if sm
+    .namespaces
+    .get(name)
+    .is_some_and(|ns| ns.keys == 0 && ns.max_keys == 0 && ns.max_bytes == 0)
+  {
+    sm.namespaces.remove(name);
+  }
+}
+
+/// Sets the quotas of a namespace, creating it if needed. Keys already over a lowered quota are
+/// kept, but no longer allowed to grow.
+pub fn set_quotas(sm: &mut pb::StateMachineData, req: pb::NamespaceRequest) -> pb::Response {
+  let ns = sm
+    .namespaces
+    .entry(req.name.clone())
+    .or_insert_with(|| pb::Namespace {
+      name: req.name.clone(),
+      ..Default::default()
+    });
+
+  ns.max_keys = req.max_keys;
+  ns.max_bytes = req.max_bytes;
+
+  forget_if_unused(sm, &req.name);
+
+  pb::Response {
+    success: true,
+    ..Default::default()
+  }
+}
+
+/// Deletes every key of a namespace along with its quotas.
+///
+/// The default namespace cannot be deleted, as its keys are not confined to a prefix.
+pub fn delete(
+  sm: &mut pb::StateMachineData,
+  req: pb::DeleteNamespaceRequest,
+  events: &mut Vec<pb::Event>,
+) -> pb::Response {
+  if req.name.is_empty() {
+    return pb::Response::default();
+  }
+
+  // Every namespace with keys is known, so this tells whether there was anything to delete.
+  let success = sm.namespaces.contains_key(&req.name);
+
+  let prefix = key(&req.name, "");
+  let keys = sm
+    .keys
+    .range::<str, _>((Bound::Included(prefix.as_str()), Bound::Unbounded))
+    .take_while(|(key, _)| key.starts_with(&prefix))
+    .filter(|(key, _)| mvcc::latest(sm, key).is_some())
+    .map(|(key, _)| key.clone())
+    .collect::<Vec<_>>();
+
+  for key in keys {
+    sm.key_leases.remove(&key);
+    mvcc::put(sm, &key, None);
+    events.push(command::event(key, None));
+  }
+
+  sm.namespaces.remove(&req.name);
+
+  tracing::info!("Deleted namespace {}", req.name);
+
+  pb::Response {
+    success,
+    ..Default::default()
+  }
+}
+
+/// Lists the namespaces that have keys or quotas, ordered by name.
+pub fn list(sm: &pb::StateMachineData) -> Vec<pb::Namespace> {
+  sm.namespaces.values().cloned().collect()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::store::command::tests::{run, value};
+  use crate::store::range::range;
+
+  fn set(namespace: &str, key: &str, value: &str) -> pb::SetRequest {
+    pb::SetRequest {
+      namespace: namespace.to_string(),
+      ..command::tests::set(key, value)
+    }
+  }
+
+  fn quotas(name: &str, max_keys: i64, max_bytes: i64) -> pb::NamespaceRequest {
+    pb::NamespaceRequest {
+      name: name.to_string(),
+      max_keys,
+      max_bytes,
+    }
+  }
+
+  fn usage(sm: &pb::StateMachineData, name: &str) -> (i64, i64) {
+    let ns = &sm.namespaces[name];
+    (ns.keys, ns.bytes)
+  }
+
+  #[test]
+  fn test_keys() {
+    assert_eq!(key("", "k"), "k");
+    assert_eq!(split(&key("", "k")), ("", "k"));
+    assert_eq!(split(&key("a", "k")), ("a", "k"));
+    assert_eq!(split(&key("a", "")), ("a", ""));
+
+    assert!(validate("", "\0k").is_err());
+    assert!(validate("a\0", "k").is_err());
+    assert!(validate("a", "\0k").is_ok());
+
+    // The empty key can be read or used as a prefix, but not written
+    assert!(validate("", "").is_ok());
+    assert!(validate_write("", "").is_err());
+    assert!(validate_write("a", "").is_err());
+    assert!(validate_write("", "\0k").is_err());
+    assert!(validate_write("a", "k").is_ok());
+
+    // Stored keys of other namespaces sort before those of the default one
+    assert!(key("zz", "k").as_str() < DEFAULT_START);
+  }
+
+  #[test]
+  fn test_isolation() {
+    let mut sm = pb::StateMachineData::default();
+    let mut events = Vec::new();
+    for (ns, value) in [("", "root"), ("a", "a"), ("ab", "ab")] {
+      assert!(command::apply(&mut sm, set(ns, "k", value).into(), &mut events).success);
+    }
+
+    assert_eq!(events[1].namespace, "a");
+    assert_eq!(events[1].key, "k");
+    assert_eq!(value(&sm, "k").as_deref(), Some("root"));
+    assert_eq!(value(&sm, &key("a", "k")).as_deref(), Some("a"));
+
+    let listed = |ns: &str| {
+      let response = range(&sm, ns, "", "", 0, sm.revision);
+      response
+        .kvs
+        .into_iter()
+        .map(|kv| kv.value)
+        .collect::<Vec<_>>()
+    };
+    assert_eq!(listed(""), vec!["root"]);
+    assert_eq!(listed("a"), vec!["a"]);
+    assert_eq!(listed("ab"), vec!["ab"]);
+
+    let names = list(&sm).into_iter().map(|ns| ns.name).collect::<Vec<_>>();
+    assert_eq!(names, vec!["", "a", "ab"]);
+  }
+
+  #[test]
+  fn test_quotas() {
+    let mut sm = pb::StateMachineData::default();
+    run(&mut sm, set("a", "k", "aa"));
+    assert_eq!(usage(&sm, "a"), (1, 3));
+
+    assert!(run(&mut sm, quotas("a", 2, 8)).success);
+    assert!(run(&mut sm, set("a", "l", "x")).success);
+    assert_eq!(usage(&sm, "a"), (2, 5));
+
+    let response = run(&mut sm, set("a", "m", "x"));
+    assert!(!response.success);
+    assert!(response.quota_exceeded);
+
+    let response = run(&mut sm, set("a", "l", "xyzwv"));
+    assert!(!response.success);
+    assert!(response.quota_exceeded);
+    assert!(run(&mut sm, set("a", "l", "xyzw")).success);
+    assert_eq!(usage(&sm, "a"), (2, 8));
+
+    // A failed batch gives back the usage of the operations before the failing one
+    let batch = pb::BatchRequest {
+      operations: vec![
+        pb::DeleteRequest {
+          key: "l".to_string(),
+          namespace: "a".to_string(),
+        }
+        .into(),
+        set("a", "m", "too long to fit").into(),
+      ],
+    };
+    assert!(run(&mut sm, batch).quota_exceeded);
+    assert_eq!(usage(&sm, "a"), (2, 8));
+
+    // Lowered quotas still allow writes that shrink the usage
+    run(&mut sm, quotas("a", 1, 1));
+    assert!(run(&mut sm, set("a", "l", "x")).success);
+    assert!(!run(&mut sm, set("a", "l", "xy")).success);
+  }
+
+  #[test]
+  fn test_unused_namespaces_are_forgotten() {
+    let mut sm = pb::StateMachineData::default();
+    run(&mut sm, set("a", "k", "1"));
+    run(&mut sm, quotas("b", 1, 0));
+    assert_eq!(sm.namespaces.len(), 2);
+
+    run(&mut sm, command::tests::delete(&key("a", "k")));
+    run(&mut sm, quotas("b", 0, 0));
+    assert!(sm.namespaces.is_empty());
+  }
+
+  #[test]
+  fn test_delete() {
+    let mut sm = pb::StateMachineData::default();
+    run(&mut sm, set("a", "k", "1"));
+    run(&mut sm, set("a", "l", "1"));
+    run(&mut sm, set("ab", "k", "1"));
+    run(&mut sm, quotas("a", 10, 0));
+
+    let mut events = Vec::new();
+    let request = pb::DeleteNamespaceRequest {
+      name: "a".to_string(),
+    };
+    assert!(command::apply(&mut sm, request.clone().into(), &mut events).success);
+    assert_eq!(events.len(), 2);
+    assert!(events.iter().all(|event| event.namespace == "a"));
+    assert!(!sm.namespaces.contains_key("a"));
+    assert_eq!(value(&sm, &key("ab", "k")).as_deref(), Some("1"));
+
+    assert!(!run(&mut sm, request).success);
+
+    // The default namespace cannot be deleted
+    let request = pb::DeleteNamespaceRequest::default();
+    assert!(!run(&mut sm, request).success);
+  }
+}
@@ -0,0 +1,85 @@
+//! Policies: the roles granted to the client certificates of each subject.
+//!
+//! Policies are part of the replicated state, so that every node authorizes the same requests.
+//! Changing them does not advance the store revision, as no key changes. Once a policy has been
+//! stored they are enforced for good, even after every policy is deleted.
+
+use crate::protobuf as pb;
+
+/// Grants a role to the certificates of a subject, replacing the policy it had.
+pub fn set(sm: &mut pb::StateMachineData, req: pb::Policy) -> pb::Response {
+  tracing::info!("Granted {:?} to {}", req.role(), req.subject);
+  sm.policies.insert(req.subject.clone(), req);
+  sm.policies_enforced = true;
+
+  pb::Response {
+    success: true,
+    ..Default::default()
+  }
+}
+
+/// Removes the policy of a subject, whose certificates are then denied every request.
+pub fn delete(sm: &mut pb::StateMachineData, req: pb::DeletePolicyRequest) -> pb::Response {
+  let success = sm.policies.remove(&req.subject).is_some();
+
+  pb::Response {
+    success,
+    ..Default::default()
+  }
+}
+
+/// Lists the policies, ordered by subject.
+pub fn list(sm: &pb::StateMachineData) -> Vec<pb::Policy> {
+  sm.policies.values().cloned().collect()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::store::command::tests::run;
+
+  fn policy(subject: &str, role: pb::Role) -> pb::Policy {
+    pb::Policy {
+      subject: subject.to_string(),
+      role: role.into(),
+      prefixes: Vec::new(),
+    }
+  }
+
+  fn delete_policy(subject: &str) -> pb::DeletePolicyRequest {
+    pb::DeletePolicyRequest {
+      subject: subject.to_string(),
+    }
+  }
+
+  #[test]
+  fn test_set_and_delete() {
+    let mut sm = pb::StateMachineData::default();
+    assert!(!sm.policies_enforced);
+
+    assert!(run(&mut sm, policy("monitoring", pb::Role::Reader)).success);
+    assert!(run(&mut sm, policy("deploy", pb::Role::Writer)).success);
+    assert!(run(&mut sm, policy("monitoring", pb::Role::Admin)).success);
+
+    let policies = list(&sm);
+    assert_eq!(policies.len(), 2);
+    assert_eq!(policies[0].subject, "deploy");
+    assert_eq!(policies[1].role(), pb::Role::Admin);
+
+    // Policies do not change any key
+    assert_eq!(sm.revision, 0);
+
+    assert!(run(&mut sm, delete_policy("deploy")).success);
+    assert!(!run(&mut sm, delete_policy("deploy")).success);
+  }
+
+  #[test]
+  fn test_enforced_for_good() {
+    let mut sm = pb::StateMachineData::default();
+    run(&mut sm, policy("monitoring", pb::Role::Reader));
+    run(&mut sm, delete_policy("monitoring"));
+
+    assert!(sm.policies.is_empty());
+    assert!(sm.policies_enforced);
+  }
+}
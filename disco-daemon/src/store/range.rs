@@ -0,0 +1,62 @@
+//! Ordered scans over the keys of the state machine.
+
+use std::ops::Bound;
+
+use super::mvcc;
+use super::namespace;
+use crate::protobuf as pb;
+
+/// Maximum number of keys returned in a single page.
+pub const MAX_LIMIT: u32 = 1000;
+
+/// Lists the keys of `ns` starting with `prefix` that sort after `start_after` as of `revision`,
+/// in lexicographic order.
+///
+/// At most `limit` keys are returned, capped at [`MAX_LIMIT`]; `more` is set if the scan stopped
+/// early. Reading every page at the revision of the first one gives a consistent listing.
+pub fn range(
+  sm: &pb::StateMachineData,
+  ns: &str,
+  prefix: &str,
+  start_after: &str,
+  limit: u32,
+  revision: i64,
+) -> pb::RangeResponse {
+  let limit = match limit {
+    0 => MAX_LIMIT,
+    limit => limit.min(MAX_LIMIT),
+  } as usize;
+
+  let prefix = namespace::key(ns, prefix);
+  let start_after = namespace::key(ns, start_after);
+
+  // The keys of other namespaces sort before those of the default namespace, so listing all of
+  // the latter starts past them rather than skipping them one by one.
+  let first = if prefix.is_empty() {
+    namespace::DEFAULT_START
+  } else {
+    prefix.as_str()
+  };
+
+  // Resuming inside the prefix skips the keys already seen; anything before it is not listed.
+  let start = if start_after.as_str() >= first {
+    Bound::Excluded(start_after.as_str())
+  } else {
+    Bound::Included(first)
+  };
+
+  let mut kvs = sm
+    .keys
+    .range::<str, _>((start, Bound::Unbounded))
+    .take_while(|(key, _)| key.starts_with(&prefix))
+    .filter_map(|(key, _)| {
+      let record = mvcc::at(sm, key, revision)?;
+      Some(mvcc::key_value(namespace::split(key).1, record))
+    });
+
+  pb::RangeResponse {
+    kvs: kvs.by_ref().take(limit).collect(),
+    more: kvs.next().is_some(),
+    revision,
+  }
+}
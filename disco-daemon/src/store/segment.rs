@@ -0,0 +1,465 @@
+//! Append-only segment files backing the Raft log.
+//!
+//! A segment is named after the index of the first entry written to it and holds a sequence of
+//! records framed as `[len: u32][crc32: u32][payload]` (little endian). A torn write at the end of
+//! the newest segment shows up as a short record or a checksum mismatch and is cut off when the
+//! segment is reopened.
+//!
+//! The payloads of an encrypted segment are sealed with its own data key, bound to the offset of
+//! their record. The wrapped data key is kept in a `<first-index>.key` file next to the segment.
+
+use std::fs;
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::io;
+use std::io::Read;
+use std::io::Seek;
+use std::io::SeekFrom;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+
+use super::crypto;
+use super::crypto::DataKey;
+use super::crypto::Keyring;
+use super::file;
+
+/// Size of the `[len][crc32]` header in front of every record.
+const HEADER_LEN: u64 = 8;
+
+const EXTENSION: &str = "seg";
+const KEY_EXTENSION: &str = "key";
+
+/// A record read back from a segment during recovery.
+pub struct Record {
+  /// Byte offset of the record header within the segment.
+  pub offset: u64,
+  pub payload: Vec<u8>,
+}
+
+#[derive(Debug)]
+pub struct Segment {
+  path: PathBuf,
+  file: File,
+  len: u64,
+
+  /// The data key of an encrypted segment.
+  key: Option<DataKey>,
+}
+
+impl Segment {
+  /// Returns the first log index encoded in a segment file name, if `path` is a segment.
+  pub fn first_index(path: &Path) -> Option<u64> {
+    if path.extension()? != EXTENSION {
+      return None;
+    }
+    path.file_stem()?.to_str()?.parse().ok()
+  }
+
+  fn path(dir: &Path, first_index: u64) -> PathBuf {
+    dir.join(format!("{:020}.{}", first_index, EXTENSION))
+  }
+
+  fn key_path(path: &Path) -> PathBuf {
+    path.with_extension(KEY_EXTENSION)
+  }
+
+  /// Creates an empty segment whose first entry will have index `first_index`, encrypted with a
+  /// new data key if a keyring is given.
+  pub fn create(dir: &Path, first_index: u64, keyring: Option<&Keyring>) -> io::Result<Self> {
+    let path = Self::path(dir, first_index);
+
+    // The key is stored first, so an encrypted segment never exists without it.
+    let key = match keyring {
+      Some(keyring) => {
+        let key = keyring.generate()?;
+        file::write_message(&Self::key_path(&path), key.wrapped())?;
+        Some(key)
+      }
+      None => None,
+    };
+
+    let file = OpenOptions::new()
+      .create_new(true)
+      .read(true)
+      .append(true)
+      .open(&path)?;
+    file::sync_dir(dir)?;
+
+    Ok(Segment {
+      path,
+      file,
+      len: 0,
+      key,
+    })
+  }
+
+  /// Opens an existing segment and reads back all of its records.
+  ///
+  /// When `repair` is set a damaged tail is truncated away, otherwise it is reported as
+  /// `InvalidData`. Only the newest segment may legitimately end with a partial write.
+  ///
+  /// Segments written before encryption was enabled are read as they are.
+  pub fn open(
+    path: &Path,
+    repair: bool,
+    keyring: Option<&Keyring>,
+  ) -> io::Result<(Self, Vec<Record>)> {
+    let wrapped = file::read_message(&Self::key_path(path))?;
+    let key = crypto::data_key(keyring, wrapped.as_ref(), path)?;
+
+    let mut file = OpenOptions::new().read(true).append(true).open(path)?;
+
+    let mut bytes = Vec::new();
+    file.read_to_end(&mut bytes)?;
+
+    let mut records = Vec::new();
+    let mut offset = 0u64;
+
+    while let Some(payload) = Self::decode_record(&bytes[offset as usize..]) {
+      let len = payload.len() as u64;
+      records.push(Record {
+        offset,
+        payload: Self::decrypt(&key, offset, payload)?,
+      });
+      offset += HEADER_LEN + len;
+    }
+
+    if offset != bytes.len() as u64 {
+      if !repair {
+        return Err(io::Error::new(
+          io::ErrorKind::InvalidData,
+          format!("corrupt record in {} at offset {}", path.display(), offset),
+        ));
+      }
+
+      tracing::warn!(
+        "Truncating torn write in {} at offset {}",
+        path.display(),
+        offset
+      );
+      file.set_len(offset)?;
+      file.sync_all()?;
+    }
+
+    let segment = Segment {
+      path: path.to_path_buf(),
+      file,
+      len: offset,
+      key,
+    };
+
+    Ok((segment, records))
+  }
+
+  fn decrypt(key: &Option<DataKey>, offset: u64, payload: &[u8]) -> io::Result<Vec<u8>> {
+    match key {
+      Some(key) => key.open(&offset.to_le_bytes(), payload),
+      None => Ok(payload.to_vec()),
+    }
+  }
+
+  /// Decodes the record at the start of `bytes`, `None` if it is incomplete or corrupt.
+  fn decode_record(bytes: &[u8]) -> Option<&[u8]> {
+    let header = bytes.get(..HEADER_LEN as usize)?;
+    let len = u32::from_le_bytes(header[0..4].try_into().ok()?) as usize;
+    let crc = u32::from_le_bytes(header[4..8].try_into().ok()?);
+
+    let payload = bytes.get(HEADER_LEN as usize..HEADER_LEN as usize + len)?;
+    (crc32fast::hash(payload) == crc).then_some(payload)
+  }
+
+  /// Size of the segment in bytes.
+  pub fn size(&self) -> u64 {
+    self.len
+  }
+
+  pub fn is_encrypted(&self) -> bool {
+    self.key.is_some()
+  }
+
+  /// Wraps the data key again with the current master key, if it is not already.
+  pub fn rewrap(&mut self, keyring: &Keyring) -> io::Result<()> {
+    let Some(key) = &mut self.key else {
+      return Ok(());
+    };
+
+    if let Some(wrapped) = keyring.rewrap(key.wrapped())? {
+      file::write_message(&Self::key_path(&self.path), &wrapped)?;
+      key.set_wrapped(wrapped);
+    }
+
+    Ok(())
+  }
+
+  /// Appends a record and returns its offset. The write is not durable until [`Segment::sync`].
+  pub fn append(&mut self, payload: &[u8]) -> io::Result<u64> {
+    let sealed;
+    let payload = match &self.key {
+      Some(key) => {
+        sealed = key.seal(&self.len.to_le_bytes(), payload)?;
+        sealed.as_slice()
+      }
+      None => payload,
+    };
+
+    let mut frame = Vec::with_capacity(HEADER_LEN as usize + payload.len());
+    frame.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    frame.extend_from_slice(&crc32fast::hash(payload).to_le_bytes());
+    frame.extend_from_slice(payload);
+
+    self.file.write_all(&frame)?;
+
+    let offset = self.len;
+    self.len += frame.len() as u64;
+    Ok(offset)
+  }
+
+  /// Flushes appended records to stable storage.
+  pub fn sync(&self) -> io::Result<()> {
+    self.file.sync_data()
+  }
+
+  /// Reads the payload of the record at `offset`, verifying its checksum.
+  pub fn read(&self, offset: u64) -> io::Result<Vec<u8>> {
+    let mut file = &self.file;
+    file.seek(SeekFrom::Start(offset))?;
+
+    let mut header = [0u8; HEADER_LEN as usize];
+    file.read_exact(&mut header)?;
+    let len = u32::from_le_bytes([header[0], header[1], header[2], header[3]]) as usize;
+    let crc = u32::from_le_bytes([header[4], header[5], header[6], header[7]]);
+
+    let mut payload = vec![0u8; len];
+    file.read_exact(&mut payload)?;
+
+    if crc32fast::hash(&payload) != crc {
+      return Err(io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!(
+          "checksum mismatch in {} at offset {}",
+          self.path.display(),
+          offset
+        ),
+      ));
+    }
+
+    Self::decrypt(&self.key, offset, &payload)
+  }
+
+  /// Discards every record starting at `offset`.
+  pub fn truncate(&mut self, offset: u64) -> io::Result<()> {
+    self.file.set_len(offset)?;
+    self.file.sync_all()?;
+    self.len = offset;
+    Ok(())
+  }
+
+  /// Deletes the segment file, along with its key.
+  pub fn remove(self) -> io::Result<()> {
+    let Segment { path, file, .. } = self;
+    drop(file);
+    fs::remove_file(&path)?;
+    file::remove_durable(&Self::key_path(&path))?;
+
+    match path.parent() {
+      Some(dir) => file::sync_dir(dir),
+      None => Ok(()),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::protobuf as pb;
+  use crate::store::crypto::tests::keyring;
+  use crate::store::crypto::tests::rotate;
+
+  fn write(path: &Path, offset: u64, bytes: &[u8]) {
+    let mut file = OpenOptions::new().write(true).open(path).unwrap();
+    file.seek(SeekFrom::Start(offset)).unwrap();
+    file.write_all(bytes).unwrap();
+  }
+
+  fn payloads(records: Vec<Record>) -> Vec<Vec<u8>> {
+    records.into_iter().map(|record| record.payload).collect()
+  }
+
+  #[test]
+  fn test_first_index() {
+    let dir = Path::new("/data/log");
+    assert_eq!(Segment::first_index(&Segment::path(dir, 42)), Some(42));
+    assert_eq!(
+      Segment::first_index(&dir.join("00000000000000000042.key")),
+      None
+    );
+    assert_eq!(Segment::first_index(&dir.join("segment.seg")), None);
+  }
+
+  #[test]
+  fn test_recover_torn_tail() {
+    let dir = tempfile::TempDir::new().unwrap();
+    let path = Segment::path(dir.path(), 1);
+
+    let mut segment = Segment::create(dir.path(), 1, None).unwrap();
+    for payload in [&b"first"[..], b"second", b"third"] {
+      segment.append(payload).unwrap();
+    }
+    segment.sync().unwrap();
+    let size = segment.size();
+    drop(segment);
+
+    // A record whose payload was only partly written
+    let mut torn = Vec::new();
+    torn.extend_from_slice(&6u32.to_le_bytes());
+    torn.extend_from_slice(&crc32fast::hash(b"fourth").to_le_bytes());
+    torn.extend_from_slice(b"fou");
+    write(&path, size, &torn);
+
+    assert!(Segment::open(&path, false, None).is_err());
+
+    let (mut segment, records) = Segment::open(&path, true, None).unwrap();
+    assert_eq!(payloads(records), [&b"first"[..], b"second", b"third"]);
+    assert_eq!(segment.size(), size);
+    assert_eq!(fs::metadata(&path).unwrap().len(), size);
+
+    // Appending resumes where the intact records end
+    assert_eq!(segment.append(b"fourth").unwrap(), size);
+    segment.sync().unwrap();
+    drop(segment);
+
+    let (_, records) = Segment::open(&path, false, None).unwrap();
+    assert_eq!(payloads(records).len(), 4);
+  }
+
+  #[test]
+  fn test_recover_corrupt_record() {
+    let dir = tempfile::TempDir::new().unwrap();
+    let path = Segment::path(dir.path(), 1);
+
+    let mut segment = Segment::create(dir.path(), 1, None).unwrap();
+    segment.append(b"first").unwrap();
+    let second = segment.append(b"second").unwrap();
+    segment.append(b"third").unwrap();
+    segment.sync().unwrap();
+
+    write(&path, second + HEADER_LEN, b"X");
+    assert!(segment.read(second).is_err());
+    drop(segment);
+
+    // Everything from the first damaged record on is cut off
+    let (segment, records) = Segment::open(&path, true, None).unwrap();
+    assert_eq!(payloads(records), [b"first"]);
+    assert_eq!(segment.size(), second);
+  }
+
+  #[test]
+  fn test_truncate() {
+    let dir = tempfile::TempDir::new().unwrap();
+    let path = Segment::path(dir.path(), 1);
+
+    let mut segment = Segment::create(dir.path(), 1, None).unwrap();
+    segment.append(b"first").unwrap();
+    let second = segment.append(b"second").unwrap();
+    segment.truncate(second).unwrap();
+    assert_eq!(segment.append(b"other").unwrap(), second);
+    segment.sync().unwrap();
+    drop(segment);
+
+    let (segment, records) = Segment::open(&path, false, None).unwrap();
+    assert_eq!(payloads(records), [&b"first"[..], b"other"]);
+
+    segment.remove().unwrap();
+    assert!(!path.exists());
+  }
+
+  #[test]
+  fn test_encrypted_records() {
+    let dir = tempfile::TempDir::new().unwrap();
+    let keyring = keyring(&dir.path().join("keys"), &["k1"]);
+
+    let mut segment = Segment::create(dir.path(), 1, Some(&keyring)).unwrap();
+    assert!(segment.is_encrypted());
+    let first = segment.append(b"first record").unwrap();
+    let second = segment.append(b"second record").unwrap();
+    segment.sync().unwrap();
+
+    assert_eq!(segment.read(first).unwrap(), b"first record");
+    assert_eq!(segment.read(second).unwrap(), b"second record");
+
+    let path = Segment::path(dir.path(), 1);
+    let contents = fs::read(&path).unwrap();
+    assert!(!contents.windows(6).any(|window| window == b"record"));
+
+    let (_, records) = Segment::open(&path, false, Some(&keyring)).unwrap();
+    assert_eq!(payloads(records), [&b"first record"[..], b"second record"]);
+
+    assert!(Segment::open(&path, false, None).is_err());
+  }
+
+  #[test]
+  fn test_encrypted_records_are_bound_to_their_offset() {
+    let dir = tempfile::TempDir::new().unwrap();
+    let keyring = keyring(&dir.path().join("keys"), &["k1"]);
+
+    let mut segment = Segment::create(dir.path(), 1, Some(&keyring)).unwrap();
+    segment.append(b"first").unwrap();
+    let second = segment.append(b"other").unwrap();
+    segment.sync().unwrap();
+
+    // Swapping two records keeps their checksums valid, but not their sealed offsets.
+    let path = Segment::path(dir.path(), 1);
+    let contents = fs::read(&path).unwrap();
+    let (first, second) = contents.split_at(second as usize);
+    write(&path, 0, &[second, first].concat());
+
+    assert!(Segment::open(&path, false, Some(&keyring)).is_err());
+    assert!(Segment::open(&path, true, Some(&keyring)).is_err());
+  }
+
+  #[test]
+  fn test_encrypted_records_reject_tampering() {
+    let dir = tempfile::TempDir::new().unwrap();
+    let keyring = keyring(&dir.path().join("keys"), &["k1"]);
+
+    let mut segment = Segment::create(dir.path(), 1, Some(&keyring)).unwrap();
+    segment.append(b"record").unwrap();
+    segment.sync().unwrap();
+
+    // Flips a byte of the authentication tag and fixes up the checksum.
+    let path = Segment::path(dir.path(), 1);
+    let mut payload = fs::read(&path).unwrap().split_off(HEADER_LEN as usize);
+    let last = payload.len() - 1;
+    payload[last] ^= 1;
+    write(&path, 4, &crc32fast::hash(&payload).to_le_bytes());
+    write(&path, HEADER_LEN, &payload);
+
+    assert!(segment.read(0).is_err());
+    assert!(Segment::open(&path, true, Some(&keyring)).is_err());
+  }
+
+  #[test]
+  fn test_rewrap_after_rotation() {
+    let dir = tempfile::TempDir::new().unwrap();
+    let keys = dir.path().join("keys");
+    let keyring = keyring(&keys, &["k1"]);
+
+    let mut segment = Segment::create(dir.path(), 1, Some(&keyring)).unwrap();
+    segment.append(b"record").unwrap();
+    segment.sync().unwrap();
+
+    rotate(&keyring, &keys, &["k1", "k2"]);
+    segment.rewrap(&keyring).unwrap();
+
+    let path = Segment::path(dir.path(), 1);
+    let wrapped = file::read_message::<pb::WrappedKey>(&Segment::key_path(&path))
+      .unwrap()
+      .unwrap();
+    assert_eq!(wrapped.key_id, "k2");
+
+    let retired = crate::store::crypto::tests::keyring(&keys, &["k2"]);
+    let (_, records) = Segment::open(&path, false, Some(&retired)).unwrap();
+    assert_eq!(records[0].payload, b"record");
+  }
+}
@@ -0,0 +1,878 @@
+//! Persistence of state machine snapshots in the data directory.
+//!
+//! Every snapshot is stored as a pair of files named after its last log id and a local counter,
+//! `<term>-<index>-<n>`. The snapshot id comes from the leader, so it is only kept in the meta.
+//! - `<name>.snap`: the encoded `StateMachineData`.
+//! - `<name>.meta`: the `SnapshotFileMeta` describing it, including the size and CRC32 of the data.
+//!   The meta file is written last, so a snapshot without one was interrupted and is ignored.
+//!
+//! Snapshots sent by the leader are streamed into the `incoming` directory and moved into place
+//! once they are installed. Staged files that are not installed are removed once dropped.
+//!
+//! When encryption is enabled the data is stored as a sequence of frames `[len: u32][sealed]`,
+//! each sealing up to [`CHUNK_SIZE`] bytes with the data key of the snapshot, bound to the number
+//! of the frame. The wrapped data key is part of the meta. The size and CRC32 always describe the
+//! plaintext, which is what gets sent to other nodes.
+
+use std::fs;
+use std::io;
+use std::io::Read;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+
+use prost::bytes::Buf;
+use tokio::io::AsyncReadExt;
+use tokio::io::AsyncWriteExt;
+
+use super::crypto;
+use super::crypto::DataKey;
+use super::crypto::Keyring;
+use super::crypto::SEAL_OVERHEAD;
+use super::file;
+use crate::protobuf as pb;
+use crate::raft_types::SnapshotMeta;
+
+const DATA_EXTENSION: &str = "snap";
+const META_EXTENSION: &str = "meta";
+
+const INCOMING_DIR: &str = "incoming";
+
+/// Size of the buffer used when streaming snapshot data.
+pub const CHUNK_SIZE: usize = 1024 * 1024;
+
+/// Snapshot data kept in a file. The contents are streamed instead of being held in memory.
+#[derive(Debug, Clone)]
+pub struct SnapshotFile {
+  path: PathBuf,
+  size: u64,
+  checksum: u32,
+
+  /// The data key of an encrypted snapshot.
+  key: Option<DataKey>,
+
+  /// The staging file of a received snapshot, removed unless it gets installed.
+  staged: Option<Arc<Staged>>,
+}
+
+impl SnapshotFile {
+  pub fn path(&self) -> &Path {
+    &self.path
+  }
+
+  /// Size of the data in bytes.
+  pub fn size(&self) -> u64 {
+    self.size
+  }
+
+  /// CRC32 of the data.
+  pub fn checksum(&self) -> u32 {
+    self.checksum
+  }
+
+  /// Opens the file for streaming its contents.
+  pub async fn open(&self) -> io::Result<SnapshotReader> {
+    Ok(SnapshotReader {
+      file: tokio::fs::File::open(&self.path).await?,
+      key: self.key.clone(),
+      frame: 0,
+    })
+  }
+
+  /// Decodes the data as a message while it is read, failing if it does not match the expected
+  /// size and checksum.
+  pub async fn decode<M>(&self) -> io::Result<M>
+  where
+    M: prost::Message + Default + Send + 'static,
+  {
+    let snapshot = self.clone();
+    tokio::task::spawn_blocking(move || {
+      snapshot
+        .read_with(|buf| M::decode(buf))?
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    })
+    .await
+    .map_err(io::Error::other)?
+  }
+
+  /// Passes the data to `f` as a buffer that reads it one chunk at a time, blocking the thread.
+  /// Whatever `f` leaves is read too, so that all of it gets checked against the expected size
+  /// and checksum.
+  fn read_with<T>(&self, f: impl FnOnce(&mut VerifiedBuf) -> T) -> io::Result<T> {
+    let mut buf = VerifiedBuf {
+      reader: BlockingReader {
+        file: fs::File::open(&self.path)?,
+        key: self.key.clone(),
+        frame: 0,
+      },
+      chunk: Vec::new(),
+      pos: 0,
+      remaining: self.size,
+      hasher: crc32fast::Hasher::new(),
+      read: 0,
+      error: None,
+    };
+    buf.fill();
+
+    let res = f(&mut buf);
+
+    let (read, checksum) = buf.finish(self.size)?;
+    self.check(read, checksum)?;
+    Ok(res)
+  }
+
+  fn check(&self, size: u64, checksum: u32) -> io::Result<()> {
+    if size != self.size || checksum != self.checksum {
+      return Err(io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!(
+          "snapshot {} is corrupt: expected {} bytes with crc32 {:08x}, found {} bytes with crc32 {:08x}",
+          self.path.display(),
+          self.size,
+          self.checksum,
+          size,
+          checksum
+        ),
+      ));
+    }
+
+    Ok(())
+  }
+}
+
+/// A file in the incoming directory, removed when the last handle to it is dropped. Once the
+/// file has been moved into place there is nothing left to remove.
+#[derive(Debug)]
+struct Staged(PathBuf);
+
+impl Drop for Staged {
+  fn drop(&mut self) {
+    match fs::remove_file(&self.0) {
+      Ok(()) => tracing::debug!("Removed staged snapshot {}", self.0.display()),
+      Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+      Err(e) => tracing::warn!(
+        "Failed to remove staged snapshot {}: {}",
+        self.0.display(),
+        e
+      ),
+    }
+  }
+}
+
+/// Reads the data of a snapshot back in chunks, decrypting it if needed.
+#[derive(Debug)]
+pub struct SnapshotReader {
+  file: tokio::fs::File,
+  key: Option<DataKey>,
+
+  /// Number of the next frame of an encrypted snapshot.
+  frame: u64,
+}
+
+impl SnapshotReader {
+  /// Returns the next chunk of at most [`CHUNK_SIZE`] bytes, `None` at the end of the data.
+  pub async fn next_chunk(&mut self) -> io::Result<Option<Vec<u8>>> {
+    let Some(key) = &self.key else {
+      let mut buf = vec![0u8; CHUNK_SIZE];
+      let n = self.file.read(&mut buf).await?;
+      if n == 0 {
+        return Ok(None);
+      }
+      buf.truncate(n);
+      return Ok(Some(buf));
+    };
+
+    // A truncated frame ends the data early, which the size check catches.
+    let mut len = [0u8; 4];
+    match self.file.read_exact(&mut len).await {
+      Ok(_) => {}
+      Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+      Err(e) => return Err(e),
+    }
+
+    let mut sealed = vec![0u8; frame_len(len)?];
+    self.file.read_exact(&mut sealed).await?;
+
+    let chunk = key.open(&self.frame.to_le_bytes(), &sealed)?;
+    self.frame += 1;
+    Ok(Some(chunk))
+  }
+}
+
+/// Reads the data of a snapshot back in chunks like [`SnapshotReader`], blocking the thread.
+#[derive(Debug)]
+struct BlockingReader {
+  file: fs::File,
+  key: Option<DataKey>,
+
+  /// Number of the next frame of an encrypted snapshot.
+  frame: u64,
+}
+
+impl BlockingReader {
+  fn next_chunk(&mut self) -> io::Result<Option<Vec<u8>>> {
+    let Some(key) = &self.key else {
+      let mut buf = vec![0u8; CHUNK_SIZE];
+      let n = self.file.read(&mut buf)?;
+      if n == 0 {
+        return Ok(None);
+      }
+      buf.truncate(n);
+      return Ok(Some(buf));
+    };
+
+    let mut len = [0u8; 4];
+    match self.file.read_exact(&mut len) {
+      Ok(()) => {}
+      Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+      Err(e) => return Err(e),
+    }
+
+    let mut sealed = vec![0u8; frame_len(len)?];
+    self.file.read_exact(&mut sealed)?;
+
+    let chunk = key.open(&self.frame.to_le_bytes(), &sealed)?;
+    self.frame += 1;
+    Ok(Some(chunk))
+  }
+}
+
+/// The data of a snapshot as a [`Buf`] of its expected size, holding one chunk at a time.
+///
+/// Data that ends early or fails to be read appears to end there, and is reported once the
+/// buffer is finished.
+#[derive(Debug)]
+struct VerifiedBuf {
+  reader: BlockingReader,
+  chunk: Vec<u8>,
+  pos: usize,
+
+  /// Bytes of the expected size not consumed yet.
+  remaining: u64,
+
+  /// CRC32 and size of the data read so far.
+  hasher: crc32fast::Hasher,
+  read: u64,
+
+  error: Option<io::Error>,
+}
+
+impl VerifiedBuf {
+  /// Reads the next chunk once the current one has been consumed.
+  fn fill(&mut self) {
+    while self.pos == self.chunk.len() && self.remaining > 0 {
+      match self.reader.next_chunk() {
+        Ok(Some(chunk)) => {
+          self.hasher.update(&chunk);
+          self.read += chunk.len() as u64;
+          self.chunk = chunk;
+          self.pos = 0;
+        }
+        Ok(None) => self.remaining = 0,
+        Err(e) => {
+          self.error = Some(e);
+          self.remaining = 0;
+        }
+      }
+    }
+  }
+
+  /// Reads the rest of the data, stopping as soon as it grows past `size`, and returns its size
+  /// and CRC32.
+  fn finish(mut self, size: u64) -> io::Result<(u64, u32)> {
+    if let Some(e) = self.error {
+      return Err(e);
+    }
+
+    while self.read <= size
+      && let Some(chunk) = self.reader.next_chunk()?
+    {
+      self.hasher.update(&chunk);
+      self.read += chunk.len() as u64;
+    }
+
+    Ok((self.read, self.hasher.finalize()))
+  }
+}
+
+impl Buf for VerifiedBuf {
+  fn remaining(&self) -> usize {
+    usize::try_from(self.remaining).unwrap_or(usize::MAX)
+  }
+
+  fn chunk(&self) -> &[u8] {
+    let chunk = &self.chunk[self.pos..];
+    &chunk[..chunk.len().min(self.remaining())]
+  }
+
+  fn advance(&mut self, cnt: usize) {
+    assert!(
+      cnt <= self.chunk().len(),
+      "advance past the end of the chunk"
+    );
+    self.pos += cnt;
+    self.remaining -= cnt as u64;
+    self.fill();
+  }
+
+  // The default one never returns if the data ends early.
+  fn copy_to_slice(&mut self, dst: &mut [u8]) {
+    let mut off = 0;
+    while off < dst.len() && self.has_remaining() {
+      let n = self.chunk().len().min(dst.len() - off);
+      dst[off..off + n].copy_from_slice(&self.chunk()[..n]);
+      self.advance(n);
+      off += n;
+    }
+  }
+}
+
+/// Length of the sealed contents of a frame, checked against the largest one ever written.
+fn frame_len(header: [u8; 4]) -> io::Result<usize> {
+  let len = u32::from_le_bytes(header) as usize;
+  if len > CHUNK_SIZE + SEAL_OVERHEAD {
+    return Err(io::Error::new(
+      io::ErrorKind::InvalidData,
+      format!("encrypted snapshot frame of {} bytes is too large", len),
+    ));
+  }
+  Ok(len)
+}
+
+/// Appends a frame sealing `chunk` to `out`.
+fn seal_frame(key: &DataKey, frame: u64, chunk: &[u8], out: &mut Vec<u8>) -> io::Result<()> {
+  let sealed = key.seal(&frame.to_le_bytes(), chunk)?;
+  out.extend_from_slice(&(sealed.len() as u32).to_le_bytes());
+  out.extend_from_slice(&sealed);
+  Ok(())
+}
+
+fn encrypt(key: &DataKey, data: &[u8]) -> io::Result<Vec<u8>> {
+  let frames = data.len() / CHUNK_SIZE + 1;
+  let mut contents = Vec::with_capacity(data.len() + frames * (4 + SEAL_OVERHEAD));
+  for (frame, chunk) in data.chunks(CHUNK_SIZE).enumerate() {
+    seal_frame(key, frame as u64, chunk, &mut contents)?;
+  }
+  Ok(contents)
+}
+
+/// Streams the data of a snapshot received from the leader into a staging file.
+///
+/// The staging file is removed if the writer is dropped before it is finished.
+#[derive(Debug)]
+pub struct SnapshotWriter {
+  staged: Arc<Staged>,
+  file: tokio::fs::File,
+  key: Option<DataKey>,
+
+  /// Data of an encrypted snapshot not yet sealed into a full frame.
+  pending: Vec<u8>,
+
+  /// Number of the next frame of an encrypted snapshot.
+  frame: u64,
+
+  /// CRC32 and size of the data received so far.
+  hasher: crc32fast::Hasher,
+  size: u64,
+}
+
+impl SnapshotWriter {
+  pub async fn write(&mut self, chunk: &[u8]) -> io::Result<()> {
+    self.hasher.update(chunk);
+    self.size += chunk.len() as u64;
+
+    let Some(key) = &self.key else {
+      return self.file.write_all(chunk).await;
+    };
+
+    self.pending.extend_from_slice(chunk);
+
+    let mut contents = Vec::new();
+    while self.pending.len() >= CHUNK_SIZE {
+      seal_frame(key, self.frame, &self.pending[..CHUNK_SIZE], &mut contents)?;
+      self.pending.drain(..CHUNK_SIZE);
+      self.frame += 1;
+    }
+
+    self.file.write_all(&contents).await
+  }
+
+  /// Flushes the received data to disk, once checked against the `size` and `checksum` announced
+  /// by the sender.
+  ///
+  /// A damaged transfer fails with `InvalidData`, and its staging file is removed.
+  pub async fn finish(mut self, size: u64, checksum: u32) -> io::Result<SnapshotFile> {
+    let snapshot = SnapshotFile {
+      path: self.staged.0.clone(),
+      size,
+      checksum,
+      key: self.key.clone(),
+      staged: Some(self.staged.clone()),
+    };
+    snapshot.check(self.size, self.hasher.clone().finalize())?;
+
+    if let Some(key) = &self.key
+      && !self.pending.is_empty()
+    {
+      let mut contents = Vec::new();
+      seal_frame(key, self.frame, &self.pending, &mut contents)?;
+      self.file.write_all(&contents).await?;
+    }
+
+    self.file.flush().await?;
+    self.file.sync_all().await?;
+
+    Ok(snapshot)
+  }
+}
+
+#[derive(Debug)]
+pub struct SnapshotStore {
+  dir: PathBuf,
+
+  /// How many of the newest snapshots are kept on disk.
+  keep: usize,
+
+  /// Distinguishes the files of snapshots with the same last log id, and concurrent transfers in
+  /// the incoming directory.
+  next_file: AtomicU64,
+
+  /// Encrypts the snapshots, if enabled.
+  keyring: Option<Arc<Keyring>>,
+}
+
+impl SnapshotStore {
+  /// Opens the snapshot directory, creating it if needed. With a keyring, new snapshots are
+  /// encrypted.
+  pub fn open(
+    dir: impl AsRef<Path>,
+    keep: usize,
+    keyring: Option<Arc<Keyring>>,
+  ) -> io::Result<Self> {
+    let dir = dir.as_ref().to_path_buf();
+
+    // Transfers interrupted by a restart are never resumed.
+    let incoming = dir.join(INCOMING_DIR);
+    match fs::remove_dir_all(&incoming) {
+      Ok(()) => {}
+      Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+      Err(e) => return Err(e),
+    }
+    fs::create_dir_all(&incoming)?;
+
+    // Continue past the counter of the files already there, so that none is overwritten.
+    let mut next_file = 0;
+    for entry in fs::read_dir(&dir)? {
+      let path = entry?.path();
+      if let Some(n) = path
+        .file_stem()
+        .and_then(|stem| stem.to_str())
+        .and_then(|stem| stem.rsplit('-').next())
+        .and_then(|n| n.parse::<u64>().ok())
+      {
+        next_file = next_file.max(n + 1);
+      }
+    }
+
+    Ok(SnapshotStore {
+      dir,
+      keep: keep.max(1),
+      next_file: AtomicU64::new(next_file),
+      keyring,
+    })
+  }
+
+  /// Picks the name of the files of a new snapshot.
+  fn name(&self, meta: &SnapshotMeta) -> String {
+    let log_id = meta.last_log_id.map(pb::LogId::from).unwrap_or_default();
+    let n = self.next_file.fetch_add(1, Ordering::Relaxed);
+    format!("{}-{}-{}", log_id.term, log_id.index, n)
+  }
+
+  fn path(&self, name: &str, extension: &str) -> PathBuf {
+    self.dir.join(format!("{}.{}", name, extension))
+  }
+
+  fn generate_key(&self) -> io::Result<Option<DataKey>> {
+    self
+      .keyring
+      .as_ref()
+      .map(|keyring| keyring.generate())
+      .transpose()
+  }
+
+  /// Durably stores a snapshot built by this node.
+  pub fn save(&self, meta: &SnapshotMeta, data: &[u8]) -> io::Result<SnapshotFile> {
+    let name = self.name(meta);
+    let snapshot = SnapshotFile {
+      path: self.path(&name, DATA_EXTENSION),
+      size: data.len() as u64,
+      checksum: crc32fast::hash(data),
+      key: self.generate_key()?,
+      staged: None,
+    };
+
+    match &snapshot.key {
+      Some(key) => file::write_atomic(&snapshot.path, &encrypt(key, data)?)?,
+      None => file::write_atomic(&snapshot.path, data)?,
+    }
+    self.commit(&name, meta, &snapshot)?;
+
+    Ok(snapshot)
+  }
+
+  /// Creates a staging file for a snapshot sent by the leader.
+  pub async fn receive(&self) -> io::Result<SnapshotWriter> {
+    let n = self.next_file.fetch_add(1, Ordering::Relaxed);
+    let path = self
+      .dir
+      .join(INCOMING_DIR)
+      .join(format!("{}.{}", n, DATA_EXTENSION));
+
+    let file = tokio::fs::File::create(&path).await?;
+    Ok(SnapshotWriter {
+      staged: Arc::new(Staged(path)),
+      file,
+      key: self.generate_key()?,
+      pending: Vec::new(),
+      frame: 0,
+      hasher: crc32fast::Hasher::new(),
+      size: 0,
+    })
+  }
+
+  /// Moves a received snapshot into place. Its data must already be on stable storage.
+  pub fn install(&self, meta: &SnapshotMeta, snapshot: &SnapshotFile) -> io::Result<()> {
+    let name = self.name(meta);
+    let installed = SnapshotFile {
+      path: self.path(&name, DATA_EXTENSION),
+      staged: None,
+      ..snapshot.clone()
+    };
+
+    fs::rename(&snapshot.path, &installed.path)?;
+    self.commit(&name, meta, &installed)
+  }
+
+  /// Writes the meta file that marks a snapshot as complete, then applies the retention policy.
+  fn commit(&self, name: &str, meta: &SnapshotMeta, snapshot: &SnapshotFile) -> io::Result<()> {
+    file::write_message(
+      &self.path(name, META_EXTENSION),
+      &pb::SnapshotFileMeta {
+        size: snapshot.size,
+        checksum: snapshot.checksum,
+        data_key: snapshot.key.as_ref().map(|key| key.wrapped().clone()),
+        ..meta.clone().into()
+      },
+    )?;
+
+    tracing::info!(
+      "Saved snapshot {} to {}",
+      meta.snapshot_id,
+      self.dir.display()
+    );
+
+    self.prune()?;
+    self.rewrap()
+  }
+
+  /// Wraps the data keys of the retained snapshots again with the current master key, so that
+  /// keys retired by a rotation are no longer needed once a new snapshot has been taken.
+  fn rewrap(&self) -> io::Result<()> {
+    let Some(keyring) = &self.keyring else {
+      return Ok(());
+    };
+
+    for (name, mut meta) in self.list()? {
+      let Some(wrapped) = &meta.data_key else {
+        continue;
+      };
+
+      if let Some(wrapped) = keyring.rewrap(wrapped)? {
+        meta.data_key = Some(wrapped);
+        file::write_message(&self.path(&name, META_EXTENSION), &meta)?;
+      }
+    }
+
+    Ok(())
+  }
+
+  /// Returns the newest complete snapshot, if any. The data is not read.
+  pub fn latest(&self) -> io::Result<Option<(SnapshotMeta, SnapshotFile)>> {
+    let Some((name, meta)) = self.list()?.pop() else {
+      return Ok(None);
+    };
+
+    let path = self.path(&name, DATA_EXTENSION);
+    let key = crypto::data_key(self.keyring.as_deref(), meta.data_key.as_ref(), &path)?;
+
+    let snapshot = SnapshotFile {
+      path,
+      size: meta.size,
+      checksum: meta.checksum,
+      key,
+      staged: None,
+    };
+
+    Ok(Some((meta.into(), snapshot)))
+  }
+
+  /// Lists the complete snapshots along with the name of their files, oldest first.
+  fn list(&self) -> io::Result<Vec<(String, pb::SnapshotFileMeta)>> {
+    let mut metas = Vec::new();
+
+    for entry in fs::read_dir(&self.dir)? {
+      let path = entry?.path();
+      if path.extension().is_none_or(|ext| ext != META_EXTENSION) {
+        continue;
+      }
+
+      let Some(name) = path.file_stem().and_then(|stem| stem.to_str()) else {
+        continue;
+      };
+
+      if let Some(meta) = file::read_message::<pb::SnapshotFileMeta>(&path)? {
+        metas.push((name.to_string(), meta));
+      }
+    }
+
+    metas.sort_by_key(|(_, meta)| meta.last_log_id.map(|log_id| log_id.index));
+    Ok(metas)
+  }
+
+  /// Deletes all but the newest `keep` snapshots, along with leftovers of interrupted writes.
+  fn prune(&self) -> io::Result<()> {
+    let metas = self.list()?;
+    let retained = metas
+      .iter()
+      .rev()
+      .take(self.keep)
+      .map(|(name, _)| name.as_str())
+      .collect::<Vec<_>>();
+
+    for entry in fs::read_dir(&self.dir)? {
+      let entry = entry?;
+      if !entry.file_type()?.is_file() {
+        continue;
+      }
+
+      let path = entry.path();
+      let Some(stem) = path.file_stem().and_then(|stem| stem.to_str()) else {
+        continue;
+      };
+
+      if retained.contains(&stem) {
+        continue;
+      }
+
+      tracing::debug!("Removing stale snapshot file {}", path.display());
+      fs::remove_file(&path)?;
+    }
+
+    file::sync_dir(&self.dir)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::collections::BTreeMap;
+
+  use super::*;
+  use crate::raft_types::LogId;
+  use crate::store::crypto::tests::keyring;
+  use crate::store::crypto::tests::rotate;
+
+  fn meta(index: u64) -> SnapshotMeta {
+    SnapshotMeta {
+      last_log_id: Some(LogId::new(1, index)),
+      snapshot_id: format!("snapshot-{}", index),
+      ..Default::default()
+    }
+  }
+
+  /// Data spanning two full frames and a partial one.
+  fn data() -> Vec<u8> {
+    (0..2 * CHUNK_SIZE + 123).map(|i| (i % 251) as u8).collect()
+  }
+
+  fn read(snapshot: &SnapshotFile) -> io::Result<Vec<u8>> {
+    snapshot.read_with(|buf| buf.copy_to_bytes(buf.remaining()).to_vec())
+  }
+
+  fn frame_offsets(contents: &[u8]) -> Vec<usize> {
+    let mut offsets = Vec::new();
+    let mut offset = 0;
+    while offset < contents.len() {
+      offsets.push(offset);
+      let len = u32::from_le_bytes(contents[offset..offset + 4].try_into().unwrap());
+      offset += 4 + len as usize;
+    }
+    offsets
+  }
+
+  #[tokio::test]
+  async fn test_encrypted_snapshot() {
+    let dir = tempfile::TempDir::new().unwrap();
+    let keyring = Arc::new(keyring(&dir.path().join("keys"), &["k1"]));
+    let store = SnapshotStore::open(dir.path().join("snapshots"), 2, Some(keyring)).unwrap();
+
+    let data = data();
+    let saved = store.save(&meta(1), &data).unwrap();
+    let contents = fs::read(saved.path()).unwrap();
+    assert_eq!(frame_offsets(&contents).len(), 3);
+    assert_eq!(contents.len(), data.len() + 3 * (4 + SEAL_OVERHEAD));
+
+    let (_, latest) = store.latest().unwrap().unwrap();
+    assert_eq!(read(&latest).unwrap(), data);
+
+    let mut writer = store.receive().await.unwrap();
+    for chunk in data.chunks(700_001) {
+      writer.write(chunk).await.unwrap();
+    }
+    let received = writer
+      .finish(data.len() as u64, crc32fast::hash(&data))
+      .await
+      .unwrap();
+    assert_eq!(fs::read(received.path()).unwrap().len(), contents.len());
+    assert_eq!(read(&received).unwrap(), data);
+
+    let plain = SnapshotStore::open(dir.path().join("snapshots"), 2, None).unwrap();
+    assert!(plain.latest().is_err());
+  }
+
+  #[tokio::test]
+  async fn test_decode() {
+    let dir = tempfile::TempDir::new().unwrap();
+    let keyring = Arc::new(keyring(&dir.path().join("keys"), &["k1"]));
+    let sm = pb::StateMachineData {
+      revision: 7,
+      key_leases: BTreeMap::from([("k".repeat(2 * CHUNK_SIZE + 123), 1)]),
+      ..Default::default()
+    };
+    let data = prost::Message::encode_to_vec(&sm);
+
+    for keyring in [None, Some(keyring)] {
+      let store = SnapshotStore::open(dir.path().join("snapshots"), 1, keyring).unwrap();
+      let snapshot = store.save(&meta(1), &data).unwrap();
+      assert_eq!(snapshot.decode::<pb::StateMachineData>().await.unwrap(), sm);
+
+      // Data past the expected size is caught, as is data ending before it.
+      let contents = fs::read(snapshot.path()).unwrap();
+      let longer = SnapshotFile {
+        size: snapshot.size - 1,
+        checksum: crc32fast::hash(&data[..data.len() - 1]),
+        ..snapshot.clone()
+      };
+      assert!(longer.decode::<pb::StateMachineData>().await.is_err());
+
+      let truncated = SnapshotFile {
+        size: snapshot.size + 1,
+        ..snapshot.clone()
+      };
+      assert!(truncated.decode::<pb::StateMachineData>().await.is_err());
+
+      fs::write(snapshot.path(), &contents[..contents.len() - 1]).unwrap();
+      assert!(snapshot.decode::<pb::StateMachineData>().await.is_err());
+      fs::remove_dir_all(dir.path().join("snapshots")).unwrap();
+    }
+  }
+
+  #[tokio::test]
+  async fn test_encrypted_snapshot_rejects_tampering() {
+    let dir = tempfile::TempDir::new().unwrap();
+    let keyring = Arc::new(keyring(&dir.path().join("keys"), &["k1"]));
+    let store = SnapshotStore::open(dir.path().join("snapshots"), 2, Some(keyring)).unwrap();
+
+    let data = data();
+    let snapshot = store.save(&meta(1), &data).unwrap();
+    let contents = fs::read(snapshot.path()).unwrap();
+    let offsets = frame_offsets(&contents);
+
+    // The first two frames have the same length, but are bound to their position.
+    let mut swapped = contents[offsets[1]..offsets[2]].to_vec();
+    swapped.extend_from_slice(&contents[offsets[0]..offsets[1]]);
+    swapped.extend_from_slice(&contents[offsets[2]..]);
+    fs::write(snapshot.path(), &swapped).unwrap();
+    assert!(read(&snapshot).is_err());
+
+    let mut tampered = contents.clone();
+    tampered[offsets[1] + 4 + SEAL_OVERHEAD] ^= 1;
+    fs::write(snapshot.path(), &tampered).unwrap();
+    assert!(read(&snapshot).is_err());
+
+    // A missing final frame is caught by the size check.
+    fs::write(snapshot.path(), &contents[..offsets[2]]).unwrap();
+    assert!(read(&snapshot).is_err());
+
+    fs::write(snapshot.path(), &contents).unwrap();
+    assert_eq!(read(&snapshot).unwrap(), data);
+  }
+
+  #[tokio::test]
+  async fn test_file_names_ignore_snapshot_id() {
+    let dir = tempfile::TempDir::new().unwrap();
+    let store = SnapshotStore::open(dir.path().join("snapshots"), 3, None).unwrap();
+
+    let escaping = SnapshotMeta {
+      snapshot_id: "../../escaped".to_string(),
+      ..meta(1)
+    };
+    let mut writer = store.receive().await.unwrap();
+    writer.write(b"first").await.unwrap();
+    let received = writer.finish(5, crc32fast::hash(b"first")).await.unwrap();
+    store.install(&escaping, &received).unwrap();
+    store.save(&meta(1), b"again").unwrap();
+
+    let mut names = fs::read_dir(dir.path())
+      .unwrap()
+      .map(|entry| entry.unwrap().file_name())
+      .collect::<Vec<_>>();
+    names.sort();
+    assert_eq!(names, ["snapshots"]);
+
+    // The counter carries on after a restart, so that no file gets overwritten.
+    let store = SnapshotStore::open(dir.path().join("snapshots"), 3, None).unwrap();
+    store.save(&meta(2), b"second").unwrap();
+
+    let mut names = store
+      .list()
+      .unwrap()
+      .into_iter()
+      .map(|(name, meta)| (name, meta.snapshot_id))
+      .collect::<Vec<_>>();
+    names.sort();
+    assert_eq!(
+      names,
+      [
+        ("1-1-1".to_string(), "../../escaped".to_string()),
+        ("1-1-2".to_string(), "snapshot-1".to_string()),
+        ("1-2-3".to_string(), "snapshot-2".to_string()),
+      ]
+    );
+  }
+
+  #[tokio::test]
+  async fn test_rewrap_after_rotation() {
+    let dir = tempfile::TempDir::new().unwrap();
+    let keys = dir.path().join("keys");
+    let keyring = Arc::new(keyring(&keys, &["k1"]));
+    let store =
+      SnapshotStore::open(dir.path().join("snapshots"), 2, Some(keyring.clone())).unwrap();
+
+    store.save(&meta(1), b"first").unwrap();
+    rotate(&keyring, &keys, &["k1", "k2"]);
+    store.save(&meta(2), b"second").unwrap();
+
+    for (_, meta) in store.list().unwrap() {
+      assert_eq!(meta.data_key.unwrap().key_id, "k2", "{}", meta.snapshot_id);
+    }
+
+    let retired = Arc::new(crate::store::crypto::tests::keyring(&keys, &["k2"]));
+    let store = SnapshotStore::open(dir.path().join("snapshots"), 2, Some(retired)).unwrap();
+    let (meta, snapshot) = store.latest().unwrap().unwrap();
+    assert_eq!(meta.snapshot_id, "snapshot-2");
+    assert_eq!(read(&snapshot).unwrap(), b"second");
+  }
+}
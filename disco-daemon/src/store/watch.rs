@@ -0,0 +1,198 @@
+//! Fan-out of applied changes to watchers.
+//!
+//! Recent changes are kept in memory so that a watcher reconnecting with the revision after the
+//! last one it saw does not miss anything. Older revisions are gone, as is the history from
+//! before a restart or an installed snapshot; such watchers have to read the current state again.
+
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::sync::Mutex;
+
+use tokio::sync::broadcast;
+
+use crate::protobuf as pb;
+
+/// Number of commands whose changes are retained for resuming watchers.
+const HISTORY_LEN: usize = 1024;
+
+/// Number of changes a watcher may fall behind before it is disconnected.
+const CHANNEL_CAPACITY: usize = 256;
+
+#[derive(Debug)]
+pub struct WatchHub {
+  inner: Mutex<WatchHubInner>,
+}
+
+#[derive(Debug)]
+struct WatchHubInner {
+  sender: broadcast::Sender<Arc<pb::WatchResponse>>,
+
+  /// Changes after this revision are available in `history`.
+  history_start: i64,
+
+  history: VecDeque<Arc<pb::WatchResponse>>,
+}
+
+/// A watcher subscribed to the changes starting at some revision.
+pub struct Subscription {
+  /// Retained changes from before the subscription, oldest first.
+  pub backlog: Vec<Arc<pb::WatchResponse>>,
+
+  /// Changes applied after the subscription.
+  pub receiver: broadcast::Receiver<Arc<pb::WatchResponse>>,
+}
+
+impl WatchHub {
+  /// Creates a hub without history before `revision`.
+  pub fn new(revision: i64) -> Self {
+    let (sender, _) = broadcast::channel(CHANNEL_CAPACITY);
+
+    WatchHub {
+      inner: Mutex::new(WatchHubInner {
+        sender,
+        history_start: revision,
+        history: VecDeque::new(),
+      }),
+    }
+  }
+
+  /// Subscribes to the changes made at `start_revision` and after, 0 for new changes only.
+  ///
+  /// Returns `None` if changes from `start_revision` on are no longer retained.
+  pub fn subscribe(&self, start_revision: i64) -> Option<Subscription> {
+    let inner = self.inner.lock().unwrap();
+
+    if start_revision != 0 && start_revision <= inner.history_start {
+      return None;
+    }
+
+    let backlog = inner
+      .history
+      .iter()
+      .filter(|response| start_revision != 0 && response.revision >= start_revision)
+      .cloned()
+      .collect();
+
+    Some(Subscription {
+      backlog,
+      receiver: inner.sender.subscribe(),
+    })
+  }
+
+  /// Records the changes made by a command and sends them to all watchers.
+  pub fn publish(&self, response: pb::WatchResponse) {
+    let mut inner = self.inner.lock().unwrap();
+    let response = Arc::new(response);
+
+    if inner.history.len() == HISTORY_LEN
+      && let Some(oldest) = inner.history.pop_front()
+    {
+      inner.history_start = oldest.revision;
+    }
+    inner.history.push_back(response.clone());
+
+    // Sending only fails when nobody is watching.
+    let _ = inner.sender.send(response);
+  }
+
+  /// Drops the history and disconnects all watchers, after the state was replaced wholesale.
+  pub fn reset(&self, revision: i64) {
+    let mut inner = self.inner.lock().unwrap();
+    let (sender, _) = broadcast::channel(CHANNEL_CAPACITY);
+
+    inner.sender = sender;
+    inner.history_start = revision;
+    inner.history.clear();
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use tokio::sync::broadcast::error::TryRecvError;
+
+  fn response(revision: i64) -> pb::WatchResponse {
+    pb::WatchResponse {
+      revision,
+      ..Default::default()
+    }
+  }
+
+  fn revisions(backlog: &[Arc<pb::WatchResponse>]) -> Vec<i64> {
+    backlog.iter().map(|response| response.revision).collect()
+  }
+
+  #[test]
+  fn test_subscribe_to_new_changes() {
+    let hub = WatchHub::new(0);
+    hub.publish(response(1));
+
+    let mut subscription = hub.subscribe(0).unwrap();
+    assert!(subscription.backlog.is_empty());
+
+    hub.publish(response(2));
+    assert_eq!(subscription.receiver.try_recv().unwrap().revision, 2);
+    assert!(matches!(
+      subscription.receiver.try_recv(),
+      Err(TryRecvError::Empty)
+    ));
+  }
+
+  #[test]
+  fn test_resume_from_history() {
+    let hub = WatchHub::new(0);
+    for revision in 1..=3 {
+      hub.publish(response(revision));
+    }
+
+    assert_eq!(revisions(&hub.subscribe(2).unwrap().backlog), vec![2, 3]);
+    assert_eq!(revisions(&hub.subscribe(1).unwrap().backlog), vec![1, 2, 3]);
+    assert!(hub.subscribe(4).unwrap().backlog.is_empty());
+  }
+
+  #[test]
+  fn test_history_is_bounded() {
+    let hub = WatchHub::new(0);
+    for revision in 1..=HISTORY_LEN as i64 + 1 {
+      hub.publish(response(revision));
+    }
+
+    assert!(hub.subscribe(1).is_none());
+
+    let backlog = hub.subscribe(2).unwrap().backlog;
+    assert_eq!(backlog.len(), HISTORY_LEN);
+    assert_eq!(backlog[0].revision, 2);
+  }
+
+  #[test]
+  fn test_reset_drops_history_and_watchers() {
+    let hub = WatchHub::new(0);
+    let mut subscription = hub.subscribe(0).unwrap();
+    hub.publish(response(1));
+
+    hub.reset(5);
+    assert!(hub.subscribe(1).is_none());
+    assert!(hub.subscribe(5).is_none());
+    assert!(hub.subscribe(6).unwrap().backlog.is_empty());
+
+    assert_eq!(subscription.receiver.try_recv().unwrap().revision, 1);
+    assert!(matches!(
+      subscription.receiver.try_recv(),
+      Err(TryRecvError::Closed)
+    ));
+  }
+
+  #[test]
+  fn test_lagging_watcher() {
+    let hub = WatchHub::new(0);
+    let mut subscription = hub.subscribe(0).unwrap();
+    for revision in 1..=CHANNEL_CAPACITY as i64 + 1 {
+      hub.publish(response(revision));
+    }
+
+    assert!(matches!(
+      subscription.receiver.try_recv(),
+      Err(TryRecvError::Lagged(1))
+    ));
+  }
+}
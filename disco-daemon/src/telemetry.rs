@@ -0,0 +1,253 @@
+//! Logging and distributed tracing.
+//!
+//! Spans are exported over OTLP when an endpoint is configured. The W3C trace context travels in
+//! the gRPC metadata of every request, and in each replicated command, so that a write can be
+//! followed from the client through the leader, the replication to the followers and the apply
+//! on every node.
+
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use opentelemetry::KeyValue;
+use opentelemetry::global;
+use opentelemetry::propagation::{Extractor, Injector};
+use opentelemetry::trace::{TraceContextExt, TracerProvider as _};
+use opentelemetry_otlp::{SpanExporter, WithExportConfig};
+use opentelemetry_sdk::Resource;
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+use opentelemetry_sdk::runtime;
+use opentelemetry_sdk::trace::TracerProvider;
+use tonic::codegen::http;
+use tonic::metadata::{MetadataKey, MetadataMap, MetadataValue};
+use tonic::service::interceptor::InterceptedService;
+use tonic::transport::Channel;
+use tonic::{Request, Status};
+use tower::{Layer, Service};
+use tracing::Span;
+use tracing::level_filters::LevelFilter;
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{EnvFilter, Layer as _};
+
+/// Trace context of a request, as W3C `traceparent`/`tracestate` entries.
+pub type Carrier = BTreeMap<String, String>;
+
+/// Flushes the spans still buffered for export when dropped.
+#[derive(Debug)]
+pub struct Telemetry {
+  provider: Option<TracerProvider>,
+}
+
+impl Drop for Telemetry {
+  fn drop(&mut self) {
+    if let Some(provider) = self.provider.take()
+      && let Err(e) = provider.shutdown()
+    {
+      tracing::warn!("Failed to flush spans: {}", e);
+    }
+  }
+}
+
+/// Installs the global subscriber, logging the events selected by `DISCO_LOG` (info and above by
+/// default) and exporting spans to `otlp_endpoint` if set.
+pub fn init(
+  service_name: &'static str,
+  otlp_endpoint: Option<&str>,
+) -> Result<Telemetry, Box<dyn std::error::Error>> {
+  global::set_text_map_propagator(TraceContextPropagator::new());
+
+  let provider = otlp_endpoint
+    .map(|endpoint| tracer_provider(service_name, endpoint))
+    .transpose()?;
+
+  let otel_layer = provider.as_ref().map(|provider| {
+    tracing_opentelemetry::layer()
+      .with_tracer(provider.tracer(service_name))
+      .with_filter(LevelFilter::INFO)
+  });
+
+  let fmt_layer = tracing_subscriber::fmt::layer()
+    .with_file(true)
+    .with_line_number(true)
+    .with_filter(
+      EnvFilter::builder()
+        .with_default_directive(LevelFilter::INFO.into())
+        .with_env_var("DISCO_LOG")
+        .from_env_lossy(),
+    );
+
+  tracing_subscriber::registry()
+    .with(fmt_layer)
+    .with(otel_layer)
+    .try_init()?;
+
+  if let Some(endpoint) = otlp_endpoint {
+    tracing::info!("Exporting spans to {}", endpoint);
+  }
+
+  Ok(Telemetry { provider })
+}
+
+/// Builds a provider exporting the spans of `service_name` in batches to an OTLP/gRPC endpoint.
+pub fn tracer_provider(
+  service_name: &'static str,
+  endpoint: &str,
+) -> Result<TracerProvider, opentelemetry::trace::TraceError> {
+  let exporter = SpanExporter::builder()
+    .with_tonic()
+    .with_endpoint(endpoint)
+    .build()?;
+
+  Ok(
+    TracerProvider::builder()
+      .with_batch_exporter(exporter, runtime::Tokio)
+      .with_resource(Resource::new([KeyValue::new("service.name", service_name)]))
+      .build(),
+  )
+}
+
+/// Makes `span` a child of the trace context in `carrier`, if any.
+pub fn set_parent(span: &Span, carrier: &Carrier) {
+  if !carrier.is_empty() {
+    span.set_parent(global::get_text_map_propagator(|propagator| {
+      propagator.extract(&CarrierExtractor(carrier))
+    }));
+  }
+}
+
+/// Links `span` to the trace context in `carrier`, if any, without making it its parent.
+pub fn add_link(span: &Span, carrier: &Carrier) {
+  let context =
+    global::get_text_map_propagator(|propagator| propagator.extract(&CarrierExtractor(carrier)));
+  let span_context = context.span().span_context().clone();
+
+  if span_context.is_valid() {
+    span.add_link(span_context);
+  }
+}
+
+/// Returns the trace context of the current span, empty if it is not traced.
+pub fn current_context() -> Carrier {
+  let mut carrier = Carrier::new();
+  global::get_text_map_propagator(|propagator| {
+    propagator.inject_context(
+      &Span::current().context(),
+      &mut CarrierInjector(&mut carrier),
+    )
+  });
+  carrier
+}
+
+struct CarrierInjector<'a>(&'a mut Carrier);
+
+impl Injector for CarrierInjector<'_> {
+  fn set(&mut self, key: &str, value: String) {
+    // Commands are stored in the log, so empty fields are left out
+    if !value.is_empty() {
+      self.0.insert(key.to_string(), value);
+    }
+  }
+}
+
+struct CarrierExtractor<'a>(&'a Carrier);
+
+impl Extractor for CarrierExtractor<'_> {
+  fn get(&self, key: &str) -> Option<&str> {
+    self.0.get(key).map(String::as_str)
+  }
+
+  fn keys(&self) -> Vec<&str> {
+    self.0.keys().map(String::as_str).collect()
+  }
+}
+
+struct MetadataInjector<'a>(&'a mut MetadataMap);
+
+impl Injector for MetadataInjector<'_> {
+  fn set(&mut self, key: &str, value: String) {
+    if let (Ok(key), Ok(value)) = (
+      MetadataKey::from_bytes(key.as_bytes()),
+      MetadataValue::try_from(value),
+    ) {
+      self.0.insert(key, value);
+    }
+  }
+}
+
+struct HeaderExtractor<'a>(&'a http::HeaderMap);
+
+impl Extractor for HeaderExtractor<'_> {
+  fn get(&self, key: &str) -> Option<&str> {
+    self.0.get(key).and_then(|value| value.to_str().ok())
+  }
+
+  fn keys(&self) -> Vec<&str> {
+    self.0.keys().map(|key| key.as_str()).collect()
+  }
+}
+
+/// Attaches the trace context of the current span to an outgoing request.
+#[allow(clippy::result_large_err)]
+pub fn propagate(mut request: Request<()>) -> Result<Request<()>, Status> {
+  global::get_text_map_propagator(|propagator| {
+    propagator.inject_context(
+      &Span::current().context(),
+      &mut MetadataInjector(request.metadata_mut()),
+    )
+  });
+  Ok(request)
+}
+
+/// A channel whose requests carry the trace context of the span they are sent from.
+pub type TracedChannel =
+  InterceptedService<Channel, fn(Request<()>) -> Result<Request<()>, Status>>;
+
+pub fn traced(channel: Channel) -> TracedChannel {
+  InterceptedService::new(channel, propagate)
+}
+
+/// Runs every request in a span continuing the trace of the caller.
+#[derive(Clone, Debug, Default)]
+pub struct TraceContextLayer;
+
+impl<S> Layer<S> for TraceContextLayer {
+  type Service = TraceContext<S>;
+
+  fn layer(&self, inner: S) -> Self::Service {
+    TraceContext { inner }
+  }
+}
+
+#[derive(Clone, Debug)]
+pub struct TraceContext<S> {
+  inner: S,
+}
+
+impl<S, B> Service<http::Request<B>> for TraceContext<S>
+where
+  S: Service<http::Request<B>>,
+  S::Future: Send + 'static,
+{
+  type Response = S::Response;
+  type Error = S::Error;
+  type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+  fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+    self.inner.poll_ready(cx)
+  }
+
+  fn call(&mut self, request: http::Request<B>) -> Self::Future {
+    let span = tracing::info_span!("grpc", rpc.method = request.uri().path());
+    span.set_parent(global::get_text_map_propagator(|propagator| {
+      propagator.extract(&HeaderExtractor(request.headers()))
+    }));
+
+    // The handler is called from within the span, so the spans it opens are its children
+    let response = span.in_scope(|| self.inner.call(request));
+
+    Box::pin(tracing::Instrument::instrument(response, span))
+  }
+}
@@ -2,22 +2,50 @@ use std::sync::Arc;
 
 use openraft::testing::log::StoreBuilder;
 use openraft::testing::log::Suite;
+use tempfile::TempDir;
 
+use crate::store::crypto::tests::keyring;
 use crate::store::LogStore;
 use crate::store::StateMachineStore;
 use crate::raft_types::*;
 use crate::TypeConfig;
 
-struct MemKVStoreBuilder {}
+struct DiskStoreBuilder {}
 
-impl StoreBuilder<TypeConfig, LogStore, Arc<StateMachineStore>, ()> for MemKVStoreBuilder {
-    async fn build(&self) -> Result<((), LogStore, Arc<StateMachineStore>), StorageError> {
-        Ok(((), LogStore::default(), Arc::default()))
+impl StoreBuilder<TypeConfig, LogStore, Arc<StateMachineStore>, TempDir> for DiskStoreBuilder {
+    async fn build(&self) -> Result<(TempDir, LogStore, Arc<StateMachineStore>), StorageError> {
+        let dir = TempDir::new().map_err(|e| StorageError::write(&e))?;
+        let log_store = LogStore::open(dir.path().join("log"), None).map_err(|e| StorageError::read_logs(&e))?;
+        let state_machine_store = StateMachineStore::open(dir.path().join("snapshots"), 3, None)
+            .await
+            .map_err(|e| StorageError::read_snapshot(None, &e))?;
+        Ok((dir, log_store, Arc::new(state_machine_store)))
     }
 }
 
+struct EncryptedDiskStoreBuilder {}
+
+impl StoreBuilder<TypeConfig, LogStore, Arc<StateMachineStore>, TempDir> for EncryptedDiskStoreBuilder {
+    async fn build(&self) -> Result<(TempDir, LogStore, Arc<StateMachineStore>), StorageError> {
+        let dir = TempDir::new().map_err(|e| StorageError::write(&e))?;
+        let keyring = Arc::new(keyring(&dir.path().join("keys"), &["test"]));
+        let log_store =
+            LogStore::open(dir.path().join("log"), Some(keyring.clone())).map_err(|e| StorageError::read_logs(&e))?;
+        let state_machine_store = StateMachineStore::open(dir.path().join("snapshots"), 3, Some(keyring))
+            .await
+            .map_err(|e| StorageError::read_snapshot(None, &e))?;
+        Ok((dir, log_store, Arc::new(state_machine_store)))
+    }
+}
+
+#[tokio::test]
+pub async fn test_disk_store() -> Result<(), StorageError> {
+    Suite::test_all(DiskStoreBuilder {}).await?;
+    Ok(())
+}
+
 #[tokio::test]
-pub async fn test_mem_store() -> Result<(), StorageError> {
-    Suite::test_all(MemKVStoreBuilder {}).await?;
+pub async fn test_encrypted_disk_store() -> Result<(), StorageError> {
+    Suite::test_all(EncryptedDiskStoreBuilder {}).await?;
     Ok(())
 }
@@ -0,0 +1,805 @@
+//! Mutual TLS between the nodes.
+//!
+//! Each node is identified by the URI `disco://<cluster>/node/<id>`, that its certificates carry
+//! as a subject alternative name. A node is verified against the URI of its id in the membership,
+//! not against the address it is dialed at.
+//!
+//! Clients must present a certificate issued by the cluster CA, except to request one: a node
+//! joining with a token has none yet.
+//!
+//! The certificates are reloaded without a restart. A CA file may hold several CA certificates,
+//! all of them trusted, so that the CA can be rolled across a live cluster.
+
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::{Arc, RwLock};
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use hyper_util::rt::TokioIo;
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::CryptoProvider;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime};
+use rustls::server::{ParsedCertificate, WebPkiClientVerifier};
+use rustls::{
+  CertificateError, ClientConfig, DigitallySignedStruct, RootCertStore, ServerConfig,
+  SignatureScheme,
+};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tokio_rustls::{TlsAcceptor, TlsConnector, server};
+use tonic::Status;
+use tonic::body::BoxBody;
+use tonic::codegen::http;
+use tonic::codegen::tokio_stream::wrappers::ReceiverStream;
+use tonic::transport::server::{TcpConnectInfo, TlsConnectInfo};
+use tonic::transport::{Channel, Endpoint, Uri};
+use tower::{Layer, Service};
+use x509_parser::extensions::GeneralName;
+
+use crate::node::runtime;
+use crate::protobuf as pb;
+
+/// Legacy identity of the nodes added without one, whose certificates were issued for
+/// `localhost`.
+const LEGACY_IDENTITY: &str = "localhost";
+
+/// Server name sent to nodes only identified by URIs, which cannot serve as one.
+const DEFAULT_SERVER_NAME: &str = "localhost";
+
+/// How long a client has to complete the TLS handshake.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Connections with a completed handshake waiting to be served, past which handshakes wait.
+const ACCEPT_BACKLOG: usize = 128;
+
+/// Delay before accepting connections again after failing to.
+const ACCEPT_RETRY_DELAY: Duration = Duration::from_millis(100);
+
+/// Methods served to clients without a certificate.
+const ANONYMOUS_METHODS: &[&str] = &["/disco.AppService/SignCertificate"];
+
+/// Returns the URI identity of node `id` of `cluster_name`.
+pub fn node_uri(cluster_name: &str, id: u64) -> String {
+  format!("disco://{}/node/{}", cluster_name, id)
+}
+
+/// Returns the id of the node of `cluster_name` that `uri` identifies, if it is a node URI.
+fn node_id(cluster_name: &str, uri: &str) -> Option<u64> {
+  uri
+    .strip_prefix("disco://")?
+    .strip_prefix(cluster_name)?
+    .strip_prefix("/node/")?
+    .parse()
+    .ok()
+}
+
+/// The identities the nodes of a cluster are recognized by in their certificates.
+///
+/// A node is identified by its URI, which only the certificates issued for it carry. Clusters
+/// whose certificates predate the URIs can opt into the legacy identities while they are
+/// reissued: the identity recorded for the node in the membership, or `localhost` for the nodes
+/// without one. Any certificate issued by the cluster CA for those names passes for the node, so
+/// they are only meant for the migration.
+#[derive(Clone, Debug)]
+pub struct NodeIdentities {
+  cluster_name: String,
+  legacy: bool,
+}
+
+impl NodeIdentities {
+  pub fn new(cluster_name: &str, legacy: bool) -> Self {
+    NodeIdentities {
+      cluster_name: cluster_name.to_string(),
+      legacy,
+    }
+  }
+
+  /// Returns the URI of `node`, which no certificate but its own carries.
+  pub fn uri(&self, node: &pb::Node) -> String {
+    node_uri(&self.cluster_name, node.node_id)
+  }
+
+  /// Returns the identities `node` may present, its URI first.
+  pub fn of(&self, node: &pb::Node) -> Vec<String> {
+    let mut identities = vec![self.uri(node)];
+
+    if self.legacy {
+      let legacy = match node.tls_identity.as_str() {
+        "" => LEGACY_IDENTITY,
+        identity => identity,
+      };
+      if legacy != identities[0] {
+        identities.push(legacy.to_string());
+      }
+    }
+
+    identities
+  }
+
+  /// Returns whether the certificate is issued for `node`.
+  pub fn authenticates(&self, cert: &CertificateDer<'_>, node: &pb::Node) -> bool {
+    self
+      .of(node)
+      .iter()
+      .any(|identity| presents(cert, identity))
+  }
+
+  /// Returns whether the certificate is issued for a node of the cluster, whichever it is.
+  ///
+  /// Only the URIs tell, and `localhost` with the legacy identities: the other names a node may
+  /// be recorded with are not known before its membership is.
+  pub fn is_node(&self, cert: &CertificateDer<'_>) -> bool {
+    names(cert)
+      .iter()
+      .any(|name| node_id(&self.cluster_name, name).is_some())
+      || (self.legacy && presents(cert, LEGACY_IDENTITY))
+  }
+}
+
+/// Returns whether `identity` is a URI rather than a DNS name.
+fn is_uri(identity: &str) -> bool {
+  identity.contains("://")
+}
+
+/// Returns the DNS names and URIs among the subject alternative names of a certificate.
+pub fn names(cert: &CertificateDer<'_>) -> Vec<String> {
+  let Ok((_, cert)) = x509_parser::parse_x509_certificate(cert) else {
+    return Vec::new();
+  };
+  let Ok(Some(san)) = cert.subject_alternative_name() else {
+    return Vec::new();
+  };
+
+  san
+    .value
+    .general_names
+    .iter()
+    .filter_map(|name| match name {
+      GeneralName::DNSName(name) | GeneralName::URI(name) => Some(name.to_string()),
+      _ => None,
+    })
+    .collect()
+}
+
+/// Returns the common name in the subject of a certificate, if it has one.
+pub fn common_name(cert: &CertificateDer<'_>) -> Option<String> {
+  let (_, cert) = x509_parser::parse_x509_certificate(cert).ok()?;
+  let common_name = cert.subject().iter_common_name().next()?;
+
+  common_name.as_str().ok().map(str::to_string)
+}
+
+/// Returns whether the certificate is issued for `identity`.
+///
+/// DNS names are matched as in server name verification, wildcards included; URIs must appear
+/// verbatim.
+pub fn presents(cert: &CertificateDer<'_>, identity: &str) -> bool {
+  if is_uri(identity) {
+    return names(cert).iter().any(|name| name == identity);
+  }
+
+  let (Ok(parsed), Ok(server_name)) = (
+    ParsedCertificate::try_from(cert),
+    ServerName::try_from(identity),
+  ) else {
+    return false;
+  };
+
+  rustls::client::verify_server_name(&parsed, &server_name).is_ok()
+}
+
+/// Reads the certificates of a PEM file.
+pub fn certificates(pem: &[u8]) -> io::Result<Vec<CertificateDer<'static>>> {
+  CertificateDer::pem_slice_iter(pem)
+    .collect::<Result<Vec<_>, _>>()
+    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/// Reads the private key of a PEM file.
+pub fn private_key(pem: &[u8]) -> io::Result<PrivateKeyDer<'static>> {
+  PrivateKeyDer::from_pem_slice(pem).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/// Reads the certificates of a PEM file into a store of trusted CAs.
+fn roots(pem: &[u8]) -> io::Result<RootCertStore> {
+  let mut roots = RootCertStore::empty();
+  for ca in certificates(pem)? {
+    roots
+      .add(ca)
+      .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+  }
+
+  Ok(roots)
+}
+
+/// Returns the process-wide crypto provider, aws-lc-rs if none was installed.
+fn crypto_provider() -> Arc<CryptoProvider> {
+  CryptoProvider::get_default()
+    .cloned()
+    .unwrap_or_else(|| Arc::new(rustls::crypto::aws_lc_rs::default_provider()))
+}
+
+/// Client side of the mutual TLS between the nodes: presents the certificate of this node and
+/// trusts the cluster CA.
+///
+/// The certificates can be reloaded while channels are open: each connection, including the ones
+/// a channel makes again after losing its own, is set up with the certificates current at the
+/// time.
+#[derive(Debug)]
+pub struct TlsClient {
+  credentials: Arc<RwLock<Arc<ClientCredentials>>>,
+}
+
+#[derive(Debug)]
+struct ClientCredentials {
+  roots: Arc<RootCertStore>,
+
+  /// Certificate chain and key presented to the servers, none for a node yet to be issued one.
+  client_auth: Option<(Vec<CertificateDer<'static>>, PrivateKeyDer<'static>)>,
+}
+
+impl ClientCredentials {
+  fn new(ca_cert: &[u8], cert: &[u8], key: &[u8]) -> io::Result<Self> {
+    Ok(ClientCredentials {
+      roots: Arc::new(roots(ca_cert)?),
+      client_auth: Some((certificates(cert)?, private_key(key)?)),
+    })
+  }
+
+  /// Returns a configuration that only accepts a server certificate issued for one of
+  /// `identities`, or any issued by the cluster CA if `None`.
+  fn config(&self, identities: Option<&[String]>) -> io::Result<ClientConfig> {
+    let provider = crypto_provider();
+
+    let verifier = IdentityVerifier {
+      roots: self.roots.clone(),
+      identities: identities.map(<[String]>::to_vec),
+      provider: provider.clone(),
+    };
+
+    let builder = ClientConfig::builder_with_provider(provider)
+      .with_safe_default_protocol_versions()
+      .map_err(io::Error::other)?
+      .dangerous()
+      .with_custom_certificate_verifier(Arc::new(verifier));
+
+    let mut config = match &self.client_auth {
+      Some((cert_chain, key)) => builder
+        .with_client_auth_cert(cert_chain.clone(), key.clone_key())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?,
+      None => builder.with_no_client_auth(),
+    };
+    config.alpn_protocols = vec![b"h2".to_vec()];
+
+    Ok(config)
+  }
+}
+
+impl TlsClient {
+  pub fn new(ca_cert: &[u8], cert: &[u8], key: &[u8]) -> io::Result<Self> {
+    let credentials = ClientCredentials::new(ca_cert, cert, key)?;
+    credentials.config(None)?;
+
+    Ok(TlsClient {
+      credentials: Arc::new(RwLock::new(Arc::new(credentials))),
+    })
+  }
+
+  /// Returns a client that presents no certificate, for a node requesting its own.
+  pub fn anonymous(ca_cert: &[u8]) -> io::Result<Self> {
+    let credentials = ClientCredentials {
+      roots: Arc::new(roots(ca_cert)?),
+      client_auth: None,
+    };
+
+    Ok(TlsClient {
+      credentials: Arc::new(RwLock::new(Arc::new(credentials))),
+    })
+  }
+
+  /// Takes over the trusted CAs and the certificate of `other`, for the connections set up from
+  /// then on.
+  pub fn replace(&self, other: TlsClient) {
+    let credentials = other.credentials.read().unwrap().clone();
+    *self.credentials.write().unwrap() = credentials;
+  }
+
+  /// Opens a channel to the node serving at `addr`, which must present one of `identities`.
+  pub async fn connect(
+    &self,
+    addr: &str,
+    identities: &[String],
+    connect_timeout: Duration,
+  ) -> Result<Channel, Box<dyn std::error::Error + Send + Sync>> {
+    self.dial(addr, Some(identities), connect_timeout).await
+  }
+
+  /// Opens a channel to whichever node of the cluster serves at `addr`.
+  pub async fn connect_any(
+    &self,
+    addr: &str,
+    connect_timeout: Duration,
+  ) -> Result<Channel, Box<dyn std::error::Error + Send + Sync>> {
+    self.dial(addr, None, connect_timeout).await
+  }
+
+  async fn dial(
+    &self,
+    addr: &str,
+    identities: Option<&[String]>,
+    connect_timeout: Duration,
+  ) -> Result<Channel, Box<dyn std::error::Error + Send + Sync>> {
+    // The name only serves as SNI; the certificate is checked against the identities.
+    let dns_name = identities
+      .unwrap_or_default()
+      .iter()
+      .find(|identity| !is_uri(identity));
+    let server_name = match dns_name.map(|name| ServerName::try_from(name.as_str())) {
+      Some(Ok(name)) => name.to_owned(),
+      _ => ServerName::try_from(DEFAULT_SERVER_NAME)?,
+    };
+
+    let credentials = self.credentials.clone();
+    let identities = identities.map(<[String]>::to_vec);
+
+    // TLS is set up by the connector, so the channel itself speaks plain HTTP/2.
+    let channel = Endpoint::from_shared(format!("http://{}", addr))?
+      .http2_keep_alive_interval(Duration::from_secs(30))
+      .keep_alive_timeout(Duration::from_secs(5))
+      .connect_with_connector(tower::service_fn(move |uri: Uri| {
+        let credentials = credentials.clone();
+        let identities = identities.clone();
+        let server_name = server_name.clone();
+
+        async move {
+          let credentials = credentials.read().unwrap().clone();
+          let config = credentials.config(identities.as_deref())?;
+          let connector = TlsConnector::from(Arc::new(config));
+          let authority = uri.authority().map(|a| a.to_string()).unwrap_or_default();
+
+          let tls = tokio::time::timeout(connect_timeout, async {
+            let tcp = TcpStream::connect(authority).await?;
+            tcp.set_nodelay(true)?;
+            connector.connect(server_name, tcp).await
+          })
+          .await
+          .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "timed out connecting"))??;
+
+          Ok::<_, io::Error>(TokioIo::new(tls))
+        }
+      }))
+      .await?;
+
+    Ok(channel)
+  }
+}
+
+/// Server side of the mutual TLS between the nodes: presents the server certificate of this node
+/// and verifies the client certificates that are presented against the cluster CA.
+///
+/// The certificates can be reloaded while serving. Connections accepted from then on use the new
+/// ones, while established connections keep going with the ones they were set up with.
+#[derive(Debug)]
+pub struct TlsServer {
+  config: RwLock<Arc<ServerConfig>>,
+}
+
+impl TlsServer {
+  pub fn new(ca_cert: &[u8], cert: &[u8], key: &[u8]) -> io::Result<Self> {
+    Ok(TlsServer {
+      config: RwLock::new(Arc::new(server_config(ca_cert, cert, key)?)),
+    })
+  }
+
+  /// Takes over the trusted CAs and the certificate of `other`, for the connections accepted
+  /// from then on.
+  pub fn replace(&self, other: TlsServer) {
+    let config = other.config.read().unwrap().clone();
+    *self.config.write().unwrap() = config;
+  }
+
+  /// Accepts connections on `listener`, yielding each once its handshake completes.
+  ///
+  /// Handshakes run concurrently, so that a slow client does not hold up the others, each with
+  /// the certificates current when its connection came in.
+  pub fn incoming(
+    self: Arc<Self>,
+    listener: TcpListener,
+  ) -> ReceiverStream<io::Result<server::TlsStream<TcpStream>>> {
+    let (sender, receiver) = mpsc::channel(ACCEPT_BACKLOG);
+
+    runtime::spawn(async move {
+      while !sender.is_closed() {
+        let (tcp, peer_addr) = match listener.accept().await {
+          Ok(accepted) => accepted,
+          Err(e) => {
+            // Mostly running out of file descriptors, which only waiting can solve
+            tracing::warn!("Failed to accept a connection: {}", e);
+            tokio::time::sleep(ACCEPT_RETRY_DELAY).await;
+            continue;
+          }
+        };
+
+        let acceptor = TlsAcceptor::from(self.config.read().unwrap().clone());
+        let sender = sender.clone();
+
+        runtime::spawn(async move {
+          if let Err(e) = tcp.set_nodelay(true) {
+            tracing::debug!("Failed to set TCP_NODELAY for {}: {}", peer_addr, e);
+          }
+
+          match tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(tcp)).await {
+            Ok(Ok(tls)) => {
+              let _ = sender.send(Ok(tls)).await;
+            }
+            Ok(Err(e)) => tracing::debug!("TLS handshake with {} failed: {}", peer_addr, e),
+            Err(_) => tracing::debug!("TLS handshake with {} timed out", peer_addr),
+          }
+        });
+      }
+    });
+
+    ReceiverStream::new(receiver)
+  }
+}
+
+/// Returns a configuration presenting `cert` and accepting clients without a certificate, or
+/// with one issued by a CA of `ca_cert`.
+fn server_config(ca_cert: &[u8], cert: &[u8], key: &[u8]) -> io::Result<ServerConfig> {
+  let provider = crypto_provider();
+
+  let verifier =
+    WebPkiClientVerifier::builder_with_provider(Arc::new(roots(ca_cert)?), provider.clone())
+      .allow_unauthenticated()
+      .build()
+      .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+  let mut config = ServerConfig::builder_with_provider(provider)
+    .with_safe_default_protocol_versions()
+    .map_err(io::Error::other)?
+    .with_client_cert_verifier(verifier)
+    .with_single_cert(certificates(cert)?, private_key(key)?)
+    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+  config.alpn_protocols = vec![b"h2".to_vec()];
+
+  Ok(config)
+}
+
+/// Verifies that the server certificate chains up to the cluster CA and, if known, is issued for
+/// one of the identities of the node being dialed.
+#[derive(Debug)]
+struct IdentityVerifier {
+  roots: Arc<RootCertStore>,
+  identities: Option<Vec<String>>,
+  provider: Arc<CryptoProvider>,
+}
+
+impl ServerCertVerifier for IdentityVerifier {
+  fn verify_server_cert(
+    &self,
+    end_entity: &CertificateDer<'_>,
+    intermediates: &[CertificateDer<'_>],
+    _server_name: &ServerName<'_>,
+    _ocsp_response: &[u8],
+    now: UnixTime,
+  ) -> Result<ServerCertVerified, rustls::Error> {
+    let cert = ParsedCertificate::try_from(end_entity)?;
+    rustls::client::verify_server_cert_signed_by_trust_anchor(
+      &cert,
+      &self.roots,
+      intermediates,
+      now,
+      self.provider.signature_verification_algorithms.all,
+    )?;
+
+    if let Some(identities) = &self.identities
+      && !identities
+        .iter()
+        .any(|identity| presents(end_entity, identity))
+    {
+      return Err(rustls::Error::InvalidCertificate(
+        CertificateError::NotValidForName,
+      ));
+    }
+
+    Ok(ServerCertVerified::assertion())
+  }
+
+  fn verify_tls12_signature(
+    &self,
+    message: &[u8],
+    cert: &CertificateDer<'_>,
+    dss: &DigitallySignedStruct,
+  ) -> Result<HandshakeSignatureValid, rustls::Error> {
+    rustls::crypto::verify_tls12_signature(
+      message,
+      cert,
+      dss,
+      &self.provider.signature_verification_algorithms,
+    )
+  }
+
+  fn verify_tls13_signature(
+    &self,
+    message: &[u8],
+    cert: &CertificateDer<'_>,
+    dss: &DigitallySignedStruct,
+  ) -> Result<HandshakeSignatureValid, rustls::Error> {
+    rustls::crypto::verify_tls13_signature(
+      message,
+      cert,
+      dss,
+      &self.provider.signature_verification_algorithms,
+    )
+  }
+
+  fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+    self
+      .provider
+      .signature_verification_algorithms
+      .supported_schemes()
+  }
+}
+
+/// Returns the certificates the client of a request presented, if any.
+pub fn peer_certs<B>(request: &http::Request<B>) -> Option<Arc<Vec<CertificateDer<'static>>>> {
+  request
+    .extensions()
+    .get::<TlsConnectInfo<TcpConnectInfo>>()
+    .and_then(|info| info.peer_certs())
+    .filter(|certs| !certs.is_empty())
+}
+
+/// Rejects the requests of clients that did not present a certificate with `UNAUTHENTICATED`,
+/// except to the methods meant for nodes that have none yet.
+///
+/// The server only asks for client certificates, so that these methods can be reached; the
+/// certificates that are presented are still verified against the cluster CA.
+#[derive(Clone, Debug, Default)]
+pub struct ClientCertLayer;
+
+impl<S> Layer<S> for ClientCertLayer {
+  type Service = ClientCert<S>;
+
+  fn layer(&self, inner: S) -> Self::Service {
+    ClientCert { inner }
+  }
+}
+
+#[derive(Clone, Debug)]
+pub struct ClientCert<S> {
+  inner: S,
+}
+
+impl<S, B> Service<http::Request<B>> for ClientCert<S>
+where
+  S: Service<http::Request<B>, Response = http::Response<BoxBody>>,
+  S::Future: Send + 'static,
+{
+  type Response = S::Response;
+  type Error = S::Error;
+  type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+  fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+    self.inner.poll_ready(cx)
+  }
+
+  fn call(&mut self, request: http::Request<B>) -> Self::Future {
+    if peer_certs(&request).is_some() || ANONYMOUS_METHODS.contains(&request.uri().path()) {
+      return Box::pin(self.inner.call(request));
+    }
+
+    let response = Status::unauthenticated("A client certificate is required").into_http();
+    Box::pin(async move { Ok(response) })
+  }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+  use rcgen::{CertificateParams, DnType, KeyPair};
+  use tonic::transport::Server;
+  use tonic_health::pb::HealthCheckRequest;
+  use tonic_health::pb::health_client::HealthClient;
+
+  use super::*;
+  use crate::ca::{CertificateAuthority, Issued, Usage};
+
+  const TIMEOUT: Duration = Duration::from_secs(5);
+
+  fn issue(ca: &CertificateAuthority, node_id: u64, usage: Usage) -> Issued {
+    ca.issue("test", node_id, usage, &[]).unwrap()
+  }
+
+  fn server(ca_pem: &str, ca: &CertificateAuthority) -> TlsServer {
+    let issued = issue(ca, 1, Usage::Server);
+    TlsServer::new(
+      ca_pem.as_bytes(),
+      issued.cert_pem.as_bytes(),
+      issued.key_pem.as_bytes(),
+    )
+    .unwrap()
+  }
+
+  fn client(ca_pem: &str, ca: &CertificateAuthority) -> TlsClient {
+    let issued = issue(ca, 2, Usage::Client);
+    TlsClient::new(
+      ca_pem.as_bytes(),
+      issued.cert_pem.as_bytes(),
+      issued.key_pem.as_bytes(),
+    )
+    .unwrap()
+  }
+
+  /// Serves the health service over `server`, returning the address it listens at.
+  async fn serve(server: Arc<TlsServer>) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    let (_, health_service) = tonic_health::server::health_reporter();
+
+    runtime::spawn(
+      Server::builder()
+        .layer(ClientCertLayer)
+        .add_service(health_service)
+        .serve_with_incoming(server.incoming(listener)),
+    );
+
+    addr
+  }
+
+  /// Whether a health check over a new connection from `client` succeeds.
+  async fn check(client: &TlsClient, addr: &str) -> Result<(), Status> {
+    let channel = client
+      .connect(addr, &[node_uri("test", 1)], TIMEOUT)
+      .await
+      .map_err(|e| Status::unavailable(e.to_string()))?;
+
+    HealthClient::new(channel)
+      .check(HealthCheckRequest::default())
+      .await
+      .map(|_| ())
+  }
+
+  fn node(node_id: u64, tls_identity: &str) -> pb::Node {
+    pb::Node {
+      node_id,
+      rpc_addr: String::new(),
+      tls_identity: tls_identity.to_string(),
+    }
+  }
+
+  /// A certificate issued by `ca` to `subject` for `dns_name` only, without a node URI, as the
+  /// nodes had before the URIs.
+  pub(crate) fn issue_dns(
+    ca: &CertificateAuthority,
+    subject: &str,
+    dns_name: &str,
+  ) -> CertificateDer<'static> {
+    let ca_key = KeyPair::from_pem(&ca.key_pem()).unwrap();
+    let ca_cert = CertificateParams::from_ca_cert_pem(&ca.cert_pem())
+      .unwrap()
+      .self_signed(&ca_key)
+      .unwrap();
+
+    let mut params = CertificateParams::new(vec![dns_name.to_string()]).unwrap();
+    params.distinguished_name.push(DnType::CommonName, subject);
+    let cert = params
+      .signed_by(&KeyPair::generate().unwrap(), &ca_cert, &ca_key)
+      .unwrap();
+
+    cert.der().clone()
+  }
+
+  #[test]
+  fn test_node_identities() {
+    let ca = CertificateAuthority::create("test").unwrap();
+    let node_1 = certificates(issue(&ca, 1, Usage::Client).cert_pem.as_bytes()).unwrap();
+    let dns = ca
+      .issue("test", 2, Usage::Client, &["db.example.com".to_string()])
+      .unwrap();
+    let dns = certificates(dns.cert_pem.as_bytes()).unwrap();
+    let other_cluster = ca.issue("other", 1, Usage::Client, &[]).unwrap();
+    let other_cluster = certificates(other_cluster.cert_pem.as_bytes()).unwrap();
+    let client = ca.issue_client("test", "monitoring").unwrap();
+    let client = certificates(client.cert_pem.as_bytes()).unwrap();
+    let localhost = issue_dns(&ca, "node", LEGACY_IDENTITY);
+
+    let identities = NodeIdentities::new("test", false);
+    assert_eq!(identities.of(&node(1, "")), vec![node_uri("test", 1)]);
+    assert_eq!(
+      identities.of(&node(1, "db.example.com")),
+      vec![node_uri("test", 1)]
+    );
+
+    // Nodes are only recognized by their URI
+    assert!(identities.authenticates(&node_1[0], &node(1, "")));
+    assert!(!identities.authenticates(&node_1[0], &node(2, "")));
+    assert!(!identities.authenticates(&dns[0], &node(3, "db.example.com")));
+    assert!(!identities.authenticates(&other_cluster[0], &node(1, "")));
+    assert!(!identities.authenticates(&localhost, &node(1, "")));
+
+    assert!(identities.is_node(&node_1[0]));
+    assert!(identities.is_node(&dns[0]));
+    assert!(!identities.is_node(&other_cluster[0]));
+    assert!(!identities.is_node(&client[0]));
+    assert!(!identities.is_node(&localhost));
+
+    // With the legacy identities, nodes are also recognized by the one recorded for them
+    let legacy = NodeIdentities::new("test", true);
+    assert_eq!(
+      legacy.of(&node(1, "")),
+      vec![node_uri("test", 1), LEGACY_IDENTITY.to_string()]
+    );
+    assert_eq!(
+      legacy.of(&node(1, &node_uri("test", 1))),
+      vec![node_uri("test", 1)]
+    );
+
+    assert!(legacy.authenticates(&node_1[0], &node(1, "")));
+    assert!(legacy.authenticates(&localhost, &node(1, "")));
+    assert!(!legacy.authenticates(&localhost, &node(1, "db.example.com")));
+    assert!(legacy.authenticates(&dns[0], &node(3, "db.example.com")));
+
+    assert!(legacy.is_node(&localhost));
+    assert!(!legacy.is_node(&client[0]));
+  }
+
+  #[tokio::test]
+  async fn test_mutual_tls() {
+    let ca = CertificateAuthority::create("test").unwrap();
+    let other = CertificateAuthority::create("test").unwrap();
+    let addr = serve(Arc::new(server(&ca.cert_pem(), &ca))).await;
+
+    assert!(check(&client(&ca.cert_pem(), &ca), &addr).await.is_ok());
+
+    // The server must present the identity of the node being dialed
+    let channel = client(&ca.cert_pem(), &ca)
+      .connect(&addr, &[node_uri("test", 3)], TIMEOUT)
+      .await;
+    assert!(channel.is_err());
+
+    // Neither side accepts certificates of another CA
+    assert!(check(&client(&other.cert_pem(), &ca), &addr).await.is_err());
+    assert!(check(&client(&ca.cert_pem(), &other), &addr).await.is_err());
+
+    // Clients without a certificate are turned away
+    let anonymous = TlsClient::anonymous(ca.cert_pem().as_bytes()).unwrap();
+    let status = check(&anonymous, &addr).await.unwrap_err();
+    assert_eq!(status.code(), tonic::Code::Unauthenticated);
+  }
+
+  #[tokio::test]
+  async fn test_reload_server() {
+    let old = CertificateAuthority::create("test").unwrap();
+    let new = CertificateAuthority::create("test").unwrap();
+    let bundle = format!("{}{}", old.cert_pem(), new.cert_pem());
+
+    let tls_server = Arc::new(server(&old.cert_pem(), &old));
+    let addr = serve(tls_server.clone()).await;
+    let old_client = client(&old.cert_pem(), &old);
+    let new_client = client(&bundle, &new);
+    assert!(check(&old_client, &addr).await.is_ok());
+    assert!(check(&new_client, &addr).await.is_err());
+
+    // While rolling the CA, the server trusts clients of both
+    tls_server.replace(server(&bundle, &new));
+    assert!(check(&new_client, &addr).await.is_ok());
+    assert!(check(&client(&bundle, &old), &addr).await.is_ok());
+    assert!(check(&old_client, &addr).await.is_err());
+  }
+
+  #[tokio::test]
+  async fn test_reload_client() {
+    let old = CertificateAuthority::create("test").unwrap();
+    let new = CertificateAuthority::create("test").unwrap();
+    let addr = serve(Arc::new(server(&new.cert_pem(), &new))).await;
+
+    let tls_client = client(&old.cert_pem(), &old);
+    assert!(check(&tls_client, &addr).await.is_err());
+
+    tls_client.replace(client(&new.cert_pem(), &new));
+    assert!(check(&tls_client, &addr).await.is_ok());
+  }
+}
@@ -0,0 +1,114 @@
+//! Exports spans to an in-process OTLP receiver and checks that the trace context of a request
+//! is carried over gRPC and through replicated commands.
+
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+
+use disco_daemon::telemetry;
+use opentelemetry_proto::tonic::collector::trace::v1::trace_service_server::{
+  TraceService, TraceServiceServer,
+};
+use opentelemetry_proto::tonic::collector::trace::v1::{
+  ExportTraceServiceRequest, ExportTraceServiceResponse,
+};
+use opentelemetry_proto::tonic::trace::v1::Span;
+use tokio::net::TcpListener;
+use tonic::transport::server::TcpIncoming;
+use tonic::transport::{Channel, Server};
+use tonic::{Request, Response, Status};
+use tonic_health::pb::HealthCheckRequest;
+use tonic_health::pb::health_client::HealthClient;
+use tracing::Instrument;
+
+/// Keeps the spans it receives.
+#[derive(Clone, Default)]
+struct Collector(Arc<Mutex<Vec<Span>>>);
+
+#[tonic::async_trait]
+impl TraceService for Collector {
+  async fn export(
+    &self,
+    request: Request<ExportTraceServiceRequest>,
+  ) -> Result<Response<ExportTraceServiceResponse>, Status> {
+    let spans = request
+      .into_inner()
+      .resource_spans
+      .into_iter()
+      .flat_map(|spans| spans.scope_spans)
+      .flat_map(|spans| spans.spans);
+    self.0.lock().unwrap().extend(spans);
+
+    Ok(Response::new(ExportTraceServiceResponse::default()))
+  }
+}
+
+async fn listen() -> (SocketAddr, TcpIncoming) {
+  let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+  let addr = listener.local_addr().unwrap();
+  (
+    addr,
+    TcpIncoming::from_listener(listener, true, None).unwrap(),
+  )
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_spans_are_exported_with_propagated_parent() {
+  let collector = Collector::default();
+  let (collector_addr, incoming) = listen().await;
+  tokio::spawn(
+    Server::builder()
+      .add_service(TraceServiceServer::new(collector.clone()))
+      .serve_with_incoming(incoming),
+  );
+
+  let (_, health) = tonic_health::server::health_reporter();
+  let (addr, incoming) = listen().await;
+  tokio::spawn(
+    Server::builder()
+      .layer(telemetry::TraceContextLayer)
+      .add_service(health)
+      .serve_with_incoming(incoming),
+  );
+
+  let endpoint = format!("http://{}", collector_addr);
+  let guard = telemetry::init("test", Some(endpoint.as_str())).unwrap();
+
+  let carrier = async {
+    let channel = Channel::from_shared(format!("http://{}", addr))
+      .unwrap()
+      .connect()
+      .await
+      .unwrap();
+    HealthClient::new(telemetry::traced(channel))
+      .check(HealthCheckRequest::default())
+      .await
+      .unwrap();
+    telemetry::current_context()
+  }
+  .instrument(tracing::info_span!("client"))
+  .await;
+
+  // Replicated commands carry the context of the request that proposed them
+  let apply = tracing::info_span!("apply");
+  telemetry::set_parent(&apply, &carrier);
+  drop(apply);
+
+  // Flushing waits for the export, which runs on the runtime
+  tokio::task::spawn_blocking(move || drop(guard))
+    .await
+    .unwrap();
+
+  let spans = collector.0.lock().unwrap();
+  let span = |name: &str| {
+    spans
+      .iter()
+      .find(|span| span.name == name)
+      .unwrap_or_else(|| panic!("no {} span in {:?}", name, spans))
+  };
+
+  let client = span("client");
+  for name in ["grpc", "apply"] {
+    assert_eq!(span(name).trace_id, client.trace_id, "{}", name);
+    assert_eq!(span(name).parent_span_id, client.span_id, "{}", name);
+  }
+}
@@ -44,7 +44,7 @@ rpc() {
     local method=$2
     local body="$3"
     local isApiService="$4"
-    cmd="grpcurl -plaintext -proto ./disco-daemon/proto/app.proto -d $body -import-path ./disco-daemon/proto localhost:$port disco.AppService/$method"
+    cmd="grpcurl -plaintext -d $body localhost:$port disco.AppService/$method"
 
     echo '---'" rpc($BASE_HOST:$port/$method, $body)"
 